@@ -20,11 +20,54 @@
  * SOFTWARE.
  */
 
-var TINYCOMMENTS_PATH = '/tinycomments';
+// Where the server is, from the script tag's data-path attribute.
+var TINYCOMMENTS_PATH = document.currentScript?.dataset.path ?? '/tinycomments';
+// The versioned API under it.
+var TINYCOMMENTS_API = `${TINYCOMMENTS_PATH}/api/v1`;
+
+// ETag of the thread currently shown, so unchanged threads aren't downloaded and redrawn.
+var comments_etag = null;
+
+// Length limits the server reports with each thread, checked before posting.
+var comment_limits = {};
+
+// Name of the hidden field bots fill in, if the server has one configured.
+var honeypot_field = null;
+
+// Fetch the settings the widget needs before anyone posts, and add the honeypot field to the
+// comment form. It is kept off-screen and out of the tab order so people leave it empty.
+async function bootstrap() {
+    let json;
+    try {
+        let res = await fetch(`${TINYCOMMENTS_API}/widget/bootstrap/`, { credentials: 'include' });
+        json = await res.json();
+        honeypot_field = json['honeypot_field'];
+    } catch (error) {
+        return;
+    }
+
+    if (json['subscriptions']) {
+        let label = document.createElement('label');
+        label.innerHTML = `<input type='checkbox' id='commentSubscribe'/> Email me about new comments on this post<br/>`;
+        document.getElementById('commentButton').before(label);
+    }
+
+    if (honeypot_field) {
+        let input = document.createElement('input');
+        input.type = 'text';
+        input.id = 'commentHoneypot';
+        input.name = honeypot_field;
+        input.tabIndex = -1;
+        input.autocomplete = 'off';
+        input.setAttribute('aria-hidden', 'true');
+        input.style = 'position: absolute; left: -10000px;';
+        document.getElementById('commentText').after(input);
+    }
+}
 
 async function get_comments() {
     let b64 = btoa(normalize_uri());
-    let url = `${TINYCOMMENTS_PATH}/comment/get/`;
+    let url = `${TINYCOMMENTS_API}/comment/get/`;
 
     let commenter_id = await get_commenter_id('', '', false);
     let comment_data = new URLSearchParams();
@@ -34,7 +77,12 @@ async function get_comments() {
     let json;
 
     try {
-        let res = await fetch(url, { method: 'POST', body: comment_data });
+        let headers = comments_etag ? { 'If-None-Match': comments_etag } : {};
+        let res = await fetch(url, { method: 'POST', body: comment_data, headers: headers, credentials: 'include' });
+        if (res.status == 304) {
+            return null;
+        }
+        comments_etag = res.headers.get('ETag');
         json = await res.json();
     } catch (error) {
         update_status(`Error getting comments: ${error}`);
@@ -49,7 +97,7 @@ async function get_comments() {
 
         update_status('Client puzzle solved.');
         try {
-            let res = await fetch(url, { method: 'POST', body: comment_data });
+            let res = await fetch(url, { method: 'POST', body: comment_data, credentials: 'include' });
             json = await res.json();
 
             if (json['code'] != 200) {
@@ -67,6 +115,15 @@ async function get_comments() {
         root.removeChild(root.firstChild);
     }
 
+    comment_limits = { max_bytes: json['max_comment_bytes'], min_chars: json['min_comment_chars'] };
+
+    if (json['locked']) {
+        let newcomment = document.getElementById('newcomment');
+        if (newcomment) {
+            newcomment.textContent = 'Comments are locked on this post.';
+        }
+    }
+
     let n_comments = 0;
 
     for (row of json['comments']) {
@@ -83,8 +140,20 @@ async function get_comments() {
         div.id = `comment-${row['id']}`;
 
         let date = new Date(row['timestamp'] * 1000);
-        name_date.textContent = 'On ' + date.toLocaleString('en-us') + ` ${row['poster_name']} wrote: (${row['votes']} upvotes!)`;
-        comment.innerHTML = row['comment'];
+        let verified = row['verified'] ? ' \u2713' : '';
+        let pinned = row['pinned'] ? '[Pinned] ' : '';
+        let author = row['is_author'] ? ' (author)' : '';
+        name_date.textContent = pinned + 'On ' + date.toLocaleString('en-us') + ` ${row['poster_name']}${verified}${author} wrote: (${row['votes']} upvotes!)`;
+        comment.innerHTML = row['comment_html'] ?? row['comment'];
+
+        if (row['avatar_url']) {
+            let avatar = document.createElement('img');
+            avatar.src = row['avatar_url'];
+            avatar.width = 32;
+            avatar.height = 32;
+            avatar.alt = '';
+            name_date.prepend(avatar);
+        }
 
         replyp.id = `replybox-${row['id']}`;
 
@@ -152,13 +221,42 @@ async function get_comments() {
             downvote.style.fontWeight = null;
         }
 
-        votediv.append(upvote);
-        votediv.append(downvote);
+        let flag = document.createElement('a');
+        flag.textContent = 'Report';
+        flag.style.cursor = 'pointer';
+        flag.addEventListener('click', function(id) {
+            return function() {
+                flag_comment(id);
+            }
+        }(row['id']));
+
+        let voting = !json['voting_locked'] && json['voting_mode'] != 'disabled';
+        if (voting) {
+            votediv.append(upvote);
+            if (json['voting_mode'] != 'up_only') {
+                votediv.append(downvote);
+            }
+            votediv.append(' ');
+        }
+        votediv.append(flag);
 
         div.append(name_date);
         div.append(votediv);
+        if (row['collapsed']) {
+            let unfold = document.createElement('a');
+            unfold.textContent = 'Show hidden comment';
+            unfold.style.cursor = 'pointer';
+            unfold.addEventListener('click', function() {
+                comment.style.display = null;
+                unfold.remove();
+            });
+            comment.style.display = 'none';
+            div.append(unfold);
+        }
         div.append(comment);
-        div.append(replyp);
+        if (!json['locked']) {
+            div.append(replyp);
+        }
         div.append(replydiv);
 
         li.append(div);
@@ -180,6 +278,15 @@ async function get_comments() {
     }
 
     document.getElementById('commentCount').textContent = `There are ${n_comments} comments on this post.`;
+
+    // Links to a single comment end in #comment-<id>; the comment only exists once loaded.
+    if (/^#comment-\d+$/.test(window.location.hash)) {
+        let linked = document.getElementById(window.location.hash.substring(1));
+        if (linked) {
+            linked.classList.add('tinycomments-linked');
+            linked.scrollIntoView();
+        }
+    }
 }
 
 function reply_box_show(id) {
@@ -247,11 +354,26 @@ function reply_comment(parent) {
                  document.getElementById(`replyCommentText-${parent}`).value, parent);
 }
 
+// After an OAuth login the server redirects back with the new commenter id in the URL fragment.
+function take_oauth_commenter_id() {
+    let prefix = '#tinycomments_commenter_id=';
+    if (window.location.hash.startsWith(prefix)) {
+        localStorage.setItem('tinycomments_commenter_id', window.location.hash.substring(prefix.length));
+        history.replaceState(null, '', window.location.pathname + window.location.search);
+    }
+}
+
+function oauth_login(provider) {
+    let return_to = encodeURIComponent(window.location.href.split('#')[0]);
+    window.location.href = `${TINYCOMMENTS_PATH}/oauth/${provider}/login/?return_to=${return_to}`;
+}
+
 async function get_commenter_id(name, email, force=false) {
-    let url = `${TINYCOMMENTS_PATH}/id/`;
+    let url = `${TINYCOMMENTS_API}/id/`;
 
+    // Older versions stored 'session' in place of the id when it was in a cookie; get a real one.
     let commenter_id = localStorage.getItem('tinycomments_commenter_id');
-    if (commenter_id && commenter_id.length > 0 && force == false) {
+    if (commenter_id && commenter_id.length > 0 && commenter_id != 'session' && force == false) {
         return commenter_id;
     } else {
         let id_data = new URLSearchParams();
@@ -261,7 +383,7 @@ async function get_commenter_id(name, email, force=false) {
         let json;
 
         try {
-            let res = await fetch(url, { method: 'POST', body: id_data });
+            let res = await fetch(url, { method: 'POST', body: id_data, credentials: 'include' });
             json = await res.json();
         } catch (error) {
             update_status(`Error getting poster id: ${error}`);
@@ -276,7 +398,7 @@ async function get_commenter_id(name, email, force=false) {
 
             update_status('Client puzzle solved.');
             try {
-                let res = await fetch(url, { method: 'POST', body: id_data });
+                let res = await fetch(url, { method: 'POST', body: id_data, credentials: 'include' });
                 json = await res.json();
 
                 if (json['code'] != 200) {
@@ -290,8 +412,11 @@ async function get_commenter_id(name, email, force=false) {
         }
 
         if (json['code'] == 200) {
-            localStorage.setItem('tinycomments_commenter_id', json['commenter_id']);
-            return json['commenter_id'];
+            // With sessions the id is also in an HttpOnly cookie, which the server prefers. Keep the
+            // id here too, for browsers that won't send cookies to another site.
+            let commenter_id = json['commenter_id'];
+            localStorage.setItem('tinycomments_commenter_id', commenter_id);
+            return commenter_id;
         } else {
             update_status(`Unable to generate id: ${json['status']}`);
             return null;
@@ -299,14 +424,33 @@ async function get_commenter_id(name, email, force=false) {
     }
 }
 
+function comment_length_error(comment) {
+    let max = comment_limits['max_bytes'];
+    let min = comment_limits['min_chars'];
+
+    if (max && new TextEncoder().encode(comment).length > max) {
+        return `Comments may be at most ${max} bytes`;
+    }
+    if (min && [...comment.trim()].length < min) {
+        return `Comments must be at least ${min} characters`;
+    }
+    return null;
+}
+
 async function post_comment(name, email, comment, parent) {
+    let length_error = comment_length_error(comment);
+    if (length_error) {
+        update_status(length_error);
+        return;
+    }
+
     let commenter_id = await get_commenter_id(name, email, false);
     if (commenter_id.length == 0) {
         return; // status text is handled by get_commenter_id
     }
 
     let b64 = btoa(normalize_uri());
-    let url = `${TINYCOMMENTS_PATH}/comment/post/`;
+    let url = `${TINYCOMMENTS_API}/comment/post/`;
 
     let comment_data = new URLSearchParams();
     comment_data.append('article', b64);
@@ -314,10 +458,20 @@ async function post_comment(name, email, comment, parent) {
     comment_data.append('comment', comment);
     comment_data.append('parent', parent);
 
+    let subscribe = document.getElementById('commentSubscribe');
+    if (subscribe && subscribe.checked) {
+        comment_data.append('subscribe', 'true');
+    }
+
+    let honeypot = document.getElementById('commentHoneypot');
+    if (honeypot_field && honeypot) {
+        comment_data.append(honeypot_field, honeypot.value);
+    }
+
     let json;
 
     try {
-        let res = await fetch(url, { method: 'POST', body: comment_data });
+        let res = await fetch(url, { method: 'POST', body: comment_data, credentials: 'include' });
         json = await res.json();
     } catch (error) {
         update_status(`Error posting comment: ${error}`);
@@ -331,7 +485,7 @@ async function post_comment(name, email, comment, parent) {
 
         update_status('Client puzzle solved.');
         try {
-            let res = await fetch(url, { method: 'POST', body: comment_data });
+            let res = await fetch(url, { method: 'POST', body: comment_data, credentials: 'include' });
             json = await res.json();
 
             if (json['code'] != 200) {
@@ -344,11 +498,16 @@ async function post_comment(name, email, comment, parent) {
         }
     }
 
+    if (json && (json['code'] == 413 || json['code'] == 422)) {
+        update_status(json['status']);
+        return;
+    }
+
     get_comments();
 }
 
 async function vote(comment_id, vote) {
-    let url = `${TINYCOMMENTS_PATH}/comment/vote/`;
+    let url = `${TINYCOMMENTS_API}/comment/vote/`;
 
     let commenter_id = await get_commenter_id('', '', false);
     if (commenter_id.length == 0) {
@@ -366,7 +525,7 @@ async function vote(comment_id, vote) {
 
     let json;
     try {
-        let res = await fetch(url, { method: 'POST', body: vote_data });
+        let res = await fetch(url, { method: 'POST', body: vote_data, credentials: 'include' });
         json = await res.json();
     } catch (error) {
         update_status(`Error casting vote: ${error}`);
@@ -380,7 +539,7 @@ async function vote(comment_id, vote) {
 
         update_status('Client puzzle solved.');
         try {
-            let res = await fetch(url, { method: 'POST', body: vote_data });
+            let res = await fetch(url, { method: 'POST', body: vote_data, credentials: 'include' });
             json = await res.json();
 
             if (json['code'] != 200) {
@@ -419,6 +578,79 @@ async function vote(comment_id, vote) {
     }
 }
 
+// Ask the reader why they are reporting a comment and send the report to the site owner.
+async function flag_comment(comment_id) {
+    let url = `${TINYCOMMENTS_API}/comment/flag/`;
+
+    let reason = prompt('Why are you reporting this comment?');
+    if (reason == null || reason.trim().length == 0) {
+        return;
+    }
+
+    let commenter_id = await get_commenter_id('', '', false);
+    if (commenter_id.length == 0) {
+        return; // status text is handled by get_commenter_id
+    }
+
+    let flag_data = new URLSearchParams();
+    flag_data.append('comment_id', comment_id);
+    flag_data.append('commenter_id', commenter_id);
+    flag_data.append('reason', reason);
+
+    let json;
+    try {
+        let res = await fetch(url, { method: 'POST', body: flag_data, credentials: 'include' });
+        json = await res.json();
+
+        if (json['code'] == 401) {
+            update_status('Solving client-puzzle due to request volume...');
+            let secret = await solve_pow(json['challenge'], json['key']);
+            flag_data.append('challenge', json['challenge']);
+            flag_data.append('secret', secret);
+
+            update_status('Client puzzle solved.');
+            res = await fetch(url, { method: 'POST', body: flag_data, credentials: 'include' });
+            json = await res.json();
+        }
+    } catch (error) {
+        update_status(`Error reporting comment: ${error}`);
+        return;
+    }
+
+    if (json['code'] == 200) {
+        update_status('Thanks, the comment has been reported.');
+        get_comments();
+    } else {
+        update_status(`Unable to report comment: ${json['status']}`);
+    }
+}
+
+// Refresh the thread whenever the server announces a new comment or vote on this article, over a
+// WebSocket where possible and server-sent events otherwise.
+function live_updates() {
+    let article = btoa(normalize_uri());
+
+    if (!window.WebSocket) {
+        if (window.EventSource) {
+            let events = new EventSource(`${TINYCOMMENTS_API}/events/${article}`);
+            events.addEventListener('comment', (e) => get_comments());
+            events.addEventListener('vote', (e) => get_comments());
+        }
+        return;
+    }
+
+    let url = new URL(`${TINYCOMMENTS_API}/ws/comments/${article}`, document.baseURI);
+    url.protocol = url.protocol == 'https:' ? 'wss:' : 'ws:';
+
+    let socket = new WebSocket(url);
+    socket.addEventListener('message', (e) => {
+        get_comments();
+    });
+    socket.addEventListener('close', (e) => {
+        setTimeout(live_updates, 30000);
+    });
+}
+
 function normalize_uri() {
     const UriRegex = new RegExp('^([^#]+)#?.*$');
 
@@ -431,7 +663,370 @@ function update_status(status) {
     document.getElementById('commentStatus').textContent = status;
 }
 
+// BLAKE2b and Argon2id (one lane, no secret or associated data), for solving Argon2id
+// proof-of-work challenges. 64-bit words are held as pairs of 32-bit halves, low half first.
+const BLAKE2B_IV = new Uint32Array([
+    0xf3bcc908, 0x6a09e667, 0x84caa73b, 0xbb67ae85, 0xfe94f82b, 0x3c6ef372, 0x5f1d36f1, 0xa54ff53a,
+    0xade682d1, 0x510e527f, 0x2b3e6c1f, 0x9b05688c, 0xfb41bd6b, 0x1f83d9ab, 0x137e2179, 0x5be0cd19,
+]);
+
+const BLAKE2B_SIGMA = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+// v[a] += w[b]
+function add64(v, a, w, b) {
+    let lo = v[a] + w[b];
+    v[a + 1] = v[a + 1] + w[b + 1] + (lo >= 0x100000000 ? 1 : 0);
+    v[a] = lo;
+}
+
+// v[d] = (v[d] ^ v[a]) rotated right by n bits
+function xor_rotr64(v, d, a, n) {
+    let lo = v[d] ^ v[a];
+    let hi = v[d + 1] ^ v[a + 1];
+    if (n >= 32) {
+        [lo, hi] = [hi, lo];
+        n -= 32;
+    }
+    if (n > 0) {
+        let rotated = (lo >>> n) ^ (hi << (32 - n));
+        hi = (hi >>> n) ^ (lo << (32 - n));
+        lo = rotated;
+    }
+    v[d] = lo;
+    v[d + 1] = hi;
+}
+
+function blake2b_g(v, m, a, b, c, d, x, y) {
+    add64(v, a, v, b);
+    add64(v, a, m, x);
+    xor_rotr64(v, d, a, 32);
+    add64(v, c, v, d);
+    xor_rotr64(v, b, c, 24);
+    add64(v, a, v, b);
+    add64(v, a, m, y);
+    xor_rotr64(v, d, a, 16);
+    add64(v, c, v, d);
+    xor_rotr64(v, b, c, 63);
+}
+
+function blake2b_compress(h, block, t, last) {
+    let v = new Uint32Array(32);
+    let m = new Uint32Array(32);
+
+    for (let i = 0; i < 32; i++) {
+        m[i] = block[4 * i] | (block[4 * i + 1] << 8) | (block[4 * i + 2] << 16) | (block[4 * i + 3] << 24);
+    }
+    v.set(h, 0);
+    v.set(BLAKE2B_IV, 16);
+    v[24] ^= t;
+    if (last) {
+        v[28] = ~v[28];
+        v[29] = ~v[29];
+    }
+
+    for (let r = 0; r < 12; r++) {
+        let s = BLAKE2B_SIGMA[r % 10];
+        blake2b_g(v, m, 0, 8, 16, 24, 2 * s[0], 2 * s[1]);
+        blake2b_g(v, m, 2, 10, 18, 26, 2 * s[2], 2 * s[3]);
+        blake2b_g(v, m, 4, 12, 20, 28, 2 * s[4], 2 * s[5]);
+        blake2b_g(v, m, 6, 14, 22, 30, 2 * s[6], 2 * s[7]);
+        blake2b_g(v, m, 0, 10, 20, 30, 2 * s[8], 2 * s[9]);
+        blake2b_g(v, m, 2, 12, 22, 24, 2 * s[10], 2 * s[11]);
+        blake2b_g(v, m, 4, 14, 16, 26, 2 * s[12], 2 * s[13]);
+        blake2b_g(v, m, 6, 8, 18, 28, 2 * s[14], 2 * s[15]);
+    }
+
+    for (let i = 0; i < 16; i++) {
+        h[i] ^= v[i] ^ v[i + 16];
+    }
+}
+
+// Unkeyed BLAKE2b of fewer than 2^32 bytes, with an outlen-byte digest.
+function blake2b(input, outlen) {
+    let h = new Uint32Array(BLAKE2B_IV);
+    h[0] ^= 0x01010000 ^ outlen;
+
+    let block = new Uint8Array(128);
+    let pos = 0;
+    let last = false;
+    while (!last) {
+        let len = Math.min(128, input.length - pos);
+        block.fill(0);
+        block.set(input.subarray(pos, pos + len));
+        pos += len;
+        last = pos == input.length;
+        blake2b_compress(h, block, pos, last);
+    }
+
+    let out = new Uint8Array(outlen);
+    for (let i = 0; i < outlen; i++) {
+        out[i] = h[i >> 2] >>> (8 * (i & 3));
+    }
+    return out;
+}
+
+function le32(n) {
+    return new Uint8Array([n, n >>> 8, n >>> 16, n >>> 24]);
+}
+
+function concat_bytes(...arrays) {
+    let out = new Uint8Array(arrays.reduce((len, a) => len + a.length, 0));
+    let pos = 0;
+    for (let a of arrays) {
+        out.set(a, pos);
+        pos += a.length;
+    }
+    return out;
+}
+
+// Argon2's variable-length hash, H'.
+function argon2_hash(input, outlen) {
+    input = concat_bytes(le32(outlen), input);
+    if (outlen <= 64) {
+        return blake2b(input, outlen);
+    }
+
+    let out = new Uint8Array(outlen);
+    let v = blake2b(input, 64);
+    out.set(v.subarray(0, 32), 0);
+    let pos = 32;
+    while (outlen - pos > 64) {
+        v = blake2b(v, 64);
+        out.set(v.subarray(0, 32), pos);
+        pos += 32;
+    }
+    out.set(blake2b(v, outlen - pos), pos);
+    return out;
+}
+
+// Returns the low and high halves of a + b + 2 * lo32(a) * lo32(b), the BlaMka addition.
+function blamka(al, ah, bl, bh) {
+    let xl = al & 0xffff, xh = al >>> 16, yl = bl & 0xffff, yh = bl >>> 16;
+    let mid1 = xh * yl + ((xl * yl) >>> 16);
+    let mid2 = xl * yh + (mid1 & 0xffff);
+    let hi = xh * yh + (mid1 >>> 16) + (mid2 >>> 16);
+    let lo = Math.imul(al, bl) >>> 0;
+
+    hi = ((hi << 1) | (lo >>> 31)) >>> 0;
+    lo = (lo << 1) >>> 0;
+
+    let sum = (al >>> 0) + (bl >>> 0) + lo;
+    let carry = (sum / 0x100000000) | 0;
+    return [sum >>> 0, (ah + bh + hi + carry) >>> 0];
+}
+
+function argon2_g(v, a, b, c, d) {
+    let al = v[a], ah = v[a + 1], bl = v[b], bh = v[b + 1];
+    let cl = v[c], ch = v[c + 1], dl = v[d], dh = v[d + 1];
+    let t;
+
+    [al, ah] = blamka(al, ah, bl, bh);
+    [dl, dh] = [dh ^ ah, dl ^ al];
+    [cl, ch] = blamka(cl, ch, dl, dh);
+    t = bl ^ cl;
+    bh ^= ch;
+    bl = (t >>> 24) ^ (bh << 8);
+    bh = (bh >>> 24) ^ (t << 8);
+    [al, ah] = blamka(al, ah, bl, bh);
+    t = dl ^ al;
+    dh ^= ah;
+    dl = (t >>> 16) ^ (dh << 16);
+    dh = (dh >>> 16) ^ (t << 16);
+    [cl, ch] = blamka(cl, ch, dl, dh);
+    t = bl ^ cl;
+    bh ^= ch;
+    bl = (bh >>> 31) ^ (t << 1);
+    bh = (t >>> 31) ^ (bh << 1);
+
+    v[a] = al;
+    v[a + 1] = ah;
+    v[b] = bl;
+    v[b + 1] = bh;
+    v[c] = cl;
+    v[c + 1] = ch;
+    v[d] = dl;
+    v[d + 1] = dh;
+}
+
+// The 64-bit words each of the 16 BLAKE2 rounds in Argon2's compression function works on: eight
+// rows, then eight columns, of the block seen as an 8x8 matrix of 128-bit registers.
+const ARGON2_ROUNDS = (function() {
+    let rounds = [];
+    for (let i = 0; i < 8; i++) {
+        rounds.push(Array.from({ length: 16 }, (_, j) => 16 * i + j));
+    }
+    for (let i = 0; i < 8; i++) {
+        rounds.push(Array.from({ length: 16 }, (_, j) => 2 * i + 16 * (j >> 1) + (j & 1)));
+    }
+    return rounds.map((round) => round.map((word) => 2 * word));
+})();
+
+const argon2_r = new Uint32Array(256);
+const argon2_tmp = new Uint32Array(256);
+
+// out = G(prev, ref), XORed into out's old contents if xor is set. Blocks are 256-element
+// slices of the arrays starting at the given offsets.
+function argon2_fill_block(prev, prev_off, ref, ref_off, out, out_off, xor) {
+    let r = argon2_r, tmp = argon2_tmp;
+    for (let i = 0; i < 256; i++) {
+        r[i] = prev[prev_off + i] ^ ref[ref_off + i];
+        tmp[i] = xor ? r[i] ^ out[out_off + i] : r[i];
+    }
+
+    for (let w of ARGON2_ROUNDS) {
+        argon2_g(r, w[0], w[4], w[8], w[12]);
+        argon2_g(r, w[1], w[5], w[9], w[13]);
+        argon2_g(r, w[2], w[6], w[10], w[14]);
+        argon2_g(r, w[3], w[7], w[11], w[15]);
+        argon2_g(r, w[0], w[5], w[10], w[15]);
+        argon2_g(r, w[1], w[6], w[11], w[12]);
+        argon2_g(r, w[2], w[7], w[8], w[13]);
+        argon2_g(r, w[3], w[4], w[9], w[14]);
+    }
+
+    for (let i = 0; i < 256; i++) {
+        out[out_off + i] = tmp[i] ^ r[i];
+    }
+}
+
+// Argon2id, version 0x13, with one lane and m KiB of memory over t passes.
+function argon2id(password, salt, m, t, outlen) {
+    let h0 = blake2b(concat_bytes(le32(1), le32(outlen), le32(m), le32(t), le32(0x13), le32(2),
+                                  le32(password.length), password, le32(salt.length), salt,
+                                  le32(0), le32(0)), 64);
+
+    let blocks = 4 * Math.floor(m / 4);
+    let segment = blocks / 4;
+    let mem = new Uint32Array(256 * blocks);
+
+    for (let i = 0; i < 2; i++) {
+        let bytes = argon2_hash(concat_bytes(h0, le32(i), le32(0)), 1024);
+        for (let j = 0; j < 256; j++) {
+            mem[256 * i + j] = bytes[4 * j] | (bytes[4 * j + 1] << 8) | (bytes[4 * j + 2] << 16) | (bytes[4 * j + 3] << 24);
+        }
+    }
+
+    let zero = new Uint32Array(256);
+    let input = new Uint32Array(256);
+    let addresses = new Uint32Array(256);
+    let next_addresses = function() {
+        input[12]++;
+        argon2_fill_block(zero, 0, input, 0, addresses, 0, false);
+        argon2_fill_block(zero, 0, addresses, 0, addresses, 0, false);
+    };
+
+    for (let pass = 0; pass < t; pass++) {
+        for (let slice = 0; slice < 4; slice++) {
+            // The first half of the first pass uses addresses that don't depend on the password.
+            let independent = pass == 0 && slice < 2;
+            if (independent) {
+                input.fill(0);
+                input[0] = pass;
+                input[4] = slice;
+                input[6] = blocks;
+                input[8] = t;
+                input[10] = 2;
+            }
+
+            let start = pass == 0 && slice == 0 ? 2 : 0;
+            if (independent && start == 2) {
+                next_addresses();
+            }
+
+            let cur = slice * segment + start;
+            let prev = cur == 0 ? blocks - 1 : cur - 1;
+            for (let i = start; i < segment; i++, cur++, prev++) {
+                if (cur % blocks == 1) {
+                    prev = cur - 1;
+                }
+
+                let rand;
+                if (independent) {
+                    if (i % 128 == 0) {
+                        next_addresses();
+                    }
+                    rand = addresses[2 * (i % 128)];
+                } else {
+                    rand = mem[256 * prev];
+                }
+
+                let area = pass == 0 ? slice * segment + i - 1 : blocks - segment + i - 1;
+                let x = BigInt(rand);
+                let relative = BigInt(area) - 1n - ((BigInt(area) * ((x * x) >> 32n)) >> 32n);
+                let start_pos = pass != 0 && slice != 3 ? (slice + 1) * segment : 0;
+                let ref = (start_pos + Number(relative)) % blocks;
+
+                argon2_fill_block(mem, 256 * prev, mem, 256 * ref, mem, 256 * cur, pass != 0);
+            }
+        }
+    }
+
+    let last = new Uint8Array(1024);
+    for (let j = 0; j < 256; j++) {
+        let word = mem[256 * (blocks - 1) + j];
+        last[4 * j] = word;
+        last[4 * j + 1] = word >>> 8;
+        last[4 * j + 2] = word >>> 16;
+        last[4 * j + 3] = word >>> 24;
+    }
+    return argon2_hash(last, outlen);
+}
+
+function leading_zero_bits(bytes) {
+    let bits = 0;
+    for (let byte of bytes) {
+        if (byte != 0) {
+            return bits + Math.clz32(byte) - 24;
+        }
+        bits += 8;
+    }
+    return bits;
+}
+
+// Argon2id challenges look like argon2id$m=<KiB>,t=<passes>,p=1,b=<bits>$<hex salt>; the answer is
+// the first counter whose hash has at least b leading zero bits.
+async function solve_argon2_pow(key) {
+    var enc = new TextEncoder('utf-8');
+    let [, param_string, salt_hex] = key.split('$');
+
+    let params = {};
+    for (let param of param_string.split(',')) {
+        let [name, value] = param.split('=');
+        params[name] = parseInt(value);
+    }
+
+    let salt = new Uint8Array(salt_hex.length / 2);
+    for (let i = 0; i < salt.length; i++) {
+        salt[i] = parseInt(salt_hex.substr(2 * i, 2), 16);
+    }
+
+    for (let i = 0; i < Math.pow(2,32); i++) {
+        let hash = argon2id(enc.encode(`${i}`), salt, params['m'], params['t'], 32);
+        if (leading_zero_bits(hash) >= params['b']) {
+            return i;
+        }
+
+        // Let the page breathe between attempts.
+        await new Promise((resolve) => setTimeout(resolve, 0));
+    }
+}
+
 async function solve_pow(challenge, key) {
+    if (key.startsWith('argon2id$')) {
+        return solve_argon2_pow(key);
+    }
+
     var enc = new TextEncoder('utf-8');
 
     for (let i = 0; i < Math.pow(2,32); i++) {
@@ -452,11 +1047,37 @@ async function solve_pow(challenge, key) {
     }
 }
 
-(function(){
-    window.addEventListener('load', (e) => {
-        TINYCOMMENTS_PATH = {{- with .Site.Params.tinycommentsPath }} '{{ . }}'; {{- else }} '/tinycomments'; {{- end }}
+// Pages that load the widget through /embed.js only have an empty #tinycomments element, so fill
+// it with the same markup the Hugo partial provides.
+function build_widget() {
+    let container = document.getElementById('tinycomments');
+    if (document.getElementById('comments') || !container) {
+        return;
+    }
+
+    container.innerHTML = `
+      <div id="newcomment">
+        Name: <input type="text" id="commentName"/> This will be displayed with your post<br/>
+        Email: <input type="text" id="commentEmail"/> This isn't visible to or shared with anyone except me (the site owner)<br/>
+        Comment: <textarea id="commentText"></textarea><br/>
+        <input id="commentButton" type="button" value="Comment!"/>
+        <i id="commentStatus"></i>
+      </div>
+      <br/>
+      <div id="commentCount"></div>
+      <div id="comments">
+        <ul id="rootCommentList">
+        </ul>
+      </div>`;
+}
 
+(function(){
+    let start = () => {
+        build_widget();
+        take_oauth_commenter_id();
+        bootstrap();
         get_comments();
+        live_updates();
 
         let button = document.getElementById('commentButton');
 
@@ -467,5 +1088,12 @@ async function solve_pow(challenge, key) {
         }();
 
         button.addEventListener('click', closure);
-    });
+    };
+
+    // The embed loader adds this script after the page may have finished loading.
+    if (document.readyState == 'complete') {
+        start();
+    } else {
+        window.addEventListener('load', start);
+    }
 })();
@@ -0,0 +1,383 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! An async client for the tinycomments HTTP API, for static-site generators and bots.
+//!
+//! Proof-of-work challenges are solved on a blocking thread and the request sent again, so
+//! callers never see them.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), tinycomments_client::Error> {
+//! use tinycomments_client::{Client, FetchOptions};
+//!
+//! let mut client = Client::new("https://example.com/tinycomments")?;
+//! client.identify("Build bot", "").await?;
+//! client
+//!     .post("https://example.com/posts/hello/", "First!", None)
+//!     .await?;
+//!
+//! let thread = client
+//!     .fetch("https://example.com/posts/hello/", &FetchOptions::default())
+//!     .await?;
+//! println!("{} comments", thread.total_count);
+//! # Ok(())
+//! # }
+//! ```
+
+use base64::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+pub use tinycomments_types as types;
+use tinycomments_types::{
+    CommentFormat, CommentSort, CountCommentsRequest, CountCommentsResponse, DeleteCommentRequest,
+    DeleteCommentResponse, EditCommentRequest, EditCommentResponse, ErrorResponse,
+    GetCommentRequest, GetCommentResponse, GetCommentsRequest, GetCommentsResponse, IdInfoRequest,
+    IdInfoResponse, IdRequest, IdResponse, NewCommentRequest, NewCommentResponse, UpdateIdRequest,
+    UpdateIdResponse, VoteRequest, VoteResponse, API_PREFIX,
+};
+
+pub mod pow;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent, or the response couldn't be read.
+    Http(reqwest::Error),
+    /// The server refused the request. `code` is its HTTP status.
+    Server { code: u16, status: String },
+    /// A proof-of-work challenge couldn't be solved.
+    Pow(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "HTTP error: {e}"),
+            Error::Server { code, status } => write!(f, "Server error {code}: {status}"),
+            Error::Pow(e) => write!(f, "Proof-of-work error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+/// How [`Client::fetch`] returns a thread. The default is the whole thread, oldest first, with
+/// replies nested in `children`.
+#[derive(Debug, Clone, Copy)]
+pub struct FetchOptions {
+    pub sort: CommentSort,
+    pub format: CommentFormat,
+    /// Return at most this many comments, starting from `offset`.
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    /// Only fetch what changed since an earlier response's `synced_at`.
+    pub since: Option<i64>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            sort: CommentSort::Oldest,
+            format: CommentFormat::Tree,
+            limit: None,
+            offset: None,
+            since: None,
+        }
+    }
+}
+
+/// The base64 id tinycomments uses for the article at `url`.
+pub fn article_id(url: &str) -> String {
+    BASE64_STANDARD.encode(url)
+}
+
+enum Reply<T> {
+    Ok(T),
+    Challenge { challenge: String, key: String },
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    commenter_id: String,
+}
+
+impl Client {
+    /// `base_url` is where tinycomments is served, e.g. `https://example.com/tinycomments`.
+    /// Session cookies are kept, so this works whether or not the server has
+    /// `commenter_sessions` enabled.
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        Ok(Client {
+            http: reqwest::Client::builder().cookie_store(true).build()?,
+            base_url: String::from(base_url.trim_end_matches('/')),
+            commenter_id: String::new(),
+        })
+    }
+
+    /// Act as a commenter identified earlier.
+    pub fn with_commenter_id(mut self, commenter_id: &str) -> Self {
+        self.commenter_id = String::from(commenter_id);
+        self
+    }
+
+    /// Empty until [`Client::identify`] is called, or if the server issued the ID as a session
+    /// cookie.
+    pub fn commenter_id(&self) -> &str {
+        &self.commenter_id
+    }
+
+    /// Get a new commenter ID, used for every later request from this client.
+    pub async fn identify(&mut self, name: &str, email: &str) -> Result<IdResponse, Error> {
+        let res: IdResponse = self
+            .call("/id/", |challenge, secret| IdRequest {
+                name: String::from(name),
+                email: String::from(email),
+                challenge,
+                secret,
+            })
+            .await?;
+
+        self.commenter_id = res.commenter_id.clone();
+        Ok(res)
+    }
+
+    /// Change the name and email stored for this client's commenter ID.
+    pub async fn update_identity(&self, name: &str, email: &str) -> Result<(), Error> {
+        let _: UpdateIdResponse = self
+            .call("/id/update/", |challenge, secret| UpdateIdRequest {
+                commenter_id: self.commenter_id.clone(),
+                name: String::from(name),
+                email: String::from(email),
+                challenge,
+                secret,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// What the server has stored for this client's commenter ID.
+    pub async fn identity(&self) -> Result<IdInfoResponse, Error> {
+        self.call("/id/info/", |_, _| IdInfoRequest {
+            commenter_id: self.commenter_id.clone(),
+        })
+        .await
+    }
+
+    /// Post a comment on the article at `article_url`, or a reply to `parent`. The response's
+    /// `status` says whether the comment was published or held for moderation.
+    pub async fn post(
+        &self,
+        article_url: &str,
+        comment: &str,
+        parent: Option<i64>,
+    ) -> Result<NewCommentResponse, Error> {
+        self.call("/comment/post/", |challenge, secret| NewCommentRequest {
+            article: article_id(article_url),
+            commenter_id: self.commenter_id.clone(),
+            comment: String::from(comment),
+            parent: parent.unwrap_or(0),
+            subscribe: false,
+            challenge,
+            secret,
+        })
+        .await
+    }
+
+    /// The published comments on the article at `article_url`.
+    pub async fn fetch(
+        &self,
+        article_url: &str,
+        options: &FetchOptions,
+    ) -> Result<GetCommentsResponse, Error> {
+        self.call("/comment/get/", |challenge, secret| GetCommentsRequest {
+            commenter_id: self.commenter_id.clone(),
+            article: article_id(article_url),
+            sort: options.sort,
+            format: options.format,
+            limit: options.limit,
+            offset: options.offset,
+            since: options.since,
+            challenge,
+            secret,
+        })
+        .await
+    }
+
+    /// One published comment, with the comments it replies to and its direct replies.
+    pub async fn comment(&self, comment_id: i64) -> Result<GetCommentResponse, Error> {
+        self.call(&format!("/comment/{comment_id}/"), |challenge, secret| {
+            GetCommentRequest { challenge, secret }
+        })
+        .await
+    }
+
+    /// The number of published comments on each article, keyed by URL.
+    pub async fn count(&self, article_urls: &[&str]) -> Result<HashMap<String, i64>, Error> {
+        let articles: Vec<String> = article_urls.iter().map(|url| article_id(url)).collect();
+
+        let res: CountCommentsResponse = self
+            .call("/comment/count/", |challenge, secret| {
+                CountCommentsRequest {
+                    articles: articles.join(","),
+                    challenge,
+                    secret,
+                }
+            })
+            .await?;
+
+        Ok(article_urls
+            .iter()
+            .zip(articles.iter())
+            .map(|(url, id)| (String::from(*url), res.counts.get(id).copied().unwrap_or(0)))
+            .collect())
+    }
+
+    /// Vote on a comment: 1 or -1, or 0 to take back an earlier vote.
+    pub async fn vote(&self, comment_id: i64, vote: i64) -> Result<(), Error> {
+        let _: VoteResponse = self
+            .call("/comment/vote/", |challenge, secret| VoteRequest {
+                voter_id: self.commenter_id.clone(),
+                comment_id,
+                vote,
+                challenge,
+                secret,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace the text of one of this commenter's comments.
+    pub async fn edit(&self, comment_id: i64, comment: &str) -> Result<(), Error> {
+        let _: EditCommentResponse = self
+            .call("/comment/edit/", |challenge, secret| EditCommentRequest {
+                commenter_id: self.commenter_id.clone(),
+                comment_id,
+                comment: String::from(comment),
+                challenge,
+                secret,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Delete one of this commenter's comments.
+    pub async fn delete(&self, comment_id: i64) -> Result<(), Error> {
+        let _: DeleteCommentResponse = self
+            .call("/comment/delete/", |challenge, secret| {
+                DeleteCommentRequest {
+                    commenter_id: self.commenter_id.clone(),
+                    comment_id,
+                    challenge,
+                    secret,
+                }
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Send a request built by `request`, and if the server asks for proof of work, solve it and
+    /// send the same request again with the solution.
+    async fn call<Req, Res>(
+        &self,
+        path: &str,
+        request: impl Fn(Option<String>, Option<String>) -> Req,
+    ) -> Result<Res, Error>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let (challenge, key) = match self.send(path, &request(None, None)).await? {
+            Reply::Ok(res) => return Ok(res),
+            Reply::Challenge { challenge, key } => (challenge, key),
+        };
+
+        let solving = challenge.clone();
+        let secret = tokio::task::spawn_blocking(move || pow::solve(&solving, &key))
+            .await
+            .map_err(|e| Error::Pow(e.to_string()))??;
+
+        match self
+            .send(path, &request(Some(challenge), Some(secret.to_string())))
+            .await?
+        {
+            Reply::Ok(res) => Ok(res),
+            Reply::Challenge { .. } => Err(Error::Pow(String::from(
+                "The server asked for another challenge",
+            ))),
+        }
+    }
+
+    async fn send<Req, Res>(&self, path: &str, request: &Req) -> Result<Reply<Res>, Error>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let res = self
+            .http
+            .post(format!("{}{API_PREFIX}{path}", self.base_url))
+            .form(request)
+            .send()
+            .await?;
+        let http_status = res.status();
+        let body = res.bytes().await?;
+
+        // Go by the body's code rather than the HTTP status, which is always 200 on servers with
+        // `legacy_status_codes` enabled.
+        let Ok(body) = serde_json::from_slice::<serde_json::Value>(&body) else {
+            return Err(Error::Server {
+                code: http_status.as_u16(),
+                status: String::from_utf8_lossy(&body).into_owned(),
+            });
+        };
+
+        let decode_error = |e: serde_json::Error| Error::Server {
+            code: http_status.as_u16(),
+            status: format!("Unexpected response: {e}"),
+        };
+
+        if body.get("code").and_then(|code| code.as_u64()) == Some(200) {
+            return serde_json::from_value(body)
+                .map(Reply::Ok)
+                .map_err(decode_error);
+        }
+
+        let error: ErrorResponse = serde_json::from_value(body).map_err(decode_error)?;
+        match (error.code, error.challenge, error.key) {
+            (401, Some(challenge), Some(key)) => Ok(Reply::Challenge { challenge, key }),
+            _ => Err(Error::Server {
+                code: error.code,
+                status: error.status,
+            }),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Solving the server's proof-of-work challenges, in either of its `pow_algorithm` modes.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::Error;
+
+/// Find the secret that answers `challenge`. This takes a while by design, so call it from a
+/// blocking thread.
+pub fn solve(challenge: &str, key: &str) -> Result<u64, Error> {
+    match key.strip_prefix("argon2id$") {
+        Some(params) => solve_argon2(params),
+        None => solve_hmac(challenge, key),
+    }
+}
+
+/// The secret is the number whose HMAC under `key` is `challenge`.
+fn solve_hmac(challenge: &str, key: &str) -> Result<u64, Error> {
+    let challenge =
+        hex::decode(challenge).map_err(|e| Error::Pow(format!("Bad challenge: {e}")))?;
+    let mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+
+    for secret in 0..=u32::MAX as u64 {
+        let mut mac = mac.clone();
+        mac.update(secret.to_string().as_bytes());
+        if mac.finalize().into_bytes()[..] == challenge[..] {
+            return Ok(secret);
+        }
+    }
+
+    Err(Error::Pow(String::from("No secret answers the challenge")))
+}
+
+/// The secret is the first number whose Argon2id hash, salted as the key says, has enough leading
+/// zero bits. `params` looks like `m=4096,t=1,p=1,b=3$<hex salt>`.
+fn solve_argon2(params: &str) -> Result<u64, Error> {
+    let bad_key = || Error::Pow(format!("Bad argon2id key: {params}"));
+
+    let (params, salt) = params.split_once('$').ok_or_else(bad_key)?;
+    let salt = hex::decode(salt).map_err(|_| bad_key())?;
+
+    let (mut memory, mut iterations, mut parallelism, mut bits) = (None, None, None, None);
+    for param in params.split(',') {
+        let (name, value) = param.split_once('=').ok_or_else(bad_key)?;
+        let value: u32 = value.parse().map_err(|_| bad_key())?;
+        match name {
+            "m" => memory = Some(value),
+            "t" => iterations = Some(value),
+            "p" => parallelism = Some(value),
+            "b" => bits = Some(value),
+            _ => {}
+        }
+    }
+
+    let (Some(memory), Some(iterations), Some(parallelism), Some(bits)) =
+        (memory, iterations, parallelism, bits)
+    else {
+        return Err(bad_key());
+    };
+
+    let params = Params::new(memory, iterations, parallelism, Some(32))
+        .map_err(|e| Error::Pow(e.to_string()))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut out = [0u8; 32];
+    for secret in 0..=u32::MAX as u64 {
+        argon2
+            .hash_password_into(secret.to_string().as_bytes(), &salt, &mut out)
+            .map_err(|e| Error::Pow(e.to_string()))?;
+        if leading_zero_bits(&out) >= bits {
+            return Ok(secret);
+        }
+    }
+
+    Err(Error::Pow(String::from("No secret answers the challenge")))
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        if *byte != 0 {
+            return bits + byte.leading_zeros();
+        }
+        bits += 8;
+    }
+    bits
+}
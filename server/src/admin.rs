@@ -0,0 +1,792 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::{post, web, HttpRequest};
+use base64::prelude::*;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::time::SystemTime;
+use tracing::info;
+
+use crate::api::API_PREFIX;
+use crate::auth::require_admin;
+use crate::cli::AdminAction;
+use crate::config::ConfigFile;
+use crate::{
+    attachments, audit, base64_decode, dashboard, db, email, error::Error, live, mentions, notify,
+    reputation, subscriptions, webhooks, AppState,
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct PendingResponse {
+    code: u16,
+    status: String,
+    comments: Vec<db::PendingComment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FlaggedResponse {
+    code: u16,
+    status: String,
+    comments: Vec<db::FlaggedComment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModerateRequest {
+    comment_id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModerateResponse {
+    code: u16,
+    status: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PruneRequest {
+    older_than_days: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PruneResponse {
+    code: u16,
+    status: String,
+    pruned: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PinRequest {
+    comment_id: i64,
+    /// Defaults to true; pass false to unpin.
+    pinned: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LockRequest {
+    /// Base64, as sent by the widget.
+    article: String,
+    /// Close the article to new comments. Defaults to true; pass false to reopen it.
+    locked: Option<bool>,
+    /// Also close the article to voting. Defaults to false.
+    lock_voting: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisterRequest {
+    /// Base64, as sent by the widget.
+    article: String,
+    /// Unix timestamp the article was published, used by `close_after_days`. Defaults to now for
+    /// a newly registered article.
+    published_at: Option<i64>,
+    /// Defaults to true; pass false to unregister the article.
+    registered: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AnonymizeRequest {
+    commenter_id: String,
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[post("/admin/moderation/pending/")]
+async fn pending(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<PendingResponse>, Error> {
+    require_admin(&req)?;
+
+    let comments = db::run(&state.db, |db| db.pending_comments())
+        .await
+        .map_err(Error::Database)?;
+
+    Ok(web::Json(PendingResponse {
+        code: 200,
+        status: String::from("OK"),
+        comments,
+    }))
+}
+
+#[post("/admin/moderation/approve/")]
+async fn approve(
+    data: web::Form<ModerateRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    moderation_response(
+        approve_comment(&state, &admin.name, data.comment_id).await,
+        "No pending comment with that id",
+        "Could not moderate comment",
+    )
+}
+
+/// Comments that commenters have flagged, most flagged first, with the reasons they gave. Includes
+/// comments hidden by `flag_hide_threshold`, which also appear among the pending comments.
+#[post("/admin/moderation/flagged/")]
+async fn flagged(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<FlaggedResponse>, Error> {
+    require_admin(&req)?;
+
+    let comments = db::run(&state.db, |db| db.flagged_comments())
+        .await
+        .map_err(Error::Database)?;
+
+    Ok(web::Json(FlaggedResponse {
+        code: 200,
+        status: String::from("OK"),
+        comments,
+    }))
+}
+
+/// Clear the flags on a comment without approving or rejecting it. A comment that was hidden by
+/// `flag_hide_threshold` stays pending until it is approved.
+#[post("/admin/moderation/dismiss/")]
+async fn dismiss(
+    data: web::Form<ModerateRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    moderation_response(
+        dismiss_flags(&state, &admin.name, data.comment_id).await,
+        "No flagged comment with that id",
+        "Could not dismiss flags",
+    )
+}
+
+pub async fn dismiss_flags(
+    state: &web::Data<AppState>,
+    actor: &str,
+    comment_id: i64,
+) -> Result<bool, String> {
+    info!("Dismissing flags on comment {comment_id}");
+
+    let (reasons, dismissed) = db::run(&state.db, move |db| {
+        let reasons = db
+            .flagged_comments()?
+            .into_iter()
+            .find(|comment| comment.id == comment_id)
+            .map(|comment| comment.reasons);
+        Ok((reasons, db.dismiss_flags(comment_id)?))
+    })
+    .await?;
+
+    if dismissed {
+        let previous = reasons.map(|reasons| json!({ "flags": reasons }));
+        audit::record(
+            state,
+            actor,
+            audit::DISMISS_FLAGS,
+            comment_id.to_string(),
+            previous,
+        )
+        .await;
+    }
+
+    Ok(dismissed)
+}
+
+/// Publish a held comment and send the notifications that were deferred while it was pending.
+/// Returns false if there was no pending comment with this id.
+pub async fn approve_comment(
+    state: &web::Data<AppState>,
+    actor: &str,
+    comment_id: i64,
+) -> Result<bool, String> {
+    info!("Approving comment {comment_id}");
+
+    let res = db::run(&state.db, move |db| db.approve_comment(comment_id)).await;
+
+    if let Ok(true) = res {
+        audit::record(
+            state,
+            actor,
+            audit::APPROVE,
+            comment_id.to_string(),
+            Some(json!({ "pending": true })),
+        )
+        .await;
+        if let Ok(Some(owner)) =
+            db::run(&state.db, move |db| db.get_comment_owner(comment_id)).await
+        {
+            state
+                .reputation
+                .record(reputation::commenter(&owner), reputation::Signal::Approved);
+        }
+        notify_approved(state, comment_id).await;
+    }
+
+    res
+}
+
+pub async fn reject_comment(
+    state: &web::Data<AppState>,
+    actor: &str,
+    comment_id: i64,
+) -> Result<bool, String> {
+    info!("Rejecting comment {comment_id}");
+
+    // The comment is gone once it's rejected, so find its poster and content first.
+    let summary = db::run(&state.db, move |db| db.get_comment_summary(comment_id)).await;
+
+    let res = db::run(&state.db, move |db| db.reject_comment(comment_id)).await;
+
+    if let (Ok(true), Ok(Some(summary))) = (&res, summary) {
+        state.reputation.record(
+            reputation::commenter(&summary.commenter_id),
+            reputation::Signal::Spam,
+        );
+        let previous = json!({
+            "article": summary.article,
+            "parent": summary.parent,
+            "commenter_id": summary.commenter_id,
+            "poster_name": summary.poster_name,
+            "comment": summary.comment,
+        });
+        audit::record(
+            state,
+            actor,
+            audit::REJECT,
+            comment_id.to_string(),
+            Some(previous),
+        )
+        .await;
+        attachments::spawn_remove_unused(state);
+    }
+
+    res
+}
+
+#[post("/admin/moderation/reject/")]
+async fn reject(
+    data: web::Form<ModerateRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    moderation_response(
+        reject_comment(&state, &admin.name, data.comment_id).await,
+        "No pending comment with that id",
+        "Could not moderate comment",
+    )
+}
+
+/// Reject held comments that have been waiting longer than `older_than_days`. Comments with
+/// replies are kept, since deleting them would orphan the replies.
+#[post("/admin/moderation/prune/")]
+async fn prune(
+    data: web::Form<PruneRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<PruneResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+    let before = now - i64::from(data.older_than_days) * 86400;
+
+    let pruned = db::run(&state.db, move |db| db.prune_pending(before))
+        .await
+        .map_err(|e| Error::Internal(format!("Could not prune comments: {e}")))?;
+    info!(
+        older_than_days = data.older_than_days,
+        pruned, "Pruned pending comments"
+    );
+    attachments::spawn_remove_unused(&state);
+    audit::record(
+        &state,
+        &admin.name,
+        audit::PRUNE,
+        format!(
+            "{pruned} pending comments older than {} days",
+            data.older_than_days
+        ),
+        None,
+    )
+    .await;
+
+    Ok(web::Json(PruneResponse {
+        code: 200,
+        status: String::from("OK"),
+        pruned,
+    }))
+}
+
+/// Pin a comment to the top of its thread, or unpin it. Pinned replies come first among their
+/// siblings.
+#[post("/admin/comment/pin/")]
+async fn pin(
+    data: web::Form<PinRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    let (comment_id, pinned) = (data.comment_id, data.pinned.unwrap_or(true));
+    info!(comment_id, pinned, "Setting comment pin");
+
+    let res = db::run(&state.db, move |db| {
+        let was_pinned = db
+            .get_published_comment(comment_id)?
+            .map(|(_, comment)| comment.pinned);
+        Ok((was_pinned, db.set_comment_pinned(comment_id, pinned)?))
+    })
+    .await;
+
+    if let Ok((was_pinned, true)) = res {
+        let previous = was_pinned.map(|pinned| json!({ "pinned": pinned }));
+        audit::record(
+            &state,
+            &admin.name,
+            audit::PIN,
+            comment_id.to_string(),
+            previous,
+        )
+        .await;
+    }
+
+    moderation_response(
+        res.map(|(_, found)| found),
+        "No comment with that id",
+        "Could not pin comment",
+    )
+}
+
+#[post("/admin/article/lock/")]
+async fn lock(
+    data: web::Form<LockRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    let decoded_article = base64_decode(data.article.clone())
+        .ok_or_else(|| Error::BadRequest(format!("Could not base64 decode '{}'", data.article)))?;
+
+    let lock = db::ArticleLock {
+        locked: data.locked.unwrap_or(true),
+        voting_locked: data.lock_voting.unwrap_or(false),
+    };
+    info!(
+        article = decoded_article,
+        locked = lock.locked,
+        voting_locked = lock.voting_locked,
+        "Setting article lock"
+    );
+
+    let article = data.article.clone();
+    let previous = db::run(&state.db, move |db| {
+        let previous = db.get_article_lock(&article)?;
+        db.set_article_lock(&article, lock)?;
+        Ok(previous)
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not lock article: {e}")))?;
+
+    audit::record(
+        &state,
+        &admin.name,
+        audit::LOCK,
+        decoded_article,
+        Some(json!({
+            "locked": previous.locked,
+            "voting_locked": previous.voting_locked,
+        })),
+    )
+    .await;
+
+    Ok(web::Json(ModerateResponse {
+        code: 200,
+        status: String::from("OK"),
+    }))
+}
+
+/// Allow comments on an article when `require_registered_articles` or `article_patterns` is set.
+#[post("/admin/article/register/")]
+async fn register(
+    data: web::Form<RegisterRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    let decoded_article = base64_decode(data.article.clone())
+        .ok_or_else(|| Error::BadRequest(format!("Could not base64 decode '{}'", data.article)))?;
+
+    let registered = data.registered.unwrap_or(true);
+    info!(
+        article = decoded_article,
+        registered,
+        published_at = data.published_at,
+        "Setting article registration"
+    );
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+
+    let article = data.article.clone();
+    let published_at = data.published_at;
+    let res = db::run(&state.db, move |db| {
+        let was_registered = db.is_article_registered(&article)?;
+        if registered {
+            db.register_article(&article, published_at, now)?;
+            Ok((was_registered, true))
+        } else {
+            Ok((was_registered, db.unregister_article(&article)?))
+        }
+    })
+    .await;
+
+    if let Ok((was_registered, true)) = res {
+        audit::record(
+            &state,
+            &admin.name,
+            audit::REGISTER,
+            decoded_article,
+            Some(json!({ "registered": was_registered })),
+        )
+        .await;
+    }
+
+    moderation_response(
+        res.map(|(_, changed)| changed),
+        "Article is not registered",
+        "Could not register article",
+    )
+}
+
+/// Erase a commenter's personal data on request. Whether their comments are kept under an anonymous
+/// identity or removed depends on `anonymize_mode`.
+#[post("/admin/commenter/anonymize/")]
+async fn anonymize(
+    data: web::Form<AnonymizeRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    let mode = state.config.anonymize_mode;
+    info!(
+        commenter_id = data.commenter_id,
+        ?mode,
+        "Anonymizing commenter"
+    );
+
+    let mut rand_bytes = [0u8; 32];
+    thread_rng().fill(&mut rand_bytes);
+    let replacement_id = hex::encode(rand_bytes);
+
+    let commenter_id = data.commenter_id.clone();
+    let res = db::run(&state.db, move |db| {
+        db.anonymize_commenter(&commenter_id, &replacement_id, mode)
+    })
+    .await;
+
+    // Their erased details are deliberately not kept as the previous state.
+    if let Ok(true) = res {
+        audit::record(
+            &state,
+            &admin.name,
+            audit::ANONYMIZE,
+            data.commenter_id.clone(),
+            None,
+        )
+        .await;
+        attachments::spawn_remove_unused(&state);
+    }
+
+    moderation_response(res, "Unknown commenter", "Could not anonymize commenter")
+}
+
+/// Comments held for moderation don't trigger webhooks or notify the parent's author, anyone they
+/// mention or the article's subscribers until they are published.
+async fn notify_approved(state: &web::Data<AppState>, comment_id: i64) {
+    let summary = match db::run(&state.db, move |db| db.get_comment_summary(comment_id)).await {
+        Ok(Some(summary)) => summary,
+        Ok(None) => return,
+        Err(e) => {
+            info!("Unable to look up approved comment {comment_id}: {e}");
+            return;
+        }
+    };
+
+    webhooks::dispatch(
+        state,
+        webhooks::COMMENT_APPROVED,
+        webhooks::CommentEvent {
+            id: comment_id,
+            article: &summary.article,
+            parent: summary.parent,
+            poster_name: &summary.poster_name,
+            comment: &summary.comment,
+            published: true,
+        },
+    );
+
+    live::comment_published(state, comment_id).await;
+
+    let article = BASE64_STANDARD.encode(&summary.article);
+    let mut notified = HashSet::new();
+    if state.config.enable_email_notifications {
+        if let Some(parent) = summary.parent {
+            match email::send_reply_notification(
+                state,
+                parent,
+                &summary.commenter_id,
+                &summary.article,
+                &summary.poster_name,
+                &summary.comment,
+            )
+            .await
+            {
+                Ok(Some(to)) => {
+                    notified.insert(to.to_lowercase());
+                }
+                Ok(None) => {}
+                Err(e) => info!("Unable to send reply notification email: {e}"),
+            }
+        }
+
+        let mentioned = mentions::resolve(state, &article, &summary.comment).await;
+        notified.extend(
+            mentions::notify(
+                state,
+                &mentioned,
+                &summary.commenter_id,
+                summary.parent,
+                &summary.article,
+                &summary.poster_name,
+                &summary.comment,
+            )
+            .await,
+        );
+    }
+
+    // As when a comment is published straight away, subscribers don't depend on
+    // enable_email_notifications.
+    subscriptions::notify(
+        state,
+        &article,
+        &summary.commenter_id,
+        notified,
+        &summary.article,
+        &summary.poster_name,
+        &summary.comment,
+    )
+    .await;
+}
+
+/// Turn the result of a moderation action into a response: `Ok(false)` means there was nothing
+/// for the action to apply to.
+fn moderation_response(
+    res: Result<bool, String>,
+    not_found: &str,
+    failed: &str,
+) -> Result<web::Json<ModerateResponse>, Error> {
+    match res {
+        Ok(true) => Ok(web::Json(ModerateResponse {
+            code: 200,
+            status: String::from("OK"),
+        })),
+        Ok(false) => Err(Error::NotFound(String::from(not_found))),
+        Err(e) => Err(Error::Internal(format!("{failed}: {e}"))),
+    }
+}
+
+/// `tinycomments admin ...`: moderate through a running server's admin API rather than the database,
+/// so that approvals send their notifications and blocklist changes take effect straight away.
+pub async fn cli(
+    config: ConfigFile,
+    server: Option<String>,
+    token: Option<String>,
+    action: AdminAction,
+) -> Result<(), String> {
+    let Some(token) = token.or(config.admin_token.clone()) else {
+        return Err(String::from(
+            "No admin token: set admin_token or pass --token",
+        ));
+    };
+    let client = AdminClient {
+        http: reqwest::Client::new(),
+        server: server.unwrap_or_else(|| local_url(&config)),
+        token,
+    };
+
+    match action {
+        AdminAction::Pending => {
+            let res = client.call("/admin/moderation/pending/", &[]).await?;
+            let comments: Vec<db::PendingComment> =
+                serde_json::from_value(res["comments"].clone()).map_err(|e| e.to_string())?;
+
+            if comments.is_empty() {
+                println!("No comments are waiting for moderation.");
+            }
+            for comment in comments {
+                print!(
+                    "#{} {} {} <{}>",
+                    comment.id,
+                    dashboard::format_timestamp(comment.timestamp),
+                    comment.poster_name,
+                    comment.poster_email
+                );
+                match comment.flags {
+                    0 => println!(),
+                    1 => println!(" (flagged once)"),
+                    n => println!(" (flagged {n} times)"),
+                }
+                println!("    {}", comment.article);
+                for line in notify::plain_text(&comment.comment).lines() {
+                    println!("    {line}");
+                }
+                println!();
+            }
+        }
+        AdminAction::Approve { comment_ids } => {
+            for id in comment_ids {
+                let id = id.to_string();
+                client
+                    .call("/admin/moderation/approve/", &[("comment_id", &id)])
+                    .await?;
+                println!("Approved comment {id}");
+            }
+        }
+        AdminAction::Reject { comment_ids } => {
+            for id in comment_ids {
+                let id = id.to_string();
+                client
+                    .call("/admin/moderation/reject/", &[("comment_id", &id)])
+                    .await?;
+                println!("Rejected comment {id}");
+            }
+        }
+        AdminAction::Ban {
+            kind,
+            pattern,
+            hold,
+        } => {
+            let action = if hold { "hold" } else { "reject" };
+            let res = client
+                .call(
+                    "/admin/blocklist/add/",
+                    &[
+                        ("kind", kind.as_str()),
+                        ("pattern", &pattern),
+                        ("action", action),
+                    ],
+                )
+                .await?;
+            println!("Added blocklist rule {}", res["id"]);
+        }
+        AdminAction::Unban { id } => {
+            let id = id.to_string();
+            client
+                .call("/admin/blocklist/remove/", &[("id", &id)])
+                .await?;
+            println!("Removed blocklist rule {id}");
+        }
+        AdminAction::Prune { older_than_days } => {
+            let days = older_than_days.to_string();
+            let res = client
+                .call("/admin/moderation/prune/", &[("older_than_days", &days)])
+                .await?;
+            println!("Pruned {} pending comments", res["pruned"]);
+        }
+    }
+
+    Ok(())
+}
+
+struct AdminClient {
+    http: reqwest::Client,
+    server: String,
+    token: String,
+}
+
+impl AdminClient {
+    /// Post a form to an admin endpoint, returning the response body. Failures are reported with the
+    /// server's status message, whether or not `legacy_status_codes` is on.
+    async fn call(&self, path: &str, form: &[(&str, &str)]) -> Result<serde_json::Value, String> {
+        let url = format!("{}{API_PREFIX}{path}", self.server.trim_end_matches('/'));
+        let res = self
+            .http
+            .post(&url)
+            .bearer_auth(&self.token)
+            .form(form)
+            .send()
+            .await
+            .map_err(|e| format!("Unable to reach {url}: {e}"))?;
+
+        let http_status = res.status();
+        let body: serde_json::Value = res
+            .json()
+            .await
+            .map_err(|_| format!("{url} answered with HTTP {http_status}"))?;
+
+        if body["code"].as_u64() != Some(200) {
+            let status = body["status"].as_str().unwrap_or("unknown error");
+            return Err(format!("{path}: {status}"));
+        }
+
+        Ok(body)
+    }
+}
+
+/// The server's own address, for when it runs on the same host as the CLI.
+fn local_url(config: &ConfigFile) -> String {
+    let scheme = if config.tls_cert_path.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let host = match config.bind_address.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        "::" => "::1",
+        address => address,
+    };
+
+    if host.contains(':') {
+        format!("{scheme}://[{host}]:{}", config.bind_port)
+    } else {
+        format!("{scheme}://{host}:{}", config.bind_port)
+    }
+}
@@ -0,0 +1,92 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::web;
+use regex::Regex;
+use std::sync::LazyLock;
+use tracing::debug;
+
+use crate::AppState;
+
+/// A web address, with or without a scheme. `https://www.example.com` counts once.
+static LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(?:https?://|www\.)[^\s<>]+").expect("link pattern is valid")
+});
+
+/// How many links `text` contains. Count the comment as submitted: sanitizing escapes the slashes
+/// in a URL.
+pub fn count_links(text: &str) -> usize {
+    LINK.find_iter(text).count()
+}
+
+/// The comment details submitted to Akismet for classification.
+pub struct SpamCheck<'a> {
+    pub article_url: &'a str,
+    pub client_ip: &'a str,
+    pub user_agent: &'a str,
+    pub name: &'a str,
+    pub email: &'a str,
+    pub comment: &'a str,
+}
+
+/// Ask Akismet whether a comment is spam. Returns `Ok(false)` without contacting Akismet when no
+/// API key is configured.
+pub async fn is_spam(state: &web::Data<AppState>, check: &SpamCheck<'_>) -> Result<bool, String> {
+    let Some(api_key) = &state.config.akismet_api_key else {
+        return Ok(false);
+    };
+
+    let blog = match &state.config.akismet_blog_url {
+        Some(blog) => blog.as_str(),
+        None => check.article_url,
+    };
+
+    let params = [
+        ("blog", blog),
+        ("user_ip", check.client_ip),
+        ("user_agent", check.user_agent),
+        ("permalink", check.article_url),
+        ("comment_type", "comment"),
+        ("comment_author", check.name),
+        ("comment_author_email", check.email),
+        ("comment_content", check.comment),
+    ];
+
+    let url = format!("https://{api_key}.rest.akismet.com/1.1/comment-check");
+    let res = match state.http.post(url).form(&params).send().await {
+        Ok(res) => res,
+        Err(e) => return Err(format!("Akismet request failed: {e}")),
+    };
+
+    let body = match res.text().await {
+        Ok(body) => body,
+        Err(e) => return Err(format!("Unable to read Akismet response: {e}")),
+    };
+
+    debug!("Akismet response for {}: {body}", check.article_url);
+
+    match body.trim() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("Unexpected Akismet response: {body}")),
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The JSON API's routes. They are mounted under [`API_PREFIX`], and again at their unversioned
+//! paths for widgets and scripts written before there was a prefix. Responses on the old paths
+//! carry a `Deprecation` header and a `Link` to the versioned path.
+//!
+//! Middleware that looks at the request path uses [`route`], so rate limits, roles, and the
+//! honeypot apply the same on both.
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::{self, Next},
+    web, Error, HttpRequest,
+};
+use tracing::debug;
+
+use crate::{
+    admin, attachments, audit, backup, blocklist, bootstrap, count_comments, delete_comment,
+    edit_comment, export, flags, get_comment, get_comments, get_pow, graphql, id, id_info, jobs,
+    live, notification_settings, post_comment, privacy, search, stats, update_id, validate_pow,
+    vote,
+};
+
+pub use tinycomments_types::API_PREFIX;
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+
+/// `path` without the API version prefix, e.g. `/comment/get/` for `/api/v1/comment/get/`.
+pub fn unversioned(path: &str) -> &str {
+    match path.strip_prefix(API_PREFIX) {
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+/// The unversioned pattern of the route a request is for, e.g. `/comment/get/`, or `/thread/{..}`
+/// for a route with parameters. Middleware runs before routing, and `req.path()` is still
+/// percent-encoded, so this resolves the decoded path the router will match instead.
+pub fn route(req: &HttpRequest) -> Option<String> {
+    let pattern = req
+        .resource_map()
+        .match_pattern(req.match_info().as_str())?;
+    Some(String::from(unversioned(&pattern)))
+}
+
+/// The versioned API, and the deprecated unversioned paths. The latter match any path, so they
+/// must be registered after every other service.
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope(API_PREFIX).configure(routes));
+    cfg.service(
+        web::scope("")
+            .wrap(middleware::from_fn(deprecated))
+            .configure(routes),
+    );
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(id)
+        .service(update_id)
+        .service(id_info)
+        .service(notification_settings)
+        .service(privacy::export)
+        .service(post_comment)
+        .service(get_comments)
+        .service(count_comments)
+        .service(get_comment)
+        .service(search::search)
+        .service(graphql::graphql)
+        .service(edit_comment)
+        .service(delete_comment)
+        .service(attachments::attach)
+        .service(vote)
+        .service(flags::flag)
+        .service(bootstrap)
+        .service(get_pow)
+        .service(validate_pow)
+        .service(live::websocket)
+        .service(live::events)
+        .service(admin::pending)
+        .service(admin::approve)
+        .service(admin::reject)
+        .service(admin::flagged)
+        .service(admin::dismiss)
+        .service(admin::pin)
+        .service(admin::lock)
+        .service(admin::register)
+        .service(admin::anonymize)
+        .service(admin::prune)
+        .service(jobs::list)
+        .service(stats::stats)
+        .service(stats::metrics)
+        .service(audit::audit)
+        .service(blocklist::list)
+        .service(blocklist::add)
+        .service(blocklist::remove)
+        .service(export::export)
+        .service(backup::backup);
+}
+
+/// Mark a response from an unversioned path as deprecated.
+async fn deprecated(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let successor = format!("<{API_PREFIX}{}>; rel=\"successor-version\"", req.path());
+
+    let mut res = next.call(req).await?;
+
+    // Paths that matched nothing end up here too.
+    if res.request().match_pattern().is_none() {
+        return Ok(res);
+    }
+
+    debug!(
+        path = res.request().path(),
+        "Request to deprecated unversioned path"
+    );
+
+    let headers = res.headers_mut();
+    headers.insert(DEPRECATION, HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(actix_web::http::header::LINK, link);
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Restricts which articles accept comments, so arbitrary base64 strings can't be used to create
+//! new threads.
+
+use regex::RegexSet;
+
+use crate::config::ConfigFile;
+
+pub struct ArticlePolicy {
+    patterns: Option<RegexSet>,
+    require_registered: bool,
+}
+
+impl ArticlePolicy {
+    /// Compile `article_patterns`. Each pattern must match the whole decoded article URL.
+    pub fn new_from_config(config: &ConfigFile) -> Result<Self, String> {
+        let patterns = if config.article_patterns.is_empty() {
+            None
+        } else {
+            let anchored = config
+                .article_patterns
+                .iter()
+                .map(|pattern| format!("^(?:{pattern})$"));
+            Some(
+                RegexSet::new(anchored)
+                    .map_err(|e| format!("Unable to compile article_patterns: {e}"))?,
+            )
+        };
+
+        Ok(ArticlePolicy {
+            patterns,
+            require_registered: config.require_registered_articles,
+        })
+    }
+
+    /// Whether any restriction is configured. When there isn't, every article is accepted.
+    pub fn restricted(&self) -> bool {
+        self.require_registered || self.patterns.is_some()
+    }
+
+    /// Whether comments may be posted to `decoded_article`, given whether an admin has
+    /// registered it.
+    pub fn allows(&self, decoded_article: &str, registered: bool) -> bool {
+        if !self.restricted() || registered {
+            return true;
+        }
+
+        self.patterns
+            .as_ref()
+            .is_some_and(|patterns| patterns.is_match(decoded_article))
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Files served from `asset_dir`: the standalone comments page, `comments.html`, at `/`, and
+//! everything under its `assets` directory, such as the widget's script and stylesheet, at
+//! `/assets/`. Responses carry an ETag, so browsers revalidate cheaply instead of downloading
+//! unchanged files. Paths that climb out of the directory and hidden files are refused.
+//!
+//! The comments page is a template, filled in with this server's settings so it works without
+//! editing: `api_base` for the widget's `data-path`, `pow_algorithm`, and `theme`, the
+//! `page_theme` table, which the bundled page turns into CSS custom properties.
+
+use actix_files::Files;
+use actix_web::{
+    dev::HttpServiceFactory,
+    get,
+    http::header::{self, ContentType},
+    middleware::DefaultHeaders,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+use tera::{Context, Tera};
+
+use crate::{
+    config::{ConfigFile, PowAlgorithm},
+    error::Error,
+    i18n, AppState,
+};
+
+/// The comments page is revalidated on every load, so edits show up at once.
+const PAGE_CACHE: &str = "no-cache";
+/// Assets are kept for an hour before being revalidated.
+const ASSET_CACHE: &str = "public, max-age=3600";
+
+fn asset_dir(config: &ConfigFile) -> &Path {
+    Path::new(config.asset_dir.as_deref().unwrap_or("."))
+}
+
+/// Serve `comments.html`, or a translation of it such as `comments.fr.html` if there is one for
+/// the reader's language.
+#[get("/")]
+async fn root(state: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    let dir = asset_dir(&state.config);
+    let locale = state.locales.negotiate(&req);
+
+    let template = match fs::read_to_string(dir.join(format!("comments.{locale}.html"))) {
+        Ok(template) if locale != i18n::SOURCE_LOCALE => template,
+        _ => fs::read_to_string(dir.join("comments.html"))
+            .map_err(|e| Error::Internal(format!("Unable to open comments.html: {e}")))?,
+    };
+
+    let page = Tera::one_off(&template, &page_context(&state.config), true)
+        .map_err(|e| Error::Internal(format!("Unable to render comments.html: {e:?}")))?;
+
+    let etag = header::EntityTag::new_strong(hex::encode(&Sha256::digest(&page)[..16]));
+
+    if let Some(header::IfNoneMatch::Items(tags)) = req.get_header::<header::IfNoneMatch>() {
+        if tags.iter().any(|tag| tag.weak_eq(&etag)) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(header::ETag(etag))
+                .finish());
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ETag(etag))
+        .insert_header((header::CACHE_CONTROL, PAGE_CACHE))
+        .insert_header((header::VARY, "Accept-Language"))
+        .content_type(ContentType::html())
+        .body(page))
+}
+
+fn page_context(config: &ConfigFile) -> Context {
+    // Served from this server, the page reaches the API at its root unless the server is proxied
+    // under a path of its own.
+    let api_base = config
+        .public_url
+        .as_deref()
+        .map(|url| url.trim_end_matches('/'))
+        .unwrap_or("");
+
+    let pow_algorithm = match config.pow_algorithm {
+        PowAlgorithm::Hmac => "HMAC-SHA256",
+        PowAlgorithm::Argon2id => "Argon2id",
+    };
+
+    let mut context = Context::new();
+    context.insert("api_base", api_base);
+    context.insert("pow_algorithm", pow_algorithm);
+    context.insert(
+        "theme",
+        &config.page_theme.iter().collect::<BTreeMap<_, _>>(),
+    );
+    context
+}
+
+/// Theme variable names and values are written into the page's stylesheet unescaped, so they
+/// must not be able to end the declaration or the `<style>` element.
+pub fn validate_theme(theme: &HashMap<String, String>) -> Vec<String> {
+    let mut problems = vec![];
+
+    for (name, value) in theme {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            problems.push(format!("page_theme has invalid variable name {name}"));
+        }
+
+        if value
+            .chars()
+            .any(|c| matches!(c, ';' | '{' | '}' | '<' | '>' | '\\') || c.is_control())
+        {
+            problems.push(format!("page_theme.{name} has an invalid value"));
+        }
+    }
+
+    problems
+}
+
+/// Everything under `asset_dir/assets`, at `/assets/`.
+pub fn files(config: &ConfigFile) -> impl HttpServiceFactory {
+    web::scope("/assets")
+        .wrap(DefaultHeaders::new().add((header::CACHE_CONTROL, ASSET_CACHE)))
+        .service(Files::new("", asset_dir(config).join("assets")))
+}
@@ -0,0 +1,522 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Images attached to comments, from the `[attachments]` table. A commenter uploads each image to
+//! `/comment/attach/` after posting the comment, as the request body, with the comment named in
+//! the query string. Uploads are recognised by their contents rather than the type they were sent
+//! with, stripped of metadata, and stored under the SHA-256 of what's left, either in `dir`,
+//! served at `/attachments/`, or in an S3-compatible bucket. Their URLs are returned with the
+//! comment in `attachments`.
+//!
+//! Deleting a comment, or its text when it is kept for its replies, deletes its attachments. A
+//! stored file is removed once no comment has it any more, since another comment may have
+//! attached the same image: straight after a comment is deleted, rejected or pruned or a commenter
+//! is anonymized, and by the `attachment_cleanup` job, which retries any that fail.
+
+use actix_files::Files;
+use actix_web::{
+    dev::HttpServiceFactory,
+    http::header,
+    middleware::DefaultHeaders,
+    post,
+    web::{self, Bytes},
+    HttpRequest,
+};
+use hmac::{Hmac, Mac};
+use reqwest::{Method, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use time::{macros::format_description, OffsetDateTime};
+use tracing::{info, warn};
+
+use crate::{
+    authorize_change, config::ConfigFile, db, error::Error, get_client_ip, images::Format, pow,
+    session, AppState,
+};
+
+/// The largest image accepted unless `max_bytes` says otherwise: 2 MiB.
+const DEFAULT_MAX_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_MAX_PER_COMMENT: i64 = 4;
+
+/// Files are named after their contents, so they never change and can be cached indefinitely.
+const ATTACHMENT_CACHE: &str = "public, max-age=31536000, immutable";
+
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where attachments are kept and how large they may be.
+#[derive(Deserialize, Debug)]
+pub struct Attachments {
+    /// The largest image accepted, in bytes. Defaults to 2 MiB.
+    pub max_bytes: Option<usize>,
+    /// The most images one comment may have. Defaults to 4.
+    pub max_per_comment: Option<i64>,
+    /// Image types accepted, from `image/png`, `image/jpeg`, `image/gif` and `image/webp`.
+    /// Defaults to all of them.
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// Store images in this directory, and serve them at `/attachments/`.
+    pub dir: Option<String>,
+    /// Store images in an S3-compatible bucket instead.
+    pub s3: Option<S3Bucket>,
+    /// URL images are linked from, such as a CDN in front of the bucket. Required with `s3`;
+    /// defaults to `/attachments` under `public_url` with `dir`.
+    pub public_url: Option<String>,
+}
+
+/// A bucket on AWS S3 or a compatible service such as MinIO or Cloudflare R2. Objects are
+/// addressed path-style, as `{endpoint}/{bucket}/{name}`.
+#[derive(Deserialize, Debug)]
+pub struct S3Bucket {
+    /// e.g. `https://s3.eu-west-1.amazonaws.com`.
+    pub endpoint: String,
+    pub bucket: String,
+    /// Defaults to `us-east-1`.
+    pub region: Option<String>,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl Attachments {
+    pub fn validate(&self, config: &ConfigFile) -> Vec<String> {
+        let mut problems = vec![];
+
+        match (&self.dir, &self.s3) {
+            (Some(_), Some(_)) => problems.push(String::from(
+                "attachments.dir and attachments.s3 can't both be set",
+            )),
+            (None, None) => problems.push(String::from(
+                "attachments requires either attachments.dir or attachments.s3",
+            )),
+            (Some(dir), None) => {
+                if !Path::new(dir).is_dir() {
+                    problems.push(format!("attachments.dir {dir} is not a directory"));
+                }
+                if self.public_url.is_none() && config.public_url.is_none() {
+                    problems.push(String::from(
+                        "attachments.dir requires public_url or attachments.public_url",
+                    ));
+                }
+            }
+            (None, Some(s3)) => {
+                if !Url::parse(&s3.endpoint)
+                    .is_ok_and(|url| url.scheme() == "https" || url.scheme() == "http")
+                {
+                    problems.push(String::from(
+                        "attachments.s3.endpoint must be an http or https URL",
+                    ));
+                }
+                if self.public_url.is_none() {
+                    problems.push(String::from(
+                        "attachments.s3 requires attachments.public_url",
+                    ));
+                }
+            }
+        }
+
+        for mime_type in &self.types {
+            if !Format::ALL.iter().any(|f| f.mime_type() == mime_type) {
+                problems.push(format!(
+                    "attachments.types has unsupported type {mime_type}"
+                ));
+            }
+        }
+
+        if self.max_bytes == Some(0) {
+            problems.push(String::from("attachments.max_bytes must be positive"));
+        }
+
+        if self.max_per_comment.is_some_and(|max| max < 1) {
+            problems.push(String::from("attachments.max_per_comment must be positive"));
+        }
+
+        problems
+    }
+
+    fn accepts(&self, format: Format) -> bool {
+        self.types.is_empty() || self.types.iter().any(|t| t == format.mime_type())
+    }
+}
+
+/// The URL readers fetch the stored attachment `name` from.
+pub fn url(config: &ConfigFile, name: &str) -> Option<String> {
+    let attachments = config.attachments.as_ref()?;
+    let base = match (&attachments.public_url, &config.public_url) {
+        (Some(base), _) => String::from(base.trim_end_matches('/')),
+        (None, Some(public_url)) => format!("{}/attachments", public_url.trim_end_matches('/')),
+        (None, None) => return None,
+    };
+    Some(format!("{base}/{name}"))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AttachRequest {
+    #[serde(default)]
+    commenter_id: String,
+    comment_id: i64,
+    challenge: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AttachResponse {
+    code: u16,
+    status: String,
+    url: String,
+    challenge: Option<String>,
+    key: Option<String>,
+}
+
+/// Attach the image in the request body to one of the commenter's comments. Images can be added
+/// for as long as the comment can be edited. A proof-of-work solution covers the SHA-256 of the
+/// body as well as the commenter and comment.
+#[post("/comment/attach/")]
+async fn attach(
+    data: web::Query<AttachRequest>,
+    body: web::Payload,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: session::Session,
+) -> Result<web::Json<AttachResponse>, Error> {
+    let Some(attachments) = &state.config.attachments else {
+        return Err(Error::NotFound(String::from("Attachments are disabled")));
+    };
+
+    // Only someone who may change the comment gets the upload read, let alone parsed.
+    let client_ip = get_client_ip(&req);
+    let commenter_id = ammonia::clean(&session.or(&data.commenter_id));
+    let comment_id = data.comment_id;
+    authorize_change(
+        &state,
+        &req,
+        &commenter_id,
+        comment_id,
+        state.config.edit_window_secs,
+        "attach images to",
+    )
+    .await?;
+
+    let max_bytes = attachments.max_bytes.unwrap_or(DEFAULT_MAX_BYTES);
+    let upload = read_limited(body, max_bytes).await?;
+
+    state.pow.handle(
+        &client_ip,
+        pow::Binding::new(
+            "/comment/attach/",
+            &[
+                &data.commenter_id,
+                &comment_id.to_string(),
+                &hex::encode(Sha256::digest(&upload)),
+            ],
+        ),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let format = Format::sniff(&upload)
+        .filter(|format| attachments.accepts(*format))
+        .ok_or_else(|| Error::Unprocessable(String::from("Unsupported image type")))?;
+    let image = crate::images::strip_metadata(format, &upload)
+        .ok_or_else(|| Error::Unprocessable(String::from("Could not read image")))?;
+    let name = format!(
+        "{}.{}",
+        hex::encode(Sha256::digest(&image)),
+        format.extension()
+    );
+
+    info!(
+        client_ip,
+        commenter_id,
+        comment_id,
+        name,
+        bytes = image.len(),
+        "Attaching image"
+    );
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::Internal(String::from("Could not generate timestamp")))?
+        .as_secs() as i64;
+    let max = attachments
+        .max_per_comment
+        .unwrap_or(DEFAULT_MAX_PER_COMMENT);
+    let stored_name = name.clone();
+    let size = image.len() as i64;
+    let attachment_id = db::run(&state.db, move |db| {
+        db.add_attachment(comment_id, &stored_name, format.mime_type(), size, now, max)
+    })
+    .await
+    .map_err(Error::Database)?
+    .ok_or_else(|| Error::Conflict(format!("A comment may have at most {max} attached images")))?;
+
+    // Only keep the record if the image is actually stored.
+    if let Err(e) = store(&state, attachments, &name, format, image).await {
+        if let Err(e) = db::run(&state.db, move |db| db.remove_attachment(attachment_id)).await {
+            warn!(
+                attachment_id,
+                "Unable to remove record of unstored attachment: {e}"
+            );
+        }
+        return Err(e);
+    }
+
+    Ok(web::Json(AttachResponse {
+        code: 200,
+        status: String::from("OK"),
+        url: url(&state.config, &name).unwrap_or_default(),
+        challenge: None,
+        key: None,
+    }))
+}
+
+/// Read the whole request body, refusing it once it passes `limit` bytes.
+async fn read_limited(body: web::Payload, limit: usize) -> Result<Bytes, Error> {
+    match body.to_bytes_limited(limit).await {
+        Ok(Ok(bytes)) => Ok(bytes),
+        Ok(Err(e)) => Err(Error::BadRequest(format!("Could not read upload: {e}"))),
+        Err(_) => Err(Error::TooLarge(format!(
+            "Images must be at most {limit} bytes"
+        ))),
+    }
+}
+
+async fn store(
+    state: &web::Data<AppState>,
+    attachments: &Attachments,
+    name: &str,
+    format: Format,
+    image: Vec<u8>,
+) -> Result<(), Error> {
+    if let Some(dir) = &attachments.dir {
+        let dir = PathBuf::from(dir);
+        let name = String::from(name);
+        return web::block(move || write_file(&dir, &name, &image))
+            .await
+            .map_err(|e| Error::Internal(format!("Could not store attachment: {e}")))?
+            .map_err(|e| Error::Internal(format!("Could not store attachment: {e}")));
+    }
+
+    if let Some(s3) = &attachments.s3 {
+        return put_object(&state.http, s3, name, format.mime_type(), image)
+            .await
+            .map_err(|e| Error::Internal(format!("Could not store attachment: {e}")));
+    }
+
+    Err(Error::Internal(String::from("No attachment storage")))
+}
+
+/// Remove the stored files of attachments no comment has any more. Files that can't be removed
+/// are tried again next time.
+pub async fn remove_unused(state: &web::Data<AppState>) -> Result<(), String> {
+    let Some(attachments) = &state.config.attachments else {
+        return Ok(());
+    };
+
+    let names = db::run(&state.db, |db| db.unused_attachments()).await?;
+    let mut failed = 0;
+    for name in names {
+        if let Err(e) = remove(state, attachments, &name).await {
+            warn!(name, "Unable to remove attachment: {e}");
+            failed += 1;
+            continue;
+        }
+
+        info!(name, "Removed unused attachment");
+        db::run(&state.db, move |db| db.forget_unused_attachment(&name)).await?;
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{failed} attachments could not be removed")),
+    }
+}
+
+/// [`remove_unused`], in the background, for a request that has just deleted comments.
+pub fn spawn_remove_unused(state: &web::Data<AppState>) {
+    if state.config.attachments.is_none() {
+        return;
+    }
+
+    let state = state.clone();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = remove_unused(&state).await {
+            warn!("Unable to remove unused attachments: {e}");
+        }
+    });
+}
+
+async fn remove(
+    state: &web::Data<AppState>,
+    attachments: &Attachments,
+    name: &str,
+) -> Result<(), String> {
+    if let Some(dir) = &attachments.dir {
+        let path = PathBuf::from(dir).join(name);
+        return web::block(move || match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+
+    if let Some(s3) = &attachments.s3 {
+        return delete_object(&state.http, s3, name).await;
+    }
+
+    Err(String::from("No attachment storage"))
+}
+
+/// Write `data` to `dir/name` unless a file is already there, which, being named after its
+/// contents, would be the same image. The file is written under a hidden temporary name and
+/// renamed into place, so a half-written image is never served.
+fn write_file(dir: &Path, name: &str, data: &[u8]) -> io::Result<()> {
+    let path = dir.join(name);
+    if path.exists() {
+        return Ok(());
+    }
+
+    let partial = dir.join(format!(".{name}.partial"));
+    fs::write(&partial, data)?;
+    fs::rename(&partial, &path)
+}
+
+/// Upload an object.
+async fn put_object(
+    http: &reqwest::Client,
+    s3: &S3Bucket,
+    name: &str,
+    content_type: &str,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    signed_request(http, s3, Method::PUT, name, &data)?
+        .header(header::CONTENT_TYPE.as_str(), content_type)
+        .header(header::CACHE_CONTROL.as_str(), ATTACHMENT_CACHE)
+        .body(data)
+        .timeout(UPLOAD_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Delete an object. Deleting one that isn't there succeeds.
+async fn delete_object(http: &reqwest::Client, s3: &S3Bucket, name: &str) -> Result<(), String> {
+    signed_request(http, s3, Method::DELETE, name, &[])?
+        .timeout(UPLOAD_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// A request for the object `name`, signed using AWS Signature Version 4.
+fn signed_request(
+    http: &reqwest::Client,
+    s3: &S3Bucket,
+    method: Method,
+    name: &str,
+    data: &[u8],
+) -> Result<reqwest::RequestBuilder, String> {
+    let url = Url::parse(&format!(
+        "{}/{}/{name}",
+        s3.endpoint.trim_end_matches('/'),
+        s3.bucket
+    ))
+    .map_err(|e| format!("Invalid object URL: {e}"))?;
+
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => String::from(host),
+        (None, _) => return Err(String::from("Object URL has no host")),
+    };
+
+    let amz_date = OffsetDateTime::now_utc()
+        .format(format_description!(
+            "[year][month][day]T[hour][minute][second]Z"
+        ))
+        .map_err(|e| format!("Could not format date: {e}"))?;
+    let date = &amz_date[..8];
+    let region = s3.region.as_deref().unwrap_or("us-east-1");
+    let payload_hash = hex::encode(Sha256::digest(data));
+
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "{method}\n{}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}",
+        url.path()
+    );
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let mut key = hmac(
+        format!("AWS4{}", s3.secret_access_key).as_bytes(),
+        date.as_bytes(),
+    );
+    for part in [region, "s3", "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+        s3.access_key_id
+    );
+
+    Ok(http
+        .request(method, url)
+        .header("x-amz-date", &amz_date)
+        .header("x-amz-content-sha256", &payload_hash)
+        .header(header::AUTHORIZATION.as_str(), authorization))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Images stored in `dir`, at `/attachments/`. They are served as the type their extension names,
+/// never sniffed, and can't run scripts even if opened directly.
+pub fn files(config: &ConfigFile) -> impl HttpServiceFactory {
+    let scope = web::scope("/attachments").wrap(
+        DefaultHeaders::new()
+            .add((header::CACHE_CONTROL, ATTACHMENT_CACHE))
+            .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .add((header::CONTENT_SECURITY_POLICY, "default-src 'none'")),
+    );
+
+    match config.attachments.as_ref().and_then(|a| a.dir.as_deref()) {
+        Some(dir) => scope.service(Files::new("", dir)),
+        None => scope,
+    }
+}
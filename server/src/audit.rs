@@ -0,0 +1,141 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! An append-only record of administrative and moderation actions, so that sites with several
+//! moderators can see who did what. Entries are listed newest first from `/admin/audit/`.
+
+use actix_web::{post, web, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::auth::require_admin;
+use crate::error::Error;
+use crate::{db, AppState};
+
+pub const APPROVE: &str = "approve";
+pub const REJECT: &str = "reject";
+pub const DISMISS_FLAGS: &str = "dismiss_flags";
+pub const PRUNE: &str = "prune";
+pub const PIN: &str = "pin";
+pub const EDIT: &str = "edit";
+pub const DELETE: &str = "delete";
+pub const LOCK: &str = "lock";
+pub const REGISTER: &str = "register";
+pub const ANONYMIZE: &str = "anonymize";
+pub const BLOCKLIST_ADD: &str = "blocklist_add";
+pub const BLOCKLIST_REMOVE: &str = "blocklist_remove";
+pub const EXPORT: &str = "export";
+pub const BACKUP: &str = "backup";
+
+/// The most entries a single request may return.
+const MAX_LIMIT: i64 = 500;
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditRequest {
+    /// Only entries older than this id, for paging through the log.
+    before: Option<i64>,
+    /// Defaults to 100.
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditResponse {
+    code: u16,
+    status: String,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    id: i64,
+    at: i64,
+    actor: String,
+    action: String,
+    target: String,
+    /// The target's state before the action, when there was one to record.
+    previous: Option<serde_json::Value>,
+}
+
+/// Append an entry for an action that has just been carried out. The action has already happened
+/// by then, so failing to record it is logged rather than reported to the caller.
+pub async fn record(
+    state: &web::Data<AppState>,
+    actor: &str,
+    action: &'static str,
+    target: impl Into<String>,
+    previous: Option<serde_json::Value>,
+) {
+    let at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+    let actor = String::from(actor);
+    let target = target.into();
+    let previous = previous.map(|previous| previous.to_string());
+
+    let res = db::run(&state.db, {
+        let (actor, target) = (actor.clone(), target.clone());
+        move |db| db.add_audit_entry(at, &actor, action, &target, previous.as_deref())
+    })
+    .await;
+
+    if let Err(e) = res {
+        warn!(
+            actor,
+            action, target, "Could not record audit log entry: {e}"
+        );
+    }
+}
+
+#[post("/admin/audit/")]
+async fn audit(
+    data: web::Form<AuditRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<AuditResponse>, Error> {
+    require_admin(&req)?;
+
+    let before = data.before;
+    let limit = data.limit.unwrap_or(100).clamp(1, MAX_LIMIT);
+    let entries = db::run(&state.db, move |db| db.audit_log(before, limit))
+        .await
+        .map_err(Error::Database)?;
+
+    Ok(web::Json(AuditResponse {
+        code: 200,
+        status: String::from("OK"),
+        entries: entries
+            .into_iter()
+            .map(|entry| Entry {
+                id: entry.id,
+                at: entry.at,
+                actor: entry.actor,
+                action: entry.action,
+                target: entry.target,
+                previous: entry
+                    .previous
+                    .and_then(|previous| serde_json::from_str(&previous).ok()),
+            })
+            .collect(),
+    }))
+}
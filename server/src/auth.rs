@@ -0,0 +1,192 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Admin accounts and what each may do. The middleware identifies the account behind every
+//! `/admin/` request, by its bearer token or, for dashboard actions, its session cookie, and
+//! refuses requests the account's role doesn't allow before they reach a handler.
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, HttpMessage, HttpRequest,
+};
+use tracing::warn;
+
+use crate::admin::constant_time_eq;
+use crate::config::{ConfigFile, Role};
+use crate::error::Error;
+use crate::{api, dashboard, AppState};
+
+/// The name of the owner account `admin_token` signs in as.
+pub const ADMIN_TOKEN_ACCOUNT: &str = "admin";
+
+/// Endpoints that only read, open to every role.
+const READONLY: &[&str] = &[
+    "/admin/moderation/pending/",
+    "/admin/moderation/flagged/",
+    "/admin/blocklist/list/",
+    "/admin/jobs/",
+    "/admin/stats/",
+    "/admin/metrics/",
+    "/admin/audit/",
+];
+
+/// Endpoints that moderate comments and commenters. Anything else under `/admin/` is for owners.
+const MODERATOR: &[&str] = &[
+    "/admin/moderation/approve/",
+    "/admin/moderation/reject/",
+    "/admin/moderation/dismiss/",
+    "/admin/moderation/prune/",
+    "/admin/comment/pin/",
+    "/admin/article/lock/",
+    "/admin/article/register/",
+    "/admin/blocklist/add/",
+    "/admin/blocklist/remove/",
+    "/admin/dashboard/moderate/",
+    "/admin/dashboard/blocklist/add/",
+    "/admin/dashboard/blocklist/remove/",
+];
+
+/// Dashboard pages that work without signing in, or check the session themselves.
+const DASHBOARD_PAGES: &[&str] = &["/admin/", "/admin/login/", "/admin/logout/"];
+
+/// A configured admin account.
+pub struct Account<'a> {
+    pub name: &'a str,
+    pub token: &'a str,
+    pub role: Role,
+}
+
+/// The account a request was made with, added to its extensions by [`middleware`].
+#[derive(Clone)]
+pub struct Admin {
+    pub name: String,
+}
+
+impl From<&Account<'_>> for Admin {
+    fn from(account: &Account<'_>) -> Self {
+        Admin {
+            name: String::from(account.name),
+        }
+    }
+}
+
+/// Every account that can sign in: `admin_token`'s owner account, then `admins`.
+pub fn accounts(config: &ConfigFile) -> impl Iterator<Item = Account<'_>> {
+    let admin_token = config.admin_token.as_deref().map(|token| Account {
+        name: ADMIN_TOKEN_ACCOUNT,
+        token,
+        role: Role::Owner,
+    });
+
+    admin_token
+        .into_iter()
+        .chain(config.admins.iter().map(|account| Account {
+            name: &account.name,
+            token: &account.token,
+            role: account.role,
+        }))
+}
+
+/// Admin endpoints and the dashboard are disabled entirely when there are no accounts.
+pub fn enabled(config: &ConfigFile) -> bool {
+    config.admin_token.is_some() || !config.admins.is_empty()
+}
+
+/// The account a token belongs to. Every account is compared, so the time taken doesn't reveal
+/// which one matched.
+pub fn find_token<'a>(config: &'a ConfigFile, supplied: &str) -> Option<Account<'a>> {
+    accounts(config).fold(None, |found, account| {
+        let matches = constant_time_eq(supplied.as_bytes(), account.token.as_bytes());
+        found.or(matches.then_some(account))
+    })
+}
+
+/// The account named in the request's `Authorization: Bearer <token>` header.
+pub fn bearer<'a>(config: &'a ConfigFile, req: &HttpRequest) -> Option<Account<'a>> {
+    let header = req.headers().get("authorization")?.to_str().ok()?;
+    find_token(config, header.strip_prefix("Bearer ")?)
+}
+
+/// The role needed for a path, or `None` if it isn't an admin endpoint or handles sign-in itself.
+fn required_role(path: &str) -> Option<Role> {
+    if !path.starts_with("/admin/") || DASHBOARD_PAGES.contains(&path) {
+        None
+    } else if READONLY.contains(&path) {
+        Some(Role::Readonly)
+    } else if MODERATOR.contains(&path) {
+        Some(Role::Moderator)
+    } else {
+        Some(Role::Owner)
+    }
+}
+
+/// Authenticate admin requests and check the account's role.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let route = api::route(req.request()).unwrap_or_default();
+    let Some(required) = required_role(&route) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    // Dashboard forms authenticate with the session cookie; the handlers check their CSRF token.
+    let account = if route.starts_with("/admin/dashboard/") {
+        dashboard::session(&state.config, req.request())
+    } else {
+        bearer(&state.config, req.request())
+    };
+
+    let Some(account) = account else {
+        return Ok(req.error_response(Error::Forbidden(String::from("Forbidden"))));
+    };
+
+    if account.role < required {
+        warn!(
+            account = account.name,
+            role = account.role.as_str(),
+            path = req.path(),
+            "Admin request refused for role"
+        );
+        return Ok(req.error_response(Error::Forbidden(format!(
+            "The {} role may not do this",
+            account.role.as_str()
+        ))));
+    }
+
+    req.extensions_mut().insert(Admin::from(&account));
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+/// The account the middleware authenticated, for handlers that refuse anyone else.
+pub fn require_admin(req: &HttpRequest) -> Result<Admin, Error> {
+    req.extensions()
+        .get::<Admin>()
+        .cloned()
+        .ok_or_else(|| Error::Forbidden(String::from("Forbidden")))
+}
@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Byte-for-byte copies of the live SQLite database, taken with SQLite's backup API so that the
+//! server needn't stop and the copy is never torn by a write landing mid-way. Streamed from
+//! `/admin/backup/` or written by the `tinycomments backup` subcommand.
+
+use actix_web::{http::header, post, web, HttpRequest, HttpResponse};
+use rand::{thread_rng, Rng};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::auth::require_admin;
+use crate::config::ConfigFile;
+use crate::db;
+use crate::error::Error;
+use crate::{audit, AppState};
+
+/// Bytes of the snapshot to read before handing them to the response body.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Stream a snapshot of the database. It is written to a temporary file first, since the backup
+/// API copies into a database rather than a stream, and the file is unlinked as soon as it's open.
+#[post("/admin/backup/")]
+async fn backup(state: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    let admin = require_admin(&req)?;
+
+    info!("Backing up database");
+
+    let path = temp_path();
+    let dest = path.to_string_lossy().into_owned();
+    let res = db::run(&state.db, move |db| db.backup(&dest)).await;
+
+    let file =
+        res.and_then(|_| File::open(&path).map_err(|e| format!("Could not read backup: {e}")));
+    let _ = fs::remove_file(&path);
+    let mut file = file.map_err(Error::Internal)?;
+    audit::record(&state, &admin.name, audit::BACKUP, "database", None).await;
+
+    let (tx, rx) = mpsc::channel::<io::Result<web::Bytes>>(4);
+    actix_web::rt::spawn(async move {
+        let chunks = tx.clone();
+        let res = web::block(move || loop {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            buf.truncate(n);
+            chunks
+                .blocking_send(Ok(web::Bytes::from(buf)))
+                .map_err(|_| io::Error::other("Backup client disconnected"))?;
+        })
+        .await;
+
+        // Failing the body stream aborts the response, so a client can't mistake a partial copy
+        // for a complete one.
+        if let Ok(Err(e)) = res {
+            warn!("Backup failed: {e}");
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0);
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/vnd.sqlite3")
+        .insert_header(header::ContentDisposition::attachment(format!(
+            "tinycomments-{now}.sqlite"
+        )))
+        .streaming(body))
+}
+
+/// `tinycomments backup --output PATH`: copy the database to a new file. Safe to run alongside the
+/// server.
+pub async fn cli(config: ConfigFile, output: String) -> io::Result<()> {
+    if fs::metadata(&output).is_ok() {
+        return Err(io::Error::other(format!("{output} already exists")));
+    }
+
+    // As with exports, the storage lives entirely on the blocking pool.
+    let res = web::block(move || db::open(&config)?.backup(&output)).await;
+
+    match res {
+        Ok(res) => res.map_err(io::Error::other),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+fn temp_path() -> PathBuf {
+    let mut rand_bytes = [0u8; 16];
+    thread_rng().fill(&mut rand_bytes);
+
+    std::env::temp_dir().join(format!(
+        "tinycomments-backup-{}.sqlite",
+        hex::encode(rand_bytes)
+    ))
+}
@@ -0,0 +1,425 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Site-owner managed rules that reject or hold submissions by client IP range, email address, or
+//! content. Rules are stored in the database and compiled into memory at startup and whenever
+//! they change.
+
+use actix_web::{post, web, HttpRequest};
+use clap::ValueEnum;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use crate::auth::require_admin;
+use crate::db::{self, Storage};
+use crate::error::Error;
+use crate::{audit, AppState};
+
+#[derive(Serialize, Deserialize, Clone, Copy, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// An address or CIDR range, e.g. `203.0.113.0/24`.
+    Ip,
+    /// An address, with `*` matching any run of characters, e.g. `*@spam.example`.
+    Email,
+    /// A word or phrase matched case-insensitively in names and comment text, or a regular
+    /// expression written as `/pattern/`.
+    Keyword,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Ip => "ip",
+            Kind::Email => "email",
+            Kind::Keyword => "keyword",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "ip" => Some(Kind::Ip),
+            "email" => Some(Kind::Email),
+            "keyword" => Some(Kind::Keyword),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to a matching submission. When several rules match, rejection wins.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Accept the submission without publishing it: comments are held for moderation and votes
+    /// are dropped. New ids are still issued, so that their comments can be held.
+    Hold,
+    /// Refuse the submission with an error.
+    Reject,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Hold => "hold",
+            Action::Reject => "reject",
+        }
+    }
+
+    fn parse(action: &str) -> Option<Self> {
+        match action {
+            "hold" => Some(Action::Hold),
+            "reject" => Some(Action::Reject),
+            _ => None,
+        }
+    }
+}
+
+enum Matcher {
+    Network(IpAddr, u8),
+    Email(Regex),
+    Keyword(Regex),
+}
+
+struct Rule {
+    matcher: Matcher,
+    action: Action,
+}
+
+pub struct Blocklist {
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Blocklist {
+            rules: RwLock::new(vec![]),
+        }
+    }
+
+    /// Recompile the rules from the database. Stored rules that no longer compile are skipped.
+    pub async fn reload(&self, db: &Arc<dyn Storage>) -> Result<(), String> {
+        let entries = db::run(db, |db| db.blocklist_entries()).await?;
+
+        let mut rules = vec![];
+        for entry in entries {
+            let (Some(kind), Some(action)) =
+                (Kind::parse(&entry.kind), Action::parse(&entry.action))
+            else {
+                warn!(
+                    "Skipping blocklist entry {} with unknown kind or action",
+                    entry.id
+                );
+                continue;
+            };
+
+            match compile(kind, &entry.pattern) {
+                Ok(matcher) => rules.push(Rule { matcher, action }),
+                Err(e) => warn!("Skipping blocklist entry {}: {e}", entry.id),
+            }
+        }
+
+        info!("Loaded {} blocklist rules", rules.len());
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    /// The action for a submission from `client_ip` by a commenter with `email`, containing
+    /// `texts`, or `None` if no rule matches.
+    pub fn check(&self, client_ip: &str, email: &str, texts: &[&str]) -> Option<Action> {
+        let ip = parse_client_ip(client_ip);
+
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|rule| match &rule.matcher {
+                Matcher::Network(network, prefix) => {
+                    ip.is_some_and(|ip| in_network(ip, *network, *prefix))
+                }
+                Matcher::Email(re) => !email.is_empty() && re.is_match(email),
+                Matcher::Keyword(re) => texts.iter().any(|text| re.is_match(text)),
+            })
+            .map(|rule| rule.action)
+            .max()
+    }
+}
+
+fn compile(kind: Kind, pattern: &str) -> Result<Matcher, String> {
+    match kind {
+        Kind::Ip => {
+            let (addr, prefix) = match pattern.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (pattern, None),
+            };
+
+            let addr: IpAddr = addr
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid IP address: {addr}"))?;
+            let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix
+                    .trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|prefix| *prefix <= max_prefix)
+                    .ok_or(format!("Invalid prefix length: {prefix}"))?,
+                None => max_prefix,
+            };
+
+            Ok(Matcher::Network(addr, prefix))
+        }
+        Kind::Email => {
+            let pattern = pattern
+                .split('*')
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join(".*");
+            Ok(Matcher::Email(build_regex(&format!("^{pattern}$"))?))
+        }
+        Kind::Keyword => {
+            if let Some(re) = pattern
+                .strip_prefix('/')
+                .and_then(|pattern| pattern.strip_suffix('/'))
+            {
+                return Ok(Matcher::Keyword(build_regex(re)?));
+            }
+
+            // Only anchor at word boundaries where the keyword itself starts or ends with a word
+            // character; otherwise \b would never match next to punctuation.
+            let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+            let start = if is_word(pattern.chars().next()) {
+                r"\b"
+            } else {
+                ""
+            };
+            let end = if is_word(pattern.chars().last()) {
+                r"\b"
+            } else {
+                ""
+            };
+
+            Ok(Matcher::Keyword(build_regex(&format!(
+                "{start}{}{end}",
+                regex::escape(pattern)
+            ))?))
+        }
+    }
+}
+
+fn build_regex(pattern: &str) -> Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(1 << 20)
+        .build()
+        .map_err(|e| format!("Invalid pattern: {e}"))
+}
+
+/// The first address in an `X-Forwarded-For` style list, with IPv4-mapped IPv6 addresses
+/// converted to IPv4.
+fn parse_client_ip(client_ip: &str) -> Option<IpAddr> {
+    let ip: IpAddr = client_ip.split(',').next()?.trim().parse().ok()?;
+    Some(ip.to_canonical())
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListResponse {
+    code: u16,
+    status: String,
+    entries: Vec<db::BlocklistEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddRequest {
+    kind: Kind,
+    pattern: String,
+    /// Defaults to reject.
+    action: Option<Action>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveRequest {
+    id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeResponse {
+    code: u16,
+    status: String,
+    id: Option<i64>,
+}
+
+#[post("/admin/blocklist/list/")]
+async fn list(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ListResponse>, Error> {
+    require_admin(&req)?;
+
+    let entries = db::run(&state.db, |db| db.blocklist_entries())
+        .await
+        .map_err(Error::Database)?;
+
+    Ok(web::Json(ListResponse {
+        code: 200,
+        status: String::from("OK"),
+        entries,
+    }))
+}
+
+#[post("/admin/blocklist/add/")]
+async fn add(
+    data: web::Form<AddRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ChangeResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    let action = data.action.unwrap_or(Action::Reject);
+    let id = add_entry(&state, &admin.name, data.kind, &data.pattern, action).await?;
+
+    Ok(web::Json(ChangeResponse {
+        code: 200,
+        status: String::from("OK"),
+        id: Some(id),
+    }))
+}
+
+#[post("/admin/blocklist/remove/")]
+async fn remove(
+    data: web::Form<RemoveRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<ChangeResponse>, Error> {
+    let admin = require_admin(&req)?;
+
+    remove_entry(&state, &admin.name, data.id).await?;
+
+    Ok(web::Json(ChangeResponse {
+        code: 200,
+        status: String::from("OK"),
+        id: Some(data.id),
+    }))
+}
+
+/// Validate and store a rule, then recompile the blocklist.
+pub async fn add_entry(
+    state: &web::Data<AppState>,
+    actor: &str,
+    kind: Kind,
+    pattern: &str,
+    action: Action,
+) -> Result<i64, Error> {
+    let pattern = String::from(pattern.trim());
+    compile(kind, &pattern).map_err(Error::BadRequest)?;
+
+    info!(
+        kind = kind.as_str(),
+        pattern,
+        action = action.as_str(),
+        "Adding blocklist entry"
+    );
+
+    let created_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+
+    let target = format!("{}:{pattern}", kind.as_str());
+    let (previous, id) = db::run(&state.db, move |db| {
+        // Adding an existing rule changes its action, which the audit log should show.
+        let previous = db
+            .blocklist_entries()?
+            .into_iter()
+            .find(|entry| entry.kind == kind.as_str() && entry.pattern == pattern);
+        let id = db.add_blocklist_entry(kind.as_str(), &pattern, action.as_str(), created_at)?;
+        Ok((previous, id))
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not add blocklist entry: {e}")))?;
+
+    let previous = previous.and_then(|entry| serde_json::to_value(entry).ok());
+    audit::record(state, actor, audit::BLOCKLIST_ADD, target, previous).await;
+
+    reload(state).await?;
+    Ok(id)
+}
+
+pub async fn remove_entry(state: &web::Data<AppState>, actor: &str, id: i64) -> Result<(), Error> {
+    info!(id, "Removing blocklist entry");
+
+    let res = db::run(&state.db, move |db| {
+        let previous = db
+            .blocklist_entries()?
+            .into_iter()
+            .find(|entry| entry.id == id);
+        Ok((previous, db.remove_blocklist_entry(id)?))
+    })
+    .await;
+
+    match res {
+        Ok((previous, true)) => {
+            let previous = previous.and_then(|entry| serde_json::to_value(entry).ok());
+            audit::record(
+                state,
+                actor,
+                audit::BLOCKLIST_REMOVE,
+                id.to_string(),
+                previous,
+            )
+            .await;
+            reload(state).await
+        }
+        Ok((_, false)) => Err(Error::NotFound(String::from(
+            "No blocklist entry with that id",
+        ))),
+        Err(e) => Err(Error::Internal(format!(
+            "Could not remove blocklist entry: {e}"
+        ))),
+    }
+}
+
+async fn reload(state: &web::Data<AppState>) -> Result<(), Error> {
+    state
+        .blocklist
+        .reload(&state.db)
+        .await
+        .map_err(|e| Error::Internal(format!("Saved, but could not reload the blocklist: {e}")))
+}
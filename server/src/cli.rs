@@ -0,0 +1,131 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Command line arguments. Settings given here take precedence over `TINYCOMMENTS_*` environment
+//! variables, which in turn take precedence over the config file.
+
+use clap::{Parser, Subcommand};
+
+use crate::blocklist;
+use crate::config::{ConfigFile, DbBackend};
+use crate::export;
+
+#[derive(Parser)]
+#[command(version, about = "A small, self-hosted comment server")]
+pub struct Cli {
+    /// Config file to read.
+    #[arg(long, env = "TINYCOMMENTS_CONFIG", default_value = "config.toml")]
+    pub config: String,
+    /// Address to listen on, overriding `bind_address`.
+    #[arg(long)]
+    pub bind: Option<String>,
+    /// Port to listen on, overriding `bind_port`.
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// SQLite database path, or a `postgres://` URL, overriding the database settings.
+    #[arg(long)]
+    pub db: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write every commenter, comment, and vote to a file.
+    Export {
+        #[arg(long, value_enum, default_value_t)]
+        format: export::Format,
+        /// Defaults to stdout.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Copy the SQLite database to a new file, without stopping the server.
+    Backup {
+        #[arg(long)]
+        output: String,
+    },
+    /// Moderate through a running server's admin API.
+    Admin {
+        /// The server's base URL. Defaults to `bind_address` and `bind_port`.
+        #[arg(long)]
+        server: Option<String>,
+        /// Admin token, overriding `admin_token`.
+        #[arg(long, env = "TINYCOMMENTS_ADMIN_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        #[command(subcommand)]
+        action: AdminAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AdminAction {
+    /// List comments waiting for moderation, oldest first.
+    Pending,
+    /// Publish held comments.
+    Approve {
+        #[arg(required = true)]
+        comment_ids: Vec<i64>,
+    },
+    /// Delete held comments.
+    Reject {
+        #[arg(required = true)]
+        comment_ids: Vec<i64>,
+    },
+    /// Add a blocklist rule for an IP address or range, a commenter's email address, or a keyword.
+    Ban {
+        #[arg(value_enum)]
+        kind: blocklist::Kind,
+        pattern: String,
+        /// Hold matching submissions for moderation instead of rejecting them.
+        #[arg(long)]
+        hold: bool,
+    },
+    /// Remove a blocklist rule by the id `ban` printed.
+    Unban { id: i64 },
+    /// Delete held comments older than `--older-than-days`, unless they have replies.
+    Prune {
+        #[arg(long, default_value_t = 30)]
+        older_than_days: u32,
+    },
+}
+
+impl Cli {
+    pub fn apply(&self, config: &mut ConfigFile) {
+        if let Some(bind) = &self.bind {
+            config.bind_address = bind.clone();
+        }
+
+        if let Some(port) = self.port {
+            config.bind_port = port;
+        }
+
+        if let Some(db) = &self.db {
+            if db.starts_with("postgres://") || db.starts_with("postgresql://") {
+                config.db_backend = DbBackend::Postgres;
+                config.db_url = Some(db.clone());
+            } else {
+                config.db_backend = DbBackend::Sqlite;
+                config.db_path = db.clone();
+            }
+        }
+    }
+}
@@ -21,7 +21,28 @@
  */
 
 use serde::Deserialize;
-use std::{fs::File, io::prelude::*};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io,
+    io::prelude::*,
+    net::IpAddr,
+};
+
+use crate::{
+    assets,
+    attachments::Attachments,
+    auth, honeypot,
+    html::{self, HtmlPolicy},
+    jobs,
+    logging::LogFile,
+    names,
+    ratelimit::RateLimitConfig,
+    reporting::ErrorReporting,
+    webhooks,
+};
+
+pub use tinycomments_types::VotingMode;
 
 #[derive(Deserialize, Debug)]
 pub enum DebugLevel {
@@ -30,12 +51,254 @@ pub enum DebugLevel {
     Trace,
 }
 
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// What `/admin/commenter/anonymize/` does with the commenter's comments.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AnonymizeMode {
+    /// Keep the comments, attributed to a new anonymous identity.
+    #[default]
+    Pseudonymize,
+    /// Remove the comments. Those with replies are replaced by a placeholder so the thread
+    /// survives.
+    Delete,
+}
+
+/// What happens to a comment containing a term from `word_filter_path`.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum WordFilterAction {
+    /// Store the comment as written, but hold it for moderation.
+    #[default]
+    Hold,
+    /// Replace the filtered terms with asterisks and publish as usual.
+    Mask,
+}
+
+/// What happens to a comment with more than `max_links_auto_publish` links.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkLimitAction {
+    /// Hold the comment for moderation.
+    #[default]
+    Hold,
+    /// Refuse the comment.
+    Reject,
+}
+
+/// What happens to submissions from an IP address or email listed by StopForumSpam or `dnsbls`.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamListAction {
+    /// Hold their comments for moderation.
+    #[default]
+    Hold,
+    /// Refuse new ids and comments.
+    Reject,
+}
+
+/// What happens to a commenter whose email address is at a disposable domain.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DisposableEmailAction {
+    /// Accept the address, but hold every comment posted with it for moderation.
+    #[default]
+    Hold,
+    /// Refuse the address at `/id/`.
+    Reject,
+}
+
+/// What happens when someone takes one of `reserved_names`, or a verified commenter's name.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ReservedNameAction {
+    /// Refuse the name at `/id/` and `/id/update/`.
+    #[default]
+    Reject,
+    /// Accept the name, but hold every comment posted under it for moderation.
+    Hold,
+}
+
+/// What happens to a comment that arrives with `honeypot_field` filled in.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HoneypotAction {
+    /// Drop the comment, but answer as if it had been posted.
+    #[default]
+    Discard,
+    /// Hold the comment for moderation.
+    Hold,
+}
+
+/// The puzzle clients solve when they are challenged.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PowAlgorithm {
+    /// Find the number whose HMAC-SHA256 matches the challenge. Fast hardware solves these far
+    /// quicker than a phone does.
+    #[default]
+    Hmac,
+    /// Find a number whose Argon2id hash starts with enough zero bits. Every attempt needs
+    /// `pow_argon2_memory_kib` of memory, which narrows the gap between phones and GPUs.
+    Argon2id,
+}
+
+/// SQLite's journal mode. With `wal`, readers carry on while a comment is being written.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteJournalMode {
+    #[default]
+    Wal,
+    Delete,
+    Truncate,
+    Persist,
+}
+
+/// How often SQLite waits for writes to reach the disk. `normal` is safe with `wal`, but may lose
+/// the last few writes on power loss with the other journal modes.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteSynchronous {
+    Off,
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub enum DbBackend {
+    #[default]
+    Sqlite,
+    Postgres,
+}
+
+/// What an admin account may do, each role allowing everything the ones before it do.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// View the moderation queues, blocklist, statistics, jobs, and audit log.
+    Readonly,
+    /// Also approve, reject, pin, and lock, and edit the blocklist.
+    Moderator,
+    /// Also export, back up, and anonymize data.
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Readonly => "readonly",
+            Role::Moderator => "moderator",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+/// A named admin API and dashboard login; see `auth.rs`.
+#[derive(Debug, Deserialize)]
+pub struct AdminAccount {
+    /// Shown in the audit log.
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: Option<String>,
+    /// Events to deliver to this webhook. All events are delivered when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// A channel for alerts about new and flagged comments; see `notify/chat.rs` and `notify/ntfy.rs`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// A Slack incoming webhook.
+    Slack { webhook_url: String },
+    /// A Discord channel webhook.
+    Discord { webhook_url: String },
+    /// A Matrix room, posted to as the user `access_token` belongs to, who must have joined it.
+    Matrix {
+        homeserver: String,
+        room_id: String,
+        access_token: String,
+    },
+    /// An ntfy topic, for push notifications to phones.
+    Ntfy {
+        /// Defaults to https://ntfy.sh.
+        server: Option<String>,
+        topic: String,
+        /// Access token for a protected topic.
+        token: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotifierConfig {
+    #[serde(flatten)]
+    pub kind: NotifierKind,
+    /// Only alert about comments held for moderation, and flagged comments.
+    #[serde(default)]
+    pub pending_only: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ConfigFile {
     pub bind_address: String,
     pub bind_port: u16,
+    /// PEM certificate chain and private key. When both are set the server listens for HTTPS
+    /// instead of plain HTTP.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// How long to wait for in-flight requests, email, and webhooks on shutdown. Defaults to 30.
+    pub shutdown_timeout_secs: Option<u64>,
+    /// Origins allowed to make cross-origin requests, e.g. the blog embedding the widget. "*"
+    /// allows any origin.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Reverse proxies whose `X-Forwarded-For` and `X-Real-IP` headers are believed. Requests from
+    /// anywhere else are attributed to the connecting address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
     pub debug: DebugLevel,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Also write the log to this file, rotated on a schedule. Stdout is still written.
+    pub log_file: Option<LogFile>,
+    /// Write one line per request in Combined Log Format, for traffic analysis tools.
+    pub access_log: Option<LogFile>,
+    /// Report server errors and panics to Sentry or a compatible service.
+    pub error_reporting: Option<ErrorReporting>,
+    #[serde(default)]
+    pub db_backend: DbBackend,
     pub db_path: String,
+    pub db_url: Option<String>,
+    /// Connections to keep open. With SQLite these only read; every write goes through one more
+    /// connection of its own. Defaults to 8.
+    pub db_pool_size: Option<u32>,
+    #[serde(default)]
+    pub sqlite_journal_mode: SqliteJournalMode,
+    #[serde(default)]
+    pub sqlite_synchronous: SqliteSynchronous,
+    /// How long a connection waits for another to finish writing before giving up, in
+    /// milliseconds. Defaults to 5000.
+    pub sqlite_busy_timeout_ms: Option<usize>,
+    /// Page cache for each pooled connection, in KiB. Defaults to SQLite's own, about 2000.
+    pub sqlite_cache_size_kib: Option<u32>,
+    /// Read up to this many MiB of the database through a memory map rather than read calls.
+    /// Off by default.
+    pub sqlite_mmap_size_mib: Option<u64>,
     pub enable_email_notifications: bool,
     pub email_notify_address: Option<String>,
     pub email_sender_address: Option<String>,
@@ -43,20 +306,653 @@ pub struct ConfigFile {
     pub email_smtp_host: Option<String>,
     pub email_smtp_user: Option<String>,
     pub email_smtp_pass: Option<String>,
+    pub email_template_new_comment: Option<String>,
+    pub email_template_reply: Option<String>,
+    pub email_template_mention: Option<String>,
+    pub email_template_subscription: Option<String>,
+    pub email_template_export_confirmation: Option<String>,
+    pub email_template_digest: Option<String>,
+    pub email_template_flag: Option<String>,
+    /// Instead of one email per new comment, send `email_notify_address` a summary of the
+    /// comments posted in each interval of this many minutes.
+    pub email_digest_interval_mins: Option<u64>,
+    pub email_max_attempts: Option<u32>,
+    pub email_retry_base_secs: Option<u32>,
+    /// How long to wait on the SMTP server when connecting or sending. Defaults to 60 seconds.
+    pub email_smtp_timeout_secs: Option<u64>,
+    /// The most idle SMTP connections to keep open for reuse. Defaults to 4.
+    pub email_smtp_pool_size: Option<u32>,
+    /// Close pooled SMTP connections after they've been idle this long. Defaults to 60 seconds.
+    pub email_smtp_idle_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub moderate_new_comments: bool,
+    /// Refuse comments longer than this many bytes, as submitted.
+    pub max_comment_bytes: Option<usize>,
+    /// Refuse comments shorter than this many characters, ignoring leading and trailing
+    /// whitespace.
+    pub min_comment_chars: Option<usize>,
+    /// Seconds after posting during which a commenter may edit their comment. Afterwards only an
+    /// admin token with the moderator role or above may. Without it, edits are always allowed.
+    pub edit_window_secs: Option<u64>,
+    /// Likewise for deleting comments through `/comment/delete/`.
+    pub delete_window_secs: Option<u64>,
+    /// With `moderate_new_comments` on, publish comments straight away from commenters who
+    /// already have this many published comments.
+    pub auto_approve_after: Option<i64>,
+    /// With `moderate_new_comments` on, publish comments straight away when both the poster and
+    /// their IP address have at least this reputation score. See `reputation.rs`.
+    pub reputation_trust_at: Option<i64>,
+    /// Hold comments for moderation when the poster or their IP address has a reputation score
+    /// below this.
+    pub reputation_hold_below: Option<i64>,
+    /// Delete comments still waiting for moderation this many days after they were posted,
+    /// unless they have replies.
+    pub retention_pending_days: Option<u64>,
+    /// Delete commenter IDs issued this many days ago that have never been used to comment, vote,
+    /// flag or subscribe.
+    pub retention_unused_commenter_days: Option<u64>,
+    /// Include a Gravatar `avatar_url` with each comment, derived from a hash of the poster's
+    /// email.
+    #[serde(default)]
+    pub enable_gravatar: bool,
+    #[serde(default)]
+    pub voting_mode: VotingMode,
+    /// Refuse a vote on a comment that someone else has already voted on from the same client IP.
+    /// Voters can still change their own vote. IPs are stored as a keyed hash, and only while this
+    /// is enabled.
+    #[serde(default)]
+    pub one_vote_per_ip: bool,
+    /// Key for hashing voter IPs. Changing it forgets which IPs have voted.
+    pub vote_ip_secret: Option<String>,
+    /// Mark comments whose vote total, which starts at 1, is below this as `collapsed`. Pinned
+    /// comments are never collapsed.
+    pub collapse_below_score: Option<i64>,
+    /// How deeply replies nest when comments are requested in the tree format. Replies below this
+    /// depth are attached to their deepest allowed ancestor. Defaults to 5.
+    pub comment_tree_max_depth: Option<usize>,
+    /// Require commenters with an email address to confirm `/id/export/` requests with a token
+    /// sent to that address, rather than trusting the commenter ID alone.
+    #[serde(default)]
+    pub export_email_confirmation: bool,
+    #[serde(default)]
+    pub anonymize_mode: AnonymizeMode,
+    /// Stop accepting comments on an article this many days after its registered publish date, or
+    /// after its first comment if it has none.
+    pub close_after_days: Option<u64>,
+    /// Only accept comments on articles registered with `/admin/article/register/` or matching
+    /// one of `article_patterns`.
+    #[serde(default)]
+    pub require_registered_articles: bool,
+    /// Regular expressions matched against the whole decoded article URL, e.g.
+    /// `https://blog\.example/posts/.+`. When set, comments on articles that match none of them
+    /// and aren't registered are refused.
+    #[serde(default)]
+    pub article_patterns: Vec<String>,
+    /// Fetch the `<title>` of each article's page to show in emails and the dashboard.
+    #[serde(default)]
+    pub fetch_article_titles: bool,
+    /// Also issue commenter IDs from `/id/` and OAuth logins in a signed HttpOnly cookie, which
+    /// takes precedence over the ID a client sends. With `allowed_origins` set the cookie is
+    /// `SameSite=None; Secure`, so cross-origin widgets must be served over HTTPS.
+    #[serde(default)]
+    pub commenter_sessions: bool,
+    /// Key for signing session cookies. Changing it invalidates every cookie, leaving commenters
+    /// to get new IDs.
+    pub commenter_session_secret: Option<String>,
+    /// Send errors with HTTP status 200 and the real status only in the body's `code` field, as
+    /// older versions did.
+    #[serde(default)]
+    pub legacy_status_codes: bool,
+    /// Serve a read-only GraphQL API over published comments at `/graphql`.
+    #[serde(default)]
+    pub enable_graphql: bool,
+    /// Serve plain HTML thread pages with a comment form at `/thread/{article}`, for readers
+    /// without JavaScript. Comments posted through them are always held for moderation. Requires
+    /// `commenter_sessions`, so each reader keeps one commenter ID.
+    #[serde(default)]
+    pub enable_thread_pages: bool,
+    /// How often one address may post through thread pages. Defaults to 3 at once, then 1 a minute.
+    pub thread_page_rate_limit: Option<RateLimitConfig>,
+    /// Let commenters follow an article's new comments by email. Requires the email sender and SMTP
+    /// settings, though not `enable_email_notifications`, and `public_url` and
+    /// `unsubscribe_secret`, for the unsubscribe link in each email.
+    #[serde(default)]
+    pub enable_subscriptions: bool,
+    /// Key for signing unsubscribe links. Changing it breaks the links in emails already sent.
+    pub unsubscribe_secret: Option<String>,
+    /// Comments containing more links than this aren't published automatically.
+    pub max_links_auto_publish: Option<usize>,
+    #[serde(default)]
+    pub max_links_action: LinkLimitAction,
+    /// Return a published comment to the moderation queue once this many commenters have flagged
+    /// it.
+    pub flag_hide_threshold: Option<i64>,
+    /// File of words and phrases, one per line, to filter out of comments.
+    pub word_filter_path: Option<String>,
+    #[serde(default)]
+    pub word_filter_action: WordFilterAction,
+    /// Name of a hidden field the widget adds to the comment form. People never see it, so a
+    /// comment that arrives with it filled in came from a bot.
+    pub honeypot_field: Option<String>,
+    #[serde(default)]
+    pub honeypot_action: HoneypotAction,
+    /// Token for an owner account named `admin`, for sites with a single administrator.
+    pub admin_token: Option<String>,
+    /// Further admin accounts, each with their own token and role.
+    #[serde(default)]
+    pub admins: Vec<AdminAccount>,
+    /// Commenter IDs the site owner comments with. Their comments, like those posted with an owner
+    /// account's token, are marked `is_author` so widgets can highlight them.
+    #[serde(default)]
+    pub author_commenter_ids: Vec<String>,
+    /// Refuse `/id/` requests that don't give an email address.
+    #[serde(default)]
+    pub require_email: bool,
+    /// Refuse email addresses whose domain has no mail server in DNS.
+    #[serde(default)]
+    pub email_check_mx: bool,
+    /// Check email addresses against a list of throwaway providers.
+    #[serde(default)]
+    pub block_disposable_emails: bool,
+    #[serde(default)]
+    pub disposable_email_action: DisposableEmailAction,
+    /// File of disposable domains, one per line, to use instead of the built-in list.
+    pub disposable_email_domains_path: Option<String>,
+    /// Names only `author_commenter_ids` may use. Like verified commenters' names, they are
+    /// compared ignoring case, accents, punctuation and lookalike characters.
+    #[serde(default)]
+    pub reserved_names: Vec<String>,
+    #[serde(default)]
+    pub reserved_name_action: ReservedNameAction,
+    /// Directory of translations, one TOML file per language, e.g. `fr.toml`. Status messages
+    /// follow each request's `Accept-Language` header.
+    pub locale_dir: Option<String>,
+    /// Language for emails and for requests that don't ask for an available one. Defaults to
+    /// English.
+    pub default_locale: Option<String>,
+    pub akismet_api_key: Option<String>,
+    pub akismet_blog_url: Option<String>,
+    /// Look up new commenters' IP addresses and emails, and commenters' IP addresses when they
+    /// post, with StopForumSpam. Both are sent to stopforumspam.org.
+    #[serde(default)]
+    pub enable_stopforumspam: bool,
+    /// StopForumSpam's confidence, from 0 to 100, that an address is a spammer at or above which it
+    /// counts as listed. Defaults to 50.
+    pub stopforumspam_min_confidence: Option<f64>,
+    /// DNS blocklist zones to look up clients' IP addresses in, e.g. `zen.spamhaus.org`.
+    #[serde(default)]
+    pub dnsbls: Vec<String>,
+    /// How many of `dnsbls` must list an address for it to count as listed. Defaults to 1.
+    pub dnsbl_min_listings: Option<usize>,
+    #[serde(default)]
+    pub spam_list_action: SpamListAction,
+    /// How long to remember lookup results. Defaults to 3600.
+    pub spam_list_cache_secs: Option<u64>,
+    /// MaxMind GeoLite2 or GeoIP2 Country database, for recording the country each comment came
+    /// from and applying the country lists below.
+    pub geoip_db_path: Option<String>,
+    /// ISO country codes, e.g. `US`, whose comments are always held for moderation.
+    #[serde(default)]
+    pub geoip_hold_countries: Vec<String>,
+    /// ISO country codes whose comments are refused.
+    #[serde(default)]
+    pub geoip_block_countries: Vec<String>,
+    /// When set, comments from any other country, or from addresses the database doesn't place,
+    /// are held for moderation.
+    #[serde(default)]
+    pub geoip_allow_countries: Vec<String>,
+    /// Markup allowed in comments. Without it, all markup is escaped and shown as typed.
+    pub html_policy: Option<HtmlPolicy>,
+    /// Turn URLs in comments into links, in each comment's `comment_html`.
+    #[serde(default)]
+    pub autolink: bool,
+    /// Schemes of the URLs `autolink` links. Defaults to http and https.
+    pub autolink_schemes: Option<Vec<String>>,
+    /// Let commenters attach images to their comments.
+    pub attachments: Option<Attachments>,
+    /// Directory holding `comments.html` and its translations, with widget files in an `assets`
+    /// directory inside it. Defaults to the working directory.
+    pub asset_dir: Option<String>,
+    /// Variables for the comments page at `/`, which the bundled page sets as CSS custom
+    /// properties, e.g. `accent = "#0a58ca"` for `--accent`.
+    #[serde(default)]
+    pub page_theme: HashMap<String, String>,
+    /// Public URL of this server, used to build OAuth callback URLs.
+    pub oauth_base_url: Option<String>,
+    /// Public URL of this server, written into `/embed.js` and used in unsubscribe links. Without
+    /// it, the widget is loaded from wherever the page fetched `/embed.js`.
+    pub public_url: Option<String>,
+    /// OAuth logins may only redirect back to URLs starting with one of these prefixes.
+    #[serde(default)]
+    pub oauth_return_urls: Vec<String>,
+    pub oauth_github_client_id: Option<String>,
+    pub oauth_github_client_secret: Option<String>,
+    pub oauth_google_client_id: Option<String>,
+    pub oauth_google_client_secret: Option<String>,
+    /// How long a proof-of-work challenge may be solved after it is issued. Defaults to 300.
+    pub pow_challenge_ttl_secs: Option<u64>,
+    /// Requests per minute, across all clients, above which every client must solve a challenge,
+    /// with difficulty rising as the rate climbs. Clients are otherwise only challenged when they
+    /// make many requests themselves.
+    pub pow_load_threshold: Option<f64>,
+    #[serde(default)]
+    pub pow_algorithm: PowAlgorithm,
+    /// Memory each Argon2id attempt needs, in KiB. Defaults to 4096.
+    pub pow_argon2_memory_kib: Option<u32>,
+    /// Passes over that memory per attempt. Defaults to 1.
+    pub pow_argon2_iterations: Option<u32>,
+    /// Token bucket limits keyed by endpoint path, or by route pattern for paths with parameters.
+    #[serde(default)]
+    pub rate_limits: HashMap<String, RateLimitConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Chat channels to alert about new and flagged comments, as well as or instead of email.
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    /// Seconds between runs of each maintenance job, keyed by name; see `jobs.rs`. 0 stops a job
+    /// from running.
+    #[serde(default)]
+    pub jobs: HashMap<String, u64>,
 }
 
+/// Environment variables made of this prefix and a field name in upper case override that field,
+/// e.g. `TINYCOMMENTS_BIND_PORT=8080`. Secrets such as `email_smtp_pass` can be left out of the
+/// config file entirely and supplied this way.
+const ENV_PREFIX: &str = "TINYCOMMENTS_";
+
+/// Variables with [`ENV_PREFIX`] that belong to command line arguments rather than config fields.
+const CLI_VARS: [&str; 2] = ["TINYCOMMENTS_CONFIG", "TINYCOMMENTS_ADMIN_TOKEN"];
+
 impl ConfigFile {
+    /// Read the config file and apply any environment overrides. The file may be missing if the
+    /// environment provides every required setting; otherwise a missing file is reported as such,
+    /// rather than as whichever setting is missing first.
     pub fn new_from_file(file: &str) -> Result<Self, String> {
-        let mut handle = match File::open(file) {
-            Ok(handle) => handle,
+        let overrides = env_overrides(std::env::vars());
+
+        let (mut table, missing) = match File::open(file) {
+            Ok(mut handle) => {
+                let mut config_text = String::new();
+                let _ = handle.read_to_string(&mut config_text);
+                match toml::from_str::<toml::Table>(&config_text) {
+                    Ok(table) => (table, None),
+                    Err(e) => return Err(format!("Unable to parse config file: {e:?}")),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !overrides.is_empty() => {
+                (toml::Table::new(), Some(e))
+            }
             Err(e) => return Err(format!("Unable to open config file: {e:?}")),
         };
 
-        let mut config_text = String::new();
-        let _ = handle.read_to_string(&mut config_text);
-        match toml::from_str(&config_text) {
-            Ok(cfg) => Ok(cfg),
-            Err(e) => Err(format!("Unable to parse config file: {e:?}")),
+        table.extend(overrides);
+
+        match (toml::Value::Table(table).try_into(), missing) {
+            (Ok(cfg), _) => Ok(cfg),
+            (Err(e), Some(missing)) => Err(format!(
+                "Unable to open config file {file}: {missing}, and the environment doesn't provide every required setting: {e}"
+            )),
+            (Err(e), None) => Err(format!("Unable to parse config file: {e:?}")),
+        }
+    }
+
+    /// Check for settings that parse but contradict each other, so they fail at startup rather
+    /// than on the first request that needs them. Every problem found is returned, each naming
+    /// the fields involved.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+
+        if self.bind_port == 0 {
+            problems.push(String::from("bind_port must not be 0"));
+        }
+
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            problems.push(String::from(
+                "tls_cert_path and tls_key_path must be set together",
+            ));
+        }
+
+        if self.sqlite_cache_size_kib == Some(0) {
+            problems.push(String::from("sqlite_cache_size_kib must not be 0"));
+        }
+
+        if matches!(self.db_backend, DbBackend::Postgres) && self.db_url.is_none() {
+            problems.push(String::from("db_backend = \"Postgres\" requires db_url"));
+        }
+
+        let email_fields = [
+            ("email_sender_address", &self.email_sender_address),
+            ("email_sender_name", &self.email_sender_name),
+            ("email_smtp_host", &self.email_smtp_host),
+        ];
+        let needs_email = |what: &str, problems: &mut Vec<String>| {
+            for (field, value) in email_fields {
+                if value.is_none() {
+                    problems.push(format!("{what} requires {field}"));
+                }
+            }
+        };
+
+        if self.enable_email_notifications {
+            needs_email("enable_email_notifications", &mut problems);
+            if self.email_notify_address.is_none() {
+                problems.push(String::from(
+                    "enable_email_notifications requires email_notify_address",
+                ));
+            }
+        }
+
+        if let Some(mins) = self.email_digest_interval_mins {
+            if mins == 0 {
+                problems.push(String::from("email_digest_interval_mins must not be 0"));
+            }
+            if !self.enable_email_notifications {
+                problems.push(String::from(
+                    "email_digest_interval_mins requires enable_email_notifications",
+                ));
+            }
+        }
+
+        if self.export_email_confirmation {
+            needs_email("export_email_confirmation", &mut problems);
+        }
+
+        if let Some(n) = self.auto_approve_after {
+            if n < 0 {
+                problems.push(String::from("auto_approve_after must not be negative"));
+            }
+            if !self.moderate_new_comments {
+                problems.push(String::from(
+                    "auto_approve_after requires moderate_new_comments",
+                ));
+            }
+        }
+
+        for (field, days) in [
+            ("retention_pending_days", self.retention_pending_days),
+            (
+                "retention_unused_commenter_days",
+                self.retention_unused_commenter_days,
+            ),
+        ] {
+            if days == Some(0) {
+                problems.push(format!("{field} must not be 0"));
+            }
+        }
+
+        if matches!(self.pow_argon2_memory_kib, Some(m) if m < 8) {
+            problems.push(String::from("pow_argon2_memory_kib must be at least 8"));
+        }
+
+        if self.reputation_trust_at.is_some() && !self.moderate_new_comments {
+            problems.push(String::from(
+                "reputation_trust_at requires moderate_new_comments",
+            ));
+        }
+
+        if let (Some(trust), Some(hold)) = (self.reputation_trust_at, self.reputation_hold_below) {
+            if trust < hold {
+                problems.push(String::from(
+                    "reputation_trust_at must not be below reputation_hold_below",
+                ));
+            }
+        }
+
+        if self.pow_argon2_iterations == Some(0) {
+            problems.push(String::from("pow_argon2_iterations must be at least 1"));
+        }
+
+        if matches!(self.pow_load_threshold, Some(n) if n.is_nan() || n <= 0.0) {
+            problems.push(String::from("pow_load_threshold must be greater than 0"));
+        }
+
+        if matches!(self.flag_hide_threshold, Some(n) if n < 1) {
+            problems.push(String::from("flag_hide_threshold must be at least 1"));
+        }
+
+        if self.one_vote_per_ip && self.vote_ip_secret.is_none() {
+            problems.push(String::from("one_vote_per_ip requires vote_ip_secret"));
+        }
+
+        if self.enable_subscriptions {
+            needs_email("enable_subscriptions", &mut problems);
+            if self.public_url.is_none() || self.unsubscribe_secret.is_none() {
+                problems.push(String::from(
+                    "enable_subscriptions requires public_url and unsubscribe_secret",
+                ));
+            }
+        }
+
+        if self.commenter_sessions && self.commenter_session_secret.is_none() {
+            problems.push(String::from(
+                "commenter_sessions requires commenter_session_secret",
+            ));
+        }
+
+        if self.enable_thread_pages && !self.commenter_sessions {
+            problems.push(String::from(
+                "enable_thread_pages requires commenter_sessions",
+            ));
+        }
+
+        if self.max_comment_bytes == Some(0) {
+            problems.push(String::from("max_comment_bytes must not be 0"));
+        }
+
+        if let (Some(min), Some(max)) = (self.min_comment_chars, self.max_comment_bytes) {
+            if min > max {
+                problems.push(String::from(
+                    "min_comment_chars must not be more than max_comment_bytes",
+                ));
+            }
+        }
+
+        if self
+            .stopforumspam_min_confidence
+            .is_some_and(|confidence| !(0.0..=100.0).contains(&confidence))
+        {
+            problems.push(String::from(
+                "stopforumspam_min_confidence must be between 0 and 100",
+            ));
+        }
+
+        if let Some(min) = self.dnsbl_min_listings {
+            if min == 0 || min > self.dnsbls.len() {
+                problems.push(format!(
+                    "dnsbl_min_listings must be between 1 and the number of dnsbls ({})",
+                    self.dnsbls.len()
+                ));
+            }
+        }
+
+        if self
+            .dnsbls
+            .iter()
+            .any(|zone| zone.trim_matches('.').is_empty())
+        {
+            problems.push(String::from("dnsbls must not contain empty zones"));
+        }
+
+        let country_lists = [
+            ("geoip_hold_countries", &self.geoip_hold_countries),
+            ("geoip_block_countries", &self.geoip_block_countries),
+            ("geoip_allow_countries", &self.geoip_allow_countries),
+        ];
+        for (field, countries) in country_lists {
+            if !countries.is_empty() && self.geoip_db_path.is_none() {
+                problems.push(format!("{field} requires geoip_db_path"));
+            }
+            for country in countries {
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                    problems.push(format!("{field} has invalid country code {country}"));
+                }
+            }
+        }
+
+        if let Some(policy) = &self.html_policy {
+            problems.extend(policy.validate());
+        }
+
+        problems.extend(html::validate_autolink_schemes(self));
+
+        if let Some(attachments) = &self.attachments {
+            problems.extend(attachments.validate(self));
+        }
+
+        if let Some(log_file) = &self.log_file {
+            problems.extend(log_file.validate("log_file"));
+        }
+
+        if let Some(access_log) = &self.access_log {
+            problems.extend(access_log.validate("access_log"));
+        }
+
+        problems.extend(assets::validate_theme(&self.page_theme));
+
+        if let Some(reporting) = &self.error_reporting {
+            problems.extend(reporting.validate());
+        }
+
+        if self.akismet_api_key.is_some() && self.akismet_blog_url.is_none() {
+            problems.push(String::from("akismet_api_key requires akismet_blog_url"));
+        }
+
+        let providers = [
+            (
+                "oauth_github_client_id",
+                &self.oauth_github_client_id,
+                "oauth_github_client_secret",
+                &self.oauth_github_client_secret,
+            ),
+            (
+                "oauth_google_client_id",
+                &self.oauth_google_client_id,
+                "oauth_google_client_secret",
+                &self.oauth_google_client_secret,
+            ),
+        ];
+        for (id_field, id, secret_field, secret) in providers {
+            if id.is_some() != secret.is_some() {
+                problems.push(format!(
+                    "{id_field} and {secret_field} must be set together"
+                ));
+            }
+            if id.is_some() && self.oauth_base_url.is_none() {
+                problems.push(format!("{id_field} requires oauth_base_url"));
+            }
+        }
+
+        if let Some(path) = &self.word_filter_path {
+            if !std::path::Path::new(path).is_file() {
+                problems.push(format!("word_filter_path {path} is not a readable file"));
+            }
+        }
+
+        for name in &self.reserved_names {
+            if names::skeleton(name).is_empty() {
+                problems.push(format!(
+                    "reserved_names entry '{name}' has no letters or digits"
+                ));
+            }
+        }
+
+        if let Some(path) = &self.disposable_email_domains_path {
+            if !std::path::Path::new(path).is_file() {
+                problems.push(format!(
+                    "disposable_email_domains_path {path} is not a readable file"
+                ));
+            }
+        }
+
+        if let Some(field) = &self.honeypot_field {
+            if field.is_empty() {
+                problems.push(String::from("honeypot_field must not be empty"));
+            }
+            if honeypot::RESERVED_FIELDS.contains(&field.as_str()) {
+                problems.push(format!(
+                    "honeypot_field must not be {field}, which the comment form already uses"
+                ));
+            }
+        }
+
+        for (i, hook) in self.webhooks.iter().enumerate() {
+            if hook.url.is_empty() {
+                problems.push(format!("webhooks[{i}].url must not be empty"));
+            }
+            for event in &hook.events {
+                if !webhooks::EVENTS.contains(&event.as_str()) {
+                    problems.push(format!("webhooks[{i}].events has unknown event {event}"));
+                }
+            }
+        }
+
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        if let Some(token) = &self.admin_token {
+            names.insert(auth::ADMIN_TOKEN_ACCOUNT);
+            tokens.insert(token.as_str());
+        }
+        for (i, account) in self.admins.iter().enumerate() {
+            if account.name.is_empty() {
+                problems.push(format!("admins[{i}].name must not be empty"));
+            } else if !names.insert(&account.name) {
+                problems.push(format!(
+                    "admins[{i}].name {} is already in use",
+                    account.name
+                ));
+            }
+            if account.token.is_empty() {
+                problems.push(format!("admins[{i}].token must not be empty"));
+            } else if !tokens.insert(&account.token) {
+                problems.push(format!("admins[{i}].token is already in use"));
+            }
+        }
+
+        for name in self.jobs.keys() {
+            if !jobs::JOBS.contains(&name.as_str()) {
+                problems.push(format!("jobs has unknown job {name}"));
+            }
+        }
+
+        for (i, notifier) in self.notifiers.iter().enumerate() {
+            let url = match &notifier.kind {
+                NotifierKind::Slack { webhook_url } | NotifierKind::Discord { webhook_url } => {
+                    webhook_url
+                }
+                NotifierKind::Matrix { homeserver, .. } => homeserver,
+                NotifierKind::Ntfy { server: None, .. } => continue,
+                NotifierKind::Ntfy {
+                    server: Some(server),
+                    ..
+                } => server,
+            };
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                problems.push(format!("notifiers[{i}] needs an http or https URL"));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
         }
     }
 }
+
+/// Values are parsed as TOML, so `true`, `8080`, and `["https://a.example"]` have their usual
+/// types, and anything that doesn't parse is taken as a string. Quote a string that would
+/// otherwise parse as something else, such as a numeric password.
+fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> toml::Table {
+    vars.filter_map(|(name, value)| {
+        if CLI_VARS.contains(&name.as_str()) {
+            return None;
+        }
+        let key = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+        let value = toml::from_str::<toml::Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or(toml::Value::String(value));
+        Some((key, value))
+    })
+    .collect()
+}
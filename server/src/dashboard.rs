@@ -0,0 +1,535 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A small HTML interface to the admin API for operators who would rather not manage comments
+//! with curl. It signs in with an admin account's token and keeps the session in an HttpOnly
+//! cookie.
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use tera::{Context, Tera, Value};
+use tracing::warn;
+
+use crate::admin::{self, constant_time_eq};
+use crate::auth::{self, Account};
+use crate::blocklist::{self, Action, Kind};
+use crate::config::{ConfigFile, Role};
+use crate::{db, get_client_ip, titles, AppState};
+
+const SESSION_COOKIE: &str = "tinycomments_admin";
+
+/// How many of the newest comments the dashboard lists.
+const RECENT_COMMENTS: i64 = 50;
+
+const STYLE: &str = r#"<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; width: 100%; margin-bottom: 2em; }
+th, td { border-bottom: 1px solid #ddd; padding: 0.4em; text-align: left; vertical-align: top; }
+.stats span { display: inline-block; margin-right: 2em; }
+.notice { background: #ffd; padding: 0.5em; }
+.pending { color: #a60; }
+form.inline { display: inline; }
+</style>"#;
+
+const LOGIN_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Tinycomments admin</title>{{ style | safe }}</head>
+<body>
+<h1>Tinycomments admin</h1>
+{% if error %}<p class="notice">{{ error }}</p>{% endif %}
+<form method="post" action="login/">
+<label>Admin token <input type="password" name="token" autofocus></label>
+<button type="submit">Sign in</button>
+</form>
+</body></html>"#;
+
+const DASHBOARD_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Tinycomments admin</title>{{ style | safe }}</head>
+<body>
+<h1>Tinycomments admin</h1>
+<form class="inline" method="post" action="logout/">
+Signed in as {{ account }}
+<input type="hidden" name="csrf" value="{{ csrf }}"><button type="submit">Sign out</button>
+</form>
+{% if notice %}<p class="notice">{{ notice }}</p>{% endif %}
+
+<p class="stats">
+<span>{{ stats.articles }} articles</span>
+<span>{{ stats.comments }} comments</span>
+<span>{{ stats.pending }} pending</span>
+<span>{{ stats.commenters }} commenters</span>
+<span>{{ stats.votes }} votes</span>
+</p>
+
+{% macro article_link(article, titles) %}{% set name = titles[article] | default(value=article) %}{% if article is starting_with("http") %}<a href="{{ article }}">{{ name }}</a>{% else %}{{ name }}{% endif %}{% endmacro %}
+
+<h2>Moderation queue</h2>
+{% if pending %}
+<table>
+<tr><th>Posted</th><th>Article</th><th>Poster</th><th>Comment</th><th></th></tr>
+{% for comment in pending %}
+<tr>
+<td>{{ comment.timestamp | datetime }}{% if comment.country %}<br>from {{ comment.country }}{% endif %}{% if comment.flags > 0 %}<br><span class="pending">flagged {{ comment.flags }} time{{ comment.flags | pluralize }}</span>{% endif %}</td>
+<td>{{ self::article_link(article=comment.article, titles=titles) }}</td>
+<td>{{ comment.poster_name | safe }}<br>{{ comment.poster_email }}</td>
+<td>{{ comment.comment | safe }}</td>
+<td>
+{% if can_moderate %}<form class="inline" method="post" action="dashboard/moderate/">
+<input type="hidden" name="csrf" value="{{ csrf }}">
+<input type="hidden" name="comment_id" value="{{ comment.id }}">
+<button type="submit" name="decision" value="approve">Approve</button>
+<button type="submit" name="decision" value="reject">Reject</button>
+</form>{% endif %}
+</td>
+</tr>
+{% endfor %}
+</table>
+{% else %}
+<p>No comments are waiting for moderation.</p>
+{% endif %}
+
+{% if flagged %}
+<h2>Flagged comments</h2>
+<table>
+<tr><th>Posted</th><th>Article</th><th>Poster</th><th>Comment</th><th>Reasons</th><th></th></tr>
+{% for comment in flagged %}
+<tr>
+<td>{{ comment.timestamp | datetime }}{% if comment.pending %}<br><span class="pending">hidden</span>{% endif %}</td>
+<td>{{ self::article_link(article=comment.article, titles=titles) }}</td>
+<td>{{ comment.poster_name | safe }}</td>
+<td>{{ comment.comment | safe }}</td>
+<td>{% for reason in comment.reasons %}{{ reason | safe }}{% if not loop.last %}<br>{% endif %}{% endfor %}</td>
+<td>
+{% if can_moderate %}<form class="inline" method="post" action="dashboard/moderate/">
+<input type="hidden" name="csrf" value="{{ csrf }}">
+<input type="hidden" name="comment_id" value="{{ comment.id }}">
+{% if comment.pending %}<button type="submit" name="decision" value="approve">Approve</button>
+<button type="submit" name="decision" value="reject">Reject</button>{% endif %}
+<button type="submit" name="decision" value="dismiss">Dismiss</button>
+</form>{% endif %}
+</td>
+</tr>
+{% endfor %}
+</table>
+{% endif %}
+
+<h2>Recent comments</h2>
+<table>
+<tr><th>Posted</th><th>Article</th><th>Poster</th><th>Comment</th><th>Votes</th></tr>
+{% for comment in recent %}
+<tr>
+<td>{{ comment.timestamp | datetime }}{% if comment.country %}<br>from {{ comment.country }}{% endif %}{% if comment.pending %}<br><span class="pending">pending</span>{% endif %}</td>
+<td>{{ self::article_link(article=comment.article, titles=titles) }}</td>
+<td>{{ comment.poster_name | safe }}</td>
+<td>{{ comment.comment | safe }}</td>
+<td>{{ comment.votes }}</td>
+</tr>
+{% endfor %}
+</table>
+
+<h2>Blocklist</h2>
+<table>
+<tr><th>Kind</th><th>Pattern</th><th>Action</th><th>Added</th><th></th></tr>
+{% for entry in blocklist %}
+<tr>
+<td>{{ entry.kind }}</td>
+<td><code>{{ entry.pattern }}</code></td>
+<td>{{ entry.action }}</td>
+<td>{{ entry.created_at | datetime }}</td>
+<td>
+{% if can_moderate %}<form class="inline" method="post" action="dashboard/blocklist/remove/">
+<input type="hidden" name="csrf" value="{{ csrf }}">
+<input type="hidden" name="id" value="{{ entry.id }}">
+<button type="submit">Remove</button>
+</form>{% endif %}
+</td>
+</tr>
+{% endfor %}
+</table>
+{% if can_moderate %}<form method="post" action="dashboard/blocklist/add/">
+<input type="hidden" name="csrf" value="{{ csrf }}">
+<select name="kind"><option value="ip">IP</option><option value="email">Email</option><option value="keyword">Keyword</option></select>
+<input type="text" name="pattern" placeholder="203.0.113.0/24, *@spam.example, or a phrase" size="40">
+<select name="action"><option value="reject">Reject</option><option value="hold">Hold</option></select>
+<button type="submit">Add</button>
+</form>{% endif %}
+</body></html>"#;
+
+/// The dashboard's pages, rendered with Tera. Unlike email templates these are auto-escaped;
+/// comment text and names are marked safe since they are sanitized before they're stored.
+pub struct Templates {
+    tera: Tera,
+}
+
+impl Templates {
+    pub fn new() -> Result<Self, String> {
+        let mut tera = Tera::default();
+        tera.register_filter("datetime", datetime);
+
+        tera.add_raw_templates(vec![
+            ("login.html", LOGIN_TEMPLATE),
+            ("dashboard.html", DASHBOARD_TEMPLATE),
+        ])
+        .map_err(|e| format!("Unable to load dashboard templates: {e:?}"))?;
+
+        Ok(Templates { tera })
+    }
+
+    fn render(&self, name: &str, mut context: Context) -> HttpResponse {
+        context.insert("style", STYLE);
+
+        match self.tera.render(name, &context) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type(ContentType::html())
+                .body(body),
+            Err(e) => {
+                warn!("Unable to render {name}: {e:?}");
+                HttpResponse::InternalServerError().body("Unable to render page")
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IndexQuery {
+    notice: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct LogoutRequest {
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Decision {
+    Approve,
+    Reject,
+    /// Clear a comment's flags, leaving it as it is.
+    Dismiss,
+}
+
+#[derive(Deserialize)]
+struct ModerateRequest {
+    csrf: String,
+    comment_id: i64,
+    decision: Decision,
+}
+
+#[derive(Deserialize)]
+struct BlocklistAddRequest {
+    csrf: String,
+    kind: Kind,
+    pattern: String,
+    action: Action,
+}
+
+#[derive(Deserialize)]
+struct BlocklistRemoveRequest {
+    csrf: String,
+    id: i64,
+}
+
+#[get("/admin/")]
+async fn index(
+    query: web::Query<IndexQuery>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::enabled(&state.config) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let Some(account) = session(&state.config, &req) else {
+        return state.dashboard.render("login.html", Context::new());
+    };
+
+    let res = db::run(&state.db, |db| {
+        Ok((
+            db.stats()?,
+            db.pending_comments()?,
+            db.flagged_comments()?,
+            db.recent_comments(RECENT_COMMENTS)?,
+            db.blocklist_entries()?,
+        ))
+    })
+    .await;
+
+    let (stats, pending, flagged, recent, blocklist) = match res {
+        Ok(data) => data,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB Error: {e}")),
+    };
+
+    let mut urls: Vec<String> = pending
+        .iter()
+        .map(|comment| comment.article.clone())
+        .chain(flagged.iter().map(|comment| comment.article.clone()))
+        .chain(recent.iter().map(|comment| comment.article.clone()))
+        .collect();
+    urls.sort();
+    urls.dedup();
+    let titles = titles::cached(&state, urls).await;
+
+    let mut context = Context::new();
+    context.insert("csrf", &signature(account.token, "csrf"));
+    context.insert("account", account.name);
+    context.insert("can_moderate", &(account.role >= Role::Moderator));
+    context.insert("notice", &query.notice);
+    context.insert("stats", &stats);
+    context.insert("pending", &pending);
+    context.insert("flagged", &flagged);
+    context.insert("recent", &recent);
+    context.insert("blocklist", &blocklist);
+    context.insert("titles", &titles);
+
+    state.dashboard.render("dashboard.html", context)
+}
+
+#[post("/admin/login/")]
+async fn login(
+    data: web::Form<LoginRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::enabled(&state.config) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let Some(account) = auth::find_token(&state.config, &data.token) else {
+        warn!(client_ip = get_client_ip(&req), "Failed dashboard sign-in");
+
+        let mut context = Context::new();
+        context.insert("error", "Incorrect admin token");
+        let mut response = state.dashboard.render("login.html", context);
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        return response;
+    };
+
+    let cookie = Cookie::build(SESSION_COOKIE, signature(account.token, "session"))
+        .path(cookie_path(&req))
+        .http_only(true)
+        .secure(req.connection_info().scheme() == "https")
+        .same_site(SameSite::Strict)
+        .finish();
+
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "../"))
+        .cookie(cookie)
+        .finish()
+}
+
+#[post("/admin/logout/")]
+async fn logout(
+    data: web::Form<LogoutRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if check_session(&state, &req, &data.csrf, Role::Readonly).is_none() {
+        return refused(&state);
+    }
+
+    let mut cookie = Cookie::build(SESSION_COOKIE, "")
+        .path(cookie_path(&req))
+        .finish();
+    cookie.make_removal();
+
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "../"))
+        .cookie(cookie)
+        .finish()
+}
+
+#[post("/admin/dashboard/moderate/")]
+async fn moderate(
+    data: web::Form<ModerateRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let Some(account) = check_session(&state, &req, &data.csrf, Role::Moderator) else {
+        return refused(&state);
+    };
+
+    let comment_id = data.comment_id;
+    let (res, done) = match data.decision {
+        Decision::Approve => (
+            admin::approve_comment(&state, account.name, comment_id).await,
+            "approved",
+        ),
+        Decision::Reject => (
+            admin::reject_comment(&state, account.name, comment_id).await,
+            "rejected",
+        ),
+        Decision::Dismiss => (
+            admin::dismiss_flags(&state, account.name, comment_id).await,
+            "flags dismissed",
+        ),
+    };
+
+    back_to_dashboard(match res {
+        Ok(true) => format!("Comment {comment_id} {done}"),
+        Ok(false) if matches!(data.decision, Decision::Dismiss) => {
+            format!("Comment {comment_id} has no flags")
+        }
+        Ok(false) => format!("Comment {comment_id} is no longer pending"),
+        Err(e) => format!("Could not moderate comment {comment_id}: {e}"),
+    })
+}
+
+#[post("/admin/dashboard/blocklist/add/")]
+async fn blocklist_add(
+    data: web::Form<BlocklistAddRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let Some(account) = check_session(&state, &req, &data.csrf, Role::Moderator) else {
+        return refused(&state);
+    };
+
+    back_to_dashboard(
+        match blocklist::add_entry(&state, account.name, data.kind, &data.pattern, data.action)
+            .await
+        {
+            Ok(_) => String::from("Blocklist entry added"),
+            Err(e) => e.to_string(),
+        },
+    )
+}
+
+#[post("/admin/dashboard/blocklist/remove/")]
+async fn blocklist_remove(
+    data: web::Form<BlocklistRemoveRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let Some(account) = check_session(&state, &req, &data.csrf, Role::Moderator) else {
+        return refused(&state);
+    };
+
+    back_to_dashboard(
+        match blocklist::remove_entry(&state, account.name, data.id).await {
+            Ok(()) => String::from("Blocklist entry removed"),
+            Err(e) => e.to_string(),
+        },
+    )
+}
+
+/// A value only the holder of an account's token can produce, so changing the token signs that
+/// account out.
+fn signature(token: &str, purpose: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(purpose.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// The account whose session cookie the request carries.
+pub fn session<'a>(config: &'a ConfigFile, req: &HttpRequest) -> Option<Account<'a>> {
+    let cookie = req.cookie(SESSION_COOKIE)?;
+
+    auth::accounts(config).find(|account| {
+        constant_time_eq(
+            cookie.value().as_bytes(),
+            signature(account.token, "session").as_bytes(),
+        )
+    })
+}
+
+/// Form submissions need both the session cookie and the CSRF token embedded in the dashboard.
+/// Returns the signed-in account when both are present and its role is at least `role`. The
+/// middleware checks roles too, but the action shouldn't depend on it having recognized the path.
+fn check_session<'a>(
+    state: &'a web::Data<AppState>,
+    req: &HttpRequest,
+    csrf: &str,
+    role: Role,
+) -> Option<Account<'a>> {
+    session(&state.config, req).filter(|account| {
+        constant_time_eq(csrf.as_bytes(), signature(account.token, "csrf").as_bytes())
+            && account.role >= role
+    })
+}
+
+/// The response to a form submission that [`check_session`] refused.
+fn refused(state: &web::Data<AppState>) -> HttpResponse {
+    if auth::enabled(&state.config) {
+        HttpResponse::Forbidden().body("Forbidden")
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// Every dashboard action is posted from `/admin/` and redirects back to it, relative to the
+/// action's own path so that the dashboard also works behind a path prefix.
+fn back_to_dashboard(notice: String) -> HttpResponse {
+    let query = serde_urlencoded::to_string([("notice", notice)]).unwrap_or_default();
+
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, format!("../../?{query}")))
+        .finish()
+}
+
+/// Scope the session cookie to the dashboard, wherever it's mounted.
+fn cookie_path(req: &HttpRequest) -> String {
+    let path = req.path();
+    let end = path.rfind("/admin/").map_or(0, |i| i + "/admin/".len());
+    String::from(&path[..end])
+}
+
+/// Tera filter formatting a Unix timestamp as a UTC date and time.
+pub fn datetime(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    match value.as_i64() {
+        Some(secs) => Ok(Value::String(format_timestamp(secs))),
+        None => Ok(value.clone()),
+    }
+}
+
+/// A Unix timestamp as a UTC date and time, e.g. `2024-05-01 09:30 UTC`.
+pub fn format_timestamp(secs: i64) -> String {
+    // Days since the epoch to a civil date, per Howard Hinnant's `civil_from_days`.
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
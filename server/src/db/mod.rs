@@ -0,0 +1,680 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::{AnonymizeMode, ConfigFile, DbBackend};
+use crate::migrations::Migration;
+
+pub mod postgres;
+pub mod sqlite;
+
+/// Shown as the poster of comments whose author has been anonymized.
+pub const ANONYMIZED_NAME: &str = "Anonymous";
+
+/// Left in place of a removed comment that still has replies.
+pub const DELETED_COMMENT: &str = "[deleted]";
+
+/// A published comment, as returned to readers.
+pub use tinycomments_types::{Comment, CommentSort};
+
+/// The ORDER BY clause for a [`CommentSort`]. Ties fall back to posting order so pages are
+/// stable.
+pub fn order_by(sort: CommentSort) -> &'static str {
+    match sort {
+        CommentSort::Oldest => "timestamp ASC, id ASC",
+        CommentSort::Newest => "timestamp DESC, id DESC",
+        CommentSort::Top => "votes DESC, timestamp ASC, id ASC",
+    }
+}
+
+/// A published comment matching a search.
+#[derive(Serialize, Deserialize)]
+pub struct SearchResult {
+    pub id: i64,
+    pub timestamp: i64,
+    pub article: String,
+    pub parent: i64,
+    pub poster_name: String,
+    pub comment: String,
+}
+
+/// A comment awaiting moderation, as returned to site admins.
+#[derive(Serialize, Deserialize)]
+pub struct PendingComment {
+    pub id: i64,
+    pub timestamp: i64,
+    pub article: String,
+    pub parent: i64,
+    pub poster_name: String,
+    pub poster_email: String,
+    pub comment: String,
+    /// How many readers have flagged it. Comments hidden by `flag_hide_threshold` wait here.
+    pub flags: i64,
+    /// ISO code of the country it was posted from, when `geoip_db_path` is set.
+    pub country: Option<String>,
+}
+
+/// A comment readers have flagged, with their reasons, oldest first.
+#[derive(Serialize, Deserialize)]
+pub struct FlaggedComment {
+    pub id: i64,
+    pub timestamp: i64,
+    pub article: String,
+    pub poster_name: String,
+    pub comment: String,
+    /// Hidden and waiting in the moderation queue.
+    pub pending: bool,
+    pub reasons: Vec<String>,
+}
+
+/// A comment as the site owner sees it in digests and the admin dashboard, published or not.
+#[derive(Serialize)]
+pub struct RecentComment {
+    pub id: i64,
+    pub timestamp: i64,
+    pub article: String,
+    pub poster_name: String,
+    pub comment: String,
+    /// Still waiting for a moderator.
+    pub pending: bool,
+    /// The sum of the votes cast on it.
+    pub votes: i64,
+    pub country: Option<String>,
+}
+
+/// Site-wide totals for the admin dashboard.
+#[derive(Serialize)]
+pub struct SiteStats {
+    pub commenters: i64,
+    pub comments: i64,
+    pub pending: i64,
+    pub votes: i64,
+    pub articles: i64,
+}
+
+/// Comments posted to one article over a period, and the votes cast on them.
+#[derive(Serialize)]
+pub struct ArticleActivity {
+    pub article: String,
+    pub comments: i64,
+    pub votes: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct VoteTotals {
+    pub upvotes: i64,
+    pub downvotes: i64,
+}
+
+pub struct Commenter {
+    pub name: String,
+    pub email: String,
+    /// How many of this commenter's comments have been published, either by a moderator or
+    /// because moderation was off.
+    pub approved_comments: i64,
+    /// Signed in through an OAuth provider, which vouches for the name and email.
+    pub verified: bool,
+    pub reply_notifications: bool,
+}
+
+/// The author of a comment being replied to or mentioned.
+#[derive(Clone)]
+pub struct ReplyRecipient {
+    pub commenter_id: String,
+    pub name: String,
+    pub email: String,
+    pub reply_notifications: bool,
+}
+
+/// A commenter following an article's new comments.
+pub struct Subscriber {
+    /// The subscription's id, which unsubscribe links carry.
+    pub id: i64,
+    pub commenter_id: String,
+    pub name: String,
+    pub email: String,
+}
+
+/// Enough of a stored comment to send notifications about it.
+pub struct CommentSummary {
+    pub article: String,
+    pub parent: Option<i64>,
+    pub commenter_id: String,
+    pub poster_name: String,
+    pub comment: String,
+}
+
+/// Whether an article has been closed to new comments or votes. Articles with no stored settings
+/// are open.
+#[derive(Default, Clone, Copy)]
+pub struct ArticleLock {
+    pub locked: bool,
+    pub voting_locked: bool,
+}
+
+/// A blocklist rule, as stored. `kind` and `action` are validated by the blocklist module.
+#[derive(Serialize, Deserialize)]
+pub struct BlocklistEntry {
+    pub id: i64,
+    pub kind: String,
+    pub pattern: String,
+    pub action: String,
+    pub created_at: i64,
+}
+
+/// One administrative or moderation action, as recorded in the audit log.
+pub struct AuditEntry {
+    pub id: i64,
+    pub at: i64,
+    /// The name of the admin account that acted.
+    pub actor: String,
+    pub action: String,
+    /// What the action applied to, such as a comment id or blocklist pattern.
+    pub target: String,
+    /// The target's state before the action, as JSON, when there was one to record.
+    pub previous: Option<String>,
+}
+
+/// An outgoing message waiting in the email queue.
+pub struct QueuedEmail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub attempts: i64,
+}
+
+/// Outstanding proof-of-work challenges and recent request times, saved so that a restart doesn't
+/// invalidate challenges clients are in the middle of solving.
+#[derive(Default)]
+pub struct PowState {
+    pub challenges: Vec<StoredChallenge>,
+    pub transactions: Vec<StoredTransaction>,
+}
+
+pub struct StoredChallenge {
+    pub challenge: String,
+    pub client_ip: String,
+    pub key: String,
+    /// Unix time in milliseconds.
+    pub issued_at_ms: i64,
+}
+
+pub struct StoredTransaction {
+    pub client_ip: String,
+    /// Unix time in milliseconds.
+    pub at_ms: i64,
+}
+
+/// Signals counted against an IP address or commenter. See [`crate::reputation`].
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Reputation {
+    pub approved: i64,
+    pub spam: i64,
+    pub pow_failures: i64,
+    pub flags: i64,
+}
+
+/// One row of a full export, as passed to the sink given to [`Storage::export`].
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ExportRecord {
+    Commenter(ExportedCommenter),
+    Comment(ExportedComment),
+    Vote(ExportedVote),
+}
+
+#[derive(Serialize)]
+pub struct ExportedCommenter {
+    pub commenter_id: String,
+    pub name: String,
+    pub email: String,
+    pub reply_notifications: bool,
+    pub verified: bool,
+    pub oauth_provider: Option<String>,
+    pub oauth_subject: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ExportedComment {
+    pub id: i64,
+    pub commenter_id: String,
+    pub timestamp: i64,
+    pub article: String,
+    pub parent: Option<i64>,
+    /// False while the comment is waiting for moderation.
+    pub moderated: bool,
+    pub comment: String,
+    pub edited_at: Option<i64>,
+    pub pinned: bool,
+}
+
+#[derive(Serialize)]
+pub struct ExportedVote {
+    pub comment_id: i64,
+    pub voter_id: String,
+    pub vote: i64,
+}
+
+#[derive(Serialize)]
+pub struct ExportedFlag {
+    pub comment_id: i64,
+    pub reason: String,
+    pub created_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct ExportedSubscription {
+    pub article: String,
+    pub created_at: i64,
+}
+
+/// Everything stored about one commenter, for answering their data-access requests.
+#[derive(Serialize)]
+pub struct CommenterExport {
+    pub commenter: ExportedCommenter,
+    pub comments: Vec<ExportedComment>,
+    pub votes: Vec<ExportedVote>,
+    pub flags: Vec<ExportedFlag>,
+    pub subscriptions: Vec<ExportedSubscription>,
+}
+
+pub struct NewComment<'a> {
+    pub article: &'a str,
+    pub commenter_id: &'a str,
+    pub parent: Option<i64>,
+    pub comment: &'a str,
+    pub moderated: bool,
+    pub timestamp: i64,
+    pub country: Option<&'a str>,
+    /// Posted by the site owner.
+    pub author: bool,
+}
+
+/// Storage operations needed by the request handlers. Implementations are synchronous and backed by
+/// a connection pool; handlers should call them through [`run`] so that database work happens on
+/// the blocking thread pool rather than the async worker threads.
+pub trait Storage: Send + Sync {
+    /// The newest migration applied to this database, or 0 for an empty database.
+    fn schema_version(&self) -> Result<i64, String>;
+    /// Apply a migration and record its version in a single transaction.
+    fn apply_migration(&self, migration: &Migration) -> Result<(), String>;
+
+    fn add_commenter(&self, commenter_id: &str, name: &str, email: &str) -> Result<(), String>;
+    fn get_commenter(&self, commenter_id: &str) -> Result<Option<Commenter>, String>;
+    /// Create a verified commenter linked to an OAuth provider account.
+    fn add_oauth_commenter(
+        &self,
+        commenter_id: &str,
+        name: &str,
+        email: &str,
+        provider: &str,
+        subject: &str,
+    ) -> Result<(), String>;
+    /// Look up the commenter id linked to an OAuth provider account.
+    fn find_oauth_commenter(&self, provider: &str, subject: &str)
+        -> Result<Option<String>, String>;
+    /// The ids and names of every verified commenter.
+    fn verified_names(&self) -> Result<Vec<(String, String)>, String>;
+    /// Change a commenter's name and email. A new email drops any OAuth verification, since the
+    /// provider doesn't vouch for it; a new name alone keeps it. Returns false if there is no such
+    /// commenter.
+    fn update_commenter(&self, commenter_id: &str, name: &str, email: &str)
+        -> Result<bool, String>;
+    /// Returns false if there is no such commenter.
+    fn set_reply_notifications(&self, commenter_id: &str, enabled: bool) -> Result<bool, String>;
+
+    /// Returns the id of the new comment.
+    fn add_comment(&self, comment: &NewComment) -> Result<i64, String>;
+    /// Pinned comments come first, then the rest in `sort` order.
+    fn get_comments(
+        &self,
+        article: &str,
+        viewer_id: &str,
+        sort: CommentSort,
+        limit: Option<i64>,
+        offset: i64,
+    ) -> Result<Vec<Comment>, String>;
+    /// The published comments on an article posted, edited, approved or pinned at or after
+    /// `since`, oldest first, and the scores of the others voted on since then, keyed by id.
+    fn get_comment_changes(
+        &self,
+        article: &str,
+        viewer_id: &str,
+        since: i64,
+    ) -> Result<(Vec<Comment>, HashMap<i64, i64>), String>;
+    /// A published comment and the article it belongs to, as [`Storage::get_comments`] would
+    /// return it to a reader who hasn't voted on it.
+    fn get_published_comment(&self, comment_id: i64) -> Result<Option<(String, Comment)>, String>;
+    /// The published replies to a comment, oldest first, as [`Storage::get_published_comment`]
+    /// returns them.
+    fn get_replies(&self, comment_id: i64) -> Result<Vec<Comment>, String>;
+    /// Record an image attached to a comment, unless the comment already has `max` of them.
+    /// Returns the new attachment's id, or None if the comment is full.
+    fn add_attachment(
+        &self,
+        comment_id: i64,
+        name: &str,
+        content_type: &str,
+        size: i64,
+        created_at: i64,
+        max: i64,
+    ) -> Result<Option<i64>, String>;
+    fn remove_attachment(&self, id: i64) -> Result<(), String>;
+    /// Names of stored attachments that no comment has any more, since their last record was
+    /// deleted, directly or with its comment.
+    fn unused_attachments(&self) -> Result<Vec<String>, String>;
+    /// Stop tracking an attachment returned by [`Storage::unused_attachments`] once its file is
+    /// gone.
+    fn forget_unused_attachment(&self, name: &str) -> Result<(), String>;
+    /// The displayed vote total of a published comment and the article it belongs to.
+    fn get_comment_votes(&self, comment_id: i64) -> Result<Option<(String, i64)>, String>;
+    fn count_comments(&self, article: &str) -> Result<i64, String>;
+    /// Published comments whose text or poster name match `query`, best matches first, along with
+    /// the total number of matches. `article` restricts the search to a single article.
+    fn search_comments(
+        &self,
+        query: &str,
+        article: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<SearchResult>, i64), String>;
+    /// Published comment counts for each of `articles` that has any comments.
+    fn count_comments_by_article(
+        &self,
+        articles: &[String],
+    ) -> Result<HashMap<String, i64>, String>;
+    fn get_comment_owner(&self, comment_id: i64) -> Result<Option<String>, String>;
+    /// The commenter ID that posted a comment, and when.
+    fn get_comment_posted(&self, comment_id: i64) -> Result<Option<(String, i64)>, String>;
+    /// Delete a comment and its votes. A comment with replies is kept as [`DELETED_COMMENT`] so
+    /// the thread stays intact. Returns false if there is no such comment.
+    fn delete_comment(&self, comment_id: i64) -> Result<bool, String>;
+    fn get_comment_summary(&self, comment_id: i64) -> Result<Option<CommentSummary>, String>;
+    fn get_reply_recipient(&self, parent_id: i64) -> Result<Option<ReplyRecipient>, String>;
+    /// Everyone with a published comment on `article`, once per commenter ID.
+    fn get_thread_participants(&self, article: &str) -> Result<Vec<ReplyRecipient>, String>;
+    /// Subscribe a commenter to new comments on `article`. Subscribing again does nothing.
+    fn add_subscription(
+        &self,
+        article: &str,
+        commenter_id: &str,
+        created_at: i64,
+    ) -> Result<(), String>;
+    fn get_subscribers(&self, article: &str) -> Result<Vec<Subscriber>, String>;
+    /// Returns false if there is no such subscription.
+    fn remove_subscription(&self, id: i64) -> Result<bool, String>;
+    fn edit_comment(
+        &self,
+        comment_id: i64,
+        commenter_id: &str,
+        comment: &str,
+        edited_at: i64,
+    ) -> Result<(), String>;
+
+    /// Record a vote, replacing the voter's earlier vote on the comment. `ip_hash` is only given
+    /// when `one_vote_per_ip` is enabled.
+    fn set_vote(
+        &self,
+        comment_id: i64,
+        voter_id: &str,
+        vote: i64,
+        ip_hash: Option<&str>,
+    ) -> Result<(), String>;
+    /// Whether a voter other than `voter_id` has voted on the comment from the same hashed IP.
+    fn ip_has_voted(&self, comment_id: i64, ip_hash: &str, voter_id: &str) -> Result<bool, String>;
+    fn remove_vote(&self, comment_id: i64, voter_id: &str) -> Result<(), String>;
+
+    fn enqueue_email(
+        &self,
+        recipient: &str,
+        subject: &str,
+        body: &str,
+        now: i64,
+    ) -> Result<(), String>;
+    /// Messages whose next delivery attempt is due, oldest first.
+    fn due_emails(&self, now: i64, limit: i64) -> Result<Vec<QueuedEmail>, String>;
+    fn reschedule_email(
+        &self,
+        id: i64,
+        attempts: i64,
+        next_attempt_at: i64,
+        last_error: &str,
+    ) -> Result<(), String>;
+    fn delete_email(&self, id: i64) -> Result<(), String>;
+
+    fn pending_comments(&self) -> Result<Vec<PendingComment>, String>;
+    /// Up to `limit` of the oldest comments posted since the last digest, in the order posted.
+    fn digest_comments(&self, limit: i64) -> Result<Vec<RecentComment>, String>;
+    /// Up to `limit` of the most recently posted comments, newest first.
+    fn recent_comments(&self, limit: i64) -> Result<Vec<RecentComment>, String>;
+    fn stats(&self) -> Result<SiteStats, String>;
+    /// How many comments were posted in `[from, to)`, keyed by the start of each `size`-second
+    /// bucket. Buckets start `offset` seconds after a multiple of `size`.
+    fn comments_per_bucket(
+        &self,
+        from: i64,
+        to: i64,
+        size: i64,
+        offset: i64,
+    ) -> Result<HashMap<i64, i64>, String>;
+    /// The `limit` articles with the most comments posted in `[from, to)`, busiest first.
+    fn top_articles(&self, from: i64, to: i64, limit: i64) -> Result<Vec<ArticleActivity>, String>;
+    /// Votes cast on the comments posted in `[from, to)`.
+    fn vote_totals(&self, from: i64, to: i64) -> Result<VoteTotals, String>;
+    /// Add to the per-day event counts kept by [`crate::stats`], given as `(day, kind, count)`.
+    fn add_daily_counts(&self, counts: &[(i64, &str, i64)]) -> Result<(), String>;
+    /// Event counts for days starting in `[from, to)`, as `(day, kind, count)`.
+    fn daily_counts(&self, from: i64, to: i64) -> Result<Vec<(i64, String, i64)>, String>;
+    /// Record that comments up to and including `last_comment_id` have been sent in a digest.
+    fn mark_digest_sent(&self, last_comment_id: i64, sent_at: i64) -> Result<(), String>;
+    /// Returns false if there was no pending comment with this id. Otherwise the approval is
+    /// counted towards the commenter's `approved_comments` and any flags on it are cleared.
+    fn approve_comment(&self, comment_id: i64) -> Result<bool, String>;
+    /// Returns false if there was no pending comment with this id.
+    fn reject_comment(&self, comment_id: i64) -> Result<bool, String>;
+    /// Reject every pending comment posted before `before` that has no replies, returning how many
+    /// were removed.
+    fn prune_pending(&self, before: i64) -> Result<i64, String>;
+    /// Delete votes on comments, or by commenters, that no longer exist, returning how many were
+    /// removed.
+    fn prune_orphaned_votes(&self) -> Result<i64, String>;
+    /// Delete commenter IDs issued before `before` that have never been used to comment, vote,
+    /// flag or subscribe, returning how many were removed.
+    fn prune_unused_commenters(&self, before: i64) -> Result<i64, String>;
+
+    /// Record a reader's report on a comment. Returns the comment's flag count, or `None` if this
+    /// reader had already flagged it.
+    fn add_flag(
+        &self,
+        comment_id: i64,
+        flagger_id: &str,
+        reason: &str,
+        created_at: i64,
+    ) -> Result<Option<i64>, String>;
+    /// Send a published comment back to the moderation queue, uncounting it from the commenter's
+    /// `approved_comments`. Returns false if it wasn't published.
+    fn hide_comment(&self, comment_id: i64) -> Result<bool, String>;
+    /// Comments with flags, published or hidden, most flagged first.
+    fn flagged_comments(&self) -> Result<Vec<FlaggedComment>, String>;
+    /// Returns false if the comment had no flags.
+    fn dismiss_flags(&self, comment_id: i64) -> Result<bool, String>;
+
+    /// Returns false if there is no such comment.
+    fn set_comment_pinned(&self, comment_id: i64, pinned: bool) -> Result<bool, String>;
+
+    fn get_article_lock(&self, article: &str) -> Result<ArticleLock, String>;
+    /// The lock on the article `comment_id` was posted to.
+    fn get_comment_article_lock(&self, comment_id: i64) -> Result<ArticleLock, String>;
+    fn set_article_lock(&self, article: &str, lock: ArticleLock) -> Result<(), String>;
+    /// When the article opened for comments: its registered publish date if it has one, otherwise
+    /// the time of its first comment. `None` for an article nobody has commented on.
+    fn get_article_opened_at(&self, article: &str) -> Result<Option<i64>, String>;
+    fn is_article_registered(&self, article: &str) -> Result<bool, String>;
+    /// Register an article to accept comments. Its publish date is set to `published_at` if
+    /// given, otherwise an already registered article keeps its date and a new one gets `now`.
+    fn register_article(
+        &self,
+        article: &str,
+        published_at: Option<i64>,
+        now: i64,
+    ) -> Result<(), String>;
+    /// Returns false if the article wasn't registered.
+    fn unregister_article(&self, article: &str) -> Result<bool, String>;
+
+    /// The cached title of the page at `url` and when it was fetched, or `None` if it never has
+    /// been. The title is `None` if the fetch failed.
+    fn get_article_title(&self, url: &str) -> Result<Option<(Option<String>, i64)>, String>;
+    fn set_article_title(
+        &self,
+        url: &str,
+        title: Option<&str>,
+        fetched_at: i64,
+    ) -> Result<(), String>;
+    /// Cached titles for whichever of `urls` have one.
+    fn get_article_titles(&self, urls: &[String]) -> Result<HashMap<String, String>, String>;
+
+    fn blocklist_entries(&self) -> Result<Vec<BlocklistEntry>, String>;
+    /// Adding a rule that already exists updates its action. Returns the rule's id.
+    fn add_blocklist_entry(
+        &self,
+        kind: &str,
+        pattern: &str,
+        action: &str,
+        created_at: i64,
+    ) -> Result<i64, String>;
+    /// Returns false if there is no such rule.
+    fn remove_blocklist_entry(&self, id: i64) -> Result<bool, String>;
+
+    fn add_audit_entry(
+        &self,
+        at: i64,
+        actor: &str,
+        action: &str,
+        target: &str,
+        previous: Option<&str>,
+    ) -> Result<(), String>;
+    /// Up to `limit` audit log entries, newest first, starting below `before` when it's given.
+    fn audit_log(&self, before: Option<i64>, limit: i64) -> Result<Vec<AuditEntry>, String>;
+
+    /// Flush any write-ahead log into the main database file before exit.
+    fn checkpoint(&self) -> Result<(), String>;
+    /// Reclaim space left by deleted rows and refresh the query planner's statistics.
+    fn optimize(&self) -> Result<(), String>;
+    /// Steps in the query plans of the comment listing queries that read all of `comments` or
+    /// `votes`, meaning an index they rely on is missing. Each is prefixed with the query's name.
+    fn full_scans(&self) -> Result<Vec<String>, String>;
+    /// Copy a consistent snapshot of the live database to a new file at `dest`, which must not
+    /// already hold a database. Only SQLite supports this.
+    fn backup(&self, dest: &str) -> Result<(), String>;
+
+    fn load_pow_state(&self) -> Result<PowState, String>;
+    /// Replace the saved proof-of-work state with `pow`.
+    fn save_pow_state(&self, pow: &PowState) -> Result<(), String>;
+
+    /// Every stored reputation, keyed by subject.
+    fn load_reputation(&self) -> Result<Vec<(String, Reputation)>, String>;
+    /// Create or overwrite these subjects' reputations.
+    fn save_reputation(
+        &self,
+        entries: &[(String, Reputation)],
+        updated_at: i64,
+    ) -> Result<(), String>;
+
+    /// Pass every commenter, then every comment, then every vote to `sink`, one row at a time and
+    /// from a single consistent snapshot. Stops at the first error from `sink`.
+    fn export(
+        &self,
+        sink: &mut dyn FnMut(ExportRecord) -> Result<(), String>,
+    ) -> Result<(), String>;
+
+    /// The commenter's identity along with every comment they posted and vote they cast, or `None`
+    /// if there is no such commenter.
+    fn export_commenter(&self, commenter_id: &str) -> Result<Option<CommenterExport>, String>;
+
+    /// Erase a commenter: drop their identity and votes and any email queued to them, and
+    /// reassign their comments to `replacement_id`, a new identity named [`ANONYMIZED_NAME`] with
+    /// no email. With [`AnonymizeMode::Delete`], their comments are removed as well, except that
+    /// comments with replies are kept as [`DELETED_COMMENT`]. Returns false if there is no such
+    /// commenter.
+    fn anonymize_commenter(
+        &self,
+        commenter_id: &str,
+        replacement_id: &str,
+        mode: AnonymizeMode,
+    ) -> Result<bool, String>;
+}
+
+pub fn open(config: &ConfigFile) -> Result<Arc<dyn Storage>, String> {
+    let pool_size = config.db_pool_size.unwrap_or(8);
+
+    match config.db_backend {
+        DbBackend::Sqlite => Ok(Arc::new(sqlite::SqliteStorage::open(
+            &config.db_path,
+            pool_size,
+            sqlite::Pragmas::new_from_config(config),
+        )?)),
+        DbBackend::Postgres => match &config.db_url {
+            Some(url) => Ok(Arc::new(postgres::PostgresStorage::open(url, pool_size)?)),
+            None => Err(String::from(
+                "db_url must be set to use the Postgres backend",
+            )),
+        },
+    }
+}
+
+/// Run a storage operation on the blocking thread pool.
+pub async fn run<F, R>(db: &Arc<dyn Storage>, f: F) -> Result<R, String>
+where
+    F: FnOnce(&dyn Storage) -> Result<R, String> + Send + 'static,
+    R: Send + 'static,
+{
+    let db = db.clone();
+
+    match web::block(move || f(db.as_ref())).await {
+        Ok(res) => res,
+        Err(e) => Err(format!("DB Error: {e:?}")),
+    }
+}
+
+/// Fold rows of one flag each, ordered by comment, into one [`FlaggedComment`] per comment, most
+/// flagged first.
+fn group_flags(rows: impl IntoIterator<Item = (FlaggedComment, String)>) -> Vec<FlaggedComment> {
+    let mut comments: Vec<FlaggedComment> = vec![];
+
+    for (comment, reason) in rows {
+        match comments.last_mut() {
+            Some(last) if last.id == comment.id => last.reasons.push(reason),
+            _ => comments.push(FlaggedComment {
+                reasons: vec![reason],
+                ..comment
+            }),
+        }
+    }
+
+    comments.sort_by_key(|comment| std::cmp::Reverse(comment.reasons.len()));
+    comments
+}
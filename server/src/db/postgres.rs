@@ -0,0 +1,1950 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::{IsolationLevel, NoTls};
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+use std::collections::HashMap;
+
+use super::{
+    group_flags, ArticleActivity, ArticleLock, AuditEntry, BlocklistEntry, Comment, CommentSort,
+    CommentSummary, Commenter, CommenterExport, ExportRecord, ExportedComment, ExportedCommenter,
+    ExportedFlag, ExportedSubscription, ExportedVote, FlaggedComment, NewComment, PendingComment,
+    PowState, QueuedEmail, RecentComment, ReplyRecipient, Reputation, SearchResult, SiteStats,
+    Storage, StoredChallenge, StoredTransaction, Subscriber, VoteTotals, ANONYMIZED_NAME,
+    DELETED_COMMENT,
+};
+use crate::base64_decode;
+use crate::config::AnonymizeMode;
+use crate::migrations::Migration;
+
+type Manager = PostgresConnectionManager<NoTls>;
+
+pub struct PostgresStorage {
+    pool: Pool<Manager>,
+}
+
+impl PostgresStorage {
+    pub fn open(url: &str, pool_size: u32) -> Result<Self, String> {
+        let config = match url.parse() {
+            Ok(config) => config,
+            Err(e) => return Err(format!("Invalid db_url: {e}")),
+        };
+
+        match Pool::builder()
+            .max_size(pool_size)
+            .build(Manager::new(config, NoTls))
+        {
+            Ok(pool) => Ok(PostgresStorage { pool }),
+            Err(e) => Err(format!("Could not connect to database: {e}")),
+        }
+    }
+
+    fn lock(&self) -> Result<PooledConnection<Manager>, String> {
+        match self.pool.get() {
+            Ok(client) => Ok(client),
+            Err(e) => Err(format!("DB Error: {e}")),
+        }
+    }
+}
+
+/// Published comments on an article with their scores and the viewer's votes, bound by viewer
+/// id, article, limit, and offset.
+fn comments_query(sort: CommentSort) -> String {
+    format!(
+        r#"SELECT id, parent, ids.name AS poster_name, COALESCE(ids.email, '') AS poster_email,
+                              COALESCE(ids.verified, false) AS verified,
+                              timestamp, comment, edited_at, pinned, author,
+                              CAST(COALESCE(SUM(v1.vote),0) + 1 AS BIGINT) AS votes,
+                              CAST(COALESCE((SELECT v2.vote FROM votes v2 WHERE v2.voter_id = $1 AND v2.comment_id = id), 0) AS BIGINT) AS myvote,
+                              (SELECT string_agg(name, chr(10) ORDER BY id) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              LEFT JOIN votes v1 on comments.id = v1.comment_id
+                              WHERE article = $2 AND id > 0 AND moderated = true
+                              GROUP BY comments.id, ids.name, ids.email, ids.verified
+                              ORDER BY pinned DESC, {}
+                              LIMIT $3 OFFSET $4;"#,
+        super::order_by(sort)
+    )
+}
+
+/// Published comments on an article changed since a time, bound by viewer id, article, and time.
+const COMMENT_CHANGES_QUERY: &str = r#"SELECT id, parent, ids.name AS poster_name, COALESCE(ids.email, '') AS poster_email,
+                              COALESCE(ids.verified, false) AS verified,
+                              timestamp, comment, edited_at, pinned, author,
+                              CAST((SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS BIGINT) AS votes,
+                              CAST(COALESCE((SELECT v2.vote FROM votes v2 WHERE v2.voter_id = $1 AND v2.comment_id = comments.id), 0) AS BIGINT) AS myvote,
+                              (SELECT string_agg(name, chr(10) ORDER BY id) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE article = $2 AND id > 0 AND moderated = true AND COALESCE(changed_at, timestamp) >= $3
+                              ORDER BY timestamp ASC, id ASC;"#;
+
+/// Count a newly published comment, bound by comment id, towards its poster's `approved_comments`.
+const APPROVED_COUNT_QUERY: &str = r#"UPDATE ids SET approved_comments = approved_comments + 1
+                                      WHERE commenter_id = (SELECT commenter_id FROM comments WHERE id = $1);"#;
+
+const VOTED_AT_QUERY: &str =
+    r#"UPDATE comments SET voted_at = EXTRACT(EPOCH FROM NOW())::BIGINT WHERE id = $1;"#;
+
+const UNAPPROVED_COUNT_QUERY: &str = r#"UPDATE ids SET approved_comments = approved_comments - 1
+                                        WHERE commenter_id = (SELECT commenter_id FROM comments WHERE id = $1);"#;
+
+/// Matches rows of `ids` that have never been used to comment, vote, flag or subscribe.
+const UNUSED_COMMENTER: &str = r#"NOT EXISTS (SELECT 1 FROM comments WHERE comments.commenter_id = ids.commenter_id)
+                                  AND NOT EXISTS (SELECT 1 FROM votes WHERE votes.voter_id = ids.commenter_id)
+                                  AND NOT EXISTS (SELECT 1 FROM flags WHERE flags.flagger_id = ids.commenter_id)
+                                  AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE subscriptions.commenter_id = ids.commenter_id)"#;
+
+fn query_err(e: postgres::Error) -> String {
+    format!("Could not execute statement: {e}")
+}
+
+fn read_err(e: postgres::Error) -> String {
+    format!("Could not read row: {e}")
+}
+
+impl Storage for PostgresStorage {
+    fn schema_version(&self) -> Result<i64, String> {
+        let create_query = r#"CREATE TABLE IF NOT EXISTS schema_version (version BIGINT PRIMARY KEY,
+                                                                         applied_at BIGINT NOT NULL);"#;
+        let query = r#"SELECT COALESCE(MAX(version), 0) AS version FROM schema_version;"#;
+
+        let mut client = self.lock()?;
+        client.batch_execute(create_query).map_err(query_err)?;
+
+        let row = client.query_one(query, &[]).map_err(query_err)?;
+        row.try_get("version").map_err(read_err)
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<(), String> {
+        let query = r#"INSERT INTO schema_version VALUES ($1, EXTRACT(EPOCH FROM NOW())::BIGINT);"#;
+
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+        transaction
+            .batch_execute(migration.postgres)
+            .map_err(|e| format!("Could not apply migration {}: {e}", migration.version))?;
+        transaction
+            .execute(query, &[&migration.version])
+            .map_err(query_err)?;
+        transaction.commit().map_err(query_err)
+    }
+
+    fn add_commenter(&self, commenter_id: &str, name: &str, email: &str) -> Result<(), String> {
+        let query = r#"INSERT INTO ids (commenter_id, name, email, created_at)
+                              VALUES ($1, $2, $3, EXTRACT(EPOCH FROM NOW())::BIGINT);"#;
+
+        self.lock()?
+            .execute(query, &[&commenter_id, &name, &email])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn get_commenter(&self, commenter_id: &str) -> Result<Option<Commenter>, String> {
+        let query = r#"SELECT name, email, approved_comments, verified, reply_notifications FROM ids WHERE commenter_id = $1"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&commenter_id])
+            .map_err(query_err)?;
+
+        row.map(|row| {
+            Ok(Commenter {
+                name: row.try_get("name").map_err(read_err)?,
+                email: row.try_get("email").map_err(read_err)?,
+                approved_comments: row.try_get("approved_comments").map_err(read_err)?,
+                verified: row.try_get("verified").map_err(read_err)?,
+                reply_notifications: row.try_get("reply_notifications").map_err(read_err)?,
+            })
+        })
+        .transpose()
+    }
+
+    fn add_oauth_commenter(
+        &self,
+        commenter_id: &str,
+        name: &str,
+        email: &str,
+        provider: &str,
+        subject: &str,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO ids (commenter_id, name, email, oauth_provider, oauth_subject, verified, created_at)
+                              VALUES ($1, $2, $3, $4, $5, true, EXTRACT(EPOCH FROM NOW())::BIGINT);"#;
+
+        self.lock()?
+            .execute(query, &[&commenter_id, &name, &email, &provider, &subject])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn find_oauth_commenter(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<String>, String> {
+        let query =
+            r#"SELECT commenter_id FROM ids WHERE oauth_provider = $1 AND oauth_subject = $2;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&provider, &subject])
+            .map_err(query_err)?;
+
+        row.map(|row| row.try_get("commenter_id").map_err(read_err))
+            .transpose()
+    }
+
+    fn verified_names(&self) -> Result<Vec<(String, String)>, String> {
+        let query = r#"SELECT commenter_id, name FROM ids WHERE verified;"#;
+
+        let rows = self.lock()?.query(query, &[]).map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("commenter_id").map_err(read_err)?,
+                    row.try_get("name").map_err(read_err)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn update_commenter(
+        &self,
+        commenter_id: &str,
+        name: &str,
+        email: &str,
+    ) -> Result<bool, String> {
+        let query = r#"UPDATE ids SET name = $1, email = $2, verified = verified AND COALESCE(email, '') = $2
+                              WHERE commenter_id = $3;"#;
+
+        let count = self
+            .lock()?
+            .execute(query, &[&name, &email, &commenter_id])
+            .map_err(query_err)?;
+        Ok(count > 0)
+    }
+
+    fn set_reply_notifications(&self, commenter_id: &str, enabled: bool) -> Result<bool, String> {
+        let query = r#"UPDATE ids SET reply_notifications = $1 WHERE commenter_id = $2;"#;
+
+        let count = self
+            .lock()?
+            .execute(query, &[&enabled, &commenter_id])
+            .map_err(query_err)?;
+        Ok(count > 0)
+    }
+
+    fn add_comment(&self, comment: &NewComment) -> Result<i64, String> {
+        let query = r#"INSERT INTO comments (article, commenter_id, parent, comment, moderated, timestamp, country, author)
+                                            VALUES($1, $2, $3, $4, $5, $6, $7, $8)
+                                            RETURNING id;"#;
+
+        let mut client = self.lock()?;
+        let row = client
+            .query_one(
+                query,
+                &[
+                    &comment.article,
+                    &comment.commenter_id,
+                    &comment.parent,
+                    &comment.comment,
+                    &comment.moderated,
+                    &comment.timestamp,
+                    &comment.country,
+                    &comment.author,
+                ],
+            )
+            .map_err(query_err)?;
+        let id: i64 = row.try_get("id").map_err(read_err)?;
+
+        if comment.moderated {
+            client
+                .execute(APPROVED_COUNT_QUERY, &[&id])
+                .map_err(query_err)?;
+        }
+
+        Ok(id)
+    }
+
+    fn get_comments(
+        &self,
+        article: &str,
+        viewer_id: &str,
+        sort: CommentSort,
+        limit: Option<i64>,
+        offset: i64,
+    ) -> Result<Vec<Comment>, String> {
+        let query = comments_query(sort);
+
+        // A NULL LIMIT means no limit in PostgreSQL.
+        let rows = self
+            .lock()?
+            .query(&query, &[&viewer_id, &article, &limit, &offset])
+            .map_err(query_err)?;
+
+        rows.iter().map(comment).collect()
+    }
+
+    fn get_comment_changes(
+        &self,
+        article: &str,
+        viewer_id: &str,
+        since: i64,
+    ) -> Result<(Vec<Comment>, HashMap<i64, i64>), String> {
+        let query = COMMENT_CHANGES_QUERY;
+        let votes_query = r#"SELECT id, CAST((SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS BIGINT) AS votes
+                              FROM comments
+                              WHERE article = $1 AND id > 0 AND moderated = true AND voted_at >= $2
+                              AND COALESCE(changed_at, timestamp) < $2;"#;
+
+        let mut client = self.lock()?;
+        let rows = client
+            .query(query, &[&viewer_id, &article, &since])
+            .map_err(query_err)?;
+        let comments = rows.iter().map(comment).collect::<Result<_, String>>()?;
+
+        let votes = client
+            .query(votes_query, &[&article, &since])
+            .map_err(query_err)?
+            .iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("id").map_err(read_err)?,
+                    row.try_get("votes").map_err(read_err)?,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok((comments, votes))
+    }
+
+    fn get_published_comment(&self, comment_id: i64) -> Result<Option<(String, Comment)>, String> {
+        let query = r#"SELECT id, article, parent, ids.name AS poster_name, COALESCE(ids.email, '') AS poster_email,
+                              COALESCE(ids.verified, false) AS verified,
+                              timestamp, comment, edited_at, pinned, author,
+                              CAST((SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS BIGINT) AS votes,
+                              CAST(0 AS BIGINT) AS myvote,
+                              (SELECT string_agg(name, chr(10) ORDER BY id) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE id = $1 AND id > 0 AND moderated = true;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&comment_id])
+            .map_err(query_err)?;
+
+        row.map(|row| Ok((row.try_get("article").map_err(read_err)?, comment(&row)?)))
+            .transpose()
+    }
+
+    fn get_replies(&self, comment_id: i64) -> Result<Vec<Comment>, String> {
+        let query = r#"SELECT id, parent, ids.name AS poster_name, COALESCE(ids.email, '') AS poster_email,
+                              COALESCE(ids.verified, false) AS verified,
+                              timestamp, comment, edited_at, pinned, author,
+                              CAST((SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS BIGINT) AS votes,
+                              CAST(0 AS BIGINT) AS myvote,
+                              (SELECT string_agg(name, chr(10) ORDER BY id) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE parent = $1 AND id > 0 AND moderated = true
+                              ORDER BY timestamp ASC, id ASC;"#;
+
+        let rows = self
+            .lock()?
+            .query(query, &[&comment_id])
+            .map_err(query_err)?;
+
+        rows.iter().map(comment).collect()
+    }
+
+    fn add_attachment(
+        &self,
+        comment_id: i64,
+        name: &str,
+        content_type: &str,
+        size: i64,
+        created_at: i64,
+        max: i64,
+    ) -> Result<Option<i64>, String> {
+        let query = r#"INSERT INTO attachments (comment_id, name, content_type, size, created_at)
+                              SELECT $1, $2, $3, $4, $5
+                              WHERE (SELECT COUNT(*) FROM attachments WHERE comment_id = $1) < $6
+                              RETURNING id;"#;
+
+        let mut client = self.lock()?;
+        let Some(row) = client
+            .query_opt(
+                query,
+                &[&comment_id, &name, &content_type, &size, &created_at, &max],
+            )
+            .map_err(query_err)?
+        else {
+            return Ok(None);
+        };
+
+        // So clients syncing with `since` pick up the new image.
+        client
+            .execute(
+                r#"UPDATE comments SET changed_at = $1 WHERE id = $2;"#,
+                &[&created_at, &comment_id],
+            )
+            .map_err(query_err)?;
+
+        Ok(Some(row.try_get("id").map_err(read_err)?))
+    }
+
+    fn remove_attachment(&self, id: i64) -> Result<(), String> {
+        self.lock()?
+            .execute(r#"DELETE FROM attachments WHERE id = $1;"#, &[&id])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn unused_attachments(&self) -> Result<Vec<String>, String> {
+        // An image attached again since is no longer unused.
+        let in_use_query =
+            r#"DELETE FROM removed_attachments WHERE name IN (SELECT name FROM attachments);"#;
+        let query = r#"SELECT DISTINCT name FROM removed_attachments;"#;
+
+        let mut client = self.lock()?;
+        client.execute(in_use_query, &[]).map_err(query_err)?;
+        client
+            .query(query, &[])
+            .map_err(query_err)?
+            .iter()
+            .map(|row| row.try_get("name").map_err(read_err))
+            .collect()
+    }
+
+    fn forget_unused_attachment(&self, name: &str) -> Result<(), String> {
+        self.lock()?
+            .execute(
+                r#"DELETE FROM removed_attachments WHERE name = $1;"#,
+                &[&name],
+            )
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn get_comment_votes(&self, comment_id: i64) -> Result<Option<(String, i64)>, String> {
+        let query = r#"SELECT article,
+                              CAST((SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS BIGINT) AS votes
+                              FROM comments
+                              WHERE id = $1 AND id > 0 AND moderated = true;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&comment_id])
+            .map_err(query_err)?;
+
+        row.map(|row| {
+            Ok((
+                row.try_get("article").map_err(read_err)?,
+                row.try_get("votes").map_err(read_err)?,
+            ))
+        })
+        .transpose()
+    }
+
+    fn count_comments(&self, article: &str) -> Result<i64, String> {
+        let query = r#"SELECT COUNT(*) AS total FROM comments WHERE article = $1 AND id > 0 AND moderated = true;"#;
+
+        let row = self
+            .lock()?
+            .query_one(query, &[&article])
+            .map_err(query_err)?;
+
+        row.try_get("total").map_err(read_err)
+    }
+
+    fn search_comments(
+        &self,
+        query: &str,
+        article: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<SearchResult>, i64), String> {
+        // The GIN index covers comment text; poster names are matched separately.
+        let from = r#"FROM comments
+                      LEFT JOIN ids ON comments.commenter_id = ids.commenter_id,
+                      plainto_tsquery('simple', $1) AS query
+                      WHERE (to_tsvector('simple', comments.comment) @@ query
+                             OR to_tsvector('simple', COALESCE(ids.name, '')) @@ query)
+                            AND comments.id > 0 AND comments.moderated = true
+                            AND ($2::TEXT IS NULL OR comments.article = $2)"#;
+        let query_sql = format!(
+            r#"SELECT comments.id AS id, timestamp, article, parent, COALESCE(ids.name, '') AS poster_name, comments.comment AS comment
+                      {from}
+                      ORDER BY ts_rank(to_tsvector('simple', comments.comment), query) DESC, comments.id DESC
+                      LIMIT $3 OFFSET $4;"#
+        );
+        let count_sql = format!(r#"SELECT COUNT(*) AS total {from};"#);
+
+        let mut client = self.lock()?;
+        let rows = client
+            .query(&query_sql, &[&query, &article, &limit, &offset])
+            .map_err(query_err)?;
+
+        let results = rows
+            .iter()
+            .map(|row| {
+                let article: String = row.try_get("article").map_err(read_err)?;
+
+                Ok(SearchResult {
+                    id: row.try_get("id").map_err(read_err)?,
+                    timestamp: row.try_get("timestamp").map_err(read_err)?,
+                    article: base64_decode(article.clone()).unwrap_or(article),
+                    parent: row
+                        .try_get::<_, Option<i64>>("parent")
+                        .map_err(read_err)?
+                        .unwrap_or(0),
+                    poster_name: row.try_get("poster_name").map_err(read_err)?,
+                    comment: row.try_get("comment").map_err(read_err)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let total = client
+            .query_one(&count_sql, &[&query, &article])
+            .map_err(query_err)?
+            .try_get("total")
+            .map_err(read_err)?;
+
+        Ok((results, total))
+    }
+
+    fn count_comments_by_article(
+        &self,
+        articles: &[String],
+    ) -> Result<HashMap<String, i64>, String> {
+        let query = r#"SELECT article, COUNT(*) AS total FROM comments
+                              WHERE article = ANY($1) AND id > 0 AND moderated = true
+                              GROUP BY article;"#;
+
+        let rows = self.lock()?.query(query, &[&articles]).map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("article").map_err(read_err)?,
+                    row.try_get("total").map_err(read_err)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn get_comment_owner(&self, comment_id: i64) -> Result<Option<String>, String> {
+        let query = r#"SELECT commenter_id FROM comments WHERE id = $1;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&comment_id])
+            .map_err(query_err)?;
+
+        row.map(|row| row.try_get("commenter_id").map_err(read_err))
+            .transpose()
+    }
+
+    fn get_comment_posted(&self, comment_id: i64) -> Result<Option<(String, i64)>, String> {
+        let query = r#"SELECT commenter_id, timestamp FROM comments WHERE id = $1;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&comment_id])
+            .map_err(query_err)?;
+
+        row.map(|row| {
+            Ok((
+                row.try_get("commenter_id").map_err(read_err)?,
+                row.try_get("timestamp").map_err(read_err)?,
+            ))
+        })
+        .transpose()
+    }
+
+    fn delete_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let keep_query = r#"UPDATE comments SET comment = $1, changed_at = EXTRACT(EPOCH FROM NOW())::BIGINT
+                               WHERE id = $2 AND id > 0 AND EXISTS (SELECT 1 FROM comments AS replies WHERE replies.parent = comments.id);"#;
+        let attachments_query = r#"DELETE FROM attachments WHERE comment_id = $1;"#;
+        let votes_query = r#"DELETE FROM votes WHERE comment_id = $1;"#;
+        let query = r#"DELETE FROM comments WHERE id = $1 AND id > 0;"#;
+
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+        let kept = transaction
+            .execute(keep_query, &[&DELETED_COMMENT, &comment_id])
+            .map_err(query_err)?;
+        let count = if kept > 0 {
+            transaction
+                .execute(attachments_query, &[&comment_id])
+                .map_err(query_err)?;
+            kept
+        } else {
+            transaction
+                .execute(votes_query, &[&comment_id])
+                .map_err(query_err)?;
+            transaction
+                .execute(query, &[&comment_id])
+                .map_err(query_err)?
+        };
+        transaction.commit().map_err(query_err)?;
+
+        Ok(count > 0)
+    }
+
+    fn get_comment_summary(&self, comment_id: i64) -> Result<Option<CommentSummary>, String> {
+        let query = r#"SELECT article, parent, comments.commenter_id, ids.name AS poster_name, comment
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE id = $1;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&comment_id])
+            .map_err(query_err)?;
+
+        row.map(|row| {
+            let article: String = row.try_get("article").map_err(read_err)?;
+
+            Ok(CommentSummary {
+                article: base64_decode(article.clone()).unwrap_or(article),
+                parent: row.try_get("parent").map_err(read_err)?,
+                commenter_id: row.try_get("commenter_id").map_err(read_err)?,
+                poster_name: row.try_get("poster_name").map_err(read_err)?,
+                comment: row.try_get("comment").map_err(read_err)?,
+            })
+        })
+        .transpose()
+    }
+
+    fn get_reply_recipient(&self, parent_id: i64) -> Result<Option<ReplyRecipient>, String> {
+        let query = r#"SELECT ids.commenter_id, name, email, reply_notifications
+                              FROM comments
+                              JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE id = $1;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&parent_id])
+            .map_err(query_err)?;
+
+        row.map(|row| {
+            Ok(ReplyRecipient {
+                commenter_id: row.try_get("commenter_id").map_err(read_err)?,
+                name: row.try_get("name").map_err(read_err)?,
+                email: row.try_get("email").map_err(read_err)?,
+                reply_notifications: row.try_get("reply_notifications").map_err(read_err)?,
+            })
+        })
+        .transpose()
+    }
+
+    fn get_thread_participants(&self, article: &str) -> Result<Vec<ReplyRecipient>, String> {
+        let query = r#"SELECT DISTINCT ids.commenter_id, name, email, reply_notifications
+                              FROM comments
+                              JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE article = $1 AND id > 0 AND moderated = true;"#;
+
+        let rows = self.lock()?.query(query, &[&article]).map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(ReplyRecipient {
+                    commenter_id: row.try_get("commenter_id").map_err(read_err)?,
+                    name: row.try_get("name").map_err(read_err)?,
+                    email: row.try_get("email").map_err(read_err)?,
+                    reply_notifications: row.try_get("reply_notifications").map_err(read_err)?,
+                })
+            })
+            .collect()
+    }
+
+    fn add_subscription(
+        &self,
+        article: &str,
+        commenter_id: &str,
+        created_at: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO subscriptions (article, commenter_id, created_at) VALUES ($1, $2, $3)
+                              ON CONFLICT (article, commenter_id) DO NOTHING;"#;
+
+        self.lock()?
+            .execute(query, &[&article, &commenter_id, &created_at])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn get_subscribers(&self, article: &str) -> Result<Vec<Subscriber>, String> {
+        let query = r#"SELECT subscriptions.id, ids.commenter_id, name, email
+                              FROM subscriptions
+                              JOIN ids on subscriptions.commenter_id = ids.commenter_id
+                              WHERE article = $1
+                              ORDER BY subscriptions.id ASC;"#;
+
+        let rows = self.lock()?.query(query, &[&article]).map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(Subscriber {
+                    id: row.try_get("id").map_err(read_err)?,
+                    commenter_id: row.try_get("commenter_id").map_err(read_err)?,
+                    name: row.try_get("name").map_err(read_err)?,
+                    email: row.try_get("email").map_err(read_err)?,
+                })
+            })
+            .collect()
+    }
+
+    fn remove_subscription(&self, id: i64) -> Result<bool, String> {
+        let query = r#"DELETE FROM subscriptions WHERE id = $1;"#;
+
+        let removed = self.lock()?.execute(query, &[&id]).map_err(query_err)?;
+        Ok(removed > 0)
+    }
+
+    fn edit_comment(
+        &self,
+        comment_id: i64,
+        commenter_id: &str,
+        comment: &str,
+        edited_at: i64,
+    ) -> Result<(), String> {
+        let query = r#"UPDATE comments SET comment = $1, edited_at = $2, changed_at = $2
+                              WHERE id = $3 AND commenter_id = $4;"#;
+
+        self.lock()?
+            .execute(query, &[&comment, &edited_at, &comment_id, &commenter_id])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn set_vote(
+        &self,
+        comment_id: i64,
+        voter_id: &str,
+        vote: i64,
+        ip_hash: Option<&str>,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO votes (comment_id, voter_id, vote, ip_hash) VALUES ($1, $2, $3, $4)
+                              ON CONFLICT(comment_id, voter_id)
+                              DO UPDATE SET vote = $3, ip_hash = COALESCE($4, votes.ip_hash);"#;
+
+        let mut client = self.lock()?;
+        client
+            .execute(query, &[&comment_id, &voter_id, &(vote as i32), &ip_hash])
+            .map_err(query_err)?;
+        client
+            .execute(VOTED_AT_QUERY, &[&comment_id])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn ip_has_voted(&self, comment_id: i64, ip_hash: &str, voter_id: &str) -> Result<bool, String> {
+        let query = r#"SELECT 1 FROM votes WHERE comment_id = $1 AND ip_hash = $2 AND voter_id != $3 LIMIT 1;"#;
+
+        let rows = self
+            .lock()?
+            .query(query, &[&comment_id, &ip_hash, &voter_id])
+            .map_err(query_err)?;
+        Ok(!rows.is_empty())
+    }
+
+    fn remove_vote(&self, comment_id: i64, voter_id: &str) -> Result<(), String> {
+        let query = r#"DELETE FROM votes WHERE comment_id = $1 AND voter_id = $2"#;
+
+        let mut client = self.lock()?;
+        client
+            .execute(query, &[&comment_id, &voter_id])
+            .map_err(query_err)?;
+        client
+            .execute(VOTED_AT_QUERY, &[&comment_id])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn enqueue_email(
+        &self,
+        recipient: &str,
+        subject: &str,
+        body: &str,
+        now: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO email_queue (recipient, subject, body, next_attempt_at, created_at)
+                              VALUES ($1, $2, $3, $4, $4);"#;
+
+        self.lock()?
+            .execute(query, &[&recipient, &subject, &body, &now])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn due_emails(&self, now: i64, limit: i64) -> Result<Vec<QueuedEmail>, String> {
+        let query = r#"SELECT id, recipient, subject, body, attempts FROM email_queue
+                              WHERE next_attempt_at <= $1
+                              ORDER BY next_attempt_at ASC, id ASC
+                              LIMIT $2;"#;
+
+        let rows = self
+            .lock()?
+            .query(query, &[&now, &limit])
+            .map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(QueuedEmail {
+                    id: row.try_get("id").map_err(read_err)?,
+                    recipient: row.try_get("recipient").map_err(read_err)?,
+                    subject: row.try_get("subject").map_err(read_err)?,
+                    body: row.try_get("body").map_err(read_err)?,
+                    attempts: row.try_get("attempts").map_err(read_err)?,
+                })
+            })
+            .collect()
+    }
+
+    fn reschedule_email(
+        &self,
+        id: i64,
+        attempts: i64,
+        next_attempt_at: i64,
+        last_error: &str,
+    ) -> Result<(), String> {
+        let query = r#"UPDATE email_queue SET attempts = $1, next_attempt_at = $2, last_error = $3 WHERE id = $4;"#;
+
+        self.lock()?
+            .execute(query, &[&attempts, &next_attempt_at, &last_error, &id])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn delete_email(&self, id: i64) -> Result<(), String> {
+        let query = r#"DELETE FROM email_queue WHERE id = $1;"#;
+
+        self.lock()?.execute(query, &[&id]).map_err(query_err)?;
+        Ok(())
+    }
+
+    fn pending_comments(&self) -> Result<Vec<PendingComment>, String> {
+        let query = r#"SELECT id, timestamp, article, parent, ids.name AS poster_name, ids.email AS poster_email, comment, country,
+                              (SELECT COUNT(*) FROM flags WHERE flags.comment_id = comments.id) AS flags
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE moderated = false
+                              ORDER BY timestamp ASC;"#;
+
+        let rows = self.lock()?.query(query, &[]).map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                let article: String = row.try_get("article").map_err(read_err)?;
+
+                Ok(PendingComment {
+                    id: row.try_get("id").map_err(read_err)?,
+                    timestamp: row.try_get("timestamp").map_err(read_err)?,
+                    article: base64_decode(article.clone()).unwrap_or(article),
+                    parent: row
+                        .try_get::<_, Option<i64>>("parent")
+                        .map_err(read_err)?
+                        .unwrap_or(0),
+                    poster_name: row.try_get("poster_name").map_err(read_err)?,
+                    poster_email: row.try_get("poster_email").map_err(read_err)?,
+                    comment: row.try_get("comment").map_err(read_err)?,
+                    flags: row.try_get("flags").map_err(read_err)?,
+                    country: row.try_get("country").map_err(read_err)?,
+                })
+            })
+            .collect()
+    }
+
+    fn digest_comments(&self, limit: i64) -> Result<Vec<RecentComment>, String> {
+        let query = format!(
+            r#"SELECT {RECENT_COMMENT_COLUMNS}
+                      FROM comments
+                      LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                      WHERE id > (SELECT last_comment_id FROM email_digest)
+                      ORDER BY id ASC
+                      LIMIT $1;"#
+        );
+
+        let rows = self.lock()?.query(&query, &[&limit]).map_err(query_err)?;
+        rows.iter().map(recent_comment).collect()
+    }
+
+    fn recent_comments(&self, limit: i64) -> Result<Vec<RecentComment>, String> {
+        let query = format!(
+            r#"SELECT {RECENT_COMMENT_COLUMNS}
+                      FROM comments
+                      LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                      WHERE id > 0
+                      ORDER BY id DESC
+                      LIMIT $1;"#
+        );
+
+        let rows = self.lock()?.query(&query, &[&limit]).map_err(query_err)?;
+        rows.iter().map(recent_comment).collect()
+    }
+
+    fn stats(&self) -> Result<SiteStats, String> {
+        let query = r#"SELECT (SELECT COUNT(*) FROM ids) AS commenters,
+                              (SELECT COUNT(*) FROM comments WHERE id > 0) AS comments,
+                              (SELECT COUNT(*) FROM comments WHERE id > 0 AND moderated = false) AS pending,
+                              (SELECT COUNT(*) FROM votes) AS votes,
+                              (SELECT COUNT(DISTINCT article) FROM comments WHERE id > 0) AS articles;"#;
+
+        let row = self.lock()?.query_one(query, &[]).map_err(query_err)?;
+        Ok(SiteStats {
+            commenters: row.try_get("commenters").map_err(read_err)?,
+            comments: row.try_get("comments").map_err(read_err)?,
+            pending: row.try_get("pending").map_err(read_err)?,
+            votes: row.try_get("votes").map_err(read_err)?,
+            articles: row.try_get("articles").map_err(read_err)?,
+        })
+    }
+
+    fn comments_per_bucket(
+        &self,
+        from: i64,
+        to: i64,
+        size: i64,
+        offset: i64,
+    ) -> Result<HashMap<i64, i64>, String> {
+        let query = r#"SELECT ((timestamp - $1) / $2) * $2 + $1 AS start, COUNT(*) AS count
+                              FROM comments
+                              WHERE id > 0 AND timestamp >= $3 AND timestamp < $4
+                              GROUP BY start;"#;
+
+        let rows = self
+            .lock()?
+            .query(query, &[&offset, &size, &from, &to])
+            .map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("start").map_err(read_err)?,
+                    row.try_get("count").map_err(read_err)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn top_articles(&self, from: i64, to: i64, limit: i64) -> Result<Vec<ArticleActivity>, String> {
+        let query = r#"SELECT article, COUNT(DISTINCT comments.id) AS comment_count, COUNT(votes.comment_id) AS vote_count
+                              FROM comments LEFT JOIN votes ON votes.comment_id = comments.id
+                              WHERE comments.id > 0 AND comments.timestamp >= $1 AND comments.timestamp < $2
+                              GROUP BY article
+                              ORDER BY comment_count DESC, vote_count DESC, article ASC
+                              LIMIT $3;"#;
+
+        let rows = self
+            .lock()?
+            .query(query, &[&from, &to, &limit])
+            .map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(ArticleActivity {
+                    article: row.try_get("article").map_err(read_err)?,
+                    comments: row.try_get("comment_count").map_err(read_err)?,
+                    votes: row.try_get("vote_count").map_err(read_err)?,
+                })
+            })
+            .collect()
+    }
+
+    fn vote_totals(&self, from: i64, to: i64) -> Result<VoteTotals, String> {
+        let query = r#"SELECT COUNT(*) FILTER (WHERE vote > 0) AS upvotes,
+                              COUNT(*) FILTER (WHERE vote < 0) AS downvotes
+                              FROM votes JOIN comments ON comments.id = votes.comment_id
+                              WHERE comments.id > 0 AND comments.timestamp >= $1 AND comments.timestamp < $2;"#;
+
+        let row = self
+            .lock()?
+            .query_one(query, &[&from, &to])
+            .map_err(query_err)?;
+
+        Ok(VoteTotals {
+            upvotes: row.try_get("upvotes").map_err(read_err)?,
+            downvotes: row.try_get("downvotes").map_err(read_err)?,
+        })
+    }
+
+    fn add_daily_counts(&self, counts: &[(i64, &str, i64)]) -> Result<(), String> {
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+
+        let statement = transaction
+            .prepare(
+                r#"INSERT INTO daily_counts (day, kind, count) VALUES ($1, $2, $3)
+                          ON CONFLICT(day, kind) DO UPDATE SET count = daily_counts.count + excluded.count;"#,
+            )
+            .map_err(query_err)?;
+        for (day, kind, count) in counts {
+            transaction
+                .execute(&statement, &[day, kind, count])
+                .map_err(query_err)?;
+        }
+
+        transaction.commit().map_err(query_err)
+    }
+
+    fn daily_counts(&self, from: i64, to: i64) -> Result<Vec<(i64, String, i64)>, String> {
+        let query = r#"SELECT day, kind, count FROM daily_counts WHERE day >= $1 AND day < $2;"#;
+
+        let rows = self
+            .lock()?
+            .query(query, &[&from, &to])
+            .map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("day").map_err(read_err)?,
+                    row.try_get("kind").map_err(read_err)?,
+                    row.try_get("count").map_err(read_err)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn mark_digest_sent(&self, last_comment_id: i64, sent_at: i64) -> Result<(), String> {
+        let query = r#"UPDATE email_digest SET last_comment_id = $1, sent_at = $2;"#;
+
+        self.lock()?
+            .execute(query, &[&last_comment_id, &sent_at])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn approve_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let query = r#"UPDATE comments SET moderated = true, changed_at = EXTRACT(EPOCH FROM NOW())::BIGINT WHERE id = $1 AND moderated = false;"#;
+
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+        let count = transaction
+            .execute(query, &[&comment_id])
+            .map_err(query_err)?;
+        if count > 0 {
+            transaction
+                .execute(APPROVED_COUNT_QUERY, &[&comment_id])
+                .map_err(query_err)?;
+            transaction
+                .execute(
+                    r#"DELETE FROM flags WHERE comment_id = $1;"#,
+                    &[&comment_id],
+                )
+                .map_err(query_err)?;
+        }
+        transaction.commit().map_err(query_err)?;
+
+        Ok(count > 0)
+    }
+
+    fn add_flag(
+        &self,
+        comment_id: i64,
+        flagger_id: &str,
+        reason: &str,
+        created_at: i64,
+    ) -> Result<Option<i64>, String> {
+        let query = r#"INSERT INTO flags (comment_id, flagger_id, reason, created_at) VALUES ($1, $2, $3, $4)
+                              ON CONFLICT(comment_id, flagger_id) DO NOTHING;"#;
+
+        let mut client = self.lock()?;
+        let count = client
+            .execute(query, &[&comment_id, &flagger_id, &reason, &created_at])
+            .map_err(query_err)?;
+
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let query = r#"SELECT COUNT(*) AS flags FROM flags WHERE comment_id = $1;"#;
+        let row = client.query_one(query, &[&comment_id]).map_err(query_err)?;
+        Ok(Some(row.try_get("flags").map_err(read_err)?))
+    }
+
+    fn hide_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let query = r#"UPDATE comments SET moderated = false WHERE id = $1 AND moderated = true;"#;
+
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+        let count = transaction
+            .execute(query, &[&comment_id])
+            .map_err(query_err)?;
+        if count > 0 {
+            transaction
+                .execute(UNAPPROVED_COUNT_QUERY, &[&comment_id])
+                .map_err(query_err)?;
+        }
+        transaction.commit().map_err(query_err)?;
+
+        Ok(count > 0)
+    }
+
+    fn flagged_comments(&self) -> Result<Vec<FlaggedComment>, String> {
+        let query = r#"SELECT comments.id, comments.timestamp, comments.article, COALESCE(ids.name, '') AS poster_name,
+                              comments.comment, comments.moderated, flags.reason
+                              FROM flags
+                              JOIN comments ON flags.comment_id = comments.id
+                              LEFT JOIN ids ON comments.commenter_id = ids.commenter_id
+                              ORDER BY comments.id ASC, flags.id ASC;"#;
+
+        let rows = self.lock()?.query(query, &[]).map_err(query_err)?;
+
+        let rows = rows
+            .iter()
+            .map(|row| {
+                let article: String = row.try_get("article").map_err(read_err)?;
+
+                Ok((
+                    FlaggedComment {
+                        id: row.try_get("id").map_err(read_err)?,
+                        timestamp: row.try_get("timestamp").map_err(read_err)?,
+                        article: base64_decode(article.clone()).unwrap_or(article),
+                        poster_name: row.try_get("poster_name").map_err(read_err)?,
+                        comment: row.try_get("comment").map_err(read_err)?,
+                        pending: !row.try_get::<_, bool>("moderated").map_err(read_err)?,
+                        reasons: vec![],
+                    },
+                    row.try_get("reason").map_err(read_err)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(group_flags(rows))
+    }
+
+    fn dismiss_flags(&self, comment_id: i64) -> Result<bool, String> {
+        let query = r#"DELETE FROM flags WHERE comment_id = $1;"#;
+
+        let count = self
+            .lock()?
+            .execute(query, &[&comment_id])
+            .map_err(query_err)?;
+        Ok(count > 0)
+    }
+
+    fn reject_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let votes_query = r#"DELETE FROM votes WHERE comment_id IN
+                                 (SELECT id FROM comments WHERE id = $1 AND moderated = false);"#;
+        let query = r#"DELETE FROM comments WHERE id = $1 AND moderated = false;"#;
+
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+        transaction
+            .execute(votes_query, &[&comment_id])
+            .map_err(query_err)?;
+        let count = transaction
+            .execute(query, &[&comment_id])
+            .map_err(query_err)?;
+        transaction.commit().map_err(query_err)?;
+
+        Ok(count > 0)
+    }
+
+    fn prune_pending(&self, before: i64) -> Result<i64, String> {
+        let stale = r#"SELECT id FROM comments
+                           WHERE moderated = false AND timestamp < $1
+                             AND NOT EXISTS (SELECT 1 FROM comments AS replies WHERE replies.parent = comments.id)"#;
+        let votes_query = format!("DELETE FROM votes WHERE comment_id IN ({stale});");
+        let query = format!("DELETE FROM comments WHERE id IN ({stale});");
+
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+        transaction
+            .execute(&votes_query, &[&before])
+            .map_err(query_err)?;
+        let count = transaction.execute(&query, &[&before]).map_err(query_err)?;
+        transaction.commit().map_err(query_err)?;
+
+        Ok(count as i64)
+    }
+
+    fn prune_orphaned_votes(&self) -> Result<i64, String> {
+        let query = r#"DELETE FROM votes
+                       WHERE comment_id NOT IN (SELECT id FROM comments)
+                          OR voter_id NOT IN (SELECT commenter_id FROM ids);"#;
+
+        let count = self.lock()?.execute(query, &[]).map_err(query_err)?;
+        Ok(count as i64)
+    }
+
+    fn prune_unused_commenters(&self, before: i64) -> Result<i64, String> {
+        let query = format!("DELETE FROM ids WHERE created_at < $1 AND {UNUSED_COMMENTER};");
+
+        let count = self
+            .lock()?
+            .execute(&query, &[&before])
+            .map_err(query_err)?;
+        Ok(count as i64)
+    }
+
+    fn set_comment_pinned(&self, comment_id: i64, pinned: bool) -> Result<bool, String> {
+        let query = r#"UPDATE comments SET pinned = $1, changed_at = EXTRACT(EPOCH FROM NOW())::BIGINT WHERE id = $2;"#;
+
+        let count = self
+            .lock()?
+            .execute(query, &[&pinned, &comment_id])
+            .map_err(query_err)?;
+
+        Ok(count > 0)
+    }
+
+    fn get_article_lock(&self, article: &str) -> Result<ArticleLock, String> {
+        let query = r#"SELECT locked, voting_locked FROM articles WHERE article = $1;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&article])
+            .map_err(query_err)?;
+        Ok(row
+            .as_ref()
+            .map(article_lock)
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    fn get_comment_article_lock(&self, comment_id: i64) -> Result<ArticleLock, String> {
+        let query = r#"SELECT locked, voting_locked FROM articles
+                              JOIN comments ON comments.article = articles.article
+                              WHERE comments.id = $1;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&comment_id])
+            .map_err(query_err)?;
+        Ok(row
+            .as_ref()
+            .map(article_lock)
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    fn set_article_lock(&self, article: &str, lock: ArticleLock) -> Result<(), String> {
+        let query = r#"INSERT INTO articles (article, locked, voting_locked) VALUES ($1, $2, $3)
+                              ON CONFLICT(article) DO UPDATE SET locked = $2, voting_locked = $3;"#;
+
+        self.lock()?
+            .execute(query, &[&article, &lock.locked, &lock.voting_locked])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn get_article_opened_at(&self, article: &str) -> Result<Option<i64>, String> {
+        let query = r#"SELECT COALESCE((SELECT published_at FROM articles WHERE article = $1),
+                                       (SELECT MIN(timestamp) FROM comments WHERE article = $1 AND id > 0)) AS opened_at;"#;
+
+        let row = self
+            .lock()?
+            .query_one(query, &[&article])
+            .map_err(query_err)?;
+        row.try_get("opened_at").map_err(read_err)
+    }
+
+    fn is_article_registered(&self, article: &str) -> Result<bool, String> {
+        let query = r#"SELECT registered FROM articles WHERE article = $1;"#;
+
+        let row = self
+            .lock()?
+            .query_opt(query, &[&article])
+            .map_err(query_err)?;
+        match row {
+            Some(row) => row.try_get("registered").map_err(read_err),
+            None => Ok(false),
+        }
+    }
+
+    fn register_article(
+        &self,
+        article: &str,
+        published_at: Option<i64>,
+        now: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO articles (article, registered, published_at) VALUES ($1, true, COALESCE($2::BIGINT, $3::BIGINT))
+                              ON CONFLICT(article) DO UPDATE
+                              SET registered = true, published_at = COALESCE($2::BIGINT, articles.published_at, $3::BIGINT);"#;
+
+        self.lock()?
+            .execute(query, &[&article, &published_at, &now])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn unregister_article(&self, article: &str) -> Result<bool, String> {
+        let query =
+            r#"UPDATE articles SET registered = false WHERE article = $1 AND registered = true;"#;
+
+        let count = self
+            .lock()?
+            .execute(query, &[&article])
+            .map_err(query_err)?;
+        Ok(count > 0)
+    }
+
+    fn get_article_title(&self, url: &str) -> Result<Option<(Option<String>, i64)>, String> {
+        let query = r#"SELECT title, fetched_at FROM article_titles WHERE url = $1;"#;
+
+        let row = self.lock()?.query_opt(query, &[&url]).map_err(query_err)?;
+        row.map(|row| {
+            Ok((
+                row.try_get("title").map_err(read_err)?,
+                row.try_get("fetched_at").map_err(read_err)?,
+            ))
+        })
+        .transpose()
+    }
+
+    fn set_article_title(
+        &self,
+        url: &str,
+        title: Option<&str>,
+        fetched_at: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO article_titles (url, title, fetched_at) VALUES ($1, $2, $3)
+                              ON CONFLICT(url) DO UPDATE SET title = $2, fetched_at = $3;"#;
+
+        self.lock()?
+            .execute(query, &[&url, &title, &fetched_at])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn get_article_titles(&self, urls: &[String]) -> Result<HashMap<String, String>, String> {
+        let query = r#"SELECT url, title FROM article_titles
+                              WHERE url = ANY($1) AND title IS NOT NULL;"#;
+
+        let rows = self.lock()?.query(query, &[&urls]).map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("url").map_err(read_err)?,
+                    row.try_get("title").map_err(read_err)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn blocklist_entries(&self) -> Result<Vec<BlocklistEntry>, String> {
+        let query =
+            r#"SELECT id, kind, pattern, action, created_at FROM blocklist ORDER BY id ASC;"#;
+
+        let rows = self.lock()?.query(query, &[]).map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(BlocklistEntry {
+                    id: row.try_get("id").map_err(read_err)?,
+                    kind: row.try_get("kind").map_err(read_err)?,
+                    pattern: row.try_get("pattern").map_err(read_err)?,
+                    action: row.try_get("action").map_err(read_err)?,
+                    created_at: row.try_get("created_at").map_err(read_err)?,
+                })
+            })
+            .collect()
+    }
+
+    fn add_blocklist_entry(
+        &self,
+        kind: &str,
+        pattern: &str,
+        action: &str,
+        created_at: i64,
+    ) -> Result<i64, String> {
+        let query = r#"INSERT INTO blocklist (kind, pattern, action, created_at) VALUES ($1, $2, $3, $4)
+                              ON CONFLICT(kind, pattern) DO UPDATE SET action = $3
+                              RETURNING id;"#;
+
+        let row = self
+            .lock()?
+            .query_one(query, &[&kind, &pattern, &action, &created_at])
+            .map_err(query_err)?;
+        row.try_get("id").map_err(read_err)
+    }
+
+    fn remove_blocklist_entry(&self, id: i64) -> Result<bool, String> {
+        let query = r#"DELETE FROM blocklist WHERE id = $1;"#;
+
+        let count = self.lock()?.execute(query, &[&id]).map_err(query_err)?;
+
+        Ok(count > 0)
+    }
+
+    fn add_audit_entry(
+        &self,
+        at: i64,
+        actor: &str,
+        action: &str,
+        target: &str,
+        previous: Option<&str>,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO audit_log (at, actor, action, target, previous) VALUES ($1, $2, $3, $4, $5);"#;
+
+        self.lock()?
+            .execute(query, &[&at, &actor, &action, &target, &previous])
+            .map_err(query_err)?;
+        Ok(())
+    }
+
+    fn audit_log(&self, before: Option<i64>, limit: i64) -> Result<Vec<AuditEntry>, String> {
+        let query = r#"SELECT id, at, actor, action, target, previous FROM audit_log
+                              WHERE id < $1 ORDER BY id DESC LIMIT $2;"#;
+
+        let rows = self
+            .lock()?
+            .query(query, &[&before.unwrap_or(i64::MAX), &limit])
+            .map_err(query_err)?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(AuditEntry {
+                    id: row.try_get("id").map_err(read_err)?,
+                    at: row.try_get("at").map_err(read_err)?,
+                    actor: row.try_get("actor").map_err(read_err)?,
+                    action: row.try_get("action").map_err(read_err)?,
+                    target: row.try_get("target").map_err(read_err)?,
+                    previous: row.try_get("previous").map_err(read_err)?,
+                })
+            })
+            .collect()
+    }
+
+    fn checkpoint(&self) -> Result<(), String> {
+        // The server handles durability; there is nothing to flush from the client side.
+        Ok(())
+    }
+
+    fn optimize(&self) -> Result<(), String> {
+        self.lock()?
+            .batch_execute("VACUUM ANALYZE;")
+            .map_err(query_err)
+    }
+
+    fn full_scans(&self) -> Result<Vec<String>, String> {
+        let no_limit: Option<i64> = None;
+        let queries: [(&str, String, &[&(dyn ToSql + Sync)]); 2] = [
+            (
+                "get_comments",
+                comments_query(CommentSort::Oldest),
+                &[&"", &"", &no_limit, &0i64],
+            ),
+            (
+                "get_comment_changes",
+                String::from(COMMENT_CHANGES_QUERY),
+                &[&"", &"", &0i64],
+            ),
+        ];
+
+        let mut client = self.lock()?;
+        let mut tx = client.transaction().map_err(query_err)?;
+        // Otherwise small tables are read whole even when a usable index exists.
+        tx.batch_execute("SET LOCAL enable_seqscan = off;")
+            .map_err(query_err)?;
+
+        let mut scans = vec![];
+        for (name, query, params) in queries {
+            for row in tx
+                .query(&format!("EXPLAIN {query}"), params)
+                .map_err(query_err)?
+            {
+                let step = row.try_get::<_, String>(0).map_err(read_err)?;
+                if step.contains("Seq Scan on comments") || step.contains("Seq Scan on votes") {
+                    scans.push(format!("{name}: {}", step.trim()));
+                }
+            }
+        }
+
+        Ok(scans)
+    }
+
+    fn backup(&self, _dest: &str) -> Result<(), String> {
+        Err(String::from(
+            "Online backups need the SQLite backend; use pg_dump with Postgres",
+        ))
+    }
+
+    fn load_pow_state(&self) -> Result<PowState, String> {
+        let mut client = self.lock()?;
+
+        let challenges = client
+            .query(
+                r#"SELECT challenge, client_ip, key, issued_at_ms FROM pow_challenges;"#,
+                &[],
+            )
+            .map_err(query_err)?
+            .iter()
+            .map(|row| {
+                Ok(StoredChallenge {
+                    challenge: row.try_get("challenge").map_err(read_err)?,
+                    client_ip: row.try_get("client_ip").map_err(read_err)?,
+                    key: row.try_get("key").map_err(read_err)?,
+                    issued_at_ms: row.try_get("issued_at_ms").map_err(read_err)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let transactions = client
+            .query(r#"SELECT client_ip, at_ms FROM pow_transactions;"#, &[])
+            .map_err(query_err)?
+            .iter()
+            .map(|row| {
+                Ok(StoredTransaction {
+                    client_ip: row.try_get("client_ip").map_err(read_err)?,
+                    at_ms: row.try_get("at_ms").map_err(read_err)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(PowState {
+            challenges,
+            transactions,
+        })
+    }
+
+    fn save_pow_state(&self, pow: &PowState) -> Result<(), String> {
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+
+        transaction
+            .batch_execute("DELETE FROM pow_challenges; DELETE FROM pow_transactions;")
+            .map_err(query_err)?;
+
+        let statement = transaction
+            .prepare(
+                r#"INSERT INTO pow_challenges (challenge, client_ip, key, issued_at_ms) VALUES ($1, $2, $3, $4);"#,
+            )
+            .map_err(query_err)?;
+        for challenge in pow.challenges.iter() {
+            transaction
+                .execute(
+                    &statement,
+                    &[
+                        &challenge.challenge,
+                        &challenge.client_ip,
+                        &challenge.key,
+                        &challenge.issued_at_ms,
+                    ],
+                )
+                .map_err(query_err)?;
+        }
+
+        let statement = transaction
+            .prepare(r#"INSERT INTO pow_transactions (client_ip, at_ms) VALUES ($1, $2);"#)
+            .map_err(query_err)?;
+        for tx in pow.transactions.iter() {
+            transaction
+                .execute(&statement, &[&tx.client_ip, &tx.at_ms])
+                .map_err(query_err)?;
+        }
+
+        transaction.commit().map_err(query_err)
+    }
+
+    fn load_reputation(&self) -> Result<Vec<(String, Reputation)>, String> {
+        let mut client = self.lock()?;
+
+        client
+            .query(
+                r#"SELECT subject, approved, spam, pow_failures, flags FROM reputation;"#,
+                &[],
+            )
+            .map_err(query_err)?
+            .iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("subject").map_err(read_err)?,
+                    Reputation {
+                        approved: row.try_get("approved").map_err(read_err)?,
+                        spam: row.try_get("spam").map_err(read_err)?,
+                        pow_failures: row.try_get("pow_failures").map_err(read_err)?,
+                        flags: row.try_get("flags").map_err(read_err)?,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn save_reputation(
+        &self,
+        entries: &[(String, Reputation)],
+        updated_at: i64,
+    ) -> Result<(), String> {
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+
+        let statement = transaction
+            .prepare(
+                r#"INSERT INTO reputation (subject, approved, spam, pow_failures, flags, updated_at) VALUES ($1, $2, $3, $4, $5, $6)
+                          ON CONFLICT(subject) DO UPDATE SET approved = $2, spam = $3, pow_failures = $4, flags = $5,
+                          updated_at = $6;"#,
+            )
+            .map_err(query_err)?;
+        for (subject, reputation) in entries.iter() {
+            transaction
+                .execute(
+                    &statement,
+                    &[
+                        subject,
+                        &reputation.approved,
+                        &reputation.spam,
+                        &reputation.pow_failures,
+                        &reputation.flags,
+                        &updated_at,
+                    ],
+                )
+                .map_err(query_err)?;
+        }
+
+        transaction.commit().map_err(query_err)
+    }
+
+    fn export(
+        &self,
+        sink: &mut dyn FnMut(ExportRecord) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut client = self.lock()?;
+
+        // Repeatable read keeps the three queries on the same snapshot while writers carry on.
+        let mut transaction = client
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .map_err(query_err)?;
+        let no_params: &[&(dyn ToSql + Sync)] = &[];
+
+        let query = r#"SELECT commenter_id, COALESCE(name, '') AS name, COALESCE(email, '') AS email,
+                              reply_notifications, verified, oauth_provider, oauth_subject
+                              FROM ids
+                              ORDER BY commenter_id ASC;"#;
+
+        let mut rows = transaction
+            .query_raw(query, no_params.iter().copied())
+            .map_err(query_err)?;
+        while let Some(row) = rows.next().map_err(query_err)? {
+            sink(ExportRecord::Commenter(exported_commenter(&row)?))?;
+        }
+        drop(rows);
+
+        let query = r#"SELECT id, commenter_id, timestamp, article, parent,
+                              COALESCE(moderated, false) AS moderated, comment, edited_at, pinned
+                              FROM comments
+                              ORDER BY id ASC;"#;
+
+        let mut rows = transaction
+            .query_raw(query, no_params.iter().copied())
+            .map_err(query_err)?;
+        while let Some(row) = rows.next().map_err(query_err)? {
+            sink(ExportRecord::Comment(exported_comment(&row)?))?;
+        }
+        drop(rows);
+
+        let query = r#"SELECT comment_id, voter_id, CAST(vote AS BIGINT) AS vote
+                              FROM votes
+                              ORDER BY comment_id ASC, voter_id ASC;"#;
+
+        let mut rows = transaction
+            .query_raw(query, no_params.iter().copied())
+            .map_err(query_err)?;
+        while let Some(row) = rows.next().map_err(query_err)? {
+            sink(ExportRecord::Vote(exported_vote(&row)?))?;
+        }
+        drop(rows);
+
+        transaction.commit().map_err(query_err)
+    }
+
+    fn anonymize_commenter(
+        &self,
+        commenter_id: &str,
+        replacement_id: &str,
+        mode: AnonymizeMode,
+    ) -> Result<bool, String> {
+        let mut client = self.lock()?;
+        let mut transaction = client.transaction().map_err(query_err)?;
+
+        let Some(row) = transaction
+            .query_opt(
+                r#"SELECT COALESCE(email, '') AS email FROM ids WHERE commenter_id = $1;"#,
+                &[&commenter_id],
+            )
+            .map_err(query_err)?
+        else {
+            return Ok(false);
+        };
+        let email: String = row.try_get("email").map_err(read_err)?;
+
+        if !email.is_empty() {
+            transaction
+                .execute(
+                    r#"DELETE FROM email_queue WHERE recipient = $1;"#,
+                    &[&email],
+                )
+                .map_err(query_err)?;
+        }
+
+        transaction
+            .execute(
+                r#"INSERT INTO ids (commenter_id, name, email, reply_notifications) VALUES ($1, $2, '', false);"#,
+                &[&replacement_id, &ANONYMIZED_NAME],
+            )
+            .map_err(query_err)?;
+        transaction
+            .execute(
+                r#"DELETE FROM votes WHERE voter_id = $1;"#,
+                &[&commenter_id],
+            )
+            .map_err(query_err)?;
+
+        if let AnonymizeMode::Delete = mode {
+            transaction
+                .execute(
+                    r#"DELETE FROM votes WHERE comment_id IN
+                           (SELECT id FROM comments WHERE commenter_id = $1
+                            AND id NOT IN (SELECT parent FROM comments WHERE parent IS NOT NULL));"#,
+                    &[&commenter_id],
+                )
+                .map_err(query_err)?;
+            transaction
+                .execute(
+                    r#"DELETE FROM comments WHERE commenter_id = $1
+                           AND id NOT IN (SELECT parent FROM comments WHERE parent IS NOT NULL);"#,
+                    &[&commenter_id],
+                )
+                .map_err(query_err)?;
+            // What's left are comments kept for their replies; their images go with their text.
+            transaction
+                .execute(
+                    r#"DELETE FROM attachments WHERE comment_id IN (SELECT id FROM comments WHERE commenter_id = $1);"#,
+                    &[&commenter_id],
+                )
+                .map_err(query_err)?;
+            transaction
+                .execute(
+                    r#"UPDATE comments SET comment = $1, changed_at = EXTRACT(EPOCH FROM NOW())::BIGINT WHERE commenter_id = $2;"#,
+                    &[&DELETED_COMMENT, &commenter_id],
+                )
+                .map_err(query_err)?;
+        }
+
+        transaction
+            .execute(
+                r#"UPDATE comments SET commenter_id = $1 WHERE commenter_id = $2;"#,
+                &[&replacement_id, &commenter_id],
+            )
+            .map_err(query_err)?;
+        transaction
+            .execute(
+                r#"DELETE FROM ids WHERE commenter_id = $1;"#,
+                &[&commenter_id],
+            )
+            .map_err(query_err)?;
+
+        transaction.commit().map_err(query_err)?;
+        Ok(true)
+    }
+
+    fn export_commenter(&self, commenter_id: &str) -> Result<Option<CommenterExport>, String> {
+        let mut client = self.lock()?;
+
+        let query = r#"SELECT commenter_id, COALESCE(name, '') AS name, COALESCE(email, '') AS email,
+                              reply_notifications, verified, oauth_provider, oauth_subject
+                              FROM ids
+                              WHERE commenter_id = $1;"#;
+
+        let Some(row) = client
+            .query_opt(query, &[&commenter_id])
+            .map_err(query_err)?
+        else {
+            return Ok(None);
+        };
+        let commenter = exported_commenter(&row)?;
+
+        let query = r#"SELECT id, commenter_id, timestamp, article, parent,
+                              COALESCE(moderated, false) AS moderated, comment, edited_at, pinned
+                              FROM comments
+                              WHERE commenter_id = $1
+                              ORDER BY id ASC;"#;
+
+        let comments = client
+            .query(query, &[&commenter_id])
+            .map_err(query_err)?
+            .iter()
+            .map(exported_comment)
+            .collect::<Result<_, String>>()?;
+
+        let query = r#"SELECT comment_id, voter_id, CAST(vote AS BIGINT) AS vote
+                              FROM votes
+                              WHERE voter_id = $1
+                              ORDER BY comment_id ASC;"#;
+
+        let votes = client
+            .query(query, &[&commenter_id])
+            .map_err(query_err)?
+            .iter()
+            .map(exported_vote)
+            .collect::<Result<_, String>>()?;
+
+        let query = r#"SELECT comment_id, reason, created_at
+                              FROM flags
+                              WHERE flagger_id = $1
+                              ORDER BY id ASC;"#;
+
+        let flags = client
+            .query(query, &[&commenter_id])
+            .map_err(query_err)?
+            .iter()
+            .map(|row| {
+                Ok(ExportedFlag {
+                    comment_id: row.try_get("comment_id").map_err(read_err)?,
+                    reason: row.try_get("reason").map_err(read_err)?,
+                    created_at: row.try_get("created_at").map_err(read_err)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let query = r#"SELECT article, created_at
+                              FROM subscriptions
+                              WHERE commenter_id = $1
+                              ORDER BY id ASC;"#;
+
+        let subscriptions = client
+            .query(query, &[&commenter_id])
+            .map_err(query_err)?
+            .iter()
+            .map(|row| {
+                let article: String = row.try_get("article").map_err(read_err)?;
+                Ok(ExportedSubscription {
+                    article: base64_decode(article.clone()).unwrap_or(article),
+                    created_at: row.try_get("created_at").map_err(read_err)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Some(CommenterExport {
+            commenter,
+            comments,
+            votes,
+            flags,
+            subscriptions,
+        }))
+    }
+}
+
+fn comment(row: &postgres::Row) -> Result<Comment, String> {
+    Ok(Comment {
+        id: row.try_get("id").map_err(read_err)?,
+        timestamp: row.try_get("timestamp").map_err(read_err)?,
+        parent: row
+            .try_get::<_, Option<i64>>("parent")
+            .map_err(read_err)?
+            .unwrap_or(0),
+        poster_name: row.try_get("poster_name").map_err(read_err)?,
+        comment: row.try_get("comment").map_err(read_err)?,
+        votes: row.try_get("votes").map_err(read_err)?,
+        myvote: row.try_get("myvote").map_err(read_err)?,
+        edited_at: row.try_get("edited_at").map_err(read_err)?,
+        verified: row.try_get("verified").map_err(read_err)?,
+        pinned: row.try_get("pinned").map_err(read_err)?,
+        is_author: row.try_get("author").map_err(read_err)?,
+        collapsed: false,
+        avatar_url: None,
+        comment_html: None,
+        poster_email: row.try_get("poster_email").map_err(read_err)?,
+        attachments: vec![],
+        attachment_names: row
+            .try_get::<_, Option<String>>("attachments")
+            .map_err(read_err)?
+            .map(|names| names.lines().map(String::from).collect())
+            .unwrap_or_default(),
+        children: None,
+    })
+}
+
+/// The columns [`recent_comment`] expects, selected from `comments` joined with `ids`.
+const RECENT_COMMENT_COLUMNS: &str = "id, timestamp, article, COALESCE(ids.name, '') AS poster_name, comment, moderated, country,
+                                      CAST((SELECT COALESCE(SUM(vote), 0) FROM votes WHERE votes.comment_id = comments.id) AS BIGINT) AS votes";
+
+fn recent_comment(row: &postgres::Row) -> Result<RecentComment, String> {
+    let article: String = row.try_get("article").map_err(read_err)?;
+
+    Ok(RecentComment {
+        id: row.try_get("id").map_err(read_err)?,
+        timestamp: row.try_get("timestamp").map_err(read_err)?,
+        article: base64_decode(article.clone()).unwrap_or(article),
+        poster_name: row.try_get("poster_name").map_err(read_err)?,
+        comment: row.try_get("comment").map_err(read_err)?,
+        pending: !row.try_get::<_, bool>("moderated").map_err(read_err)?,
+        votes: row.try_get("votes").map_err(read_err)?,
+        country: row.try_get("country").map_err(read_err)?,
+    })
+}
+
+fn article_lock(row: &postgres::Row) -> Result<ArticleLock, String> {
+    Ok(ArticleLock {
+        locked: row.try_get("locked").map_err(read_err)?,
+        voting_locked: row.try_get("voting_locked").map_err(read_err)?,
+    })
+}
+
+fn exported_commenter(row: &postgres::Row) -> Result<ExportedCommenter, String> {
+    Ok(ExportedCommenter {
+        commenter_id: row.try_get("commenter_id").map_err(read_err)?,
+        name: row.try_get("name").map_err(read_err)?,
+        email: row.try_get("email").map_err(read_err)?,
+        reply_notifications: row.try_get("reply_notifications").map_err(read_err)?,
+        verified: row.try_get("verified").map_err(read_err)?,
+        oauth_provider: row.try_get("oauth_provider").map_err(read_err)?,
+        oauth_subject: row.try_get("oauth_subject").map_err(read_err)?,
+    })
+}
+
+fn exported_comment(row: &postgres::Row) -> Result<ExportedComment, String> {
+    let article: String = row.try_get("article").map_err(read_err)?;
+
+    Ok(ExportedComment {
+        id: row.try_get("id").map_err(read_err)?,
+        commenter_id: row.try_get("commenter_id").map_err(read_err)?,
+        timestamp: row.try_get("timestamp").map_err(read_err)?,
+        article: base64_decode(article.clone()).unwrap_or(article),
+        parent: row.try_get("parent").map_err(read_err)?,
+        moderated: row.try_get("moderated").map_err(read_err)?,
+        comment: row.try_get("comment").map_err(read_err)?,
+        edited_at: row.try_get("edited_at").map_err(read_err)?,
+        pinned: row.try_get("pinned").map_err(read_err)?,
+    })
+}
+
+fn exported_vote(row: &postgres::Row) -> Result<ExportedVote, String> {
+    Ok(ExportedVote {
+        comment_id: row.try_get("comment_id").map_err(read_err)?,
+        voter_id: row.try_get("voter_id").map_err(read_err)?,
+        vote: row.try_get("vote").map_err(read_err)?,
+    })
+}
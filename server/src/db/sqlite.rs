@@ -0,0 +1,2587 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use r2d2::{Pool, PooledConnection};
+use sqlite::Value::Null;
+use sqlite3_sys as ffi;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use super::{
+    group_flags, ArticleActivity, ArticleLock, AuditEntry, BlocklistEntry, Comment, CommentSort,
+    CommentSummary, Commenter, CommenterExport, ExportRecord, ExportedComment, ExportedCommenter,
+    ExportedFlag, ExportedSubscription, ExportedVote, FlaggedComment, NewComment, PendingComment,
+    PowState, QueuedEmail, RecentComment, ReplyRecipient, Reputation, SearchResult, SiteStats,
+    Storage, StoredChallenge, StoredTransaction, Subscriber, VoteTotals, ANONYMIZED_NAME,
+    DELETED_COMMENT,
+};
+use crate::base64_decode;
+use crate::config::{AnonymizeMode, ConfigFile, SqliteJournalMode, SqliteSynchronous};
+use crate::migrations::Migration;
+
+/// Per-connection settings, from the `sqlite_*` config fields.
+pub struct Pragmas {
+    busy_timeout_ms: usize,
+    statements: String,
+}
+
+impl Pragmas {
+    pub fn new_from_config(config: &ConfigFile) -> Self {
+        let journal_mode = match config.sqlite_journal_mode {
+            SqliteJournalMode::Wal => "WAL",
+            SqliteJournalMode::Delete => "DELETE",
+            SqliteJournalMode::Truncate => "TRUNCATE",
+            SqliteJournalMode::Persist => "PERSIST",
+        };
+        let synchronous = match config.sqlite_synchronous {
+            SqliteSynchronous::Off => "OFF",
+            SqliteSynchronous::Normal => "NORMAL",
+            SqliteSynchronous::Full => "FULL",
+            SqliteSynchronous::Extra => "EXTRA",
+        };
+
+        let mut statements = format!(
+            "PRAGMA foreign_keys = ON; PRAGMA journal_mode = {journal_mode}; \
+             PRAGMA synchronous = {synchronous};"
+        );
+        // A negative cache_size is in KiB rather than pages.
+        if let Some(kib) = config.sqlite_cache_size_kib {
+            statements.push_str(&format!(" PRAGMA cache_size = -{kib};"));
+        }
+        if let Some(mib) = config.sqlite_mmap_size_mib {
+            statements.push_str(&format!(
+                " PRAGMA mmap_size = {};",
+                mib.saturating_mul(1024 * 1024)
+            ));
+        }
+
+        Pragmas {
+            busy_timeout_ms: config.sqlite_busy_timeout_ms.unwrap_or(5000),
+            statements,
+        }
+    }
+}
+
+/// Opens connections for the r2d2 pool, applying per-connection settings.
+pub struct SqliteConnectionManager {
+    path: String,
+    pragmas: Arc<Pragmas>,
+    /// Refuse writes, for connections in the reader pool.
+    read_only: bool,
+}
+
+impl r2d2::ManageConnection for SqliteConnectionManager {
+    type Connection = sqlite::Connection;
+    type Error = sqlite::Error;
+
+    fn connect(&self) -> Result<sqlite::Connection, sqlite::Error> {
+        let mut conn = sqlite::open(&self.path)?;
+        conn.set_busy_timeout(self.pragmas.busy_timeout_ms)?;
+        conn.execute(&self.pragmas.statements)?;
+        if self.read_only {
+            conn.execute("PRAGMA query_only = ON;")?;
+        }
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut sqlite::Connection) -> Result<(), sqlite::Error> {
+        conn.execute("SELECT 1;")
+    }
+
+    fn has_broken(&self, _conn: &mut sqlite::Connection) -> bool {
+        false
+    }
+}
+
+/// Reads are spread over a pool of query-only connections, while every write goes through a single
+/// connection, so SQLite never has writers waiting on each other's locks and a burst of page loads
+/// doesn't hold up new comments.
+pub struct SqliteStorage {
+    readers: Pool<SqliteConnectionManager>,
+    writer: Pool<SqliteConnectionManager>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str, pool_size: u32, pragmas: Pragmas) -> Result<Self, String> {
+        let pragmas = Arc::new(pragmas);
+        let manager = |read_only| SqliteConnectionManager {
+            path: String::from(path),
+            pragmas: pragmas.clone(),
+            read_only,
+        };
+
+        // The writer comes first, so the database file exists before any reader opens it.
+        let writer = Pool::builder()
+            .max_size(1)
+            .build(manager(false))
+            .map_err(|e| format!("Could not open database {path}: {e}"))?;
+        let readers = Pool::builder()
+            .max_size(pool_size)
+            .build(manager(true))
+            .map_err(|e| format!("Could not open database {path}: {e}"))?;
+
+        Ok(SqliteStorage { readers, writer })
+    }
+
+    fn read(&self) -> Result<PooledConnection<SqliteConnectionManager>, String> {
+        match self.readers.get() {
+            Ok(conn) => Ok(conn),
+            Err(e) => Err(format!("DB Error: {e}")),
+        }
+    }
+
+    fn write(&self) -> Result<PooledConnection<SqliteConnectionManager>, String> {
+        match self.writer.get() {
+            Ok(conn) => Ok(conn),
+            Err(e) => Err(format!("DB Error: {e}")),
+        }
+    }
+}
+
+/// Published comments on an article with their scores and the viewer's votes, bound by viewer
+/// id, article, limit, and offset.
+fn comments_query(sort: CommentSort) -> String {
+    format!(
+        r#"SELECT id, parent, ids.name AS poster_name, ids.email AS poster_email, ids.verified AS verified, timestamp, comment, edited_at, pinned, author, COALESCE(SUM(v1.vote),0) + 1 AS votes,
+                              COALESCE((SELECT v2.vote FROM votes v2 WHERE v2.voter_id = ? AND v2.comment_id = id), 0) AS myvote,
+                              (SELECT group_concat(name, char(10)) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              LEFT JOIN votes v1 on comments.id = v1.comment_id
+                              WHERE article = ? AND id > 0 AND moderated = true
+                              GROUP BY comments.id
+                              ORDER BY pinned DESC, {}
+                              LIMIT ? OFFSET ?;"#,
+        super::order_by(sort)
+    )
+}
+
+/// Published comments on an article changed since a time, bound by viewer id, article, and time.
+const COMMENT_CHANGES_QUERY: &str = r#"SELECT id, parent, ids.name AS poster_name, ids.email AS poster_email, ids.verified AS verified, timestamp, comment, edited_at, pinned, author,
+                              (SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS votes,
+                              COALESCE((SELECT v2.vote FROM votes v2 WHERE v2.voter_id = ? AND v2.comment_id = comments.id), 0) AS myvote,
+                              (SELECT group_concat(name, char(10)) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE article = ? AND id > 0 AND moderated = true AND COALESCE(changed_at, timestamp) >= ?
+                              ORDER BY timestamp ASC, id ASC;"#;
+
+/// Count a newly published comment, bound by comment id, towards its poster's `approved_comments`.
+const APPROVED_COUNT_QUERY: &str = r#"UPDATE ids SET approved_comments = approved_comments + 1
+                                      WHERE commenter_id = (SELECT commenter_id FROM comments WHERE id = ?);"#;
+
+const VOTED_AT_QUERY: &str =
+    r#"UPDATE comments SET voted_at = strftime('%s', 'now') WHERE id = ?;"#;
+
+const UNAPPROVED_COUNT_QUERY: &str = r#"UPDATE ids SET approved_comments = approved_comments - 1
+                                        WHERE commenter_id = (SELECT commenter_id FROM comments WHERE id = ?);"#;
+
+/// Matches rows of `ids` that have never been used to comment, vote, flag or subscribe.
+const UNUSED_COMMENTER: &str = r#"NOT EXISTS (SELECT 1 FROM comments WHERE comments.commenter_id = ids.commenter_id)
+                                  AND NOT EXISTS (SELECT 1 FROM votes WHERE votes.voter_id = ids.commenter_id)
+                                  AND NOT EXISTS (SELECT 1 FROM flags WHERE flags.flagger_id = ids.commenter_id)
+                                  AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE subscriptions.commenter_id = ids.commenter_id)"#;
+
+fn prepare<'a>(conn: &'a sqlite::Connection, query: &str) -> Result<sqlite::Statement<'a>, String> {
+    conn.prepare(query)
+        .map_err(|e| format!("Could not prepare statement: {e}"))
+}
+
+fn step(statement: &mut sqlite::Statement) -> Result<(), String> {
+    match statement.next() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Could not execute statement: {e}")),
+    }
+}
+
+/// The most recent error on `conn`, for FFI calls the sqlite crate doesn't wrap.
+fn error_message(conn: &sqlite::Connection) -> String {
+    // SAFETY: sqlite3_errmsg always returns a valid string, owned by the connection.
+    unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(conn.as_raw())) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn bind_err(e: sqlite::Error) -> String {
+    format!("Could not bind statement: {e}")
+}
+
+fn read_err(e: sqlite::Error) -> String {
+    format!("Could not read row: {e}")
+}
+
+impl Storage for SqliteStorage {
+    fn schema_version(&self) -> Result<i64, String> {
+        let create_query = r#"CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY,
+                                                                         applied_at INTEGER NOT NULL);"#;
+        let query = r#"SELECT COALESCE(MAX(version), 0) AS version FROM schema_version;"#;
+
+        let conn = self.write()?;
+        conn.execute(create_query)
+            .map_err(|e| format!("Could not create schema_version table: {e}"))?;
+
+        let statement = prepare(&conn, query)?;
+        let version = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<i64, _>("version")
+                .map_err(read_err)?,
+            None => 0,
+        };
+
+        Ok(version)
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<(), String> {
+        let conn = self.write()?;
+        let script = format!(
+            "BEGIN; {} INSERT INTO schema_version VALUES ({}, strftime('%s', 'now')); COMMIT;",
+            migration.sqlite, migration.version
+        );
+
+        if let Err(e) = conn.execute(script) {
+            let _ = conn.execute("ROLLBACK;");
+            return Err(format!(
+                "Could not apply migration {}: {e}",
+                migration.version
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn add_commenter(&self, commenter_id: &str, name: &str, email: &str) -> Result<(), String> {
+        let query = r#"INSERT INTO ids (commenter_id, name, email, created_at)
+                              VALUES (?, ?, ?, strftime('%s', 'now'));"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, commenter_id), (2, name), (3, email)][..])
+            .map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn get_commenter(&self, commenter_id: &str) -> Result<Option<Commenter>, String> {
+        let query = r#"SELECT name, email, approved_comments, verified, reply_notifications FROM ids WHERE commenter_id = ?"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, commenter_id)).map_err(bind_err)?;
+
+        let commenter = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                Some(Commenter {
+                    name: String::from(row.try_read::<&str, _>("name").map_err(read_err)?),
+                    email: String::from(row.try_read::<&str, _>("email").map_err(read_err)?),
+                    approved_comments: row
+                        .try_read::<i64, _>("approved_comments")
+                        .map_err(read_err)?,
+                    verified: row.try_read::<i64, _>("verified").map_err(read_err)? != 0,
+                    reply_notifications: row
+                        .try_read::<i64, _>("reply_notifications")
+                        .map_err(read_err)?
+                        != 0,
+                })
+            }
+            None => None,
+        };
+
+        Ok(commenter)
+    }
+
+    fn add_oauth_commenter(
+        &self,
+        commenter_id: &str,
+        name: &str,
+        email: &str,
+        provider: &str,
+        subject: &str,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO ids (commenter_id, name, email, oauth_provider, oauth_subject, verified, created_at)
+                              VALUES (?, ?, ?, ?, ?, true, strftime('%s', 'now'));"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(
+                &[
+                    (1, commenter_id),
+                    (2, name),
+                    (3, email),
+                    (4, provider),
+                    (5, subject),
+                ][..],
+            )
+            .map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn find_oauth_commenter(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<String>, String> {
+        let query =
+            r#"SELECT commenter_id FROM ids WHERE oauth_provider = ? AND oauth_subject = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, provider), (2, subject)][..])
+            .map_err(bind_err)?;
+
+        let commenter_id = match statement.into_iter().next() {
+            Some(row) => Some(String::from(
+                row.map_err(read_err)?
+                    .try_read::<&str, _>("commenter_id")
+                    .map_err(read_err)?,
+            )),
+            None => None,
+        };
+
+        Ok(commenter_id)
+    }
+
+    fn verified_names(&self) -> Result<Vec<(String, String)>, String> {
+        let query = r#"SELECT commenter_id, name FROM ids WHERE verified;"#;
+
+        let conn = self.read()?;
+        let statement = prepare(&conn, query)?;
+
+        let mut names = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            names.push((
+                String::from(row.try_read::<&str, _>("commenter_id").map_err(read_err)?),
+                String::from(row.try_read::<&str, _>("name").map_err(read_err)?),
+            ));
+        }
+
+        Ok(names)
+    }
+
+    fn update_commenter(
+        &self,
+        commenter_id: &str,
+        name: &str,
+        email: &str,
+    ) -> Result<bool, String> {
+        let query = r#"UPDATE ids SET name = ?1, email = ?2, verified = verified AND COALESCE(email, '') = ?2
+                              WHERE commenter_id = ?3;"#;
+        // The search index triggers only follow changes to the comment text.
+        let fts_query = r#"UPDATE comments_fts SET poster_name = ?
+                               WHERE rowid IN (SELECT id FROM comments WHERE commenter_id = ?);"#;
+
+        let conn = self.write()?;
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+
+        let res = (|| {
+            let mut statement = prepare(&conn, query)?;
+            statement
+                .bind(&[(1, name), (2, email), (3, commenter_id)][..])
+                .map_err(bind_err)?;
+            step(&mut statement)?;
+            let found = conn.change_count() > 0;
+
+            let mut statement = prepare(&conn, fts_query)?;
+            statement
+                .bind(&[(1, name), (2, commenter_id)][..])
+                .map_err(bind_err)?;
+            step(&mut statement)?;
+
+            Ok(found)
+        })();
+
+        match res {
+            Ok(found) => {
+                conn.execute("COMMIT;")
+                    .map_err(|e| format!("Could not commit transaction: {e}"))?;
+                Ok(found)
+            }
+            Err(e) => {
+                let _ = conn.execute("ROLLBACK;");
+                Err(e)
+            }
+        }
+    }
+
+    fn set_reply_notifications(&self, commenter_id: &str, enabled: bool) -> Result<bool, String> {
+        let query = r#"UPDATE ids SET reply_notifications = ? WHERE commenter_id = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, enabled as i64)).map_err(bind_err)?;
+        statement.bind((2, commenter_id)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn add_comment(&self, comment: &NewComment) -> Result<i64, String> {
+        let query = r#"INSERT INTO comments (article, commenter_id, parent, comment, moderated, timestamp, country, author)
+                                            VALUES(?, ?, ?, ?, ?, ?, ?, ?);"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment.article)).map_err(bind_err)?;
+        statement
+            .bind((2, comment.commenter_id))
+            .map_err(bind_err)?;
+
+        match comment.parent {
+            Some(parent) => statement.bind((3, parent)).map_err(bind_err)?,
+            None => statement.bind((3, Null)).map_err(bind_err)?,
+        }
+
+        statement.bind((4, comment.comment)).map_err(bind_err)?;
+        statement
+            .bind((5, comment.moderated as i64))
+            .map_err(bind_err)?;
+        statement.bind((6, comment.timestamp)).map_err(bind_err)?;
+        match comment.country {
+            Some(country) => statement.bind((7, country)).map_err(bind_err)?,
+            None => statement.bind((7, Null)).map_err(bind_err)?,
+        }
+        statement
+            .bind((8, comment.author as i64))
+            .map_err(bind_err)?;
+        step(&mut statement)?;
+
+        let statement = prepare(&conn, "SELECT last_insert_rowid() AS id;")?;
+        let id = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<i64, _>("id")
+                .map_err(read_err)?,
+            None => return Err(String::from("Could not read new comment id")),
+        };
+
+        if comment.moderated {
+            let mut statement = prepare(&conn, APPROVED_COUNT_QUERY)?;
+            statement.bind((1, id)).map_err(bind_err)?;
+            step(&mut statement)?;
+        }
+
+        Ok(id)
+    }
+
+    fn get_comments(
+        &self,
+        article: &str,
+        viewer_id: &str,
+        sort: CommentSort,
+        limit: Option<i64>,
+        offset: i64,
+    ) -> Result<Vec<Comment>, String> {
+        let query = comments_query(sort);
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, &query)?;
+        statement.bind((1, viewer_id)).map_err(bind_err)?;
+        statement.bind((2, article)).map_err(bind_err)?;
+        // A negative LIMIT means no limit in SQLite.
+        statement.bind((3, limit.unwrap_or(-1))).map_err(bind_err)?;
+        statement.bind((4, offset)).map_err(bind_err)?;
+
+        let mut comments = vec![];
+        for row in statement.into_iter() {
+            comments.push(read_comment(&row.map_err(read_err)?)?);
+        }
+
+        Ok(comments)
+    }
+
+    fn get_comment_changes(
+        &self,
+        article: &str,
+        viewer_id: &str,
+        since: i64,
+    ) -> Result<(Vec<Comment>, HashMap<i64, i64>), String> {
+        let query = COMMENT_CHANGES_QUERY;
+        let votes_query = r#"SELECT id, (SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS votes
+                              FROM comments
+                              WHERE article = ? AND id > 0 AND moderated = true AND voted_at >= ?
+                              AND COALESCE(changed_at, timestamp) < ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, viewer_id)).map_err(bind_err)?;
+        statement.bind((2, article)).map_err(bind_err)?;
+        statement.bind((3, since)).map_err(bind_err)?;
+
+        let mut comments = vec![];
+        for row in statement.into_iter() {
+            comments.push(read_comment(&row.map_err(read_err)?)?);
+        }
+
+        let mut statement = prepare(&conn, votes_query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+        statement.bind((2, since)).map_err(bind_err)?;
+        statement.bind((3, since)).map_err(bind_err)?;
+
+        let mut votes = HashMap::new();
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            votes.insert(
+                row.try_read::<i64, _>("id").map_err(read_err)?,
+                row.try_read::<i64, _>("votes").map_err(read_err)?,
+            );
+        }
+
+        Ok((comments, votes))
+    }
+
+    fn get_published_comment(&self, comment_id: i64) -> Result<Option<(String, Comment)>, String> {
+        let query = r#"SELECT id, article, parent, ids.name AS poster_name, ids.email AS poster_email, ids.verified AS verified, timestamp, comment, edited_at, pinned, author,
+                              (SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS votes,
+                              0 AS myvote,
+                              (SELECT group_concat(name, char(10)) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE id = ? AND id > 0 AND moderated = true;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+
+        let comment = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                Some((
+                    String::from(row.try_read::<&str, _>("article").map_err(read_err)?),
+                    read_comment(&row)?,
+                ))
+            }
+            None => None,
+        };
+
+        Ok(comment)
+    }
+
+    fn get_replies(&self, comment_id: i64) -> Result<Vec<Comment>, String> {
+        let query = r#"SELECT id, parent, ids.name AS poster_name, ids.email AS poster_email, ids.verified AS verified, timestamp, comment, edited_at, pinned, author,
+                              (SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS votes,
+                              0 AS myvote,
+                              (SELECT group_concat(name, char(10)) FROM attachments WHERE attachments.comment_id = comments.id) AS attachments
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE parent = ? AND id > 0 AND moderated = true
+                              ORDER BY timestamp ASC, id ASC;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+
+        let mut replies = vec![];
+        for row in statement.into_iter() {
+            replies.push(read_comment(&row.map_err(read_err)?)?);
+        }
+
+        Ok(replies)
+    }
+
+    fn add_attachment(
+        &self,
+        comment_id: i64,
+        name: &str,
+        content_type: &str,
+        size: i64,
+        created_at: i64,
+        max: i64,
+    ) -> Result<Option<i64>, String> {
+        let query = r#"INSERT INTO attachments (comment_id, name, content_type, size, created_at)
+                              SELECT ?1, ?2, ?3, ?4, ?5
+                              WHERE (SELECT COUNT(*) FROM attachments WHERE comment_id = ?1) < ?6;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        statement.bind((2, name)).map_err(bind_err)?;
+        statement.bind((3, content_type)).map_err(bind_err)?;
+        statement.bind((4, size)).map_err(bind_err)?;
+        statement.bind((5, created_at)).map_err(bind_err)?;
+        statement.bind((6, max)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        if conn.change_count() == 0 {
+            return Ok(None);
+        }
+
+        let statement = prepare(&conn, "SELECT last_insert_rowid() AS id;")?;
+        let id = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<i64, _>("id")
+                .map_err(read_err)?,
+            None => return Err(String::from("Could not read new attachment id")),
+        };
+
+        // So clients syncing with `since` pick up the new image.
+        let mut statement = prepare(&conn, r#"UPDATE comments SET changed_at = ? WHERE id = ?;"#)?;
+        statement.bind((1, created_at)).map_err(bind_err)?;
+        statement.bind((2, comment_id)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(Some(id))
+    }
+
+    fn remove_attachment(&self, id: i64) -> Result<(), String> {
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, r#"DELETE FROM attachments WHERE id = ?;"#)?;
+        statement.bind((1, id)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn unused_attachments(&self) -> Result<Vec<String>, String> {
+        // An image attached again since is no longer unused.
+        let in_use_query =
+            r#"DELETE FROM removed_attachments WHERE name IN (SELECT name FROM attachments);"#;
+        let query = r#"SELECT DISTINCT name FROM removed_attachments;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, in_use_query)?;
+        step(&mut statement)?;
+
+        let statement = prepare(&conn, query)?;
+        let mut names = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            names.push(String::from(
+                row.try_read::<&str, _>("name").map_err(read_err)?,
+            ));
+        }
+
+        Ok(names)
+    }
+
+    fn forget_unused_attachment(&self, name: &str) -> Result<(), String> {
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, r#"DELETE FROM removed_attachments WHERE name = ?;"#)?;
+        statement.bind((1, name)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn get_comment_votes(&self, comment_id: i64) -> Result<Option<(String, i64)>, String> {
+        let query = r#"SELECT article, (SELECT COALESCE(SUM(vote), 0) + 1 FROM votes WHERE votes.comment_id = comments.id) AS votes
+                              FROM comments
+                              WHERE id = ? AND id > 0 AND moderated = true;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+
+        let votes = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                Some((
+                    String::from(row.try_read::<&str, _>("article").map_err(read_err)?),
+                    row.try_read::<i64, _>("votes").map_err(read_err)?,
+                ))
+            }
+            None => None,
+        };
+
+        Ok(votes)
+    }
+
+    fn count_comments(&self, article: &str) -> Result<i64, String> {
+        let query = r#"SELECT COUNT(*) AS total FROM comments WHERE article = ? AND id > 0 AND moderated = true;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+
+        let total = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<i64, _>("total")
+                .map_err(read_err)?,
+            None => 0,
+        };
+
+        Ok(total)
+    }
+
+    fn search_comments(
+        &self,
+        query: &str,
+        article: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<SearchResult>, i64), String> {
+        let from = r#"FROM comments_fts
+                      JOIN comments ON comments.id = comments_fts.rowid
+                      LEFT JOIN ids ON comments.commenter_id = ids.commenter_id
+                      WHERE comments_fts MATCH ?1 AND comments.id > 0 AND comments.moderated = true
+                            AND (?2 = '' OR comments.article = ?2)"#;
+        let query_sql = format!(
+            r#"SELECT comments.id AS id, timestamp, article, parent, ids.name AS poster_name, comments.comment AS comment
+                      {from}
+                      ORDER BY comments_fts.rank
+                      LIMIT ?3 OFFSET ?4;"#
+        );
+        let count_sql = format!(r#"SELECT COUNT(*) AS total {from};"#);
+
+        let fts_query = fts_query(query);
+        let article = article.unwrap_or("");
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, &query_sql)?;
+        statement
+            .bind(&[(1, &fts_query[..]), (2, article)][..])
+            .map_err(bind_err)?;
+        statement.bind((3, limit)).map_err(bind_err)?;
+        statement.bind((4, offset)).map_err(bind_err)?;
+
+        let mut results = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            let article = String::from(row.try_read::<&str, _>("article").map_err(read_err)?);
+
+            results.push(SearchResult {
+                id: row.try_read::<i64, _>("id").map_err(read_err)?,
+                timestamp: row.try_read::<i64, _>("timestamp").map_err(read_err)?,
+                article: base64_decode(article.clone()).unwrap_or(article),
+                parent: row
+                    .try_read::<Option<i64>, _>("parent")
+                    .map_err(read_err)?
+                    .unwrap_or(0),
+                poster_name: String::from(
+                    row.try_read::<Option<&str>, _>("poster_name")
+                        .map_err(read_err)?
+                        .unwrap_or(""),
+                ),
+                comment: String::from(row.try_read::<&str, _>("comment").map_err(read_err)?),
+            });
+        }
+
+        let mut statement = prepare(&conn, &count_sql)?;
+        statement
+            .bind(&[(1, &fts_query[..]), (2, article)][..])
+            .map_err(bind_err)?;
+
+        let total = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<i64, _>("total")
+                .map_err(read_err)?,
+            None => 0,
+        };
+
+        Ok((results, total))
+    }
+
+    fn count_comments_by_article(
+        &self,
+        articles: &[String],
+    ) -> Result<HashMap<String, i64>, String> {
+        let mut counts = HashMap::new();
+        if articles.is_empty() {
+            return Ok(counts);
+        }
+
+        let placeholders = vec!["?"; articles.len()].join(", ");
+        let query = format!(
+            r#"SELECT article, COUNT(*) AS total FROM comments
+                      WHERE article IN ({placeholders}) AND id > 0 AND moderated = true
+                      GROUP BY article;"#
+        );
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, &query)?;
+        for (i, article) in articles.iter().enumerate() {
+            statement.bind((i + 1, &article[..])).map_err(bind_err)?;
+        }
+
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            counts.insert(
+                String::from(row.try_read::<&str, _>("article").map_err(read_err)?),
+                row.try_read::<i64, _>("total").map_err(read_err)?,
+            );
+        }
+
+        Ok(counts)
+    }
+
+    fn get_comment_owner(&self, comment_id: i64) -> Result<Option<String>, String> {
+        let query = r#"SELECT commenter_id FROM comments WHERE id = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+
+        let owner = match statement.into_iter().next() {
+            Some(row) => Some(String::from(
+                row.map_err(read_err)?
+                    .try_read::<&str, _>("commenter_id")
+                    .map_err(read_err)?,
+            )),
+            None => None,
+        };
+
+        Ok(owner)
+    }
+
+    fn get_comment_posted(&self, comment_id: i64) -> Result<Option<(String, i64)>, String> {
+        let query = r#"SELECT commenter_id, timestamp FROM comments WHERE id = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+
+        let posted = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                Some((
+                    String::from(row.try_read::<&str, _>("commenter_id").map_err(read_err)?),
+                    row.try_read::<i64, _>("timestamp").map_err(read_err)?,
+                ))
+            }
+            None => None,
+        };
+
+        Ok(posted)
+    }
+
+    fn delete_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let keep_query = r#"UPDATE comments SET comment = ?, changed_at = strftime('%s', 'now')
+                               WHERE id = ? AND id > 0 AND EXISTS (SELECT 1 FROM comments AS replies WHERE replies.parent = comments.id);"#;
+        let attachments_query = r#"DELETE FROM attachments WHERE comment_id = ?;"#;
+        let votes_query = r#"DELETE FROM votes WHERE comment_id = ?;"#;
+        let query = r#"DELETE FROM comments WHERE id = ? AND id > 0;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, keep_query)?;
+        statement.bind((1, DELETED_COMMENT)).map_err(bind_err)?;
+        statement.bind((2, comment_id)).map_err(bind_err)?;
+        step(&mut statement)?;
+        if conn.change_count() > 0 {
+            let mut statement = prepare(&conn, attachments_query)?;
+            statement.bind((1, comment_id)).map_err(bind_err)?;
+            step(&mut statement)?;
+            return Ok(true);
+        }
+
+        for query in [votes_query, query] {
+            let mut statement = prepare(&conn, query)?;
+            statement.bind((1, comment_id)).map_err(bind_err)?;
+            step(&mut statement)?;
+        }
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn get_comment_summary(&self, comment_id: i64) -> Result<Option<CommentSummary>, String> {
+        let query = r#"SELECT article, parent, comments.commenter_id, ids.name AS poster_name, comment
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE id = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+
+        let summary = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                let article = String::from(row.try_read::<&str, _>("article").map_err(read_err)?);
+
+                Some(CommentSummary {
+                    article: base64_decode(article.clone()).unwrap_or(article),
+                    parent: row.try_read::<Option<i64>, _>("parent").map_err(read_err)?,
+                    commenter_id: String::from(
+                        row.try_read::<&str, _>("commenter_id").map_err(read_err)?,
+                    ),
+                    poster_name: String::from(
+                        row.try_read::<&str, _>("poster_name").map_err(read_err)?,
+                    ),
+                    comment: String::from(row.try_read::<&str, _>("comment").map_err(read_err)?),
+                })
+            }
+            None => None,
+        };
+
+        Ok(summary)
+    }
+
+    fn get_reply_recipient(&self, parent_id: i64) -> Result<Option<ReplyRecipient>, String> {
+        let query = r#"SELECT ids.commenter_id, name, email, reply_notifications
+                              FROM comments
+                              JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE id = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, parent_id)).map_err(bind_err)?;
+
+        let recipient = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+
+                Some(ReplyRecipient {
+                    commenter_id: String::from(
+                        row.try_read::<&str, _>("commenter_id").map_err(read_err)?,
+                    ),
+                    name: String::from(row.try_read::<&str, _>("name").map_err(read_err)?),
+                    email: String::from(row.try_read::<&str, _>("email").map_err(read_err)?),
+                    reply_notifications: row
+                        .try_read::<i64, _>("reply_notifications")
+                        .map_err(read_err)?
+                        != 0,
+                })
+            }
+            None => None,
+        };
+
+        Ok(recipient)
+    }
+
+    fn get_thread_participants(&self, article: &str) -> Result<Vec<ReplyRecipient>, String> {
+        let query = r#"SELECT DISTINCT ids.commenter_id, name, email, reply_notifications
+                              FROM comments
+                              JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE article = ? AND id > 0 AND moderated = true;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+
+        let mut participants = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+
+            participants.push(ReplyRecipient {
+                commenter_id: String::from(
+                    row.try_read::<&str, _>("commenter_id").map_err(read_err)?,
+                ),
+                name: String::from(row.try_read::<&str, _>("name").map_err(read_err)?),
+                email: String::from(row.try_read::<&str, _>("email").map_err(read_err)?),
+                reply_notifications: row
+                    .try_read::<i64, _>("reply_notifications")
+                    .map_err(read_err)?
+                    != 0,
+            });
+        }
+
+        Ok(participants)
+    }
+
+    fn add_subscription(
+        &self,
+        article: &str,
+        commenter_id: &str,
+        created_at: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO subscriptions (article, commenter_id, created_at) VALUES (?, ?, ?)
+                              ON CONFLICT (article, commenter_id) DO NOTHING;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+        statement.bind((2, commenter_id)).map_err(bind_err)?;
+        statement.bind((3, created_at)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn get_subscribers(&self, article: &str) -> Result<Vec<Subscriber>, String> {
+        let query = r#"SELECT subscriptions.id, ids.commenter_id, name, email
+                              FROM subscriptions
+                              JOIN ids on subscriptions.commenter_id = ids.commenter_id
+                              WHERE article = ?
+                              ORDER BY subscriptions.id ASC;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+
+        let mut subscribers = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+
+            subscribers.push(Subscriber {
+                id: row.try_read::<i64, _>("id").map_err(read_err)?,
+                commenter_id: String::from(
+                    row.try_read::<&str, _>("commenter_id").map_err(read_err)?,
+                ),
+                name: String::from(row.try_read::<&str, _>("name").map_err(read_err)?),
+                email: String::from(row.try_read::<&str, _>("email").map_err(read_err)?),
+            });
+        }
+
+        Ok(subscribers)
+    }
+
+    fn remove_subscription(&self, id: i64) -> Result<bool, String> {
+        let query = r#"DELETE FROM subscriptions WHERE id = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, id)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn edit_comment(
+        &self,
+        comment_id: i64,
+        commenter_id: &str,
+        comment: &str,
+        edited_at: i64,
+    ) -> Result<(), String> {
+        let query = r#"UPDATE comments SET comment = ?1, edited_at = ?2, changed_at = ?2
+                              WHERE id = ?3 AND commenter_id = ?4;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment)).map_err(bind_err)?;
+        statement.bind((2, edited_at)).map_err(bind_err)?;
+        statement.bind((3, comment_id)).map_err(bind_err)?;
+        statement.bind((4, commenter_id)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn set_vote(
+        &self,
+        comment_id: i64,
+        voter_id: &str,
+        vote: i64,
+        ip_hash: Option<&str>,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO votes (comment_id, voter_id, vote, ip_hash) VALUES (?, ?, ?, ?)
+                              ON CONFLICT(comment_id, voter_id)
+                              DO UPDATE SET vote = excluded.vote, ip_hash = COALESCE(excluded.ip_hash, votes.ip_hash);"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        statement.bind((2, voter_id)).map_err(bind_err)?;
+        statement.bind((3, vote)).map_err(bind_err)?;
+        match ip_hash {
+            Some(ip_hash) => statement.bind((4, ip_hash)),
+            None => statement.bind((4, Null)),
+        }
+        .map_err(bind_err)?;
+        step(&mut statement)?;
+
+        let mut statement = prepare(&conn, VOTED_AT_QUERY)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn ip_has_voted(&self, comment_id: i64, ip_hash: &str, voter_id: &str) -> Result<bool, String> {
+        let query = r#"SELECT 1 FROM votes WHERE comment_id = ? AND ip_hash = ? AND voter_id != ? LIMIT 1;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        statement.bind((2, ip_hash)).map_err(bind_err)?;
+        statement.bind((3, voter_id)).map_err(bind_err)?;
+
+        let voted = match statement.into_iter().next() {
+            Some(row) => row.map(|_| true).map_err(read_err)?,
+            None => false,
+        };
+        Ok(voted)
+    }
+
+    fn remove_vote(&self, comment_id: i64, voter_id: &str) -> Result<(), String> {
+        let query = r#"DELETE FROM votes WHERE comment_id = ? AND voter_id = ?"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        statement.bind((2, voter_id)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        let mut statement = prepare(&conn, VOTED_AT_QUERY)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn enqueue_email(
+        &self,
+        recipient: &str,
+        subject: &str,
+        body: &str,
+        now: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO email_queue (recipient, subject, body, next_attempt_at, created_at)
+                              VALUES (?, ?, ?, ?, ?);"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, recipient)).map_err(bind_err)?;
+        statement.bind((2, subject)).map_err(bind_err)?;
+        statement.bind((3, body)).map_err(bind_err)?;
+        statement.bind((4, now)).map_err(bind_err)?;
+        statement.bind((5, now)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn due_emails(&self, now: i64, limit: i64) -> Result<Vec<QueuedEmail>, String> {
+        let query = r#"SELECT id, recipient, subject, body, attempts FROM email_queue
+                              WHERE next_attempt_at <= ?
+                              ORDER BY next_attempt_at ASC, id ASC
+                              LIMIT ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, now)).map_err(bind_err)?;
+        statement.bind((2, limit)).map_err(bind_err)?;
+
+        let mut emails = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+
+            emails.push(QueuedEmail {
+                id: row.try_read::<i64, _>("id").map_err(read_err)?,
+                recipient: String::from(row.try_read::<&str, _>("recipient").map_err(read_err)?),
+                subject: String::from(row.try_read::<&str, _>("subject").map_err(read_err)?),
+                body: String::from(row.try_read::<&str, _>("body").map_err(read_err)?),
+                attempts: row.try_read::<i64, _>("attempts").map_err(read_err)?,
+            });
+        }
+
+        Ok(emails)
+    }
+
+    fn reschedule_email(
+        &self,
+        id: i64,
+        attempts: i64,
+        next_attempt_at: i64,
+        last_error: &str,
+    ) -> Result<(), String> {
+        let query = r#"UPDATE email_queue SET attempts = ?, next_attempt_at = ?, last_error = ? WHERE id = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, attempts)).map_err(bind_err)?;
+        statement.bind((2, next_attempt_at)).map_err(bind_err)?;
+        statement.bind((3, last_error)).map_err(bind_err)?;
+        statement.bind((4, id)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn delete_email(&self, id: i64) -> Result<(), String> {
+        let query = r#"DELETE FROM email_queue WHERE id = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, id)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn pending_comments(&self) -> Result<Vec<PendingComment>, String> {
+        let query = r#"SELECT id, timestamp, article, parent, ids.name AS poster_name, ids.email AS poster_email, comment, country,
+                              (SELECT COUNT(*) FROM flags WHERE flags.comment_id = comments.id) AS flags
+                              FROM comments
+                              LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                              WHERE moderated = false
+                              ORDER BY timestamp ASC;"#;
+
+        let conn = self.read()?;
+        let statement = prepare(&conn, query)?;
+
+        let mut comments = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            let article = String::from(row.try_read::<&str, _>("article").map_err(read_err)?);
+
+            comments.push(PendingComment {
+                id: row.try_read::<i64, _>("id").map_err(read_err)?,
+                timestamp: row.try_read::<i64, _>("timestamp").map_err(read_err)?,
+                article: base64_decode(article.clone()).unwrap_or(article),
+                parent: row
+                    .try_read::<Option<i64>, _>("parent")
+                    .map_err(read_err)?
+                    .unwrap_or(0),
+                poster_name: String::from(
+                    row.try_read::<&str, _>("poster_name").map_err(read_err)?,
+                ),
+                poster_email: String::from(
+                    row.try_read::<&str, _>("poster_email").map_err(read_err)?,
+                ),
+                comment: String::from(row.try_read::<&str, _>("comment").map_err(read_err)?),
+                flags: row.try_read::<i64, _>("flags").map_err(read_err)?,
+                country: row
+                    .try_read::<Option<&str>, _>("country")
+                    .map_err(read_err)?
+                    .map(String::from),
+            });
+        }
+
+        Ok(comments)
+    }
+
+    fn digest_comments(&self, limit: i64) -> Result<Vec<RecentComment>, String> {
+        let query = format!(
+            r#"SELECT {RECENT_COMMENT_COLUMNS}
+                      FROM comments
+                      LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                      WHERE id > (SELECT last_comment_id FROM email_digest)
+                      ORDER BY id ASC
+                      LIMIT ?;"#
+        );
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, &query)?;
+        statement.bind((1, limit)).map_err(bind_err)?;
+        read_recent_comments(statement)
+    }
+
+    fn recent_comments(&self, limit: i64) -> Result<Vec<RecentComment>, String> {
+        let query = format!(
+            r#"SELECT {RECENT_COMMENT_COLUMNS}
+                      FROM comments
+                      LEFT JOIN ids on comments.commenter_id = ids.commenter_id
+                      WHERE id > 0
+                      ORDER BY id DESC
+                      LIMIT ?;"#
+        );
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, &query)?;
+        statement.bind((1, limit)).map_err(bind_err)?;
+        read_recent_comments(statement)
+    }
+
+    fn stats(&self) -> Result<SiteStats, String> {
+        let query = r#"SELECT (SELECT COUNT(*) FROM ids) AS commenters,
+                              (SELECT COUNT(*) FROM comments WHERE id > 0) AS comments,
+                              (SELECT COUNT(*) FROM comments WHERE id > 0 AND moderated = false) AS pending,
+                              (SELECT COUNT(*) FROM votes) AS votes,
+                              (SELECT COUNT(DISTINCT article) FROM comments WHERE id > 0) AS articles;"#;
+
+        let conn = self.read()?;
+        let statement = prepare(&conn, query)?;
+        let stats = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                SiteStats {
+                    commenters: row.try_read::<i64, _>("commenters").map_err(read_err)?,
+                    comments: row.try_read::<i64, _>("comments").map_err(read_err)?,
+                    pending: row.try_read::<i64, _>("pending").map_err(read_err)?,
+                    votes: row.try_read::<i64, _>("votes").map_err(read_err)?,
+                    articles: row.try_read::<i64, _>("articles").map_err(read_err)?,
+                }
+            }
+            None => return Err(String::from("Could not read stats")),
+        };
+
+        Ok(stats)
+    }
+
+    fn comments_per_bucket(
+        &self,
+        from: i64,
+        to: i64,
+        size: i64,
+        offset: i64,
+    ) -> Result<HashMap<i64, i64>, String> {
+        let query = r#"SELECT ((timestamp - ?) / ?) * ? + ? AS start, COUNT(*) AS count
+                              FROM comments
+                              WHERE id > 0 AND timestamp >= ? AND timestamp < ?
+                              GROUP BY start;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(
+                &[
+                    (1, offset),
+                    (2, size),
+                    (3, size),
+                    (4, offset),
+                    (5, from),
+                    (6, to),
+                ][..],
+            )
+            .map_err(bind_err)?;
+
+        let mut counts = HashMap::new();
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            counts.insert(
+                row.try_read::<i64, _>("start").map_err(read_err)?,
+                row.try_read::<i64, _>("count").map_err(read_err)?,
+            );
+        }
+
+        Ok(counts)
+    }
+
+    fn top_articles(&self, from: i64, to: i64, limit: i64) -> Result<Vec<ArticleActivity>, String> {
+        let query = r#"SELECT article, COUNT(DISTINCT comments.id) AS comment_count, COUNT(votes.comment_id) AS vote_count
+                              FROM comments LEFT JOIN votes ON votes.comment_id = comments.id
+                              WHERE comments.id > 0 AND comments.timestamp >= ? AND comments.timestamp < ?
+                              GROUP BY article
+                              ORDER BY comment_count DESC, vote_count DESC, article ASC
+                              LIMIT ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, from), (2, to), (3, limit)][..])
+            .map_err(bind_err)?;
+
+        let mut articles = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            articles.push(ArticleActivity {
+                article: String::from(row.try_read::<&str, _>("article").map_err(read_err)?),
+                comments: row.try_read::<i64, _>("comment_count").map_err(read_err)?,
+                votes: row.try_read::<i64, _>("vote_count").map_err(read_err)?,
+            });
+        }
+
+        Ok(articles)
+    }
+
+    fn vote_totals(&self, from: i64, to: i64) -> Result<VoteTotals, String> {
+        let query = r#"SELECT COALESCE(SUM(CASE WHEN vote > 0 THEN 1 ELSE 0 END), 0) AS upvotes,
+                              COALESCE(SUM(CASE WHEN vote < 0 THEN 1 ELSE 0 END), 0) AS downvotes
+                              FROM votes JOIN comments ON comments.id = votes.comment_id
+                              WHERE comments.id > 0 AND comments.timestamp >= ? AND comments.timestamp < ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, from), (2, to)][..])
+            .map_err(bind_err)?;
+
+        let totals = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                VoteTotals {
+                    upvotes: row.try_read::<i64, _>("upvotes").map_err(read_err)?,
+                    downvotes: row.try_read::<i64, _>("downvotes").map_err(read_err)?,
+                }
+            }
+            None => VoteTotals::default(),
+        };
+
+        Ok(totals)
+    }
+
+    fn add_daily_counts(&self, counts: &[(i64, &str, i64)]) -> Result<(), String> {
+        let query = r#"INSERT INTO daily_counts (day, kind, count) VALUES (?, ?, ?)
+                              ON CONFLICT(day, kind) DO UPDATE SET count = daily_counts.count + excluded.count;"#;
+
+        let conn = self.write()?;
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+
+        let res = (|| {
+            let mut statement = prepare(&conn, query)?;
+            for (day, kind, count) in counts {
+                statement.reset().map_err(bind_err)?;
+                statement.bind((1, *day)).map_err(bind_err)?;
+                statement.bind((2, *kind)).map_err(bind_err)?;
+                statement.bind((3, *count)).map_err(bind_err)?;
+                step(&mut statement)?;
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = res {
+            let _ = conn.execute("ROLLBACK;");
+            return Err(e);
+        }
+
+        conn.execute("COMMIT;")
+            .map_err(|e| format!("Could not commit transaction: {e}"))
+    }
+
+    fn daily_counts(&self, from: i64, to: i64) -> Result<Vec<(i64, String, i64)>, String> {
+        let query = r#"SELECT day, kind, count FROM daily_counts WHERE day >= ? AND day < ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, from), (2, to)][..])
+            .map_err(bind_err)?;
+
+        let mut counts = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            counts.push((
+                row.try_read::<i64, _>("day").map_err(read_err)?,
+                String::from(row.try_read::<&str, _>("kind").map_err(read_err)?),
+                row.try_read::<i64, _>("count").map_err(read_err)?,
+            ));
+        }
+
+        Ok(counts)
+    }
+
+    fn mark_digest_sent(&self, last_comment_id: i64, sent_at: i64) -> Result<(), String> {
+        let query = r#"UPDATE email_digest SET last_comment_id = ?, sent_at = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, last_comment_id), (2, sent_at)][..])
+            .map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn approve_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let conn = self.write()?;
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+
+        let approved = match write_approval(&conn, comment_id) {
+            Ok(approved) => approved,
+            Err(e) => {
+                let _ = conn.execute("ROLLBACK;");
+                return Err(e);
+            }
+        };
+
+        conn.execute("COMMIT;")
+            .map_err(|e| format!("Could not commit transaction: {e}"))?;
+        Ok(approved)
+    }
+
+    fn reject_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let votes_query = r#"DELETE FROM votes WHERE comment_id IN
+                                 (SELECT id FROM comments WHERE id = ? AND moderated = false);"#;
+        let query = r#"DELETE FROM comments WHERE id = ? AND moderated = false;"#;
+
+        let conn = self.write()?;
+        for query in [votes_query, query] {
+            let mut statement = prepare(&conn, query)?;
+            statement.bind((1, comment_id)).map_err(bind_err)?;
+            step(&mut statement)?;
+        }
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn prune_pending(&self, before: i64) -> Result<i64, String> {
+        let stale = r#"SELECT id FROM comments
+                           WHERE moderated = false AND timestamp < ?
+                             AND NOT EXISTS (SELECT 1 FROM comments AS replies WHERE replies.parent = comments.id)"#;
+        let votes_query = format!("DELETE FROM votes WHERE comment_id IN ({stale});");
+        let query = format!("DELETE FROM comments WHERE id IN ({stale});");
+
+        let conn = self.write()?;
+        for query in [&votes_query, &query] {
+            let mut statement = prepare(&conn, query)?;
+            statement.bind((1, before)).map_err(bind_err)?;
+            step(&mut statement)?;
+        }
+
+        Ok(conn.change_count() as i64)
+    }
+
+    fn prune_orphaned_votes(&self) -> Result<i64, String> {
+        let query = r#"DELETE FROM votes
+                       WHERE comment_id NOT IN (SELECT id FROM comments)
+                          OR voter_id NOT IN (SELECT commenter_id FROM ids);"#;
+
+        let conn = self.write()?;
+        conn.execute(query)
+            .map_err(|e| format!("Could not execute statement: {e}"))?;
+
+        Ok(conn.change_count() as i64)
+    }
+
+    fn prune_unused_commenters(&self, before: i64) -> Result<i64, String> {
+        let query = format!("DELETE FROM ids WHERE created_at < ? AND {UNUSED_COMMENTER};");
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, &query)?;
+        statement.bind((1, before)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(conn.change_count() as i64)
+    }
+
+    fn add_flag(
+        &self,
+        comment_id: i64,
+        flagger_id: &str,
+        reason: &str,
+        created_at: i64,
+    ) -> Result<Option<i64>, String> {
+        let query = r#"INSERT INTO flags (comment_id, flagger_id, reason, created_at) VALUES (?, ?, ?, ?)
+                              ON CONFLICT(comment_id, flagger_id) DO NOTHING;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        statement.bind((2, flagger_id)).map_err(bind_err)?;
+        statement.bind((3, reason)).map_err(bind_err)?;
+        statement.bind((4, created_at)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        if conn.change_count() == 0 {
+            return Ok(None);
+        }
+
+        let query = r#"SELECT COUNT(*) AS flags FROM flags WHERE comment_id = ?;"#;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+
+        let flags = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<i64, _>("flags")
+                .map_err(read_err)?,
+            None => 0,
+        };
+
+        Ok(Some(flags))
+    }
+
+    fn hide_comment(&self, comment_id: i64) -> Result<bool, String> {
+        let conn = self.write()?;
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+
+        let hidden = match write_hide(&conn, comment_id) {
+            Ok(hidden) => hidden,
+            Err(e) => {
+                let _ = conn.execute("ROLLBACK;");
+                return Err(e);
+            }
+        };
+
+        conn.execute("COMMIT;")
+            .map_err(|e| format!("Could not commit transaction: {e}"))?;
+        Ok(hidden)
+    }
+
+    fn flagged_comments(&self) -> Result<Vec<FlaggedComment>, String> {
+        let query = r#"SELECT comments.id, comments.timestamp, comments.article, COALESCE(ids.name, '') AS poster_name,
+                              comments.comment, comments.moderated, flags.reason
+                              FROM flags
+                              JOIN comments ON flags.comment_id = comments.id
+                              LEFT JOIN ids ON comments.commenter_id = ids.commenter_id
+                              ORDER BY comments.id ASC, flags.id ASC;"#;
+
+        let conn = self.read()?;
+        let statement = prepare(&conn, query)?;
+
+        let mut rows = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            let article = String::from(row.try_read::<&str, _>("article").map_err(read_err)?);
+
+            rows.push((
+                FlaggedComment {
+                    id: row.try_read::<i64, _>("id").map_err(read_err)?,
+                    timestamp: row.try_read::<i64, _>("timestamp").map_err(read_err)?,
+                    article: base64_decode(article.clone()).unwrap_or(article),
+                    poster_name: String::from(
+                        row.try_read::<&str, _>("poster_name").map_err(read_err)?,
+                    ),
+                    comment: String::from(row.try_read::<&str, _>("comment").map_err(read_err)?),
+                    pending: row.try_read::<i64, _>("moderated").map_err(read_err)? == 0,
+                    reasons: vec![],
+                },
+                String::from(row.try_read::<&str, _>("reason").map_err(read_err)?),
+            ));
+        }
+
+        Ok(group_flags(rows))
+    }
+
+    fn dismiss_flags(&self, comment_id: i64) -> Result<bool, String> {
+        let query = r#"DELETE FROM flags WHERE comment_id = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn set_comment_pinned(&self, comment_id: i64, pinned: bool) -> Result<bool, String> {
+        let query =
+            r#"UPDATE comments SET pinned = ?, changed_at = strftime('%s', 'now') WHERE id = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, pinned as i64)).map_err(bind_err)?;
+        statement.bind((2, comment_id)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn get_article_lock(&self, article: &str) -> Result<ArticleLock, String> {
+        let query = r#"SELECT locked, voting_locked FROM articles WHERE article = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+        read_article_lock(statement)
+    }
+
+    fn get_comment_article_lock(&self, comment_id: i64) -> Result<ArticleLock, String> {
+        let query = r#"SELECT locked, voting_locked FROM articles
+                              JOIN comments ON comments.article = articles.article
+                              WHERE comments.id = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, comment_id)).map_err(bind_err)?;
+        read_article_lock(statement)
+    }
+
+    fn set_article_lock(&self, article: &str, lock: ArticleLock) -> Result<(), String> {
+        let query = r#"INSERT INTO articles (article, locked, voting_locked) VALUES (?1, ?2, ?3)
+                              ON CONFLICT(article) DO UPDATE SET locked = ?2, voting_locked = ?3;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+        statement.bind((2, lock.locked as i64)).map_err(bind_err)?;
+        statement
+            .bind((3, lock.voting_locked as i64))
+            .map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn get_article_opened_at(&self, article: &str) -> Result<Option<i64>, String> {
+        let query = r#"SELECT COALESCE((SELECT published_at FROM articles WHERE article = ?1),
+                                       (SELECT MIN(timestamp) FROM comments WHERE article = ?1 AND id > 0)) AS opened_at;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+
+        let opened_at = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<Option<i64>, _>("opened_at")
+                .map_err(read_err)?,
+            None => None,
+        };
+
+        Ok(opened_at)
+    }
+
+    fn is_article_registered(&self, article: &str) -> Result<bool, String> {
+        let query = r#"SELECT registered FROM articles WHERE article = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+
+        let registered = match statement.into_iter().next() {
+            Some(row) => {
+                row.map_err(read_err)?
+                    .try_read::<i64, _>("registered")
+                    .map_err(read_err)?
+                    != 0
+            }
+            None => false,
+        };
+
+        Ok(registered)
+    }
+
+    fn register_article(
+        &self,
+        article: &str,
+        published_at: Option<i64>,
+        now: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO articles (article, registered, published_at) VALUES (?1, true, COALESCE(?2, ?3))
+                              ON CONFLICT(article) DO UPDATE
+                              SET registered = true, published_at = COALESCE(?2, articles.published_at, ?3);"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+        match published_at {
+            Some(published_at) => statement.bind((2, published_at)).map_err(bind_err)?,
+            None => statement.bind((2, Null)).map_err(bind_err)?,
+        }
+        statement.bind((3, now)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn unregister_article(&self, article: &str) -> Result<bool, String> {
+        let query =
+            r#"UPDATE articles SET registered = false WHERE article = ? AND registered = true;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, article)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn get_article_title(&self, url: &str) -> Result<Option<(Option<String>, i64)>, String> {
+        let query = r#"SELECT title, fetched_at FROM article_titles WHERE url = ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, url)).map_err(bind_err)?;
+
+        let title = match statement.into_iter().next() {
+            Some(row) => {
+                let row = row.map_err(read_err)?;
+                Some((
+                    row.try_read::<Option<&str>, _>("title")
+                        .map_err(read_err)?
+                        .map(String::from),
+                    row.try_read::<i64, _>("fetched_at").map_err(read_err)?,
+                ))
+            }
+            None => None,
+        };
+
+        Ok(title)
+    }
+
+    fn set_article_title(
+        &self,
+        url: &str,
+        title: Option<&str>,
+        fetched_at: i64,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO article_titles (url, title, fetched_at) VALUES (?1, ?2, ?3)
+                              ON CONFLICT(url) DO UPDATE SET title = ?2, fetched_at = ?3;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, url)).map_err(bind_err)?;
+        match title {
+            Some(title) => statement.bind((2, title)).map_err(bind_err)?,
+            None => statement.bind((2, Null)).map_err(bind_err)?,
+        }
+        statement.bind((3, fetched_at)).map_err(bind_err)?;
+        step(&mut statement)
+    }
+
+    fn get_article_titles(&self, urls: &[String]) -> Result<HashMap<String, String>, String> {
+        let mut titles = HashMap::new();
+        if urls.is_empty() {
+            return Ok(titles);
+        }
+
+        let placeholders = vec!["?"; urls.len()].join(", ");
+        let query = format!(
+            r#"SELECT url, title FROM article_titles
+                      WHERE url IN ({placeholders}) AND title IS NOT NULL;"#
+        );
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, &query)?;
+        for (i, url) in urls.iter().enumerate() {
+            statement.bind((i + 1, &url[..])).map_err(bind_err)?;
+        }
+
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            titles.insert(
+                String::from(row.try_read::<&str, _>("url").map_err(read_err)?),
+                String::from(row.try_read::<&str, _>("title").map_err(read_err)?),
+            );
+        }
+
+        Ok(titles)
+    }
+
+    fn blocklist_entries(&self) -> Result<Vec<BlocklistEntry>, String> {
+        let query =
+            r#"SELECT id, kind, pattern, action, created_at FROM blocklist ORDER BY id ASC;"#;
+
+        let conn = self.read()?;
+        let statement = prepare(&conn, query)?;
+
+        let mut entries = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            entries.push(BlocklistEntry {
+                id: row.try_read::<i64, _>("id").map_err(read_err)?,
+                kind: String::from(row.try_read::<&str, _>("kind").map_err(read_err)?),
+                pattern: String::from(row.try_read::<&str, _>("pattern").map_err(read_err)?),
+                action: String::from(row.try_read::<&str, _>("action").map_err(read_err)?),
+                created_at: row.try_read::<i64, _>("created_at").map_err(read_err)?,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn add_blocklist_entry(
+        &self,
+        kind: &str,
+        pattern: &str,
+        action: &str,
+        created_at: i64,
+    ) -> Result<i64, String> {
+        let query = r#"INSERT INTO blocklist (kind, pattern, action, created_at) VALUES (?1, ?2, ?3, ?4)
+                              ON CONFLICT(kind, pattern) DO UPDATE SET action = ?3
+                              RETURNING id;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, kind), (2, pattern), (3, action)][..])
+            .map_err(bind_err)?;
+        statement.bind((4, created_at)).map_err(bind_err)?;
+
+        let id = match statement.into_iter().next() {
+            Some(row) => row
+                .map_err(read_err)?
+                .try_read::<i64, _>("id")
+                .map_err(read_err)?,
+            None => return Err(String::from("Could not add blocklist entry")),
+        };
+
+        Ok(id)
+    }
+
+    fn remove_blocklist_entry(&self, id: i64) -> Result<bool, String> {
+        let query = r#"DELETE FROM blocklist WHERE id = ?;"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, id)).map_err(bind_err)?;
+        step(&mut statement)?;
+
+        Ok(conn.change_count() > 0)
+    }
+
+    fn add_audit_entry(
+        &self,
+        at: i64,
+        actor: &str,
+        action: &str,
+        target: &str,
+        previous: Option<&str>,
+    ) -> Result<(), String> {
+        let query = r#"INSERT INTO audit_log (at, actor, action, target, previous) VALUES (?, ?, ?, ?, ?);"#;
+
+        let conn = self.write()?;
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, at)).map_err(bind_err)?;
+        statement
+            .bind(&[(2, actor), (3, action), (4, target)][..])
+            .map_err(bind_err)?;
+        match previous {
+            Some(previous) => statement.bind((5, previous)).map_err(bind_err)?,
+            None => statement.bind((5, Null)).map_err(bind_err)?,
+        }
+        step(&mut statement)
+    }
+
+    fn audit_log(&self, before: Option<i64>, limit: i64) -> Result<Vec<AuditEntry>, String> {
+        let query = r#"SELECT id, at, actor, action, target, previous FROM audit_log
+                              WHERE id < ? ORDER BY id DESC LIMIT ?;"#;
+
+        let conn = self.read()?;
+        let mut statement = prepare(&conn, query)?;
+        statement
+            .bind(&[(1, before.unwrap_or(i64::MAX)), (2, limit)][..])
+            .map_err(bind_err)?;
+
+        let mut entries = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            entries.push(AuditEntry {
+                id: row.try_read::<i64, _>("id").map_err(read_err)?,
+                at: row.try_read::<i64, _>("at").map_err(read_err)?,
+                actor: String::from(row.try_read::<&str, _>("actor").map_err(read_err)?),
+                action: String::from(row.try_read::<&str, _>("action").map_err(read_err)?),
+                target: String::from(row.try_read::<&str, _>("target").map_err(read_err)?),
+                previous: row
+                    .try_read::<Option<&str>, _>("previous")
+                    .map_err(read_err)?
+                    .map(String::from),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn checkpoint(&self) -> Result<(), String> {
+        self.write()?
+            .execute("PRAGMA wal_checkpoint(TRUNCATE);")
+            .map_err(|e| format!("Could not checkpoint database: {e}"))
+    }
+
+    fn optimize(&self) -> Result<(), String> {
+        self.write()?
+            .execute("VACUUM; ANALYZE;")
+            .map_err(|e| format!("Could not optimize database: {e}"))
+    }
+
+    fn full_scans(&self) -> Result<Vec<String>, String> {
+        let queries = [
+            ("get_comments", comments_query(CommentSort::Oldest)),
+            ("get_comment_changes", String::from(COMMENT_CHANGES_QUERY)),
+        ];
+
+        let conn = self.read()?;
+        let mut scans = vec![];
+        for (name, query) in queries {
+            let statement = prepare(&conn, &format!("EXPLAIN QUERY PLAN {query}"))?;
+            for row in statement.into_iter() {
+                let row = row.map_err(read_err)?;
+                let detail = row.try_read::<&str, _>("detail").map_err(read_err)?;
+                // Aliased tables are named by their alias, e.g. "SCAN v1". `id > 0` turns a read
+                // of every comment into a search of the rowid range, so that counts too.
+                let mut words = detail.split(' ');
+                let whole_table = match words.next() {
+                    Some("SCAN") => true,
+                    Some("SEARCH") => detail.contains("INTEGER PRIMARY KEY (rowid>?)"),
+                    _ => false,
+                };
+                let table = words.next();
+                if whole_table && matches!(table, Some("comments" | "votes" | "v1" | "v2")) {
+                    scans.push(format!("{name}: {detail}"));
+                }
+            }
+        }
+
+        Ok(scans)
+    }
+
+    fn backup(&self, dest: &str) -> Result<(), String> {
+        let conn = self.read()?;
+        let target =
+            sqlite::open(dest).map_err(|e| format!("Could not create backup {dest}: {e}"))?;
+
+        // Copying every page in a single step holds one read snapshot throughout, so writes made
+        // while the backup runs are left out rather than torn.
+        //
+        // SAFETY: both handles are open connections that outlive the backup object, which is
+        // always finished before they are dropped.
+        let (step, finish) = unsafe {
+            let backup = ffi::sqlite3_backup_init(
+                target.as_raw(),
+                c"main".as_ptr(),
+                conn.as_raw(),
+                c"main".as_ptr(),
+            );
+            if backup.is_null() {
+                return Err(format!(
+                    "Could not start backup: {}",
+                    error_message(&target)
+                ));
+            }
+            (
+                ffi::sqlite3_backup_step(backup, -1),
+                ffi::sqlite3_backup_finish(backup),
+            )
+        };
+
+        if step != ffi::SQLITE_DONE || finish != ffi::SQLITE_OK {
+            return Err(format!(
+                "Could not back up database: {}",
+                error_message(&target)
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn load_pow_state(&self) -> Result<PowState, String> {
+        let conn = self.read()?;
+        let mut pow = PowState::default();
+
+        let statement = prepare(
+            &conn,
+            r#"SELECT challenge, client_ip, key, issued_at_ms FROM pow_challenges;"#,
+        )?;
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            pow.challenges.push(StoredChallenge {
+                challenge: String::from(row.try_read::<&str, _>("challenge").map_err(read_err)?),
+                client_ip: String::from(row.try_read::<&str, _>("client_ip").map_err(read_err)?),
+                key: String::from(row.try_read::<&str, _>("key").map_err(read_err)?),
+                issued_at_ms: row.try_read::<i64, _>("issued_at_ms").map_err(read_err)?,
+            });
+        }
+
+        let statement = prepare(&conn, r#"SELECT client_ip, at_ms FROM pow_transactions;"#)?;
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            pow.transactions.push(StoredTransaction {
+                client_ip: String::from(row.try_read::<&str, _>("client_ip").map_err(read_err)?),
+                at_ms: row.try_read::<i64, _>("at_ms").map_err(read_err)?,
+            });
+        }
+
+        Ok(pow)
+    }
+
+    fn save_pow_state(&self, pow: &PowState) -> Result<(), String> {
+        let conn = self.write()?;
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+
+        if let Err(e) = write_pow_state(&conn, pow) {
+            let _ = conn.execute("ROLLBACK;");
+            return Err(e);
+        }
+
+        conn.execute("COMMIT;")
+            .map_err(|e| format!("Could not commit transaction: {e}"))
+    }
+
+    fn load_reputation(&self) -> Result<Vec<(String, Reputation)>, String> {
+        let conn = self.read()?;
+        let statement = prepare(
+            &conn,
+            r#"SELECT subject, approved, spam, pow_failures, flags FROM reputation;"#,
+        )?;
+
+        let mut entries = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            entries.push((
+                String::from(row.try_read::<&str, _>("subject").map_err(read_err)?),
+                Reputation {
+                    approved: row.try_read::<i64, _>("approved").map_err(read_err)?,
+                    spam: row.try_read::<i64, _>("spam").map_err(read_err)?,
+                    pow_failures: row.try_read::<i64, _>("pow_failures").map_err(read_err)?,
+                    flags: row.try_read::<i64, _>("flags").map_err(read_err)?,
+                },
+            ));
+        }
+
+        Ok(entries)
+    }
+
+    fn save_reputation(
+        &self,
+        entries: &[(String, Reputation)],
+        updated_at: i64,
+    ) -> Result<(), String> {
+        let conn = self.write()?;
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+
+        if let Err(e) = write_reputation(&conn, entries, updated_at) {
+            let _ = conn.execute("ROLLBACK;");
+            return Err(e);
+        }
+
+        conn.execute("COMMIT;")
+            .map_err(|e| format!("Could not commit transaction: {e}"))
+    }
+
+    fn export(
+        &self,
+        sink: &mut dyn FnMut(ExportRecord) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let conn = self.read()?;
+
+        // A read transaction keeps the three queries on the same snapshot while writers carry on.
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+        let res = write_export(&conn, sink);
+        let _ = conn.execute("COMMIT;");
+        res
+    }
+
+    fn export_commenter(&self, commenter_id: &str) -> Result<Option<CommenterExport>, String> {
+        let conn = self.read()?;
+
+        let query = r#"SELECT commenter_id, name, email, reply_notifications, verified, oauth_provider, oauth_subject
+                              FROM ids
+                              WHERE commenter_id = ?;"#;
+
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, commenter_id)).map_err(bind_err)?;
+        let commenter = match statement.into_iter().next() {
+            Some(row) => exported_commenter(&row.map_err(read_err)?)?,
+            None => return Ok(None),
+        };
+
+        let query = r#"SELECT id, commenter_id, timestamp, article, parent, moderated, comment, edited_at, pinned
+                              FROM comments
+                              WHERE commenter_id = ?
+                              ORDER BY id ASC;"#;
+
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, commenter_id)).map_err(bind_err)?;
+        let mut comments = vec![];
+        for row in statement.into_iter() {
+            comments.push(exported_comment(&row.map_err(read_err)?)?);
+        }
+
+        let query = r#"SELECT comment_id, voter_id, vote
+                              FROM votes
+                              WHERE voter_id = ?
+                              ORDER BY comment_id ASC;"#;
+
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, commenter_id)).map_err(bind_err)?;
+        let mut votes = vec![];
+        for row in statement.into_iter() {
+            votes.push(exported_vote(&row.map_err(read_err)?)?);
+        }
+
+        let query = r#"SELECT comment_id, reason, created_at
+                              FROM flags
+                              WHERE flagger_id = ?
+                              ORDER BY id ASC;"#;
+
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, commenter_id)).map_err(bind_err)?;
+        let mut flags = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            flags.push(ExportedFlag {
+                comment_id: row.try_read::<i64, _>("comment_id").map_err(read_err)?,
+                reason: String::from(row.try_read::<&str, _>("reason").map_err(read_err)?),
+                created_at: row.try_read::<i64, _>("created_at").map_err(read_err)?,
+            });
+        }
+
+        let query = r#"SELECT article, created_at
+                              FROM subscriptions
+                              WHERE commenter_id = ?
+                              ORDER BY id ASC;"#;
+
+        let mut statement = prepare(&conn, query)?;
+        statement.bind((1, commenter_id)).map_err(bind_err)?;
+        let mut subscriptions = vec![];
+        for row in statement.into_iter() {
+            let row = row.map_err(read_err)?;
+            let article = String::from(row.try_read::<&str, _>("article").map_err(read_err)?);
+            subscriptions.push(ExportedSubscription {
+                article: base64_decode(article.clone()).unwrap_or(article),
+                created_at: row.try_read::<i64, _>("created_at").map_err(read_err)?,
+            });
+        }
+
+        Ok(Some(CommenterExport {
+            commenter,
+            comments,
+            votes,
+            flags,
+            subscriptions,
+        }))
+    }
+
+    fn anonymize_commenter(
+        &self,
+        commenter_id: &str,
+        replacement_id: &str,
+        mode: AnonymizeMode,
+    ) -> Result<bool, String> {
+        let conn = self.write()?;
+        conn.execute("BEGIN;")
+            .map_err(|e| format!("Could not begin transaction: {e}"))?;
+
+        match write_anonymize(&conn, commenter_id, replacement_id, mode) {
+            Ok(found) => {
+                conn.execute("COMMIT;")
+                    .map_err(|e| format!("Could not commit transaction: {e}"))?;
+                Ok(found)
+            }
+            Err(e) => {
+                let _ = conn.execute("ROLLBACK;");
+                Err(e)
+            }
+        }
+    }
+}
+
+fn write_export(
+    conn: &sqlite::Connection,
+    sink: &mut dyn FnMut(ExportRecord) -> Result<(), String>,
+) -> Result<(), String> {
+    let query = r#"SELECT commenter_id, name, email, reply_notifications, verified, oauth_provider, oauth_subject
+                          FROM ids
+                          ORDER BY commenter_id ASC;"#;
+
+    for row in prepare(conn, query)?.into_iter() {
+        let row = row.map_err(read_err)?;
+        sink(ExportRecord::Commenter(exported_commenter(&row)?))?;
+    }
+
+    let query = r#"SELECT id, commenter_id, timestamp, article, parent, moderated, comment, edited_at, pinned
+                          FROM comments
+                          ORDER BY id ASC;"#;
+
+    for row in prepare(conn, query)?.into_iter() {
+        let row = row.map_err(read_err)?;
+        sink(ExportRecord::Comment(exported_comment(&row)?))?;
+    }
+
+    let query = r#"SELECT comment_id, voter_id, vote
+                          FROM votes
+                          ORDER BY comment_id ASC, voter_id ASC;"#;
+
+    for row in prepare(conn, query)?.into_iter() {
+        let row = row.map_err(read_err)?;
+        sink(ExportRecord::Vote(exported_vote(&row)?))?;
+    }
+
+    Ok(())
+}
+
+fn read_article_lock(statement: sqlite::Statement) -> Result<ArticleLock, String> {
+    match statement.into_iter().next() {
+        Some(row) => {
+            let row = row.map_err(read_err)?;
+            Ok(ArticleLock {
+                locked: row.try_read::<i64, _>("locked").map_err(read_err)? != 0,
+                voting_locked: row.try_read::<i64, _>("voting_locked").map_err(read_err)? != 0,
+            })
+        }
+        None => Ok(ArticleLock::default()),
+    }
+}
+
+fn write_anonymize(
+    conn: &sqlite::Connection,
+    commenter_id: &str,
+    replacement_id: &str,
+    mode: AnonymizeMode,
+) -> Result<bool, String> {
+    let run = |query: &str, params: &[&str]| -> Result<(), String> {
+        let mut statement = prepare(conn, query)?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind((i + 1, *param)).map_err(bind_err)?;
+        }
+        step(&mut statement)
+    };
+
+    let mut statement = prepare(conn, r#"SELECT email FROM ids WHERE commenter_id = ?;"#)?;
+    statement.bind((1, commenter_id)).map_err(bind_err)?;
+    let email = match statement.into_iter().next() {
+        Some(row) => String::from(
+            row.map_err(read_err)?
+                .try_read::<Option<&str>, _>("email")
+                .map_err(read_err)?
+                .unwrap_or(""),
+        ),
+        None => return Ok(false),
+    };
+
+    if !email.is_empty() {
+        run(r#"DELETE FROM email_queue WHERE recipient = ?;"#, &[&email])?;
+    }
+
+    run(
+        r#"INSERT INTO ids (commenter_id, name, email, reply_notifications) VALUES (?, ?, '', false);"#,
+        &[replacement_id, ANONYMIZED_NAME],
+    )?;
+    run(r#"DELETE FROM votes WHERE voter_id = ?;"#, &[commenter_id])?;
+
+    if let AnonymizeMode::Delete = mode {
+        run(
+            r#"DELETE FROM votes WHERE comment_id IN
+                   (SELECT id FROM comments WHERE commenter_id = ?
+                    AND id NOT IN (SELECT parent FROM comments WHERE parent IS NOT NULL));"#,
+            &[commenter_id],
+        )?;
+        run(
+            r#"DELETE FROM comments WHERE commenter_id = ?
+                   AND id NOT IN (SELECT parent FROM comments WHERE parent IS NOT NULL);"#,
+            &[commenter_id],
+        )?;
+        // What's left are comments kept for their replies; their images go with their text.
+        run(
+            r#"DELETE FROM attachments WHERE comment_id IN (SELECT id FROM comments WHERE commenter_id = ?);"#,
+            &[commenter_id],
+        )?;
+        run(
+            r#"UPDATE comments SET comment = ?, changed_at = strftime('%s', 'now') WHERE commenter_id = ?;"#,
+            &[DELETED_COMMENT, commenter_id],
+        )?;
+    }
+
+    run(
+        r#"UPDATE comments SET commenter_id = ? WHERE commenter_id = ?;"#,
+        &[replacement_id, commenter_id],
+    )?;
+    // The search index triggers only follow changes to the comment text.
+    run(
+        r#"UPDATE comments_fts SET poster_name = ?
+               WHERE rowid IN (SELECT id FROM comments WHERE commenter_id = ?);"#,
+        &[ANONYMIZED_NAME, replacement_id],
+    )?;
+    run(
+        r#"DELETE FROM ids WHERE commenter_id = ?;"#,
+        &[commenter_id],
+    )?;
+
+    Ok(true)
+}
+
+fn read_comment(row: &sqlite::Row) -> Result<Comment, String> {
+    Ok(Comment {
+        id: row.try_read::<i64, _>("id").map_err(read_err)?,
+        timestamp: row.try_read::<i64, _>("timestamp").map_err(read_err)?,
+        parent: row
+            .try_read::<Option<i64>, _>("parent")
+            .map_err(read_err)?
+            .unwrap_or(0),
+        poster_name: String::from(row.try_read::<&str, _>("poster_name").map_err(read_err)?),
+        comment: String::from(row.try_read::<&str, _>("comment").map_err(read_err)?),
+        votes: row.try_read::<i64, _>("votes").map_err(read_err)?,
+        myvote: row.try_read::<i64, _>("myvote").map_err(read_err)?,
+        edited_at: row
+            .try_read::<Option<i64>, _>("edited_at")
+            .map_err(read_err)?,
+        verified: row
+            .try_read::<Option<i64>, _>("verified")
+            .map_err(read_err)?
+            .unwrap_or(0)
+            != 0,
+        pinned: row.try_read::<i64, _>("pinned").map_err(read_err)? != 0,
+        is_author: row.try_read::<i64, _>("author").map_err(read_err)? != 0,
+        collapsed: false,
+        avatar_url: None,
+        comment_html: None,
+        poster_email: String::from(
+            row.try_read::<Option<&str>, _>("poster_email")
+                .map_err(read_err)?
+                .unwrap_or(""),
+        ),
+        attachments: vec![],
+        attachment_names: row
+            .try_read::<Option<&str>, _>("attachments")
+            .map_err(read_err)?
+            .map(|names| names.lines().map(String::from).collect())
+            .unwrap_or_default(),
+        children: None,
+    })
+}
+
+/// The columns [`read_recent_comments`] expects, selected from `comments` joined with `ids`.
+const RECENT_COMMENT_COLUMNS: &str = "id, timestamp, article, COALESCE(ids.name, '') AS poster_name, comment, moderated, country,
+                                      (SELECT COALESCE(SUM(vote), 0) FROM votes WHERE votes.comment_id = comments.id) AS votes";
+
+fn read_recent_comments(statement: sqlite::Statement) -> Result<Vec<RecentComment>, String> {
+    let mut comments = vec![];
+    for row in statement.into_iter() {
+        let row = row.map_err(read_err)?;
+        let article = String::from(row.try_read::<&str, _>("article").map_err(read_err)?);
+
+        comments.push(RecentComment {
+            id: row.try_read::<i64, _>("id").map_err(read_err)?,
+            timestamp: row.try_read::<i64, _>("timestamp").map_err(read_err)?,
+            article: base64_decode(article.clone()).unwrap_or(article),
+            poster_name: String::from(row.try_read::<&str, _>("poster_name").map_err(read_err)?),
+            comment: String::from(row.try_read::<&str, _>("comment").map_err(read_err)?),
+            pending: row.try_read::<i64, _>("moderated").map_err(read_err)? == 0,
+            votes: row.try_read::<i64, _>("votes").map_err(read_err)?,
+            country: row
+                .try_read::<Option<&str>, _>("country")
+                .map_err(read_err)?
+                .map(String::from),
+        });
+    }
+
+    Ok(comments)
+}
+
+fn write_approval(conn: &sqlite::Connection, comment_id: i64) -> Result<bool, String> {
+    let query = r#"UPDATE comments SET moderated = true, changed_at = strftime('%s', 'now') WHERE id = ? AND moderated = false;"#;
+    let mut statement = prepare(conn, query)?;
+    statement.bind((1, comment_id)).map_err(bind_err)?;
+    step(&mut statement)?;
+
+    if conn.change_count() == 0 {
+        return Ok(false);
+    }
+
+    let mut statement = prepare(conn, APPROVED_COUNT_QUERY)?;
+    statement.bind((1, comment_id)).map_err(bind_err)?;
+    step(&mut statement)?;
+
+    let mut statement = prepare(conn, r#"DELETE FROM flags WHERE comment_id = ?;"#)?;
+    statement.bind((1, comment_id)).map_err(bind_err)?;
+    step(&mut statement)?;
+
+    Ok(true)
+}
+
+fn write_hide(conn: &sqlite::Connection, comment_id: i64) -> Result<bool, String> {
+    let query = r#"UPDATE comments SET moderated = false WHERE id = ? AND moderated = true;"#;
+    let mut statement = prepare(conn, query)?;
+    statement.bind((1, comment_id)).map_err(bind_err)?;
+    step(&mut statement)?;
+
+    if conn.change_count() == 0 {
+        return Ok(false);
+    }
+
+    let mut statement = prepare(conn, UNAPPROVED_COUNT_QUERY)?;
+    statement.bind((1, comment_id)).map_err(bind_err)?;
+    step(&mut statement)?;
+
+    Ok(true)
+}
+
+fn exported_commenter(row: &sqlite::Row) -> Result<ExportedCommenter, String> {
+    Ok(ExportedCommenter {
+        commenter_id: String::from(row.try_read::<&str, _>("commenter_id").map_err(read_err)?),
+        name: String::from(
+            row.try_read::<Option<&str>, _>("name")
+                .map_err(read_err)?
+                .unwrap_or(""),
+        ),
+        email: String::from(
+            row.try_read::<Option<&str>, _>("email")
+                .map_err(read_err)?
+                .unwrap_or(""),
+        ),
+        reply_notifications: row
+            .try_read::<i64, _>("reply_notifications")
+            .map_err(read_err)?
+            != 0,
+        verified: row.try_read::<i64, _>("verified").map_err(read_err)? != 0,
+        oauth_provider: row
+            .try_read::<Option<&str>, _>("oauth_provider")
+            .map_err(read_err)?
+            .map(String::from),
+        oauth_subject: row
+            .try_read::<Option<&str>, _>("oauth_subject")
+            .map_err(read_err)?
+            .map(String::from),
+    })
+}
+
+fn exported_comment(row: &sqlite::Row) -> Result<ExportedComment, String> {
+    let article = String::from(row.try_read::<&str, _>("article").map_err(read_err)?);
+
+    Ok(ExportedComment {
+        id: row.try_read::<i64, _>("id").map_err(read_err)?,
+        commenter_id: String::from(row.try_read::<&str, _>("commenter_id").map_err(read_err)?),
+        timestamp: row.try_read::<i64, _>("timestamp").map_err(read_err)?,
+        article: base64_decode(article.clone()).unwrap_or(article),
+        parent: row.try_read::<Option<i64>, _>("parent").map_err(read_err)?,
+        moderated: row
+            .try_read::<Option<i64>, _>("moderated")
+            .map_err(read_err)?
+            .unwrap_or(0)
+            != 0,
+        comment: String::from(row.try_read::<&str, _>("comment").map_err(read_err)?),
+        edited_at: row
+            .try_read::<Option<i64>, _>("edited_at")
+            .map_err(read_err)?,
+        pinned: row.try_read::<i64, _>("pinned").map_err(read_err)? != 0,
+    })
+}
+
+fn exported_vote(row: &sqlite::Row) -> Result<ExportedVote, String> {
+    Ok(ExportedVote {
+        comment_id: row.try_read::<i64, _>("comment_id").map_err(read_err)?,
+        voter_id: String::from(row.try_read::<&str, _>("voter_id").map_err(read_err)?),
+        vote: row.try_read::<i64, _>("vote").map_err(read_err)?,
+    })
+}
+
+/// Quote each word of a reader's query so FTS5 treats it as plain text rather than query syntax.
+/// Every word must match.
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn write_pow_state(conn: &sqlite::Connection, pow: &PowState) -> Result<(), String> {
+    conn.execute("DELETE FROM pow_challenges; DELETE FROM pow_transactions;")
+        .map_err(|e| format!("Could not clear proof-of-work state: {e}"))?;
+
+    let mut statement = prepare(
+        conn,
+        r#"INSERT INTO pow_challenges (challenge, client_ip, key, issued_at_ms) VALUES (?, ?, ?, ?);"#,
+    )?;
+    for challenge in pow.challenges.iter() {
+        statement.reset().map_err(bind_err)?;
+        statement
+            .bind(
+                &[
+                    (1, &challenge.challenge[..]),
+                    (2, &challenge.client_ip[..]),
+                    (3, &challenge.key[..]),
+                ][..],
+            )
+            .map_err(bind_err)?;
+        statement
+            .bind((4, challenge.issued_at_ms))
+            .map_err(bind_err)?;
+        step(&mut statement)?;
+    }
+
+    let mut statement = prepare(
+        conn,
+        r#"INSERT INTO pow_transactions (client_ip, at_ms) VALUES (?, ?);"#,
+    )?;
+    for tx in pow.transactions.iter() {
+        statement.reset().map_err(bind_err)?;
+        statement.bind((1, &tx.client_ip[..])).map_err(bind_err)?;
+        statement.bind((2, tx.at_ms)).map_err(bind_err)?;
+        step(&mut statement)?;
+    }
+
+    Ok(())
+}
+
+fn write_reputation(
+    conn: &sqlite::Connection,
+    entries: &[(String, Reputation)],
+    updated_at: i64,
+) -> Result<(), String> {
+    let mut statement = prepare(
+        conn,
+        r#"INSERT INTO reputation (subject, approved, spam, pow_failures, flags, updated_at) VALUES (?, ?, ?, ?, ?, ?)
+                  ON CONFLICT(subject) DO UPDATE SET approved = excluded.approved, spam = excluded.spam,
+                  pow_failures = excluded.pow_failures, flags = excluded.flags, updated_at = excluded.updated_at;"#,
+    )?;
+    for (subject, reputation) in entries.iter() {
+        statement.reset().map_err(bind_err)?;
+        statement.bind((1, &subject[..])).map_err(bind_err)?;
+        statement
+            .bind(
+                &[
+                    (2, reputation.approved),
+                    (3, reputation.spam),
+                    (4, reputation.pow_failures),
+                    (5, reputation.flags),
+                    (6, updated_at),
+                ][..],
+            )
+            .map_err(bind_err)?;
+        step(&mut statement)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,240 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Checks of the email address a commenter gives at `/id/`: whether one is required
+//! (`require_email`), whether it looks like an address at all, and, with `email_check_mx`,
+//! whether its domain accepts mail. A DNS lookup that fails or times out lets the address
+//! through, so an unreachable resolver never blocks commenting.
+//!
+//! With `block_disposable_emails`, addresses at throwaway providers are refused, or their comments
+//! are always held for moderation, following `disposable_email_action`.
+
+use actix_web::web;
+use hickory_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+use std::collections::HashSet;
+use std::fs;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::config::{ConfigFile, DisposableEmailAction};
+use crate::error::Error;
+use crate::AppState;
+
+/// The list used unless `disposable_email_domains_path` names another.
+const DISPOSABLE_DOMAINS: &str = include_str!("disposable_domains.txt");
+
+/// The longest to wait for the domain's MX or address records.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Limits from RFC 5321.
+const MAX_ADDRESS_LEN: usize = 254;
+const MAX_LOCAL_LEN: usize = 64;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Domains of throwaway email providers.
+pub struct DisposableDomains {
+    domains: HashSet<String>,
+}
+
+impl DisposableDomains {
+    pub fn new_from_config(config: &ConfigFile) -> Result<Self, String> {
+        if !config.block_disposable_emails {
+            return Ok(DisposableDomains {
+                domains: HashSet::new(),
+            });
+        }
+
+        let contents = match &config.disposable_email_domains_path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read disposable email domains '{path}': {e}"))?,
+            None => String::from(DISPOSABLE_DOMAINS),
+        };
+
+        let domains = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|domain| domain.trim_end_matches('.').to_lowercase())
+            .collect();
+
+        Ok(DisposableDomains { domains })
+    }
+
+    /// Whether the address is at a listed domain, or a subdomain of one.
+    pub fn contains(&self, email: &str) -> bool {
+        if self.domains.is_empty() {
+            return false;
+        }
+
+        let Some((_, domain)) = email.rsplit_once('@') else {
+            return false;
+        };
+
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let mut rest = domain.as_str();
+        loop {
+            if self.domains.contains(rest) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Whether a commenter's comments must be held because of their email address.
+pub fn holds(state: &AppState, email: &str) -> bool {
+    matches!(
+        state.config.disposable_email_action,
+        DisposableEmailAction::Hold
+    ) && state.disposable_domains.contains(email)
+}
+
+/// Refuse an email address the configured policy doesn't allow. An empty address is fine unless
+/// `require_email` is set.
+pub async fn check(state: &web::Data<AppState>, email: &str) -> Result<(), Error> {
+    let config = &state.config;
+
+    if email.is_empty() {
+        return match config.require_email {
+            true => Err(Error::Unprocessable(String::from(
+                "An email address is required",
+            ))),
+            false => Ok(()),
+        };
+    }
+
+    let Some((_, domain)) = split(email) else {
+        return Err(Error::Unprocessable(String::from("Invalid email address")));
+    };
+
+    if matches!(
+        config.disposable_email_action,
+        DisposableEmailAction::Reject
+    ) && state.disposable_domains.contains(email)
+    {
+        info!(domain, "Refused disposable email address");
+        return Err(Error::Unprocessable(String::from(
+            "Disposable email addresses are not accepted",
+        )));
+    }
+
+    if config.email_check_mx && !accepts_mail(domain).await {
+        return Err(Error::Unprocessable(format!(
+            "{domain} does not accept email"
+        )));
+    }
+
+    Ok(())
+}
+
+/// The local part and domain of a syntactically valid address: a dot-atom local part, as nearly
+/// every real address has, and a domain of at least two labels. Quoted local parts and IP address
+/// literals aren't accepted.
+pub fn split(email: &str) -> Option<(&str, &str)> {
+    if email.len() > MAX_ADDRESS_LEN {
+        return None;
+    }
+
+    let (local, domain) = email.rsplit_once('@')?;
+
+    let local_ok = !local.is_empty()
+        && local.len() <= MAX_LOCAL_LEN
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c))
+        });
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LEN
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+        && !labels[labels.len() - 1].chars().all(|c| c.is_ascii_digit());
+
+    (local_ok && domain_ok).then_some((local, domain))
+}
+
+fn resolver() -> Option<&'static TokioAsyncResolver> {
+    static RESOLVER: OnceLock<Option<TokioAsyncResolver>> = OnceLock::new();
+
+    RESOLVER
+        .get_or_init(|| match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => Some(resolver),
+            Err(e) => {
+                warn!("Unable to read the system DNS configuration; not checking MX records: {e}");
+                None
+            }
+        })
+        .as_ref()
+}
+
+/// Whether `domain` has a mail server: an MX record other than the "null MX" of RFC 7505, or
+/// failing any MX records, an address record, which mail is delivered to instead.
+async fn accepts_mail(domain: &str) -> bool {
+    let Some(resolver) = resolver() else {
+        return true;
+    };
+
+    // A trailing dot keeps the resolver from trying the system's search domains.
+    let fqdn = format!("{domain}.");
+
+    match timeout(LOOKUP_TIMEOUT, resolver.mx_lookup(fqdn.as_str())).await {
+        Ok(Ok(mx)) => {
+            return mx.iter().any(|record| !record.exchange().is_root());
+        }
+        Ok(Err(e)) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+            debug!(domain, "No MX records; trying address records");
+        }
+        Ok(Err(e)) => {
+            warn!(domain, "MX lookup failed: {e}");
+            return true;
+        }
+        Err(_) => {
+            warn!(domain, "MX lookup timed out");
+            return true;
+        }
+    }
+
+    match timeout(LOOKUP_TIMEOUT, resolver.lookup_ip(fqdn.as_str())).await {
+        Ok(Ok(ips)) => ips.iter().next().is_some(),
+        Ok(Err(e)) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => false,
+        Ok(Err(e)) => {
+            warn!(domain, "Address lookup failed: {e}");
+            true
+        }
+        Err(_) => {
+            warn!(domain, "Address lookup timed out");
+            true
+        }
+    }
+}
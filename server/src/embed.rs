@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The embeddable widget, for sites that aren't built with the Hugo partial. A page only needs
+//!
+//! ```html
+//! <script src="https://example.com/tinycomments/embed.js" async></script>
+//! ```
+//!
+//! which serves a small loader, cached briefly. The loader pulls in the widget and its stylesheet
+//! under URLs carrying a hash of their contents, which are cached for good, so upgrading the
+//! server reaches every page within minutes without readers re-downloading an unchanged widget.
+
+use actix_web::{get, http::header, web, HttpResponse};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+use crate::AppState;
+
+const WIDGET_JS: &str = include_str!("../../assets/js/tinycomments.js");
+const WIDGET_CSS: &str = include_str!("../../assets/css/tinycomments.css");
+
+/// For the loader and for requests naming an outdated version.
+const SHORT_CACHE: &str = "public, max-age=300";
+const IMMUTABLE_CACHE: &str = "public, max-age=31536000, immutable";
+
+/// `BASE` is the server's public URL, or null to work it out from the loader's own URL.
+const LOADER: &str = r#"(function() {
+    var script = document.currentScript;
+    var base = BASE || script.src.replace(/\/embed\.js(\?.*)?$/, '');
+
+    if (!document.getElementById('tinycomments') && !document.getElementById('comments')) {
+        var container = document.createElement('div');
+        container.id = 'tinycomments';
+        script.after(container);
+    }
+
+    var css = document.createElement('link');
+    css.rel = 'stylesheet';
+    css.href = base + '/embed.css?v=CSS_VERSION';
+    document.head.appendChild(css);
+
+    var widget = document.createElement('script');
+    widget.src = base + '/embed.js?v=JS_VERSION';
+    widget.dataset.path = base;
+    document.head.appendChild(widget);
+})();
+"#;
+
+#[derive(Deserialize)]
+pub struct EmbedQuery {
+    /// The content hash from the loader.
+    v: Option<String>,
+}
+
+/// A short hash of `contents`, to version its URL.
+fn version(contents: &str) -> String {
+    hex::encode(&Sha256::digest(contents)[..8])
+}
+
+fn js_version() -> &'static str {
+    static VERSION: OnceLock<String> = OnceLock::new();
+    VERSION.get_or_init(|| version(WIDGET_JS))
+}
+
+fn css_version() -> &'static str {
+    static VERSION: OnceLock<String> = OnceLock::new();
+    VERSION.get_or_init(|| version(WIDGET_CSS))
+}
+
+/// The loader, or the widget itself when a version is given.
+#[get("/embed.js")]
+async fn script(query: web::Query<EmbedQuery>, state: web::Data<AppState>) -> HttpResponse {
+    let Some(requested) = &query.v else {
+        let base = match &state.config.public_url {
+            Some(url) => serde_json::to_string(url.trim_end_matches('/'))
+                .unwrap_or_else(|_| String::from("null")),
+            None => String::from("null"),
+        };
+
+        let loader = LOADER
+            .replace("BASE", &base)
+            .replace("CSS_VERSION", css_version())
+            .replace("JS_VERSION", js_version());
+        return asset(loader, "text/javascript; charset=utf-8", SHORT_CACHE);
+    };
+
+    asset(
+        String::from(WIDGET_JS),
+        "text/javascript; charset=utf-8",
+        cache_for(requested, js_version()),
+    )
+}
+
+#[get("/embed.css")]
+async fn stylesheet(query: web::Query<EmbedQuery>) -> HttpResponse {
+    let cache = match &query.v {
+        Some(requested) => cache_for(requested, css_version()),
+        None => SHORT_CACHE,
+    };
+
+    asset(String::from(WIDGET_CSS), "text/css; charset=utf-8", cache)
+}
+
+/// Only the current version may be cached for good: a page still asking for an old one gets the
+/// current contents, which must not stick under the old URL.
+fn cache_for(requested: &str, current: &str) -> &'static str {
+    if requested == current {
+        IMMUTABLE_CACHE
+    } else {
+        SHORT_CACHE
+    }
+}
+
+fn asset(body: String, content_type: &str, cache: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, content_type))
+        .insert_header((header::CACHE_CONTROL, cache))
+        .body(body)
+}
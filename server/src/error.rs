@@ -0,0 +1,236 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! The error type handlers return. Each variant maps to an HTTP status, and goes out with the
+//! same JSON body shape as every other response: the status number in `code` and a description in
+//! `status`.
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    HttpMessage, HttpResponse, ResponseError,
+};
+use futures_util::FutureExt;
+use std::{fmt, panic::AssertUnwindSafe};
+use tinycomments_types::ErrorResponse;
+use tracing::error;
+
+use crate::logging::RequestId;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request is malformed or incomplete.
+    BadRequest(String),
+    /// The client must solve this proof-of-work challenge and send the request again.
+    ChallengeRequired {
+        challenge: String,
+        key: String,
+    },
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    TooLarge(String),
+    /// The request is well-formed but its content isn't acceptable, e.g. a comment that is too
+    /// short.
+    Unprocessable(String),
+    /// The article or comment is locked.
+    Locked(String),
+    /// A query against the storage backend failed.
+    Database(String),
+    /// Anything else that went wrong on the server.
+    Internal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadRequest(status)
+            | Error::Forbidden(status)
+            | Error::NotFound(status)
+            | Error::Conflict(status)
+            | Error::TooLarge(status)
+            | Error::Unprocessable(status)
+            | Error::Locked(status)
+            | Error::Internal(status) => f.write_str(status),
+            Error::ChallengeRequired { .. } => Ok(()),
+            Error::Database(e) => write!(f, "DB Error: {e}"),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::ChallengeRequired { .. } => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Locked(_) => StatusCode::LOCKED,
+            Error::Database(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.body(None))
+    }
+}
+
+impl Error {
+    fn body(&self, request_id: Option<String>) -> ErrorResponse {
+        let (challenge, key) = match self {
+            Error::ChallengeRequired { challenge, key } => {
+                (Some(challenge.clone()), Some(key.clone()))
+            }
+            _ => (None, None),
+        };
+
+        ErrorResponse {
+            code: self.status_code().as_u16(),
+            status: self.to_string(),
+            challenge,
+            key,
+            request_id,
+        }
+    }
+}
+
+/// Send every error as JSON with the request's id, so the widget can always parse a response.
+/// This covers panics, which become a 500, and errors raised outside the handlers, like a form
+/// that doesn't deserialize or a path with no route, which actix answers in plain text or with an
+/// empty body. Error pages rendered as HTML on purpose, like those of the no-JavaScript view, are
+/// left alone.
+pub async fn json_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+
+    let res = match AssertUnwindSafe(next.call(req)).catch_unwind().await {
+        Ok(res) => res?,
+        Err(_) => {
+            error!("Handler panicked");
+            return Err(Panicked(request_id).into());
+        }
+    };
+
+    if !res.status().is_client_error() && !res.status().is_server_error() {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let body = match res.response().error() {
+        Some(e) => match e.as_error::<Error>() {
+            Some(e) => e.body(request_id),
+            None if is_plain(&res) => ErrorResponse {
+                code: res.status().as_u16(),
+                status: e.to_string(),
+                challenge: None,
+                key: None,
+                request_id,
+            },
+            None => return Ok(res.map_into_boxed_body()),
+        },
+        None if is_plain(&res) => ErrorResponse {
+            code: res.status().as_u16(),
+            status: String::from(res.status().canonical_reason().unwrap_or("Error")),
+            challenge: None,
+            key: None,
+            request_id,
+        },
+        None => return Ok(res.map_into_boxed_body()),
+    };
+
+    let Ok(body) = serde_json::to_string(&body) else {
+        return Ok(res.map_into_boxed_body());
+    };
+
+    Ok(res.map_body(|head, _| {
+        head.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        BoxBody::new(body)
+    }))
+}
+
+/// A handler panicked. The request went down with it, so the response is built from this error
+/// instead.
+#[derive(Debug)]
+struct Panicked(Option<String>);
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Internal server error")
+    }
+}
+
+impl ResponseError for Panicked {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::InternalServerError().json(ErrorResponse {
+            code: 500,
+            status: self.to_string(),
+            challenge: None,
+            key: None,
+            request_id: self.0.clone(),
+        })
+    }
+}
+
+/// Whether a response is one of actix's own: plain text, or no body at all.
+fn is_plain<B>(res: &ServiceResponse<B>) -> bool {
+    match res.headers().get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type
+            .to_str()
+            .is_ok_and(|content_type| content_type.starts_with("text/plain")),
+        None => true,
+    }
+}
+
+/// Send handler errors with HTTP status 200, leaving the real status only in the body's `code`
+/// field, for clients written before tinycomments used HTTP statuses. Enabled by
+/// `legacy_status_codes`.
+pub async fn legacy_status(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut res = next.call(req).await?;
+
+    if res
+        .response()
+        .error()
+        .is_some_and(|e| e.as_error::<Error>().is_some())
+    {
+        *res.response_mut().status_mut() = StatusCode::OK;
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,249 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Logical backups of the whole dataset, either streamed from `/admin/export/` or written by the
+//! `tinycomments export` subcommand.
+
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::auth::require_admin;
+use crate::config::ConfigFile;
+use crate::db::{self, ExportRecord};
+use crate::error::Error;
+use crate::{audit, migrations, AppState};
+
+/// Bytes of encoded rows to collect before handing them to the response body.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A single object holding `commenters`, `comments`, and `votes` arrays.
+    #[default]
+    Json,
+    /// One object per line, each tagged with its `type`.
+    Ndjson,
+}
+
+impl Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportRequest {
+    #[serde(default)]
+    format: Format,
+}
+
+/// Sections of the JSON format, in the order [`db::Storage::export`] produces them.
+const SECTIONS: [&str; 3] = ["commenters", "comments", "votes"];
+
+/// Incrementally encodes export rows, so the full dataset never has to be held in memory.
+struct Encoder {
+    format: Format,
+    buf: Vec<u8>,
+    /// The JSON section currently open, and whether it has any rows yet.
+    section: Option<(usize, bool)>,
+}
+
+impl Encoder {
+    fn new(format: Format) -> Self {
+        Encoder {
+            format,
+            buf: vec![],
+            section: None,
+        }
+    }
+
+    fn record(&mut self, record: &ExportRecord) -> Result<(), String> {
+        let res = match self.format {
+            Format::Ndjson => serde_json::to_writer(&mut self.buf, record).map(|_| {
+                self.buf.push(b'\n');
+            }),
+            Format::Json => {
+                let section = match record {
+                    ExportRecord::Commenter(_) => 0,
+                    ExportRecord::Comment(_) => 1,
+                    ExportRecord::Vote(_) => 2,
+                };
+                self.open_section(section);
+
+                match record {
+                    ExportRecord::Commenter(row) => serde_json::to_writer(&mut self.buf, row),
+                    ExportRecord::Comment(row) => serde_json::to_writer(&mut self.buf, row),
+                    ExportRecord::Vote(row) => serde_json::to_writer(&mut self.buf, row),
+                }
+            }
+        };
+
+        res.map_err(|e| format!("Unable to encode export row: {e}"))
+    }
+
+    /// Close any sections before `section`, including empty ones, and start a new row in it.
+    fn open_section(&mut self, section: usize) {
+        loop {
+            match self.section {
+                Some((current, ref mut has_rows)) if current == section => {
+                    if *has_rows {
+                        self.buf.push(b',');
+                    }
+                    *has_rows = true;
+                    return;
+                }
+                Some((current, _)) => {
+                    self.buf.extend_from_slice(b"],");
+                    self.start_section(current + 1);
+                }
+                None => {
+                    self.buf.push(b'{');
+                    self.start_section(0);
+                }
+            }
+        }
+    }
+
+    fn start_section(&mut self, section: usize) {
+        self.buf
+            .extend_from_slice(format!("\"{}\":[", SECTIONS[section]).as_bytes());
+        self.section = Some((section, false));
+    }
+
+    fn finish(&mut self) {
+        if let Format::Json = self.format {
+            self.open_section(SECTIONS.len() - 1);
+            // open_section() assumes a row follows; back out the separator if one was added.
+            if self.buf.last() == Some(&b',') {
+                self.buf.pop();
+            }
+            self.buf.extend_from_slice(b"]}\n");
+        }
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// Stream every commenter, comment, and vote. Rows are read and encoded on the blocking thread
+/// pool and passed to the response through a bounded channel, so a slow client holds back the
+/// database read rather than buffering the export in memory.
+#[post("/admin/export/")]
+async fn export(
+    data: web::Form<ExportRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let admin = require_admin(&req)?;
+
+    let format = data.format;
+    info!("Exporting all comments");
+    let target = match format {
+        Format::Json => "all comments as JSON",
+        Format::Ndjson => "all comments as NDJSON",
+    };
+    audit::record(&state, &admin.name, audit::EXPORT, target, None).await;
+
+    let (tx, rx) = mpsc::channel::<io::Result<web::Bytes>>(4);
+    let db = state.db.clone();
+    actix_web::rt::spawn(async move {
+        let chunks = tx.clone();
+        let res = db::run(&db, move |db| {
+            let mut encoder = Encoder::new(format);
+            let send = |bytes: Vec<u8>| {
+                chunks
+                    .blocking_send(Ok(web::Bytes::from(bytes)))
+                    .map_err(|_| String::from("Export client disconnected"))
+            };
+
+            db.export(&mut |record| {
+                encoder.record(&record)?;
+                if encoder.buf.len() >= CHUNK_SIZE {
+                    send(encoder.take())?;
+                }
+                Ok(())
+            })?;
+
+            encoder.finish();
+            send(encoder.take())
+        })
+        .await;
+
+        // Failing the body stream aborts the response, so a client can't mistake a partial export
+        // for a complete one.
+        if let Err(e) = res {
+            warn!("Export failed: {e}");
+            let _ = tx.send(Err(io::Error::other(e))).await;
+        }
+    });
+
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(body))
+}
+
+/// `tinycomments export [--format json|ndjson] [--output PATH]`: write an export to a file, or to
+/// stdout if no path is given.
+pub async fn cli(config: ConfigFile, format: Format, output: Option<String>) -> io::Result<()> {
+    let mut writer: Box<dyn Write + Send> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    // The synchronous Postgres client can't be opened or dropped on an async thread, so the
+    // storage lives entirely on the blocking pool.
+    let res = web::block(move || {
+        let db = db::open(&config)?;
+        migrations::run(db.as_ref())?;
+
+        let write_err = |e: io::Error| format!("Unable to write export: {e}");
+        let mut encoder = Encoder::new(format);
+
+        db.export(&mut |record| {
+            encoder.record(&record)?;
+            writer.write_all(&encoder.take()).map_err(write_err)
+        })?;
+
+        encoder.finish();
+        writer.write_all(&encoder.take()).map_err(write_err)?;
+        writer.flush().map_err(write_err)
+    })
+    .await;
+
+    match res {
+        Ok(res) => res.map_err(io::Error::other),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
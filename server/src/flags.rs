@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Reports from commenters about other commenters' comments.
+
+use actix_web::{post, web, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use tracing::info;
+
+use crate::{
+    base64_decode, db, error::Error, get_client_ip, notify, pow, reputation, session, AppState,
+};
+
+/// The longest reason a commenter may give for flagging a comment, in characters.
+const MAX_REASON_CHARS: usize = 500;
+
+#[derive(Serialize, Deserialize)]
+pub struct FlagRequest {
+    #[serde(default)]
+    commenter_id: String,
+    comment_id: i64,
+    reason: String,
+    challenge: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FlagResponse {
+    code: u16,
+    status: String,
+    challenge: Option<String>,
+    key: Option<String>,
+}
+
+/// Report a published comment to the site owner. Each commenter may flag a comment once, and not
+/// their own. Once `flag_hide_threshold` commenters have flagged a comment it is returned to the
+/// moderation queue until the site owner approves it again or dismisses the flags.
+#[post("/comment/flag/")]
+async fn flag(
+    data: web::Form<FlagRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: session::Session,
+) -> Result<web::Json<FlagResponse>, Error> {
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new(
+            "/comment/flag/",
+            &[
+                &data.commenter_id,
+                &data.comment_id.to_string(),
+                &data.reason,
+            ],
+        ),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let reason = data.reason.trim();
+    if reason.is_empty() {
+        return Err(Error::BadRequest(String::from("A reason is required")));
+    }
+
+    if reason.chars().count() > MAX_REASON_CHARS {
+        return Err(Error::TooLarge(format!(
+            "Reason must be at most {MAX_REASON_CHARS} characters"
+        )));
+    }
+
+    let flagger_id = ammonia::clean(&session.or(&data.commenter_id));
+    let reason = ammonia::clean_text(reason);
+    let comment_id = data.comment_id;
+    let created_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::Internal(String::from("Could not generate timestamp")))?
+        .as_secs() as i64;
+
+    info!(
+        client_ip = get_client_ip(&req),
+        commenter_id = flagger_id,
+        comment_id,
+        "Flagging comment"
+    );
+
+    let stored_reason = reason.clone();
+    let (article, comment, owner, flags) = db::run(&state.db, move |db| {
+        if db.get_commenter(&flagger_id)?.is_none() {
+            return Ok(Err(Error::Forbidden(String::from("Unknown commenter"))));
+        }
+
+        let Some((article, comment)) = db.get_published_comment(comment_id)? else {
+            return Ok(Err(Error::NotFound(String::from(
+                "No comment with that id",
+            ))));
+        };
+
+        let owner = db.get_comment_owner(comment_id)?.unwrap_or_default();
+        if owner == flagger_id {
+            return Ok(Err(Error::Forbidden(String::from(
+                "You may not flag your own comment",
+            ))));
+        }
+
+        match db.add_flag(comment_id, &flagger_id, &stored_reason, created_at)? {
+            Some(flags) => Ok(Ok((article, comment, owner, flags))),
+            None => Ok(Err(Error::Conflict(String::from(
+                "You have already flagged this comment",
+            )))),
+        }
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not flag comment: {e}")))??;
+
+    state
+        .reputation
+        .record(reputation::commenter(&owner), reputation::Signal::Flagged);
+
+    let mut hidden = false;
+    if let Some(threshold) = state.config.flag_hide_threshold {
+        if flags >= threshold {
+            info!(comment_id, flags, "Hiding flagged comment");
+            match db::run(&state.db, move |db| db.hide_comment(comment_id)).await {
+                Ok(res) => hidden = res,
+                Err(e) => info!("Unable to hide flagged comment {comment_id}: {e}"),
+            }
+        }
+    }
+
+    let article = base64_decode(article.clone()).unwrap_or(article);
+    notify::dispatch(
+        &state,
+        notify::Alert::new(
+            notify::AlertKind::Flagged {
+                reason,
+                flags,
+                hidden,
+            },
+            &article,
+            &comment.poster_name,
+            &comment.comment,
+        ),
+    );
+
+    Ok(web::Json(FlagResponse {
+        code: 200,
+        status: String::from("OK"),
+        challenge: None,
+        key: None,
+    }))
+}
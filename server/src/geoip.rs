@@ -0,0 +1,96 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The country a comment was posted from, looked up in a MaxMind database, and what the
+//! `geoip_*_countries` lists say to do with comments from it.
+
+use maxminddb::{geoip2, Reader};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use crate::config::ConfigFile;
+
+/// What the country lists say to do with a comment.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Hold,
+    Block,
+}
+
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+    hold: HashSet<String>,
+    block: HashSet<String>,
+    allow: HashSet<String>,
+}
+
+impl GeoIp {
+    /// Load the database at `geoip_db_path`, if set. The whole file is read into memory.
+    pub fn new_from_config(config: &ConfigFile) -> Result<Self, String> {
+        let reader = match &config.geoip_db_path {
+            Some(path) => Some(
+                Reader::open_readfile(path)
+                    .map_err(|e| format!("Unable to open GeoIP database '{path}': {e}"))?,
+            ),
+            None => None,
+        };
+
+        let codes = |countries: &[String]| {
+            countries
+                .iter()
+                .map(|country| country.to_ascii_uppercase())
+                .collect()
+        };
+
+        Ok(GeoIp {
+            reader,
+            hold: codes(&config.geoip_hold_countries),
+            block: codes(&config.geoip_block_countries),
+            allow: codes(&config.geoip_allow_countries),
+        })
+    }
+
+    /// The ISO code of the country an address is in, if there's a database and it knows.
+    pub fn country(&self, client_ip: &str) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        let ip = client_ip.parse::<IpAddr>().ok()?.to_canonical();
+
+        let found: geoip2::Country = reader.lookup(ip).ok()?;
+        found
+            .country
+            .and_then(|country| country.iso_code)
+            .map(String::from)
+    }
+
+    pub fn verdict(&self, country: Option<&str>) -> Verdict {
+        let listed = |list: &HashSet<String>| country.is_some_and(|country| list.contains(country));
+
+        if listed(&self.block) {
+            Verdict::Block
+        } else if listed(&self.hold) || (!self.allow.is_empty() && !listed(&self.allow)) {
+            Verdict::Hold
+        } else {
+            Verdict::Accept
+        }
+    }
+}
@@ -0,0 +1,384 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! An optional, read-only GraphQL API over published comments, for static-site build pipelines
+//! that want threads in exactly the shape they render. Enabled by `enable_graphql`.
+//!
+//! Articles are identified the same way as everywhere else: base64-encoded, as the widget sends
+//! them. A thread is read once per query, and replies, filters and aggregates are resolved from
+//! that copy, so nesting doesn't cost extra database round trips.
+
+use actix_web::{post, web};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Enum, InputObject, Object, SimpleObject,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::{
+    base64_decode, comments_closed, db, error::Error, fill_display_fields, AppState,
+    MAX_COUNT_ARTICLES,
+};
+
+/// Deep enough for any reply chain a widget would show; a query can't nest further.
+const MAX_DEPTH: usize = 32;
+/// Each field costs one, so this bounds the size of a single response.
+const MAX_COMPLEXITY: usize = 10_000;
+
+pub type Schema = async_graphql::Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> Schema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// Execute a query sent as `{"query": ..., "variables": ...}`. Query errors are reported in the
+/// response's `errors`, as GraphQL clients expect.
+#[post("/graphql")]
+async fn graphql(
+    request: web::Json<async_graphql::Request>,
+    state: web::Data<AppState>,
+) -> Result<web::Json<async_graphql::Response>, Error> {
+    if !state.config.enable_graphql {
+        return Err(Error::NotFound(String::from("GraphQL is disabled")));
+    }
+
+    let request = request.into_inner().data(state.clone());
+    Ok(web::Json(state.graphql.execute(request).await))
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "db::CommentSort")]
+enum Sort {
+    Oldest,
+    Newest,
+    /// Highest vote score first.
+    Top,
+}
+
+/// Conditions a comment must meet to be returned. Every condition given must hold.
+#[derive(InputObject, Default)]
+struct CommentFilter {
+    /// Only replies to this comment, or only top-level comments if 0.
+    parent: Option<i64>,
+    /// Only comments posted at or after this Unix timestamp.
+    since: Option<i64>,
+    /// Only comments with at least this vote score.
+    min_votes: Option<i64>,
+    poster_name: Option<String>,
+    pinned: Option<bool>,
+    verified: Option<bool>,
+}
+
+impl CommentFilter {
+    fn matches(&self, comment: &db::Comment) -> bool {
+        self.parent.is_none_or(|parent| comment.parent == parent)
+            && self.since.is_none_or(|since| comment.timestamp >= since)
+            && self.min_votes.is_none_or(|min| comment.votes >= min)
+            && self
+                .poster_name
+                .as_ref()
+                .is_none_or(|name| &comment.poster_name == name)
+            && self.pinned.is_none_or(|pinned| comment.pinned == pinned)
+            && self
+                .verified
+                .is_none_or(|verified| comment.verified == verified)
+    }
+}
+
+/// A thread's comments, in the order [`db::Storage::get_comments`] returned them.
+struct ThreadData {
+    comments: Vec<db::Comment>,
+}
+
+impl ThreadData {
+    /// The comments matching `filter`, paginated, as resolvable objects.
+    fn select(
+        data: &Arc<ThreadData>,
+        filter: CommentFilter,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Vec<Comment> {
+        data.comments
+            .iter()
+            .enumerate()
+            .filter(|(_, comment)| filter.matches(comment))
+            .skip(offset.unwrap_or(0))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(index, _)| Comment {
+                data: data.clone(),
+                index,
+            })
+            .collect()
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// The published comments on an article.
+    async fn thread(
+        &self,
+        ctx: &Context<'_>,
+        article: String,
+        sort: Option<Sort>,
+    ) -> async_graphql::Result<Thread> {
+        let state = ctx.data::<web::Data<AppState>>()?;
+        let url = base64_decode(article.clone())
+            .ok_or_else(|| format!("Could not base64 decode '{article}'"))?;
+
+        let sort = sort.map(db::CommentSort::from).unwrap_or_default();
+        let (mut comments, lock, opened_at) = db::run(&state.db, move |db| {
+            Ok((
+                db.get_comments(&article, "", sort, None, 0)?,
+                db.get_article_lock(&article)?,
+                db.get_article_opened_at(&article)?,
+            ))
+        })
+        .await?;
+
+        for comment in comments.iter_mut() {
+            fill_display_fields(&state.config, comment);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_secs() as i64)
+            .unwrap_or(0);
+
+        Ok(Thread {
+            url,
+            locked: comments_closed(&state.config, &lock, opened_at, now),
+            voting_locked: lock.voting_locked,
+            data: Arc::new(ThreadData { comments }),
+        })
+    }
+
+    /// The number of published comments on each article, including those with none.
+    async fn comment_counts(
+        &self,
+        ctx: &Context<'_>,
+        articles: Vec<String>,
+    ) -> async_graphql::Result<Vec<ArticleCount>> {
+        if articles.len() > MAX_COUNT_ARTICLES {
+            return Err(
+                format!("At most {MAX_COUNT_ARTICLES} articles may be counted at once").into(),
+            );
+        }
+
+        let state = ctx.data::<web::Data<AppState>>()?;
+        let requested = articles.clone();
+        let counts = db::run(&state.db, move |db| db.count_comments_by_article(&articles)).await?;
+
+        Ok(requested
+            .into_iter()
+            .map(|article| ArticleCount {
+                count: counts.get(&article).copied().unwrap_or(0),
+                article,
+            })
+            .collect())
+    }
+}
+
+#[derive(SimpleObject)]
+struct ArticleCount {
+    article: String,
+    count: i64,
+}
+
+pub struct Thread {
+    url: String,
+    locked: bool,
+    voting_locked: bool,
+    data: Arc<ThreadData>,
+}
+
+#[Object]
+impl Thread {
+    /// The article's URL, decoded.
+    async fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Closed to new comments.
+    async fn locked(&self) -> bool {
+        self.locked
+    }
+
+    async fn voting_locked(&self) -> bool {
+        self.voting_locked
+    }
+
+    async fn total_count(&self) -> usize {
+        self.data.comments.len()
+    }
+
+    /// The sum of every comment's vote score.
+    async fn vote_total(&self) -> i64 {
+        self.data.comments.iter().map(|comment| comment.votes).sum()
+    }
+
+    /// Comments anywhere in the thread. Pass `filter: {parent: 0}` and follow `replies` for the
+    /// thread as a tree.
+    async fn comments(
+        &self,
+        filter: Option<CommentFilter>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Vec<Comment> {
+        ThreadData::select(&self.data, filter.unwrap_or_default(), limit, offset)
+    }
+
+    /// Everyone who has commented, in the order of their first comment. Commenters are told apart
+    /// by name, since their IDs are never published.
+    async fn commenters(&self) -> Vec<Commenter> {
+        let mut commenters: Vec<Commenter> = vec![];
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+
+        for comment in &self.data.comments {
+            let index = *seen.entry(&comment.poster_name).or_insert_with(|| {
+                commenters.push(Commenter {
+                    name: comment.poster_name.clone(),
+                    verified: false,
+                    avatar_url: comment.avatar_url.clone(),
+                    comment_count: 0,
+                    vote_total: 0,
+                });
+                commenters.len() - 1
+            });
+
+            let commenter = &mut commenters[index];
+            commenter.verified |= comment.verified;
+            commenter.comment_count += 1;
+            commenter.vote_total += comment.votes;
+        }
+
+        commenters
+    }
+}
+
+#[derive(SimpleObject)]
+struct Commenter {
+    name: String,
+    /// At least one of their comments was posted after signing in through an OAuth provider.
+    verified: bool,
+    avatar_url: Option<String>,
+    comment_count: i64,
+    /// The sum of their comments' vote scores.
+    vote_total: i64,
+}
+
+pub struct Comment {
+    data: Arc<ThreadData>,
+    index: usize,
+}
+
+impl Comment {
+    fn comment(&self) -> &db::Comment {
+        &self.data.comments[self.index]
+    }
+}
+
+#[Object]
+impl Comment {
+    async fn id(&self) -> i64 {
+        self.comment().id
+    }
+
+    /// Unix timestamp of when the comment was posted.
+    async fn timestamp(&self) -> i64 {
+        self.comment().timestamp
+    }
+
+    async fn edited_at(&self) -> Option<i64> {
+        self.comment().edited_at
+    }
+
+    /// The comment this replies to, if any.
+    async fn parent(&self) -> Option<i64> {
+        Some(self.comment().parent).filter(|parent| *parent != 0)
+    }
+
+    async fn poster_name(&self) -> &str {
+        &self.comment().poster_name
+    }
+
+    /// The comment text, HTML-escaped.
+    async fn text(&self) -> &str {
+        &self.comment().comment
+    }
+
+    /// `text` with its URLs turned into links. Only set when `autolink` is enabled.
+    async fn html(&self) -> Option<&str> {
+        self.comment().comment_html.as_deref()
+    }
+
+    async fn votes(&self) -> i64 {
+        self.comment().votes
+    }
+
+    async fn verified(&self) -> bool {
+        self.comment().verified
+    }
+
+    async fn pinned(&self) -> bool {
+        self.comment().pinned
+    }
+
+    /// Posted by the site owner.
+    async fn is_author(&self) -> bool {
+        self.comment().is_author
+    }
+
+    /// Voted below `collapse_below_score`.
+    async fn collapsed(&self) -> bool {
+        self.comment().collapsed
+    }
+
+    /// Only set when Gravatar support is enabled.
+    async fn avatar_url(&self) -> Option<&str> {
+        self.comment().avatar_url.as_deref()
+    }
+
+    /// URLs of the images attached to the comment.
+    async fn attachments(&self) -> &[String] {
+        &self.comment().attachments
+    }
+
+    /// Direct replies, in thread order. Any `parent` in the filter is ignored.
+    async fn replies(
+        &self,
+        filter: Option<CommentFilter>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Vec<Comment> {
+        let filter = CommentFilter {
+            parent: Some(self.comment().id),
+            ..filter.unwrap_or_default()
+        };
+        ThreadData::select(&self.data, filter, limit, offset)
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! The honeypot field: a form field the widget hides from people, so that only bots, which fill in
+//! every field they find, send it with a value. Comments that do are discarded or held for
+//! moderation, according to `honeypot_action`.
+
+use actix_http::h1;
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::config::HoneypotAction;
+use crate::{api, get_client_ip, stats, AppState};
+use tinycomments_types::NewCommentResponse;
+
+/// Fields the comment form already sends, which can't double as the honeypot.
+pub const RESERVED_FIELDS: &[&str] = &[
+    "article",
+    "commenter_id",
+    "comment",
+    "parent",
+    "challenge",
+    "secret",
+    "subscribe",
+];
+
+/// Added to the extensions of a comment submission that filled in the honeypot, when
+/// `honeypot_action` is `hold`.
+pub struct Tripped;
+
+/// Check comment submissions for the honeypot field.
+pub async fn middleware(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let Some(field) = &state.config.honeypot_field else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    if api::route(req.request()).as_deref() != Some("/comment/post/") {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    // Read the body to look for the field, then put it back for the handler.
+    let body = req.extract::<web::Bytes>().await?;
+    let tripped = serde_urlencoded::from_bytes::<HashMap<String, String>>(&body)
+        .is_ok_and(|form| form.get(field).is_some_and(|value| !value.is_empty()));
+    let (_, mut payload) = h1::Payload::create(true);
+    payload.unread_data(body);
+    req.set_payload(payload.into());
+
+    if tripped {
+        let client_ip = get_client_ip(req.request());
+        match state.config.honeypot_action {
+            HoneypotAction::Discard => {
+                info!(client_ip, "Discarded comment that filled in the honeypot");
+                state.stats.record(stats::SPAM_BLOCKED);
+                let res = HttpResponse::Ok().json(NewCommentResponse {
+                    code: 200,
+                    status: String::from("OK"),
+                    mentions: vec![],
+                    challenge: None,
+                    key: None,
+                });
+                return Ok(req.into_response(res));
+            }
+            HoneypotAction::Hold => {
+                info!(client_ip, "Comment filled in the honeypot");
+                req.extensions_mut().insert(Tripped);
+            }
+        }
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
@@ -0,0 +1,349 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Sanitizing comment bodies. Without an `[html_policy]` every tag is escaped and comments show
+//! exactly what was typed; with one, the listed tags and attributes are kept and everything else
+//! is removed by ammonia.
+//!
+//! With `autolink`, URLs in a comment's text are also turned into links when it is read, in the
+//! comment's `comment_html`. The stored comment is left as it was.
+
+use ammonia::Builder;
+use regex::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use crate::config::ConfigFile;
+
+/// `rel` on the links `autolink` makes, so they pass on no ranking and can't reach back into the
+/// page.
+const AUTOLINK_REL: &str = "nofollow noopener";
+
+/// The longest character reference [`decode_entities`] looks for, from `&` to `;`.
+const MAX_ENTITY_LEN: usize = 12;
+
+/// Schemes that can run script or smuggle in content, which may never be linked or allowed.
+const FORBIDDEN_SCHEMES: [&str; 3] = ["javascript", "vbscript", "data"];
+
+/// Tags that can run script, load other documents, or take input, which may never be allowed.
+const FORBIDDEN_TAGS: [&str; 16] = [
+    "base", "button", "embed", "form", "frame", "frameset", "iframe", "input", "link", "math",
+    "meta", "object", "script", "select", "style", "svg",
+];
+
+/// Which markup comments may contain.
+#[derive(Debug, Deserialize)]
+pub struct HtmlPolicy {
+    /// Tags kept in comments, e.g. `["b", "a", "code", "blockquote"]`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Attributes kept on each tag, e.g. `{ a = ["href", "title"] }`.
+    #[serde(default)]
+    pub tag_attributes: HashMap<String, Vec<String>>,
+    /// Schemes allowed in `href` and `src` URLs. Defaults to http, https and mailto.
+    pub url_schemes: Option<Vec<String>>,
+    /// `rel` added to every link. Defaults to `nofollow noopener noreferrer`; an empty string
+    /// leaves links without one.
+    pub link_rel: Option<String>,
+}
+
+impl HtmlPolicy {
+    fn url_schemes(&self) -> HashSet<&str> {
+        match &self.url_schemes {
+            Some(schemes) => schemes.iter().map(String::as_str).collect(),
+            None => HashSet::from(["http", "https", "mailto"]),
+        }
+    }
+
+    fn link_rel(&self) -> Option<&str> {
+        match self.link_rel.as_deref() {
+            Some("") => None,
+            Some(rel) => Some(rel),
+            None => Some("nofollow noopener noreferrer"),
+        }
+    }
+
+    /// Problems with the policy, for `ConfigFile::validate`.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        for tag in &self.tags {
+            if FORBIDDEN_TAGS.contains(&tag.to_ascii_lowercase().as_str()) {
+                problems.push(format!("html_policy may not allow the {tag} tag"));
+            }
+        }
+
+        for (tag, attributes) in &self.tag_attributes {
+            for attribute in attributes {
+                let attribute = attribute.to_ascii_lowercase();
+                if attribute.starts_with("on") || attribute == "style" || attribute == "srcdoc" {
+                    problems.push(format!(
+                        "html_policy may not allow the {attribute} attribute on {tag}"
+                    ));
+                }
+                if attribute == "rel" && self.link_rel().is_some() {
+                    problems.push(format!(
+                        "html_policy may only allow rel on {tag} when link_rel is empty"
+                    ));
+                }
+            }
+        }
+
+        for scheme in self.url_schemes() {
+            if FORBIDDEN_SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()) {
+                problems.push(format!("html_policy may not allow {scheme}: URLs"));
+            }
+        }
+
+        problems
+    }
+
+    fn builder(&self) -> Builder<'_> {
+        let mut builder = Builder::empty();
+        builder
+            .tags(self.tags.iter().map(String::as_str).collect())
+            .tag_attributes(
+                self.tag_attributes
+                    .iter()
+                    .map(|(tag, attributes)| {
+                        (
+                            tag.as_str(),
+                            attributes.iter().map(String::as_str).collect(),
+                        )
+                    })
+                    .collect(),
+            )
+            .generic_attributes(HashSet::new())
+            .url_schemes(self.url_schemes())
+            .link_rel(self.link_rel());
+        builder
+    }
+}
+
+/// Sanitize a comment body for storage. The result is shown as HTML by the widget.
+pub fn clean_comment(config: &ConfigFile, text: &str) -> String {
+    match &config.html_policy {
+        Some(policy) if !policy.tags.is_empty() => policy.builder().clean(text).to_string(),
+        _ => ammonia::clean_text(text),
+    }
+}
+
+/// Remove any tags a policy let through, leaving their text, for channels that show plain text.
+pub fn strip_tags(html: &str) -> String {
+    Builder::empty().clean(html).to_string()
+}
+
+/// A stored comment with the URLs in its text turned into links. Only URLs with one of
+/// `autolink_schemes` are linked, and those need `//` after the scheme unless it's `mailto`.
+/// Text that is already inside a link is left alone.
+pub fn autolink(config: &ConfigFile, html: &str) -> String {
+    let pattern = url_pattern(config);
+    let mut out = String::with_capacity(html.len());
+    let mut link_depth = 0usize;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = tag_end(rest);
+            let tag = &rest[..end];
+            match tag_name(tag) {
+                (name, false) if name == "a" => link_depth += 1,
+                (name, true) if name == "a" => link_depth = link_depth.saturating_sub(1),
+                _ => {}
+            }
+            out.push_str(tag);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let end = rest.find('<').unwrap_or(rest.len());
+        let text = &rest[..end];
+        let decoded = decode_entities(text);
+        if link_depth > 0 || !pattern.is_match(&decoded) {
+            out.push_str(text);
+        } else {
+            link_text(&decoded, pattern, &mut out);
+        }
+        rest = &rest[end..];
+    }
+
+    out
+}
+
+fn autolink_schemes(config: &ConfigFile) -> HashSet<&str> {
+    match &config.autolink_schemes {
+        Some(schemes) => schemes.iter().map(String::as_str).collect(),
+        None => HashSet::from(["http", "https"]),
+    }
+}
+
+/// Problems with `autolink_schemes`, for `ConfigFile::validate`.
+pub fn validate_autolink_schemes(config: &ConfigFile) -> Vec<String> {
+    autolink_schemes(config)
+        .into_iter()
+        .filter(|scheme| FORBIDDEN_SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()))
+        .map(|scheme| format!("autolink_schemes may not include {scheme}"))
+        .collect()
+}
+
+/// Anything that starts like a URL in one of `autolink_schemes`, e.g. the `https://...` in
+/// `see:https://...`. Which of them are linked is decided in [`link_text`]. Config is fixed for
+/// the life of the process, so the pattern is built once.
+fn url_pattern(config: &ConfigFile) -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        let schemes = autolink_schemes(config)
+            .into_iter()
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join("|");
+        Regex::new(&format!(r#"(?i)\b({schemes}):(//)?[^\s<>"'`]+"#)).expect("URL pattern is valid")
+    })
+}
+
+/// Write `text` to `out` escaped, with its URLs as links. Each candidate is looked at once, so
+/// the time taken grows with the length of the text rather than the number of near misses in it.
+fn link_text(text: &str, pattern: &Regex, out: &mut String) {
+    let mut written = 0;
+
+    for captures in pattern.captures_iter(text) {
+        let Some(found) = captures.get(0) else {
+            continue;
+        };
+        let url = trim_url(found.as_str());
+
+        if (captures.get(2).is_none() && !captures[1].eq_ignore_ascii_case("mailto"))
+            || Url::parse(url).is_err()
+        {
+            continue;
+        }
+
+        let url_html = ammonia::clean_text(url);
+        out.push_str(&ammonia::clean_text(&text[written..found.start()]));
+        out.push_str(&format!(
+            r#"<a href="{url_html}" rel="{AUTOLINK_REL}">{url_html}</a>"#
+        ));
+        written = found.start() + url.len();
+    }
+
+    out.push_str(&ammonia::clean_text(&text[written..]));
+}
+
+/// Drop the punctuation that usually ends the sentence around a URL rather than the URL itself,
+/// including a closing parenthesis with no opening one in the URL.
+fn trim_url(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?']);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(inner) if trimmed.matches('(').count() < trimmed.matches(')').count() => inner,
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// The length of the tag `html` starts with. Attribute values may contain `>`.
+fn tag_end(html: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in html.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => return i + 1,
+            _ => {}
+        }
+    }
+    html.len()
+}
+
+/// The lowercased name of a tag, and whether it's a closing tag.
+fn tag_name(tag: &str) -> (String, bool) {
+    let inner = tag.trim_start_matches('<');
+    let (closing, inner) = match inner.strip_prefix('/') {
+        Some(inner) => (true, inner),
+        None => (false, inner),
+    };
+    let name = inner
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    (name, closing)
+}
+
+/// Decode the character references ammonia writes, and the others common in HTML text. Anything
+/// unrecognised is left as written.
+pub fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // Only look a short way for the `;`, so text full of `&` is still read in one pass.
+        let decoded = rest
+            .bytes()
+            .take(MAX_ENTITY_LEN)
+            .position(|b| b == b';')
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end + 1)));
+
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "grave" => Some('`'),
+        "nbsp" => Some('\u{a0}'),
+        _ => match name.strip_prefix('#') {
+            Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16)
+                .ok()
+                .and_then(char::from_u32),
+            Some(decimal) => decimal.parse().ok().and_then(char::from_u32),
+            None => None,
+        },
+    }
+}
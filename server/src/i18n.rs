@@ -0,0 +1,301 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Translations of status messages, email subjects and templates, and the built-in comments page.
+//!
+//! Each locale is a TOML file named for its language tag, e.g. `fr.toml`, in `locale_dir`.
+//! Messages are looked up by their English text, which is also what's returned when there's no
+//! translation. A `{}` in a message stands for a number or name filled in by the server:
+//!
+//! ```toml
+//! [messages]
+//! "Unknown article" = "Article inconnu"
+//! "Comments may be at most {} bytes" = "Les commentaires sont limités à {} octets"
+//!
+//! [email_templates]
+//! reply = "<p>Bonjour {{ recipient_name }},</p> ..."
+//! ```
+
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::ErrorInternalServerError,
+    http::header::{self, HeaderValue},
+    middleware::Next,
+    web, Error, HttpRequest,
+};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::ConfigFile;
+use crate::AppState;
+
+/// The language the server's messages are written in.
+pub const SOURCE_LOCALE: &str = "en";
+
+#[derive(Deserialize)]
+struct Bundle {
+    #[serde(default)]
+    messages: HashMap<String, String>,
+    /// Replacements for the built-in email templates, keyed by template name.
+    #[serde(default)]
+    email_templates: HashMap<String, String>,
+}
+
+struct Catalog {
+    messages: HashMap<String, String>,
+    /// Messages with `{}` placeholders, matched against the whole message.
+    patterns: Vec<(Regex, String)>,
+    email_templates: HashMap<String, String>,
+}
+
+impl Catalog {
+    fn new(bundle: Bundle) -> Result<Self, String> {
+        let mut messages = HashMap::new();
+        let mut patterns = vec![];
+
+        for (english, translated) in bundle.messages {
+            if !english.contains("{}") {
+                messages.insert(english, translated);
+                continue;
+            }
+
+            let parts: Vec<String> = english.split("{}").map(regex::escape).collect();
+            let re = Regex::new(&format!("^{}$", parts.join("(.*?)")))
+                .map_err(|e| format!("Invalid message '{english}': {e}"))?;
+            patterns.push((re, translated));
+        }
+
+        Ok(Catalog {
+            messages,
+            patterns,
+            email_templates: bundle.email_templates,
+        })
+    }
+
+    fn translate(&self, text: &str) -> Option<String> {
+        if let Some(translated) = self.messages.get(text) {
+            return Some(translated.clone());
+        }
+
+        for (re, translated) in self.patterns.iter() {
+            let Some(captures) = re.captures(text) else {
+                continue;
+            };
+
+            let mut values = captures.iter().skip(1).flatten().map(|m| m.as_str());
+            let mut out = String::new();
+            let mut pieces = translated.split("{}").peekable();
+            while let Some(piece) = pieces.next() {
+                out.push_str(piece);
+                if pieces.peek().is_some() {
+                    out.push_str(values.next().unwrap_or_default());
+                }
+            }
+
+            return Some(out);
+        }
+
+        None
+    }
+}
+
+pub struct Locales {
+    default: String,
+    catalogs: HashMap<String, Catalog>,
+}
+
+impl Locales {
+    pub fn new_from_config(config: &ConfigFile) -> Result<Self, String> {
+        let mut catalogs = HashMap::new();
+
+        if let Some(dir) = &config.locale_dir {
+            let entries =
+                fs::read_dir(dir).map_err(|e| format!("Unable to read locale_dir {dir}: {e}"))?;
+
+            for entry in entries {
+                let path = entry
+                    .map_err(|e| format!("Unable to read locale_dir {dir}: {e}"))?
+                    .path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+                    continue;
+                }
+
+                let Some(tag) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+
+                catalogs.insert(tag.to_lowercase(), load(&path)?);
+            }
+        }
+
+        let default = config
+            .default_locale
+            .as_deref()
+            .unwrap_or(SOURCE_LOCALE)
+            .to_lowercase();
+        if default != SOURCE_LOCALE && !catalogs.contains_key(&default) {
+            return Err(format!(
+                "No translations found for default_locale {default}"
+            ));
+        }
+
+        Ok(Locales { default, catalogs })
+    }
+
+    /// Whether any translations are loaded, so responses depend on `Accept-Language`.
+    pub fn enabled(&self) -> bool {
+        !self.catalogs.is_empty()
+    }
+
+    /// The best locale for the request's `Accept-Language` header, or the default locale if none
+    /// of its languages are available.
+    pub fn negotiate(&self, req: &HttpRequest) -> &str {
+        let Some(accept) = req
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return &self.default;
+        };
+
+        let mut ranges: Vec<(String, f32)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim().to_lowercase();
+                let q = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && q > 0.0).then_some((tag, q))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (tag, _) in ranges.iter() {
+            if tag == "*" {
+                return &self.default;
+            }
+
+            let primary = tag.split('-').next().unwrap_or_default();
+            for candidate in [tag.as_str(), primary] {
+                if candidate == SOURCE_LOCALE {
+                    return SOURCE_LOCALE;
+                }
+                if let Some((locale, _)) = self.catalogs.get_key_value(candidate) {
+                    return locale;
+                }
+            }
+        }
+
+        &self.default
+    }
+
+    /// `text` in `locale`, or unchanged if it has no translation.
+    pub fn translate(&self, locale: &str, text: &str) -> String {
+        self.catalogs
+            .get(locale)
+            .and_then(|catalog| catalog.translate(text))
+            .unwrap_or_else(|| String::from(text))
+    }
+
+    /// `text` in the default locale, for messages with no request to negotiate from, like emails.
+    pub fn translate_default(&self, text: &str) -> String {
+        self.translate(&self.default, text)
+    }
+
+    /// The default locale's replacement for a built-in email template, if it has one.
+    pub fn email_template(&self, name: &str) -> Option<&str> {
+        self.catalogs
+            .get(&self.default)
+            .and_then(|catalog| catalog.email_templates.get(name))
+            .map(String::as_str)
+    }
+}
+
+fn load(path: &Path) -> Result<Catalog, String> {
+    let display = path.display();
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read {display}: {e}"))?;
+    let bundle: Bundle =
+        toml::from_str(&contents).map_err(|e| format!("Unable to parse {display}: {e}"))?;
+    Catalog::new(bundle).map_err(|e| format!("{display}: {e}"))
+}
+
+/// Translate the `status` of JSON responses into the language negotiated from the request's
+/// `Accept-Language` header.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    if !state.locales.enabled() {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let locale = String::from(state.locales.negotiate(req.request()));
+    let mut res = next.call(req).await?;
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-language"));
+
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"));
+
+    if locale == SOURCE_LOCALE || !is_json {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let (mut res, body) = res.map_into_boxed_body().into_parts();
+    let bytes = body::to_bytes(body)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    let bytes = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(mut value) => {
+            if let Some(serde_json::Value::String(status)) = value.get_mut("status") {
+                *status = state.locales.translate(&locale, status);
+            }
+            serde_json::to_vec(&value).map_or(bytes, web::Bytes::from)
+        }
+        Err(_) => bytes,
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&locale) {
+        res.headers_mut().insert(header::CONTENT_LANGUAGE, value);
+    }
+
+    Ok(ServiceResponse::new(
+        req,
+        res.set_body(bytes).map_into_boxed_body(),
+    ))
+}
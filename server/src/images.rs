@@ -0,0 +1,251 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Recognising uploaded images by their contents, whatever name or type they were sent with, and
+//! removing the metadata cameras and editors embed in them: EXIF, which often records where a
+//! photo was taken, XMP, IPTC, and comments. Only whole metadata blocks are dropped; the image
+//! data is copied as is. EXIF orientation goes with the rest, so photos show as they were stored.
+
+/// The image formats attachments may be in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// PNG chunks holding metadata rather than image data.
+const PNG_METADATA: &[&[u8]] = &[b"eXIf", b"tEXt", b"iTXt", b"zTXt", b"tIME"];
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::Png, Format::Jpeg, Format::Gif, Format::Webp];
+
+    /// The format `data` is in, going by its leading bytes.
+    pub fn sniff(data: &[u8]) -> Option<Format> {
+        if data.starts_with(PNG_SIGNATURE) {
+            Some(Format::Png)
+        } else if data.starts_with(b"\xff\xd8\xff") {
+            Some(Format::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(Format::Gif)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(Format::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Jpeg => "image/jpeg",
+            Format::Gif => "image/gif",
+            Format::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Gif => "gif",
+            Format::Webp => "webp",
+        }
+    }
+}
+
+/// `data` without its metadata, or None if it isn't a well-formed image in `format`.
+pub fn strip_metadata(format: Format, data: &[u8]) -> Option<Vec<u8>> {
+    match format {
+        Format::Png => strip_png(data),
+        Format::Jpeg => strip_jpeg(data),
+        Format::Gif => strip_gif(data),
+        Format::Webp => strip_webp(data),
+    }
+}
+
+/// Copy every chunk up to and including IEND except the metadata ones. Chunks are copied whole,
+/// so their CRCs still hold.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut pos = PNG_SIGNATURE.len();
+
+    loop {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind = data.get(pos + 4..pos + 8)?;
+        // Length, type, data and CRC.
+        let end = pos.checked_add(len)?.checked_add(12)?;
+        let chunk = data.get(pos..end)?;
+
+        if !PNG_METADATA.contains(&kind) {
+            out.extend_from_slice(chunk);
+        }
+        if kind == b"IEND" {
+            return Some(out);
+        }
+        pos = end;
+    }
+}
+
+/// Copy the segments before the first scan except APP1 (EXIF and XMP), APP13 (IPTC) and
+/// comments, then the scan data and everything after it as is.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data.get(..2)?.to_vec();
+    let mut pos = 2;
+
+    loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+        // Markers may be padded with any number of 0xff fill bytes.
+        while data.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // Start of scan, or end of image.
+            0xda | 0xd9 => {
+                out.extend_from_slice(&data[pos..]);
+                return Some(out);
+            }
+            // Markers without a length.
+            0x01 | 0xd0..=0xd7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        if len < 2 {
+            return None;
+        }
+        let end = pos + 2 + len;
+        let segment = data.get(pos..end)?;
+
+        if !matches!(marker, 0xe1 | 0xed | 0xfe) {
+            out.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+}
+
+/// Copy the blocks up to the trailer except comments and application extensions, which is where
+/// XMP goes. The extensions that make animations loop are kept.
+fn strip_gif(data: &[u8]) -> Option<Vec<u8>> {
+    // The header and logical screen descriptor, then the global color table.
+    let mut pos = 13 + color_table_len(*data.get(10)?);
+    let mut out = data.get(..pos)?.to_vec();
+
+    loop {
+        let start = pos;
+        match *data.get(pos)? {
+            // Trailer.
+            0x3b => {
+                out.push(0x3b);
+                return Some(out);
+            }
+            // Image descriptor, local color table, LZW minimum code size, then image data.
+            0x2c => {
+                pos += 10 + color_table_len(*data.get(pos + 9)?) + 1;
+                pos = skip_sub_blocks(data, pos)?;
+                out.extend_from_slice(data.get(start..pos)?);
+            }
+            0x21 => {
+                let label = *data.get(pos + 1)?;
+                pos = skip_sub_blocks(data, pos + 2)?;
+                let keep = match label {
+                    0xfe => false,
+                    0xff => matches!(
+                        data.get(start + 2..start + 14),
+                        Some(b"\x0bNETSCAPE2.0") | Some(b"\x0bANIMEXTS1.0")
+                    ),
+                    _ => true,
+                };
+                if keep {
+                    out.extend_from_slice(data.get(start..pos)?);
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn color_table_len(flags: u8) -> usize {
+    if flags & 0x80 != 0 {
+        3 << ((flags & 0x07) + 1)
+    } else {
+        0
+    }
+}
+
+/// The position just past a run of GIF data sub-blocks starting at `pos`.
+fn skip_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// Copy every chunk except EXIF and XMP, clear the flags announcing them in the VP8X header, and
+/// rewrite the RIFF size to match.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    let riff_len = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+    let body = data.get(12..riff_len.checked_add(8)?)?;
+
+    let mut chunks = vec![];
+    let mut pos = 0;
+    while pos < body.len() {
+        let kind = body.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(body.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // Chunks are padded to an even length.
+        let end = pos.checked_add(8)?.checked_add(len)?.checked_add(len & 1)?;
+        let chunk = body.get(pos..end)?;
+
+        match kind {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = chunks.len();
+                chunks.extend_from_slice(chunk);
+                if let Some(flags) = chunks.get_mut(start + 8) {
+                    *flags &= !(0x08 | 0x04);
+                }
+            }
+            _ => chunks.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&u32::try_from(chunks.len() + 4).ok()?.to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(&chunks);
+    Some(out)
+}
@@ -0,0 +1,269 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Periodic maintenance. Each job runs on its own timer, every `[jobs]` interval or its default,
+//! and `/admin/jobs/` reports when each last ran and how it went.
+
+use actix_web::{post, web, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::{interval_at, MissedTickBehavior};
+use tracing::{info, warn};
+
+use crate::auth::require_admin;
+use crate::config::ConfigFile;
+use crate::error::Error;
+use crate::{attachments, db, email, stats, AppState};
+
+/// Names accepted in the `[jobs]` config table.
+pub const JOBS: [&str; 6] = [
+    "pow_cleanup",
+    "optimize",
+    "digest",
+    "retention",
+    "stats",
+    "attachment_cleanup",
+];
+
+#[derive(Clone, Copy)]
+enum Job {
+    /// Drop expired proof-of-work challenges and idle clients.
+    PowCleanup,
+    /// Reclaim space left by deleted rows and refresh the query planner's statistics.
+    Optimize,
+    /// Send `email_notify_address` a summary of new comments.
+    Digest,
+    /// Delete stale pending comments, orphaned votes and unused commenter IDs.
+    Retention,
+    /// Save the event counts behind `/admin/stats/`.
+    Stats,
+    /// Remove the stored images of deleted attachments.
+    AttachmentCleanup,
+}
+
+impl Job {
+    fn name(&self) -> &'static str {
+        match self {
+            Job::PowCleanup => "pow_cleanup",
+            Job::Optimize => "optimize",
+            Job::Digest => "digest",
+            Job::Retention => "retention",
+            Job::Stats => "stats",
+            Job::AttachmentCleanup => "attachment_cleanup",
+        }
+    }
+
+    /// How often the job runs unless `[jobs]` says otherwise, or `None` if it isn't needed with
+    /// this config.
+    fn default_interval(&self, config: &ConfigFile) -> Option<Duration> {
+        match self {
+            Job::PowCleanup | Job::Stats => Some(Duration::from_secs(60)),
+            Job::AttachmentCleanup => config
+                .attachments
+                .as_ref()
+                .map(|_| Duration::from_secs(60 * 60)),
+            Job::Optimize => Some(Duration::from_secs(24 * 60 * 60)),
+            Job::Digest => match (
+                config.enable_email_notifications,
+                config.email_digest_interval_mins,
+            ) {
+                (true, Some(mins)) => Some(Duration::from_secs(mins.max(1) * 60)),
+                _ => None,
+            },
+            Job::Retention => {
+                if config.retention_pending_days.is_some()
+                    || config.retention_unused_commenter_days.is_some()
+                {
+                    Some(Duration::from_secs(24 * 60 * 60))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    async fn run(&self, state: &web::Data<AppState>) -> Result<(), String> {
+        match self {
+            Job::PowCleanup => {
+                state.pow.purge_expired();
+                Ok(())
+            }
+            Job::Optimize => db::run(&state.db, |db| db.optimize()).await,
+            Job::Digest => email::send_digest(state).await,
+            Job::Retention => retention(state).await,
+            Job::Stats => stats::persist(state).await,
+            Job::AttachmentCleanup => attachments::remove_unused(state).await,
+        }
+    }
+}
+
+/// Apply `retention_pending_days` and `retention_unused_commenter_days`. Votes left pointing at
+/// missing comments or commenters are always removed, and so are the images of deleted comments.
+async fn retention(state: &web::Data<AppState>) -> Result<(), String> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+    let days_ago = |days: u64| now - days as i64 * 24 * 60 * 60;
+
+    let pending_before = state.config.retention_pending_days.map(days_ago);
+    let commenters_before = state.config.retention_unused_commenter_days.map(days_ago);
+
+    let (comments, votes, commenters) = db::run(&state.db, move |db| {
+        let comments = match pending_before {
+            Some(before) => db.prune_pending(before)?,
+            None => 0,
+        };
+        let votes = db.prune_orphaned_votes()?;
+        let commenters = match commenters_before {
+            Some(before) => db.prune_unused_commenters(before)?,
+            None => 0,
+        };
+        Ok((comments, votes, commenters))
+    })
+    .await?;
+
+    info!(comments, votes, commenters, "Applied retention policy");
+    attachments::remove_unused(state).await
+}
+
+/// How a job has fared since the server started.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobStatus {
+    name: String,
+    interval_secs: u64,
+    runs: u64,
+    /// Unix time the most recent run started.
+    last_run_at: Option<i64>,
+    last_duration_ms: Option<u64>,
+    /// Why the most recent run failed, if it did.
+    last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JobsResponse {
+    code: u16,
+    status: String,
+    jobs: Vec<JobStatus>,
+}
+
+/// The jobs scheduled with this config, and how each has fared.
+pub struct Scheduler {
+    jobs: Vec<(Job, Duration)>,
+    status: Mutex<Vec<JobStatus>>,
+}
+
+impl Scheduler {
+    pub fn new_from_config(config: &ConfigFile) -> Self {
+        let jobs: Vec<(Job, Duration)> = [
+            Job::PowCleanup,
+            Job::Optimize,
+            Job::Digest,
+            Job::Retention,
+            Job::Stats,
+            Job::AttachmentCleanup,
+        ]
+        .into_iter()
+        .filter_map(|job| {
+            let interval = match config.jobs.get(job.name()) {
+                Some(0) => return None,
+                Some(secs) => Some(Duration::from_secs(*secs)),
+                None => job.default_interval(config),
+            };
+            interval.map(|interval| (job, interval))
+        })
+        .collect();
+
+        let status = jobs
+            .iter()
+            .map(|(job, interval)| JobStatus {
+                name: String::from(job.name()),
+                interval_secs: interval.as_secs(),
+                runs: 0,
+                last_run_at: None,
+                last_duration_ms: None,
+                last_error: None,
+            })
+            .collect();
+
+        Scheduler {
+            jobs,
+            status: Mutex::new(status),
+        }
+    }
+
+    fn record(&self, i: usize, started_at: i64, duration: Duration, res: Result<(), String>) {
+        let mut status = self.status.lock().unwrap();
+        let status = &mut status[i];
+        status.runs += 1;
+        status.last_run_at = Some(started_at);
+        status.last_duration_ms = Some(duration.as_millis() as u64);
+        status.last_error = res.err();
+    }
+}
+
+/// Start a task for each scheduled job. A job's first run is one interval after startup, and a
+/// run that overruns its interval delays the next rather than being followed by a burst.
+pub fn spawn(state: web::Data<AppState>) {
+    for (i, (job, interval)) in state.jobs.jobs.iter().copied().enumerate() {
+        let state = state.clone();
+        actix_web::rt::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticks = interval_at(start, interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                ticks.tick().await;
+
+                let started_at = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|t| t.as_secs() as i64)
+                    .unwrap_or(0);
+                let timer = Instant::now();
+                let res = job.run(&state).await;
+
+                if let Err(e) = &res {
+                    warn!(job = job.name(), "Maintenance job failed: {e}");
+                }
+                state.jobs.record(i, started_at, timer.elapsed(), res);
+            }
+        });
+    }
+}
+
+#[post("/admin/jobs/")]
+async fn list(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<JobsResponse>, Error> {
+    require_admin(&req)?;
+
+    let jobs = state.jobs.status.lock().unwrap().clone();
+    info!("Listing maintenance jobs");
+
+    Ok(web::Json(JobsResponse {
+        code: 200,
+        status: String::from("OK"),
+        jobs,
+    }))
+}
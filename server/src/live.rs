@@ -0,0 +1,254 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Pushes newly published comments and vote changes to readers as they happen, so embedded
+//! widgets can update a thread without polling. Updates go out over WebSockets, or as server-sent
+//! events for clients that can't use them; both are fed from the same bus.
+
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::interval;
+use tracing::{debug, warn};
+
+use crate::{db, fill_display_fields, AppState};
+
+/// How many updates a slow subscriber may fall behind before it starts missing them.
+const BUS_CAPACITY: usize = 1024;
+
+/// Keeps idle connections from being dropped by proxies.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A change to an article's thread, already serialized for sending to readers.
+#[derive(Clone)]
+pub struct Update {
+    /// The base64 article, as stored and as sent by the widget.
+    pub article: String,
+    /// `comment` or `vote`; also the `type` field of `data`.
+    pub event: &'static str,
+    pub data: String,
+}
+
+#[derive(Serialize)]
+struct CommentMessage<'a> {
+    #[serde(rename = "type")]
+    event: &'static str,
+    comment: &'a db::Comment,
+}
+
+#[derive(Serialize)]
+struct VoteMessage {
+    #[serde(rename = "type")]
+    event: &'static str,
+    comment_id: i64,
+    votes: i64,
+}
+
+/// Fans updates out to every connected reader. Each connection filters for its own article.
+pub struct Bus {
+    sender: broadcast::Sender<Update>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BUS_CAPACITY);
+        Bus { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Update> {
+        self.sender.subscribe()
+    }
+
+    fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    fn publish(&self, article: String, event: &'static str, data: &impl Serialize) {
+        match serde_json::to_string(data) {
+            Ok(data) => {
+                // Sending only fails when nobody is listening.
+                let _ = self.sender.send(Update {
+                    article,
+                    event,
+                    data,
+                });
+            }
+            Err(e) => warn!("Unable to serialize {event} update: {e}"),
+        }
+    }
+}
+
+/// Announce a comment that has just become visible to readers.
+pub async fn comment_published(state: &web::Data<AppState>, comment_id: i64) {
+    if !state.live.has_subscribers() {
+        return;
+    }
+
+    let (article, mut comment) =
+        match db::run(&state.db, move |db| db.get_published_comment(comment_id)).await {
+            Ok(Some(found)) => found,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Unable to look up comment {comment_id} for live update: {e}");
+                return;
+            }
+        };
+
+    fill_display_fields(&state.config, &mut comment);
+
+    state.live.publish(
+        article,
+        "comment",
+        &CommentMessage {
+            event: "comment",
+            comment: &comment,
+        },
+    );
+}
+
+/// Announce a comment's new vote total.
+pub async fn votes_changed(state: &web::Data<AppState>, comment_id: i64) {
+    if !state.live.has_subscribers() {
+        return;
+    }
+
+    let (article, votes) =
+        match db::run(&state.db, move |db| db.get_comment_votes(comment_id)).await {
+            Ok(Some(found)) => found,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Unable to look up votes on {comment_id} for live update: {e}");
+                return;
+            }
+        };
+
+    state.live.publish(
+        article,
+        "vote",
+        &VoteMessage {
+            event: "vote",
+            comment_id,
+            votes,
+        },
+    );
+}
+
+/// Send each update for the article as a JSON text message, `{"type": "comment", "comment":
+/// {...}}` or `{"type": "vote", "comment_id": ..., "votes": ...}`. Messages from the client are
+/// ignored apart from pings and close frames.
+#[get("/ws/comments/{article:.*}")]
+async fn websocket(
+    path: web::Path<String>,
+    body: web::Payload,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let article = path.into_inner();
+    let mut updates = state.live.subscribe();
+
+    actix_web::rt::spawn(async move {
+        let mut ping = interval(PING_INTERVAL);
+
+        let reason = loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) if update.article == article => {
+                        if session.text(update.data).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("Live update subscriber missed {missed} updates");
+                    }
+                    Err(RecvError::Closed) => break None,
+                },
+                message = messages.recv() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(reason))) => break reason,
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break None,
+                },
+                _ = ping.tick() => {
+                    if session.ping(b"").await.is_err() {
+                        return;
+                    }
+                }
+            }
+        };
+
+        let _ = session.close(reason).await;
+    });
+
+    Ok(response)
+}
+
+/// Send each update for the article as a `comment` or `vote` event, with the same JSON data as the
+/// WebSocket messages.
+#[get("/events/{article:.*}")]
+async fn events(path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
+    let article = path.into_inner();
+    let updates = state.live.subscribe();
+    let ping = interval(PING_INTERVAL);
+
+    let body = futures_util::stream::unfold(
+        (updates, ping, article),
+        |(mut updates, mut ping, article)| async move {
+            let chunk = loop {
+                tokio::select! {
+                    update = updates.recv() => match update {
+                        Ok(update) if update.article == article => {
+                            break format!("event: {}\ndata: {}\n\n", update.event, update.data);
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => {
+                            debug!("Live update subscriber missed {missed} updates");
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = ping.tick() => break String::from(": ping\n\n"),
+                }
+            };
+
+            Some((
+                Ok::<_, Infallible>(web::Bytes::from(chunk)),
+                (updates, ping, article),
+            ))
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Stop nginx from buffering the stream.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body)
+}
@@ -0,0 +1,303 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    Error, HttpMessage,
+};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use std::{io::Write, path::Path, sync::OnceLock, time::Instant};
+use time::{macros::format_description, OffsetDateTime};
+use tracing::{info, info_span, Instrument, Level};
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::MakeWriter, layer::SubscriberExt, Layer, Registry,
+};
+
+use crate::config::{ConfigFile, DebugLevel, LogFormat};
+use crate::get_client_ip;
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The id of the request being handled, added to its extensions by [`log_request`].
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+/// Where the access log is written, when `access_log` is configured.
+static ACCESS_LOG: OnceLock<NonBlocking> = OnceLock::new();
+
+/// A log file, rotated on a schedule. Rotated files are named after `path` with the date and time
+/// of the period they cover appended, e.g. `tinycomments.log.2024-05-01`.
+#[derive(Deserialize, Debug)]
+pub struct LogFile {
+    pub path: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Rotated files to keep. Older ones are deleted. Without it, none are.
+    pub max_files: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Weekly,
+    Never,
+}
+
+impl LogFile {
+    pub fn validate(&self, field: &str) -> Vec<String> {
+        let mut problems = vec![];
+
+        if Path::new(&self.path).file_name().is_none() {
+            problems.push(format!("{field}.path must name a file"));
+        }
+
+        if self.max_files == Some(0) {
+            problems.push(format!("{field}.max_files must not be 0"));
+        }
+
+        problems
+    }
+
+    fn open(&self) -> (NonBlocking, WorkerGuard) {
+        let path = Path::new(&self.path);
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let rotation = match self.rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Weekly => Rotation::WEEKLY,
+            LogRotation::Never => Rotation::NEVER,
+        };
+
+        let mut builder = RollingFileAppender::builder().rotation(rotation);
+        if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+            builder = builder.filename_prefix(file_name);
+        }
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files);
+        }
+
+        let appender = match builder.build(directory) {
+            Ok(appender) => appender,
+            Err(e) => panic!("Could not open log file {}: {e}", self.path),
+        };
+
+        tracing_appender::non_blocking(appender)
+    }
+}
+
+/// Install the global tracing subscriber, writing to stdout and, with `log_file`, to a rotated
+/// file as well. JSON output puts each event's fields at the top level of the object, so they can
+/// be indexed by log aggregators; the request span's fields, including `request_id`, are under
+/// `span`.
+///
+/// Log lines are written to files from a background thread. The returned guards flush it, and must
+/// be held until the server exits.
+pub fn init(config: &ConfigFile) -> Vec<WorkerGuard> {
+    let tracing_level = match config.debug {
+        DebugLevel::Info => Level::INFO,
+        DebugLevel::Debug => Level::DEBUG,
+        DebugLevel::Trace => Level::TRACE,
+    };
+
+    let mut guards = vec![];
+    let mut layers = vec![fmt_layer(&config.log_format, std::io::stdout, true)];
+
+    if let Some(log_file) = &config.log_file {
+        let (writer, guard) = log_file.open();
+        layers.push(fmt_layer(&config.log_format, writer, false));
+        guards.push(guard);
+    }
+
+    if let Some(access_log) = &config.access_log {
+        let (writer, guard) = access_log.open();
+        let _ = ACCESS_LOG.set(writer);
+        guards.push(guard);
+    }
+
+    let subscriber = tracing_subscriber::registry()
+        .with(layers)
+        .with(LevelFilter::from_level(tracing_level));
+
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("Could not set default global tracing subscriber");
+
+    guards
+}
+
+fn fmt_layer<W>(format: &LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+/// Run each request in a span tagged with a request id, log it with its status and latency, and
+/// return the id in an `X-Request-Id` header. An id supplied by a reverse proxy is reused.
+pub async fn log_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let received = OffsetDateTime::now_utc();
+    let client_ip = get_client_ip(req.request());
+    let method = req.method().to_string();
+    let path = String::from(req.path());
+    let request_line = format!("{method} {} {:?}", req.uri(), req.version());
+
+    let request_id = match req.headers().get(&REQUEST_ID_HEADER) {
+        Some(id) if is_valid_request_id(id) => String::from(id.to_str().unwrap_or_default()),
+        _ => new_request_id(),
+    };
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let span = info_span!("request", request_id);
+
+    let mut res = next.call(req).instrument(span.clone()).await?;
+
+    span.in_scope(|| {
+        info!(
+            client_ip,
+            method,
+            path,
+            status = res.status().as_u16(),
+            latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+            "Handled request"
+        )
+    });
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    if let Some(access_log) = ACCESS_LOG.get() {
+        let line = access_log_line(&client_ip, received, &request_line, &res);
+        if let Err(e) = access_log.clone().write_all(line.as_bytes()) {
+            tracing::error!("Could not write to access log: {e}");
+        }
+    }
+
+    Ok(res)
+}
+
+/// One request in the Combined Log Format used by Apache and nginx, which most traffic analysis
+/// tools read:
+///
+/// `203.0.113.7 - - [01/May/2024:13:55:36 +0000] "GET /comment/get/ HTTP/1.1" 200 512 "https://blog.example.com/" "Mozilla/5.0 ..."`
+fn access_log_line<B: MessageBody>(
+    client_ip: &str,
+    received: OffsetDateTime,
+    request_line: &str,
+    res: &ServiceResponse<B>,
+) -> String {
+    let timestamp = received
+        .format(format_description!(
+            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+        ))
+        .unwrap_or_default();
+
+    let bytes = match res.response().body().size() {
+        BodySize::Sized(size) if size > 0 => size.to_string(),
+        _ => String::from("-"),
+    };
+
+    let headers = res.request().headers();
+
+    format!(
+        "{} - - [{timestamp}] \"{}\" {} {bytes} \"{}\" \"{}\"\n",
+        escape(client_ip),
+        escape(request_line),
+        res.status().as_u16(),
+        header_field(headers, &header::REFERER),
+        header_field(headers, &header::USER_AGENT),
+    )
+}
+
+fn header_field(headers: &HeaderMap, name: &HeaderName) -> String {
+    match headers.get(name).and_then(|value| value.to_str().ok()) {
+        Some(value) => escape(value),
+        None => String::from("-"),
+    }
+}
+
+/// Quotes and backslashes are escaped, as Apache does, so a crafted header can't break a line
+/// into extra fields. Control characters are written as hex escapes.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn new_request_id() -> String {
+    let mut rand_bytes = [0u8; 16];
+    thread_rng().fill(&mut rand_bytes);
+    hex::encode(rand_bytes)
+}
+
+/// Only short ids made of URL-safe characters are accepted from clients, so they can't be used to
+/// inject text into the logs.
+fn is_valid_request_id(id: &HeaderValue) -> bool {
+    let id = id.as_bytes();
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || *c == b'-' || *c == b'_')
+}
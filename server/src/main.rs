@@ -20,202 +20,423 @@
  * SOFTWARE.
  */
 
+use actix_cors::Cors;
 use actix_web::{
-    get, http::header::ContentType, post, web, App, HttpRequest, HttpResponse, HttpServer,
+    get,
+    http::header::{self, ContentType},
+    middleware, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer,
 };
 use base64::prelude::*;
-use chrono::DateTime;
+use clap::Parser;
+use hmac::{Hmac, Mac};
 use rand::{thread_rng, Rng};
-use serde::{Deserialize, Serialize};
-use sqlite::Value::Null;
-use std::fs::File;
-use std::io::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::str;
-use std::sync::{Mutex, MutexGuard};
-use std::time::SystemTime;
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
-
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tinycomments_types::{
+    BootstrapResponse, CommentFormat, CountCommentsRequest, CountCommentsResponse,
+    DeleteCommentRequest, DeleteCommentResponse, EditCommentRequest, EditCommentResponse,
+    GetCommentRequest, GetCommentResponse, GetCommentsRequest, GetCommentsResponse, GetPowResponse,
+    IdInfoRequest, IdInfoResponse, IdRequest, IdResponse, NewCommentRequest, NewCommentResponse,
+    NotificationSettingsRequest, NotificationSettingsResponse, UpdateIdRequest, UpdateIdResponse,
+    ValidatePowRequest, ValidatePowResponse, VoteRequest, VoteResponse,
+};
+use tracing::{info, warn};
+
+mod admin;
+mod antispam;
+mod api;
+mod articles;
+mod assets;
+mod attachments;
+mod audit;
+mod auth;
+mod backup;
+mod blocklist;
+mod cli;
 mod config;
-mod email;
+mod dashboard;
+mod db;
+mod emailcheck;
+mod embed;
+mod error;
+mod export;
+mod flags;
+mod geoip;
+mod graphql;
+mod honeypot;
+mod html;
+mod i18n;
+mod images;
+mod jobs;
+mod live;
+mod logging;
+mod mentions;
+mod migrations;
+mod names;
+mod nojs;
+mod notify;
+mod oauth;
 mod pow;
+mod privacy;
+mod ratelimit;
+mod reporting;
+mod reputation;
+mod search;
+mod session;
+mod spamlists;
+mod stats;
+mod subscriptions;
+mod titles;
+mod tls;
+mod webhooks;
+mod wordfilter;
+
+use error::Error;
+use notify::email;
 
 struct AppState {
     config: config::ConfigFile,
-    db_conn: Mutex<sqlite::Connection>,
+    db: Arc<dyn db::Storage>,
+    http: reqwest::Client,
+    email_templates: email::Templates,
+    mailer: Option<lettre::SmtpTransport>,
+    locales: i18n::Locales,
+    dashboard: dashboard::Templates,
+    nojs: nojs::Templates,
+    email_queue: email::Queue,
+    notifiers: notify::Notifiers,
     pow: pow::PowTable,
+    reputation: Arc<reputation::Reputations>,
+    webhooks: webhooks::Deliveries,
+    oauth: oauth::OAuthLogins,
+    export_confirmations: privacy::ExportConfirmations,
+    blocklist: blocklist::Blocklist,
+    live: live::Bus,
+    ratelimit: ratelimit::RateLimiter,
+    word_filter: wordfilter::WordFilter,
+    disposable_domains: emailcheck::DisposableDomains,
+    reserved_names: names::ReservedNames,
+    articles: articles::ArticlePolicy,
+    graphql: graphql::Schema,
+    jobs: jobs::Scheduler,
+    stats: Arc<stats::Counters>,
+    spam_lists: spamlists::SpamLists,
+    geoip: geoip::GeoIp,
 }
 
-#[derive(Serialize, Deserialize)]
-struct IdRequest {
-    name: String,
-    email: String,
-    challenge: Option<String>,
-    secret: Option<String>,
-}
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let cli = cli::Cli::parse();
 
-#[derive(Serialize, Deserialize)]
-struct IdResponse {
-    commenter_id: String,
-    code: u16,
-    status: String,
-    challenge: Option<String>,
-    key: Option<String>,
-}
+    let mut config = match config::ConfigFile::new_from_file(&cli.config) {
+        Ok(config) => config,
+        Err(e) => panic!("Unable to read config file: {e}"),
+    };
+    cli.apply(&mut config);
 
-#[derive(Serialize, Deserialize)]
-struct GetCommentsRequest {
-    commenter_id: String,
-    article: String,
-    challenge: Option<String>,
-    secret: Option<String>,
-}
+    if let Err(problems) = config.validate() {
+        for problem in problems {
+            eprintln!("Invalid configuration: {problem}");
+        }
+        std::process::exit(1);
+    }
 
-#[derive(Serialize, Deserialize)]
-struct GetCommentsResponse {
-    code: u16,
-    status: String,
-    comments: Vec<Comment>,
-    challenge: Option<String>,
-    key: Option<String>,
-}
+    match cli.command {
+        Some(cli::Command::Export { format, output }) => {
+            if let Err(e) = export::cli(config, format, output).await {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(cli::Command::Backup { output }) => {
+            if let Err(e) = backup::cli(config, output).await {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(cli::Command::Admin {
+            server,
+            token,
+            action,
+        }) => {
+            if let Err(e) = admin::cli(config, server, token, action).await {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
-#[derive(Serialize, Deserialize)]
-struct Comment {
-    id: i64,
-    timestamp: i64,
-    parent: i64,
-    poster_name: String,
-    comment: String,
-    votes: i64,
-    myvote: i64,
-}
+    let _log_guards = logging::init(&config);
+    let error_reports = reporting::init(&config);
 
-#[derive(Deserialize)]
-struct NewCommentRequest {
-    article: String,
-    commenter_id: String,
-    comment: String,
-    parent: i64,
-    challenge: Option<String>,
-    secret: Option<String>,
-}
+    info!("Starting tracing log for Tinycomments");
 
-#[derive(Serialize, Deserialize)]
-struct NewCommentResponse {
-    code: u16,
-    status: String,
-    challenge: Option<String>,
-    key: Option<String>,
-}
+    let locales = match i18n::Locales::new_from_config(&config) {
+        Ok(locales) => locales,
+        Err(e) => panic!("{e}"),
+    };
 
-#[derive(Serialize, Deserialize)]
-struct VoteRequest {
-    voter_id: String,
-    comment_id: i64,
-    vote: i64,
-    challenge: Option<String>,
-    secret: Option<String>,
-}
+    let email_templates = match email::Templates::new_from_config(&config, &locales) {
+        Ok(templates) => templates,
+        Err(e) => panic!("{e}"),
+    };
 
-#[derive(Serialize, Deserialize)]
-struct VoteResponse {
-    code: u16,
-    status: String,
-    challenge: Option<String>,
-    key: Option<String>,
-}
+    let word_filter = match wordfilter::WordFilter::new_from_config(&config) {
+        Ok(word_filter) => word_filter,
+        Err(e) => panic!("{e}"),
+    };
 
-#[derive(Serialize, Deserialize)]
-struct GetPowResponse {
-    code: u16,
-    key: String,
-    challenge: String,
-}
+    let disposable_domains = match emailcheck::DisposableDomains::new_from_config(&config) {
+        Ok(domains) => domains,
+        Err(e) => panic!("{e}"),
+    };
 
-#[derive(Serialize, Deserialize)]
-struct ValidatePowRequest {
-    challenge: String,
-    secret: String,
-}
+    let reserved_names = names::ReservedNames::new_from_config(&config);
 
-#[derive(Serialize, Deserialize)]
-struct ValidatePowResponse {
-    code: u16,
-    status: String,
-}
+    let geoip = match geoip::GeoIp::new_from_config(&config) {
+        Ok(geoip) => geoip,
+        Err(e) => panic!("{e}"),
+    };
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let config = match config::ConfigFile::new_from_file("config.toml") {
-        Ok(config) => config,
-        Err(e) => panic!("Unable to read config file: {e}"),
+    let articles = match articles::ArticlePolicy::new_from_config(&config) {
+        Ok(articles) => articles,
+        Err(e) => panic!("{e}"),
+    };
+
+    let dashboard = match dashboard::Templates::new() {
+        Ok(templates) => templates,
+        Err(e) => panic!("{e}"),
+    };
+
+    let nojs = match nojs::Templates::new() {
+        Ok(templates) => templates,
+        Err(e) => panic!("{e}"),
     };
 
-    let tracing_level = match config.debug {
-        config::DebugLevel::Info => Level::INFO,
-        config::DebugLevel::Debug => Level::DEBUG,
-        config::DebugLevel::Trace => Level::TRACE,
+    let mailer = match email::mailer_from_config(&config) {
+        Ok(mailer) => mailer,
+        Err(e) => panic!("{e}"),
     };
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(tracing_level)
-        .without_time()
-        .finish();
+    let notifiers = notify::Notifiers::new_from_config(&config);
+    let jobs = jobs::Scheduler::new_from_config(&config);
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Could not set default global tracing subscriber");
+    let (email_queue, email_wake) = email::Queue::new();
+    let (webhook_deliveries, webhook_queue) = webhooks::Deliveries::new();
 
-    info!("Starting tracing log for Tinycomments");
+    let db = match db::open(&config) {
+        Ok(db) => db,
+        Err(e) => panic!("Unable to open database: {e}"),
+    };
 
-    let db_conn = Mutex::new(sqlite::open(&config.db_path).unwrap());
+    if let Err(e) = db::run(&db, migrations::run).await {
+        panic!("Unable to migrate database schema: {e}");
+    }
 
-    match db_conn.lock() {
-        Ok(conn) => {
-            let mut statement = conn.prepare("PRAGMA foreign_keys = ON;").unwrap();
-            if let Err(e) = statement.next() {
-                panic!("Could not enable foreign key support: {e:?}");
-            }
-        }
-        Err(e) => {
-            panic!("Could not get DB lock: {e:?}");
+    if !matches!(config.debug, config::DebugLevel::Info) {
+        if let Err(e) = db::run(&db, migrations::check_indexes).await {
+            warn!("Unable to check query plans: {e}");
         }
     }
 
+    let reputation = Arc::new(reputation::Reputations::new());
+    let stats = Arc::new(stats::Counters::new());
+    match db::run(&db, |db| db.load_reputation()).await {
+        Ok(saved) => reputation.restore(saved),
+        Err(e) => warn!("Unable to restore reputations: {e}"),
+    }
+
+    let pow = match pow::PowTable::new_from_config(&config, reputation.clone(), stats.clone()) {
+        Ok(pow) => pow,
+        Err(e) => panic!("{e}"),
+    };
+
+    let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => match tls::load_server_config(cert, key) {
+            Ok(tls_config) => Some(tls_config),
+            Err(e) => panic!("{e}"),
+        },
+        (None, None) => None,
+        _ => panic!("tls_cert_path and tls_key_path must be set together"),
+    };
+
+    let shutdown_timeout = config.shutdown_timeout_secs.unwrap_or(30);
+
     let bind_addr = config.bind_address.clone();
     let bind_port = config.bind_port;
 
     let state = web::Data::new(AppState {
         config,
-        db_conn,
-        pow: pow::PowTable::new(),
+        db,
+        http: reqwest::Client::new(),
+        email_templates,
+        mailer,
+        locales,
+        dashboard,
+        nojs,
+        email_queue,
+        notifiers,
+        pow,
+        reputation,
+        webhooks: webhook_deliveries,
+        oauth: oauth::OAuthLogins::new(),
+        export_confirmations: privacy::ExportConfirmations::new(),
+        blocklist: blocklist::Blocklist::new(),
+        live: live::Bus::new(),
+        ratelimit: ratelimit::RateLimiter::new(),
+        word_filter,
+        disposable_domains,
+        reserved_names,
+        articles,
+        graphql: graphql::schema(),
+        jobs,
+        stats,
+        spam_lists: spamlists::SpamLists::new(),
+        geoip,
     });
 
-    HttpServer::new(move || {
+    match db::run(&state.db, |db| db.load_pow_state()).await {
+        Ok(saved) => state.pow.restore(saved),
+        Err(e) => warn!("Unable to restore proof-of-work state: {e}"),
+    }
+
+    if let Err(e) = state.blocklist.reload(&state.db).await {
+        panic!("Unable to load blocklist: {e}");
+    }
+
+    email::spawn_queue_worker(state.clone(), email_wake);
+    webhooks::spawn_delivery_worker(state.clone(), webhook_queue);
+    pow::spawn_persist_worker(state.clone());
+    jobs::spawn(state.clone());
+    reputation::spawn_persist_worker(state.clone());
+    if let Some(error_reports) = error_reports {
+        reporting::spawn_worker(state.http.clone(), &state.config, error_reports);
+    }
+
+    let app_state = state.clone();
+    let form_limit = form_limit(&state.config);
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(state.clone())
-            .service(id)
-            .service(post_comment)
-            .service(get_comments)
-            .service(vote)
-            .service(get_root)
-            .service(get_pow)
-            .service(validate_pow)
+            .app_data(app_state.clone())
+            .app_data(web::FormConfig::default().limit(form_limit))
+            .wrap(middleware::from_fn(auth::middleware))
+            .wrap(middleware::Condition::new(
+                app_state.config.legacy_status_codes,
+                middleware::from_fn(error::legacy_status),
+            ))
+            .wrap(middleware::from_fn(honeypot::middleware))
+            .wrap(middleware::from_fn(ratelimit::middleware))
+            .wrap(middleware::from_fn(i18n::middleware))
+            .wrap(middleware::from_fn(reporting::middleware))
+            .wrap(middleware::from_fn(error::json_errors))
+            .wrap(middleware::from_fn(logging::log_request))
+            .wrap(middleware::Condition::new(
+                !app_state.config.allowed_origins.is_empty(),
+                cors(&app_state.config.allowed_origins),
+            ))
+            .service(assets::root)
+            .service(embed::script)
+            .service(embed::stylesheet)
+            .service(subscriptions::confirm)
+            .service(subscriptions::unsubscribe)
+            .service(nojs::thread)
+            .service(nojs::post)
+            .service(dashboard::index)
+            .service(dashboard::login)
+            .service(dashboard::logout)
+            .service(dashboard::moderate)
+            .service(dashboard::blocklist_add)
+            .service(dashboard::blocklist_remove)
+            .service(oauth::login)
+            .service(oauth::callback)
+            .service(assets::files(&app_state.config))
+            .service(attachments::files(&app_state.config))
+            .configure(api::services)
     })
-    .bind((bind_addr, bind_port))?
-    .run()
-    .await
+    .shutdown_timeout(shutdown_timeout);
+
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_0_23((bind_addr, bind_port), tls_config)?,
+        None => server.bind((bind_addr, bind_port))?,
+    };
+
+    // Runs until SIGINT or SIGTERM, then stops accepting connections and waits for in-flight
+    // requests to finish.
+    server.run().await?;
+
+    shutdown(&state, Duration::from_secs(shutdown_timeout)).await;
+
+    Ok(())
+}
+
+/// Drain background work once the HTTP server has stopped.
+async fn shutdown(state: &web::Data<AppState>, timeout: Duration) {
+    info!("Draining webhook and email queues before exit");
+
+    let drain = async {
+        webhooks::drain(state).await;
+        email::flush(state).await;
+    };
+
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        warn!("Timed out draining queues; undelivered email will be retried on next start");
+    }
+
+    pow::persist(state).await;
+    reputation::persist(state).await;
+    if let Err(e) = stats::persist(state).await {
+        warn!("{e}");
+    }
+
+    if let Err(e) = db::run(&state.db, |db| db.checkpoint()).await {
+        warn!("Unable to checkpoint database: {e}");
+    }
+
+    info!("Shutdown complete");
 }
 
-#[get("/")]
-async fn get_root(_state: web::Data<AppState>) -> HttpResponse {
-    let mut handle = File::open("comments.html").expect("Unable to open file");
-    let mut contents = String::new();
-    let _ = handle.read_to_string(&mut contents);
+fn cors(allowed_origins: &[String]) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(vec!["GET", "POST"])
+        .allowed_headers(vec![
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+        ])
+        .expose_headers(vec!["x-request-id", "etag"])
+        .supports_credentials()
+        .max_age(3600);
+
+    for origin in allowed_origins.iter() {
+        cors = match &origin[..] {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        };
+    }
 
-    HttpResponse::Ok()
-        .content_type(ContentType::html())
-        .body(contents)
+    cors
+}
+
+/// Settings the widget needs before it renders the comment form.
+#[get("/widget/bootstrap/")]
+async fn bootstrap(state: web::Data<AppState>) -> web::Json<BootstrapResponse> {
+    web::Json(BootstrapResponse {
+        code: 200,
+        status: String::from("OK"),
+        honeypot_field: state.config.honeypot_field.clone(),
+        voting_mode: state.config.voting_mode,
+        max_comment_bytes: state.config.max_comment_bytes,
+        min_comment_chars: state.config.min_comment_chars,
+        subscriptions: state.config.enable_subscriptions,
+    })
 }
 
 #[post("/id/")]
@@ -223,76 +444,141 @@ async fn id(
     data: web::Form<IdRequest>,
     state: web::Data<AppState>,
     req: HttpRequest,
-) -> web::Json<IdResponse> {
-    let query = r#"INSERT INTO ids VALUES (?, ?, ?);"#;
-
-    let clean_name = ammonia::clean(&data.name[..]);
-    let clean_email = ammonia::clean(&data.email[..]);
-
+) -> Result<HttpResponse, Error> {
     let mut response = IdResponse {
         code: 200,
         status: String::from("OK"),
         commenter_id: String::from(""),
         challenge: None,
         key: None,
+        session: false,
     };
 
-    if let Some(result) = state.pow.handle(&get_client_ip(&req), &data.challenge, &data.secret) {
-        response.code = result.code;
-        response.status = result.status.unwrap_or(String::from(""));
-        response.challenge = result.challenge;
-        response.key = result.key;
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new("/id/", &[&data.name, &data.email]),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let commenter_id = new_commenter(&state, &get_client_ip(&req), &data.name, &data.email).await?;
+
+    let cookie = session::cookie(&state, &req, &commenter_id);
+    response.commenter_id = commenter_id;
+    match cookie {
+        Some(cookie) => {
+            response.session = true;
+            Ok(HttpResponse::Ok().cookie(cookie).json(response))
+        }
+        None => Ok(HttpResponse::Ok().json(response)),
+    }
+}
+
+/// Change the name and email stored for a commenter ID. Later notifications and mentions use the
+/// new ones, and comments already posted show the new name.
+#[post("/id/update/")]
+async fn update_id(
+    data: web::Form<UpdateIdRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: session::Session,
+) -> Result<web::Json<UpdateIdResponse>, Error> {
+    let client_ip = get_client_ip(&req);
+    let commenter_id = ammonia::clean(&session.or(&data.commenter_id));
+
+    state.pow.handle(
+        &client_ip,
+        pow::Binding::new("/id/update/", &[&commenter_id, &data.name, &data.email]),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let clean_name = ammonia::clean(&data.name);
+    let clean_email = ammonia::clean(&data.email);
+
+    check_identity(
+        &state,
+        &client_ip,
+        Some(&commenter_id),
+        &clean_name,
+        &clean_email,
+        false,
+    )
+    .await?;
 
-        return web::Json(response);
+    info!(
+        client_ip,
+        commenter_id,
+        name = clean_name,
+        email = clean_email,
+        "Updating ID"
+    );
+
+    let updated = db::run(&state.db, move |db| {
+        db.update_commenter(&commenter_id, &clean_name, &clean_email)
+    })
+    .await
+    .map_err(Error::Database)?;
+
+    if !updated {
+        return Err(Error::NotFound(String::from("Unknown commenter id")));
     }
 
-    if let Ok(t) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        let client_ip = get_client_ip(&req);
+    Ok(web::Json(UpdateIdResponse {
+        code: 200,
+        status: String::from("OK"),
+        challenge: None,
+        key: None,
+    }))
+}
 
-        let mut rand_bytes = [0u8; 32];
-        thread_rng().fill(&mut rand_bytes);
+#[post("/id/info/")]
+async fn id_info(
+    data: web::Form<IdInfoRequest>,
+    state: web::Data<AppState>,
+    session: session::Session,
+) -> Result<web::Json<IdInfoResponse>, Error> {
+    let commenter_id = ammonia::clean(&session.or(&data.commenter_id));
 
-        let commenter_id = hex::encode(rand_bytes);
+    let commenter = db::run(&state.db, move |db| db.get_commenter(&commenter_id))
+        .await
+        .map_err(Error::Database)?
+        .ok_or_else(|| Error::NotFound(String::from("Unknown commenter id")))?;
 
-        info!(
-            "{} Generating new ID '{}' for name: '{}' email: '{}' for client {}",
-            DateTime::from_timestamp(t.as_secs() as i64, 0).unwrap(),
-            commenter_id,
-            clean_name,
-            clean_email,
-            client_ip
-        );
+    Ok(web::Json(IdInfoResponse {
+        code: 200,
+        status: String::from("OK"),
+        name: commenter.name,
+        email: commenter.email,
+        verified: commenter.verified,
+        reply_notifications: commenter.reply_notifications,
+        approved_comments: commenter.approved_comments,
+    }))
+}
 
-        match state.db_conn.lock() {
-            Ok(conn) => {
-                let mut statement = conn.prepare(query).unwrap();
-                statement.bind((1, &commenter_id[..])).unwrap();
-                statement.bind((2, &clean_name[..])).unwrap();
-                statement.bind((3, &clean_email[..])).unwrap();
-
-                if let Err(e) = statement.next() {
-                    response.code = 500;
-                    response.status = format!("Could not insert new ID: {e}");
-
-                    web::Json(response)
-                } else {
-                    response.commenter_id = commenter_id;
-                    web::Json(response)
-                }
-            }
-            Err(e) => {
-                response.code = 500;
-                response.status = format!("DB Error: {:?}", e);
+#[post("/id/notifications/")]
+async fn notification_settings(
+    data: web::Form<NotificationSettingsRequest>,
+    state: web::Data<AppState>,
+    session: session::Session,
+) -> Result<web::Json<NotificationSettingsResponse>, Error> {
+    let commenter_id = ammonia::clean(&session.or(&data.commenter_id));
+    let enabled = data.reply_notifications;
 
-                web::Json(response)
-            }
-        }
-    } else {
-        response.code = 500;
-        response.status = String::from("Could not generate timestamp");
+    let updated = db::run(&state.db, move |db| {
+        db.set_reply_notifications(&commenter_id, enabled)
+    })
+    .await
+    .map_err(Error::Database)?;
 
-        web::Json(response)
+    if !updated {
+        return Err(Error::NotFound(String::from("Unknown commenter id")));
     }
+
+    Ok(web::Json(NotificationSettingsResponse {
+        code: 200,
+        status: String::from("OK"),
+    }))
 }
 
 #[post("/comment/post/")]
@@ -300,185 +586,554 @@ async fn post_comment(
     data: web::Form<NewCommentRequest>,
     state: web::Data<AppState>,
     req: HttpRequest,
-) -> web::Json<NewCommentResponse> {
-    let query = r#"INSERT INTO comments (article, commenter_id, parent, comment, moderated, timestamp)
-                                        VALUES(?, ?, ?, ?, true, ?);"#;
-
+    session: session::Session,
+) -> Result<web::Json<NewCommentResponse>, Error> {
     let mut response = NewCommentResponse {
         code: 200,
         status: String::from("OK"),
+        mentions: vec![],
         challenge: None,
         key: None,
     };
 
-    if let Some(result) = state.pow.handle(&get_client_ip(&req), &data.challenge, &data.secret) {
-        response.code = result.code;
-        response.status = result.status.unwrap_or(String::from(""));
-        response.challenge = result.challenge;
-        response.key = result.key;
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new(
+            "/comment/post/",
+            &[
+                &data.article,
+                &data.commenter_id,
+                &data.comment,
+                &data.parent.to_string(),
+            ],
+        ),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let posted = submit_comment(
+        &state,
+        &req,
+        Submission {
+            article: &data.article,
+            commenter_id: ammonia::clean(&session.or(&data.commenter_id)),
+            comment: &data.comment,
+            parent: data.parent,
+            hold: false,
+            subscribe: data.subscribe,
+        },
+    )
+    .await?;
+
+    if !posted.published {
+        response.status = String::from("Comment held for moderation");
+    }
+    response.mentions = posted.mentions;
 
-        return web::Json(response);
+    Ok(web::Json(response))
+}
+
+/// The most articles a single count request may ask about.
+const MAX_COUNT_ARTICLES: usize = 100;
+
+#[post("/comment/count/")]
+async fn count_comments(
+    data: web::Form<CountCommentsRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<CountCommentsResponse>, Error> {
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new("/comment/count/", &[&data.articles]),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let mut articles: Vec<String> = data
+        .articles
+        .split(',')
+        .map(|article| String::from(article.trim()))
+        .filter(|article| !article.is_empty())
+        .collect();
+    articles.sort();
+    articles.dedup();
+
+    if articles.len() > MAX_COUNT_ARTICLES {
+        return Err(Error::BadRequest(format!(
+            "At most {MAX_COUNT_ARTICLES} articles may be counted at once"
+        )));
     }
 
-    let commenter_id = &ammonia::clean(&data.commenter_id[..])[..];
-    let clean_comment_text = &ammonia::clean_text(&data.comment[..])[..];
+    let requested = articles.clone();
+    let counts = db::run(&state.db, move |db| db.count_comments_by_article(&articles))
+        .await
+        .map_err(|e| Error::Internal(format!("Could not count comments: {e}")))?;
+
+    // Articles without comments are reported as zero rather than left out.
+    let counts = requested
+        .into_iter()
+        .map(|article| {
+            let count = counts.get(&article).copied().unwrap_or(0);
+            (article, count)
+        })
+        .collect();
 
-    let Ok(sys_t) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) else {
-        response.code = 500;
-        response.status = String::from("Could not generate timestamp");
-        return web::Json(response);
+    Ok(web::Json(CountCommentsResponse {
+        code: 200,
+        status: String::from("OK"),
+        counts,
+        challenge: None,
+        key: None,
+    }))
+}
+
+/// Each thread is sent with an ETag, and a client that already has it can send the tag back in
+/// `If-None-Match` to get an empty 304 response instead.
+#[post("/comment/get/")]
+async fn get_comments(
+    data: web::Form<GetCommentsRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: session::Session,
+) -> Result<HttpResponse, Error> {
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new("/comment/get/", &[&data.article, &data.commenter_id]),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let viewer_id = session.or(&data.commenter_id);
+    let mut response = thread_response(&data, viewer_id, &state, &req).await?;
+
+    let encode = |response: &GetCommentsResponse| {
+        serde_json::to_vec(response)
+            .map_err(|e| Error::Internal(format!("Could not encode comments: {e}")))
     };
 
-    let client_ip = get_client_ip(&req);
+    // The thread as this viewer sees it, including their own votes, so the tag is only reusable by
+    // the same viewer. `synced_at` changes every second, so it's left out; a client whose thread
+    // is unchanged can keep sending its older one.
+    let synced_at = std::mem::take(&mut response.synced_at);
+    let etag =
+        header::EntityTag::new_strong(hex::encode(&Sha256::digest(encode(&response)?)[..16]));
+    response.synced_at = synced_at;
+    let body = encode(&response)?;
+
+    if let Some(header::IfNoneMatch::Items(tags)) = req.get_header::<header::IfNoneMatch>() {
+        if tags.iter().any(|tag| tag.weak_eq(&etag)) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(header::ETag(etag))
+                .finish());
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ETag(etag))
+        .content_type(ContentType::json())
+        .body(body))
+}
 
-    let Some(decoded_article) = base64_decode(data.article.clone()) else {
-        response.code = 500;
-        response.status = format!("Could not base64 decode '{}'", data.article);
-        return web::Json(response);
+async fn thread_response(
+    data: &GetCommentsRequest,
+    viewer_id: String,
+    state: &web::Data<AppState>,
+    req: &HttpRequest,
+) -> Result<GetCommentsResponse, Error> {
+    let mut response = GetCommentsResponse {
+        code: 200,
+        status: String::from("OK"),
+        comments: vec![],
+        total_count: 0,
+        next_cursor: None,
+        synced_at: 0,
+        vote_changes: HashMap::new(),
+        locked: false,
+        voting_locked: false,
+        voting_mode: state.config.voting_mode,
+        max_comment_bytes: state.config.max_comment_bytes,
+        min_comment_chars: state.config.min_comment_chars,
+        challenge: None,
+        key: None,
     };
 
+    let client_ip = get_client_ip(req);
+
+    let decoded_article = base64_decode(data.article.clone()).ok_or_else(|| {
+        Error::BadRequest(format!(
+            "Unable to decode supplied article id: {}",
+            data.article
+        ))
+    })?;
+
+    // Clients that don't paginate get the whole thread.
+    let limit = data.limit.filter(|limit| *limit >= 0);
+    let offset = data.offset.unwrap_or(0).max(0);
+
     info!(
-        "{} Posting comment for '{}' for client {} with id '{}'",
-        DateTime::from_timestamp(sys_t.as_secs() as i64, 0).unwrap(),
-        decoded_article,
         client_ip,
-        commenter_id,
+        article = decoded_article,
+        commenter_id = viewer_id,
+        "Getting comments"
     );
 
-    match state.db_conn.lock() {
-        Ok(conn) => {
-            let mut statement = conn.prepare(query).unwrap();
-            statement
-                .bind((1, &ammonia::clean(&data.article[..])[..]))
-                .unwrap();
-            statement.bind((2, commenter_id)).unwrap();
-
-            if data.parent == 0 {
-                statement.bind((3, Null)).unwrap();
-            } else {
-                statement.bind((3, data.parent)).unwrap();
-            }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+
+    let article = data.article.clone();
+    let sort = data.sort;
+    let since = data.since;
+    let ((mut comments, vote_changes), total_count, lock, opened_at) =
+        db::run(&state.db, move |db| {
+            let comments = match since {
+                Some(since) => db.get_comment_changes(&article, &viewer_id, since)?,
+                None => (
+                    db.get_comments(&article, &viewer_id, sort, limit, offset)?,
+                    HashMap::new(),
+                ),
+            };
+            Ok((
+                comments,
+                db.count_comments(&article)?,
+                db.get_article_lock(&article)?,
+                db.get_article_opened_at(&article)?,
+            ))
+        })
+        .await
+        .map_err(Error::Database)?;
+
+    response.locked = comments_closed(&state.config, &lock, opened_at, now);
+    response.voting_locked = lock.voting_locked;
+
+    for comment in comments.iter_mut() {
+        fill_display_fields(&state.config, comment);
+    }
 
-            statement.bind((4, clean_comment_text)).unwrap();
-            statement.bind((5, sys_t.as_secs() as i64)).unwrap();
+    let next = offset + comments.len() as i64;
+    response.comments = match data.format {
+        CommentFormat::Flat => comments,
+        CommentFormat::Tree => nest_comments(
+            comments,
+            state
+                .config
+                .comment_tree_max_depth
+                .unwrap_or(DEFAULT_TREE_MAX_DEPTH),
+        ),
+    };
+    response.total_count = total_count;
+    response.synced_at = now;
+    response.vote_changes = vote_changes;
 
-            if let Err(e) = statement.next() {
-                response.code = 500;
-                response.status = format!("Could not add comment: {e}");
-                return web::Json(response);
-            }
+    if since.is_none() && limit.is_some() && next < total_count {
+        response.next_cursor = Some(next);
+    }
 
-            if state.config.enable_email_notifications {
-                if let Some((name, _email)) = get_commenter_info(&conn, commenter_id) {
-                    let _ = email::send_email(
-                        &state,
-                        &decoded_article,
-                        &name,
-                        clean_comment_text,
-                    );
-                } else {
-                    info!("Unable to send notification email");
-                }
-            }
-            return web::Json(response);
-        }
-        Err(e) => {
-            response.code = 500;
-            response.status = format!("DB Error: {:?}", e);
-            web::Json(response)
-        }
+    Ok(response)
+}
+
+/// Fill in the fields derived from configuration rather than stored with a comment.
+fn fill_display_fields(config: &config::ConfigFile, comment: &mut db::Comment) {
+    if config.enable_gravatar {
+        comment.avatar_url = gravatar_url(&comment.poster_email);
+    }
+    comment.collapsed = is_collapsed(config, comment);
+    if config.autolink {
+        comment.comment_html = Some(html::autolink(config, &comment.comment));
     }
+    comment.attachments = comment
+        .attachment_names
+        .iter()
+        .filter_map(|name| attachments::url(config, name))
+        .collect();
 }
 
-#[post("/comment/get/")]
-async fn get_comments(
-    data: web::Form<GetCommentsRequest>,
+/// One published comment with the comments above it and its direct replies, so links can point
+/// at a single comment.
+#[post("/comment/{comment_id:\\d+}/")]
+async fn get_comment(
+    path: web::Path<i64>,
+    data: web::Form<GetCommentRequest>,
     state: web::Data<AppState>,
     req: HttpRequest,
-) -> web::Json<GetCommentsResponse> {
-    let query = r#"SELECT id, parent, ids.name AS poster_name, timestamp, comment, COALESCE(SUM(v1.vote),0) + 1 AS votes,
-                          COALESCE((SELECT v2.vote FROM votes v2 WHERE v2.voter_id = ? AND v2.comment_id = id), 0) AS myvote
-                          FROM comments
-                          LEFT JOIN ids on comments.commenter_id = ids.commenter_id
-                          LEFT JOIN votes v1 on comments.id = v1.comment_id
-                          WHERE article = ? AND id > 0 AND moderated = true
-                          GROUP BY comments.id
-                          ORDER BY timestamp ASC;"#;
+) -> Result<web::Json<GetCommentResponse>, Error> {
+    let comment_id = path.into_inner();
+
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new("/comment/", &[&comment_id.to_string()]),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let found = db::run(&state.db, move |db| {
+        let Some((article, comment)) = db.get_published_comment(comment_id)? else {
+            return Ok(None);
+        };
+
+        // A reply always has a higher id than its parent, so the walk can't loop.
+        let mut ancestors = vec![];
+        let mut below = comment.id;
+        let mut next = comment.parent;
+        while next > 0 && next < below {
+            let Some((_, ancestor)) = db.get_published_comment(next)? else {
+                break;
+            };
+            below = ancestor.id;
+            next = ancestor.parent;
+            ancestors.push(ancestor);
+        }
+        ancestors.reverse();
 
-    let mut response = GetCommentsResponse {
+        let replies = db.get_replies(comment_id)?;
+        Ok(Some((article, comment, ancestors, replies)))
+    })
+    .await
+    .map_err(Error::Database)?;
+
+    let Some((article, mut comment, mut ancestors, mut replies)) = found else {
+        return Err(Error::NotFound(String::from("No such comment")));
+    };
+
+    for comment in std::iter::once(&mut comment)
+        .chain(ancestors.iter_mut())
+        .chain(replies.iter_mut())
+    {
+        fill_display_fields(&state.config, comment);
+    }
+
+    Ok(web::Json(GetCommentResponse {
         code: 200,
         status: String::from("OK"),
-        comments: vec![],
+        article,
+        comment,
+        ancestors,
+        replies,
         challenge: None,
         key: None,
-    };
-
-    if let Some(result) = state.pow.handle(&get_client_ip(&req), &data.challenge, &data.secret) {
-        response.code = result.code;
-        response.status = result.status.unwrap_or(String::from(""));
-        response.challenge = result.challenge;
-        response.key = result.key;
+    }))
+}
 
-        return web::Json(response);
+#[post("/comment/edit/")]
+async fn edit_comment(
+    data: web::Form<EditCommentRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: session::Session,
+) -> Result<web::Json<EditCommentResponse>, Error> {
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new(
+            "/comment/edit/",
+            &[
+                &data.commenter_id,
+                &data.comment_id.to_string(),
+                &data.comment,
+            ],
+        ),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    check_comment_length(&state.config, &data.comment)?;
+
+    // As with filtered words, an edit can't be held, so one that adds too many links is refused.
+    if let Some(max) = state.config.max_links_auto_publish {
+        if antispam::count_links(&data.comment) > max {
+            return Err(Error::Forbidden(String::from(
+                "Comment contains too many links",
+            )));
+        }
     }
 
-    let Ok(sys_t) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) else {
-        response.code = 500;
-        response.status = String::from("Unable to get system time");
-        return web::Json(response);
+    let commenter_id = ammonia::clean(&session.or(&data.commenter_id));
+    let clean_comment_text = if !state.word_filter.is_match(&data.comment) {
+        html::clean_comment(&state.config, &data.comment)
+    } else {
+        match state.word_filter.action {
+            config::WordFilterAction::Mask => {
+                html::clean_comment(&state.config, &state.word_filter.mask(&data.comment))
+            }
+            // An edit can't send a published comment back to the moderation queue, so refuse it.
+            config::WordFilterAction::Hold => {
+                return Err(Error::Forbidden(String::from(
+                    "Comment contains filtered words",
+                )));
+            }
+        }
     };
 
-    let client_ip = get_client_ip(&req);
+    let sys_t = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::Internal(String::from("Could not generate timestamp")))?;
 
-    let Some(decoded_article) = base64_decode(data.article.clone()) else {
-        response.code = 500;
-        response.status = format!("Unable to decode supplied article id: {}", data.article);
-        return web::Json(response);
+    info!(
+        client_ip = get_client_ip(&req),
+        commenter_id,
+        comment_id = data.comment_id,
+        "Editing comment"
+    );
+
+    let comment_id = data.comment_id;
+    let edited_at = sys_t.as_secs() as i64;
+    let (owner, admin) = authorize_change(
+        &state,
+        &req,
+        &commenter_id,
+        comment_id,
+        state.config.edit_window_secs,
+        "edit",
+    )
+    .await?;
+
+    let previous = match &admin {
+        Some(_) => previous_text(&state, comment_id).await,
+        None => None,
     };
 
+    db::run(&state.db, move |db| {
+        db.edit_comment(comment_id, &owner, &clean_comment_text, edited_at)
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not edit comment: {e}")))?;
+
+    if let Some(admin) = admin {
+        audit::record(
+            &state,
+            &admin,
+            audit::EDIT,
+            comment_id.to_string(),
+            previous,
+        )
+        .await;
+    }
+
+    Ok(web::Json(EditCommentResponse {
+        code: 200,
+        status: String::from("OK"),
+        challenge: None,
+        key: None,
+    }))
+}
+
+/// Delete one of the commenter's comments. Comments with replies are kept as "[deleted]".
+#[post("/comment/delete/")]
+async fn delete_comment(
+    data: web::Form<DeleteCommentRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: session::Session,
+) -> Result<web::Json<DeleteCommentResponse>, Error> {
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new(
+            "/comment/delete/",
+            &[&data.commenter_id, &data.comment_id.to_string()],
+        ),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let commenter_id = ammonia::clean(&session.or(&data.commenter_id));
+    let comment_id = data.comment_id;
+
     info!(
-        "{} Getting comments for '{}' for client {}",
-        DateTime::from_timestamp(sys_t.as_secs() as i64, 0).unwrap(),
-        decoded_article,
-        client_ip
+        client_ip = get_client_ip(&req),
+        commenter_id, comment_id, "Deleting comment"
     );
 
-    match state.db_conn.lock() {
-        Ok(conn) => {
-            for row in conn
-                .prepare(query)
-                .unwrap()
-                .into_iter()
-                .bind((1, &data.commenter_id[..]))
-                .unwrap()
-                .bind((2, &data.article[..]))
-                .unwrap()
-                .map(|row| row.unwrap())
-            {
-                let mut parent: i64 = 0;
-                if let Some(cell) = row.read::<Option<i64>, _>("parent") {
-                    parent = cell;
-                }
+    let (_, admin) = authorize_change(
+        &state,
+        &req,
+        &commenter_id,
+        comment_id,
+        state.config.delete_window_secs,
+        "delete",
+    )
+    .await?;
+
+    let previous = match &admin {
+        Some(_) => previous_text(&state, comment_id).await,
+        None => None,
+    };
 
-                response.comments.push(Comment {
-                    id: row.read::<i64, _>("id"),
-                    timestamp: row.read::<i64, _>("timestamp"),
-                    parent,
-                    poster_name: String::from(row.read::<&str, _>("poster_name")),
-                    comment: String::from(row.read::<&str, _>("comment")),
-                    votes: row.read::<i64, _>("votes"),
-                    myvote: row.read::<i64, _>("myvote"),
-                });
-            }
-        }
-        Err(e) => {
-            response.code = 500;
-            response.status = format!("Database error: {:?}", e);
-            return web::Json(response);
+    let deleted = db::run(&state.db, move |db| db.delete_comment(comment_id))
+        .await
+        .map_err(|e| Error::Internal(format!("Could not delete comment: {e}")))?;
+
+    if !deleted {
+        return Err(Error::NotFound(String::from("No comment with that id")));
+    }
+    attachments::spawn_remove_unused(&state);
+
+    if let Some(admin) = admin {
+        audit::record(
+            &state,
+            &admin,
+            audit::DELETE,
+            comment_id.to_string(),
+            previous,
+        )
+        .await;
+    }
+
+    Ok(web::Json(DeleteCommentResponse {
+        code: 200,
+        status: String::from("OK"),
+        challenge: None,
+        key: None,
+    }))
+}
+
+/// Check that a comment may be edited or deleted, returning its owner and, if it's an admin
+/// making the change, their name. Authors may change their own comments until `window` seconds
+/// after posting them; admins with the moderator role or above may change any comment at any time.
+async fn authorize_change(
+    state: &web::Data<AppState>,
+    req: &HttpRequest,
+    commenter_id: &str,
+    comment_id: i64,
+    window: Option<u64>,
+    action: &str,
+) -> Result<(String, Option<String>), Error> {
+    let (owner, posted_at) = db::run(&state.db, move |db| db.get_comment_posted(comment_id))
+        .await
+        .map_err(Error::Database)?
+        .ok_or_else(|| Error::NotFound(String::from("No comment with that id")))?;
+
+    if let Some(account) = auth::bearer(&state.config, req) {
+        if account.role >= config::Role::Moderator {
+            return Ok((owner, Some(String::from(account.name))));
         }
     }
 
-    web::Json(response)
+    if owner != commenter_id {
+        return Err(Error::Forbidden(format!(
+            "You may only {action} your own comments"
+        )));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+    if window.is_some_and(|window| now - posted_at > window as i64) {
+        return Err(Error::Forbidden(format!(
+            "This comment can no longer be changed; ask a moderator to {action} it"
+        )));
+    }
+
+    Ok((owner, None))
+}
+
+/// A comment's text before an admin changes it, for the audit log.
+async fn previous_text(state: &web::Data<AppState>, comment_id: i64) -> Option<serde_json::Value> {
+    db::run(&state.db, move |db| db.get_comment_summary(comment_id))
+        .await
+        .ok()
+        .flatten()
+        .map(|summary| serde_json::json!({ "comment": summary.comment }))
 }
 
 #[post("/comment/vote/")]
@@ -486,100 +1141,142 @@ async fn vote(
     data: web::Form<VoteRequest>,
     state: web::Data<AppState>,
     req: HttpRequest,
-) -> web::Json<VoteResponse> {
-    let upsert_query = r#"INSERT INTO votes VALUES (?, ?, ?) ON CONFLICT(comment_id, voter_id) DO UPDATE SET vote = ?;"#;
-    let unvote_query = r#"DELETE FROM votes WHERE comment_id = ? AND voter_id = ?"#;
-
-    let voter_id = ammonia::clean(&data.voter_id[..]);
+    session: session::Session,
+) -> Result<web::Json<VoteResponse>, Error> {
+    let voter_id = ammonia::clean(&session.or(&data.voter_id));
     let comment_id = data.comment_id;
     let vote = data.vote;
 
-    let mut response = VoteResponse {
+    let response = VoteResponse {
         code: 200,
         status: String::from("OK"),
         challenge: None,
         key: None,
     };
 
-    if let Some(result) = state.pow.handle(&get_client_ip(&req), &data.challenge, &data.secret) {
-        response.code = result.code;
-        response.status = result.status.unwrap_or(String::from(""));
-        response.challenge = result.challenge;
-        response.key = result.key;
-
-        return web::Json(response);
-    }
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new(
+            "/comment/vote/",
+            &[
+                &data.voter_id,
+                &data.comment_id.to_string(),
+                &data.vote.to_string(),
+            ],
+        ),
+        &data.challenge,
+        &data.secret,
+    )?;
 
     if !(-1..=1).contains(&vote) {
-        response.code = 500;
-        response.status = String::from("Invalid vote");
-
-        return web::Json(response);
+        return Err(Error::BadRequest(String::from("Invalid vote")));
     }
 
-    let Ok(sys_t) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) else {
-        response.code = 500;
-        response.status = String::from("Could not generate timestamp");
-        return web::Json(response);
-    };
-
-    let client_ip = get_client_ip(&req);
+    match state.config.voting_mode {
+        config::VotingMode::Disabled => {
+            return Err(Error::Forbidden(String::from("Voting is disabled")));
+        }
+        config::VotingMode::UpOnly if vote < 0 => {
+            return Err(Error::Forbidden(String::from("Downvotes are disabled")));
+        }
+        _ => {}
+    }
 
     info!(
-        "{} Casting vote '{}' for commenter: '{}' for client {}",
-        DateTime::from_timestamp(sys_t.as_secs() as i64, 0).unwrap(),
+        client_ip = get_client_ip(&req),
+        commenter_id = voter_id,
+        comment_id,
         vote,
-        voter_id,
-        client_ip
+        "Casting vote"
     );
 
-    match state.db_conn.lock() {
-        Ok(conn) => {
-            let mut statement = if vote == 0 {
-                let mut statement = conn.prepare(unvote_query).unwrap();
-                statement.bind((1, comment_id)).unwrap();
-                statement.bind((2, &voter_id[..])).unwrap();
-
-                statement
-            } else {
-                let mut statement = conn.prepare(upsert_query).unwrap();
-                statement.bind((1, comment_id)).unwrap();
-                statement.bind((2, &voter_id[..])).unwrap();
-                statement.bind((3, vote)).unwrap();
-                statement.bind((4, vote)).unwrap();
-
-                statement
-            };
+    let client_ip = get_client_ip(&req);
+    let lookup_id = voter_id.clone();
+    let voter_email = db::run(&state.db, move |db| db.get_commenter(&lookup_id))
+        .await
+        .map_err(|e| Error::Internal(format!("Could not vote: {e}")))?
+        .map(|c| c.email)
+        .unwrap_or_default();
+
+    match state.blocklist.check(&client_ip, &voter_email, &[]) {
+        Some(blocklist::Action::Reject) => {
+            info!(client_ip, voter_id, "Blocklist rejected vote");
+            return Err(Error::Forbidden(String::from("Blocked")));
+        }
+        Some(blocklist::Action::Hold) => {
+            info!(client_ip, voter_id, "Blocklist dropped vote");
+            return Ok(web::Json(response));
+        }
+        None => {}
+    }
 
-            if let Err(e) = statement.next() {
-                response.code = 500;
-                response.status = format!("Could not vote: {e}");
-                web::Json(response)
-            } else {
-                web::Json(response)
-            }
+    let lock = db::run(&state.db, move |db| db.get_comment_article_lock(comment_id))
+        .await
+        .map_err(|e| Error::Internal(format!("Could not vote: {e}")))?;
+
+    if lock.voting_locked {
+        return Err(Error::Locked(String::from(
+            "Voting is closed on this article",
+        )));
+    }
+
+    let ip_hash = match (state.config.one_vote_per_ip, &state.config.vote_ip_secret) {
+        (true, Some(secret)) => Some(hash_ip(secret, &client_ip)),
+        _ => None,
+    };
+
+    let counted = db::run(&state.db, move |db| {
+        if vote == 0 {
+            return db.remove_vote(comment_id, &voter_id).map(|_| true);
         }
-        Err(e) => {
-            response.code = 500;
-            response.status = format!("DB Error: {:?}", e);
 
-            web::Json(response)
+        if let Some(ip_hash) = &ip_hash {
+            if db.ip_has_voted(comment_id, ip_hash, &voter_id)? {
+                return Ok(false);
+            }
         }
+
+        db.set_vote(comment_id, &voter_id, vote, ip_hash.as_deref())
+            .map(|_| true)
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not vote: {e}")))?;
+
+    if !counted {
+        info!(client_ip, comment_id, "Refused second vote from client IP");
+        return Err(Error::Conflict(String::from(
+            "A vote has already been cast from your address",
+        )));
     }
+
+    webhooks::dispatch(
+        &state,
+        webhooks::VOTE_CAST,
+        webhooks::VoteEvent { comment_id, vote },
+    );
+    live::votes_changed(&state, comment_id).await;
+
+    Ok(web::Json(response))
 }
 
+/// Challenges issued here are bound to `/pow/validate/` and can't authorize any other request.
 #[post("/pow/get/")]
 async fn get_pow(state: web::Data<AppState>, req: HttpRequest) -> web::Json<GetPowResponse> {
-    match state.pow.get_challenge(&get_client_ip(&req)) {
+    match state
+        .pow
+        .get_challenge(&get_client_ip(&req), pow::Binding::new("/pow/", &[]))
+    {
         Some(pow) => web::Json(GetPowResponse {
             code: 401,
             key: pow.key,
             challenge: pow.challenge,
+            algorithm: String::from(state.pow.algorithm()),
         }),
         None => web::Json(GetPowResponse {
             code: 300,
             key: String::from(""),
             challenge: String::from("Challenge not required."),
+            algorithm: String::from(state.pow.algorithm()),
         }),
     }
 }
@@ -589,70 +1286,743 @@ async fn validate_pow(
     data: web::Form<ValidatePowRequest>,
     state: web::Data<AppState>,
     req: HttpRequest,
-) -> web::Json<ValidatePowResponse> {
-    match state
+) -> Result<web::Json<ValidatePowResponse>, Error> {
+    state
         .pow
-        .validate_pow(&get_client_ip(&req), &data.challenge, &data.secret)
+        .validate_pow(
+            &get_client_ip(&req),
+            pow::Binding::new("/pow/", &[]),
+            &data.challenge,
+            &data.secret,
+        )
+        .map_err(Error::Forbidden)?;
+
+    Ok(web::Json(ValidatePowResponse {
+        code: 200,
+        status: String::from("OK"),
+    }))
+}
+
+/// Issue a new commenter ID for `name` and `email`, unless the blocklist rejects them.
+async fn new_commenter(
+    state: &web::Data<AppState>,
+    client_ip: &str,
+    name: &str,
+    email: &str,
+) -> Result<String, Error> {
+    let clean_name = ammonia::clean(name);
+    let clean_email = ammonia::clean(email);
+
+    check_identity(state, client_ip, None, &clean_name, &clean_email, false).await?;
+
+    let mut rand_bytes = [0u8; 32];
+    thread_rng().fill(&mut rand_bytes);
+
+    let commenter_id = hex::encode(rand_bytes);
+
+    info!(
+        client_ip,
+        commenter_id,
+        name = clean_name,
+        email = clean_email,
+        "Generating new ID"
+    );
+
+    let new_id = commenter_id.clone();
+    db::run(&state.db, move |db| {
+        db.add_commenter(&new_id, &clean_name, &clean_email)
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not insert new ID: {e}")))?;
+
+    Ok(commenter_id)
+}
+
+/// Refuse a name and email the email policy, name reservations, blocklist, or spam lists reject.
+/// Held commenters still get an id; it's their comments that are held. `commenter_id` is the
+/// commenter being renamed, if any, and `verified` whether a login provider vouches for them.
+async fn check_identity(
+    state: &web::Data<AppState>,
+    client_ip: &str,
+    commenter_id: Option<&str>,
+    name: &str,
+    email: &str,
+    verified: bool,
+) -> Result<(), Error> {
+    emailcheck::check(state, email).await?;
+
+    let author = commenter_id.is_some_and(|renamed| is_author_id(&state.config, renamed));
+
+    if !author
+        && matches!(
+            state.config.reserved_name_action,
+            config::ReservedNameAction::Reject
+        )
     {
-        Ok(_) => web::Json(ValidatePowResponse {
-            code: 200,
-            status: String::from("OK"),
-        }),
-        Err(e) => web::Json(ValidatePowResponse {
-            code: 500,
-            status: e,
-        }),
+        if let Some(conflict) = names::conflict(state, name, commenter_id, verified).await? {
+            info!(client_ip, name, "Refused reserved or impersonating name");
+            return Err(Error::Unprocessable(String::from(conflict.reason())));
+        }
+    }
+
+    if let Some(blocklist::Action::Reject) = state.blocklist.check(client_ip, email, &[name]) {
+        info!(client_ip, email, "Blocklist rejected ID");
+        return Err(Error::Forbidden(String::from("Blocked")));
+    }
+
+    // Likewise, only a rejecting spam list policy affects the id itself.
+    if matches!(
+        state.config.spam_list_action,
+        config::SpamListAction::Reject
+    ) {
+        if let Some(list) = spamlists::check(state, client_ip, Some(email)).await {
+            info!(client_ip, email, list, "Spam list rejected ID");
+            state.stats.record(stats::SPAM_BLOCKED);
+            return Err(Error::Forbidden(String::from("Blocked")));
+        }
     }
+
+    Ok(())
 }
 
-fn get_commenter_info(
-    conn: &MutexGuard<'_, sqlite::Connection>,
-    commenter_id: &str,
-) -> Option<(String, String)> {
-    let query = r#"SELECT name, email FROM ids WHERE commenter_id = ?"#;
+/// A comment to store, from the widget or the no-JavaScript thread page.
+struct Submission<'a> {
+    /// Base64, as sent by the widget.
+    article: &'a str,
+    commenter_id: String,
+    comment: &'a str,
+    parent: i64,
+    /// Hold the comment for moderation whatever else is decided about it, and throttle the alerts
+    /// about it, since it comes without proof-of-work.
+    hold: bool,
+    /// Follow the article's new comments by email.
+    subscribe: bool,
+}
 
-    if let Some(row) = conn
-        .prepare(query)
-        .unwrap()
-        .into_iter()
-        .bind((1, commenter_id))
-        .unwrap()
-        .map(|row| row.unwrap())
-        .next()
-    {
-        Some((
-            String::from(row.read::<&str, _>("name")),
-            String::from(row.read::<&str, _>("email")),
+/// What became of a stored comment.
+struct Posted {
+    /// Published, rather than held for moderation.
+    published: bool,
+    /// Names of the commenters it mentions.
+    mentions: Vec<String>,
+}
+
+/// Whether a comment is the site owner's: posted with one of `author_commenter_ids`, or with an
+/// owner account's token as a bearer token. Other admin roles post as anyone else does.
+fn is_author(config: &config::ConfigFile, req: &HttpRequest, commenter_id: &str) -> bool {
+    is_author_id(config, commenter_id)
+        || auth::bearer(config, req).is_some_and(|account| account.role == config::Role::Owner)
+}
+
+/// Whether `commenter_id` is one of `author_commenter_ids`.
+fn is_author_id(config: &config::ConfigFile, commenter_id: &str) -> bool {
+    config
+        .author_commenter_ids
+        .iter()
+        .any(|author_id| admin::constant_time_eq(author_id.as_bytes(), commenter_id.as_bytes()))
+}
+
+/// Check a comment against the length limits, the article's settings and the spam defences, then
+/// store it and send whatever notifications are due.
+async fn submit_comment(
+    state: &web::Data<AppState>,
+    req: &HttpRequest,
+    submission: Submission<'_>,
+) -> Result<Posted, Error> {
+    check_comment_length(&state.config, submission.comment)?;
+
+    let commenter_id = submission.commenter_id;
+    let filtered = state.word_filter.is_match(submission.comment);
+    let clean_comment_text = match (filtered, state.word_filter.action) {
+        (true, config::WordFilterAction::Mask) => {
+            html::clean_comment(&state.config, &state.word_filter.mask(submission.comment))
+        }
+        _ => html::clean_comment(&state.config, submission.comment),
+    };
+
+    let sys_t = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::Internal(String::from("Could not generate timestamp")))?;
+
+    let client_ip = get_client_ip(req);
+
+    let decoded_article = base64_decode(String::from(submission.article)).ok_or_else(|| {
+        Error::BadRequest(format!("Could not base64 decode '{}'", submission.article))
+    })?;
+
+    info!(
+        client_ip,
+        article = decoded_article,
+        commenter_id,
+        "Posting comment"
+    );
+
+    let article = String::from(submission.article);
+    let now = sys_t.as_secs() as i64;
+    let check_registered = state.articles.restricted();
+    let (lock, opened_at, registered) = db::run(&state.db, move |db| {
+        Ok((
+            db.get_article_lock(&article)?,
+            db.get_article_opened_at(&article)?,
+            check_registered && db.is_article_registered(&article)?,
         ))
-    } else {
-        info!("error getting commenter_id");
+    })
+    .await
+    .map_err(Error::Database)?;
+
+    if !state.articles.allows(&decoded_article, registered) {
+        info!(
+            client_ip,
+            article = decoded_article,
+            "Refusing comment on unregistered article"
+        );
+        return Err(Error::NotFound(String::from("Unknown article")));
+    }
+
+    if comments_closed(&state.config, &lock, opened_at, now) {
+        return Err(Error::Locked(String::from(
+            "Comments are closed on this article",
+        )));
+    }
+
+    let poster_id = commenter_id.clone();
+    let commenter = db::run(&state.db, move |db| db.get_commenter(&poster_id))
+        .await
+        .map_err(Error::Database)?
+        .ok_or_else(|| Error::Forbidden(String::from("Unknown commenter id")))?;
+
+    let score = state.reputation.poster_score(&client_ip, &commenter_id);
+    let trusted = state
+        .config
+        .auto_approve_after
+        .is_some_and(|n| commenter.approved_comments >= n)
+        || state.config.reputation_trust_at.is_some_and(|n| score >= n);
+    let mut moderated = !state.config.moderate_new_comments || trusted;
+
+    if state
+        .config
+        .reputation_hold_below
+        .is_some_and(|n| score < n)
+    {
+        info!(
+            client_ip,
+            commenter_id, score, "Held comment from poorly reputed poster for moderation"
+        );
+        moderated = false;
+    }
+
+    let author = is_author(&state.config, req, &commenter_id);
+
+    let country = state.geoip.country(&client_ip);
+    match state.geoip.verdict(country.as_deref()) {
+        geoip::Verdict::Block => {
+            info!(
+                client_ip,
+                commenter_id, country, "Refused comment by country"
+            );
+            record_spam(state, &client_ip, &commenter_id);
+            return Err(Error::Forbidden(String::from("Blocked")));
+        }
+        geoip::Verdict::Hold => {
+            info!(
+                client_ip,
+                commenter_id, country, "Held comment for moderation by country"
+            );
+            moderated = false;
+        }
+        geoip::Verdict::Accept => {}
+    }
+
+    match state.blocklist.check(
+        &client_ip,
+        &commenter.email,
+        &[&commenter.name, submission.comment],
+    ) {
+        Some(blocklist::Action::Reject) => {
+            info!(client_ip, commenter_id, "Blocklist rejected comment");
+            record_spam(state, &client_ip, &commenter_id);
+            return Err(Error::Forbidden(String::from("Blocked")));
+        }
+        Some(blocklist::Action::Hold) => {
+            info!(
+                client_ip,
+                commenter_id, "Blocklist held comment for moderation"
+            );
+            moderated = false;
+        }
+        None => {}
+    }
+
+    if !author
+        && matches!(
+            state.config.reserved_name_action,
+            config::ReservedNameAction::Hold
+        )
+        && names::conflict(
+            state,
+            &commenter.name,
+            Some(&commenter_id),
+            commenter.verified,
+        )
+        .await?
+        .is_some()
+    {
+        info!(
+            client_ip,
+            commenter_id, "Held comment posted under a reserved or impersonating name"
+        );
+        moderated = false;
+    }
+
+    if emailcheck::holds(state, &commenter.email) {
+        info!(
+            client_ip,
+            commenter_id, "Held comment from disposable email address for moderation"
+        );
+        moderated = false;
+    }
+
+    // A comment that's already held needn't wait on the lookups.
+    let spam_list_action = state.config.spam_list_action;
+    if moderated || matches!(spam_list_action, config::SpamListAction::Reject) {
+        if let Some(list) = spamlists::check(state, &client_ip, Some(&commenter.email)).await {
+            match spam_list_action {
+                config::SpamListAction::Hold => {
+                    info!(
+                        client_ip,
+                        commenter_id, list, "Held comment from listed spammer for moderation"
+                    );
+                    moderated = false;
+                }
+                config::SpamListAction::Reject => {
+                    info!(
+                        client_ip,
+                        commenter_id, list, "Rejected comment from listed spammer"
+                    );
+                    record_spam(state, &client_ip, &commenter_id);
+                    return Err(Error::Forbidden(String::from("Blocked")));
+                }
+            }
+        }
+    }
+
+    if filtered && matches!(state.word_filter.action, config::WordFilterAction::Hold) {
+        info!(
+            client_ip,
+            commenter_id, "Word filter held comment for moderation"
+        );
+        moderated = false;
+    }
+
+    if req.extensions().contains::<honeypot::Tripped>() {
+        info!(
+            client_ip,
+            commenter_id, "Held comment that filled in the honeypot for moderation"
+        );
+        moderated = false;
+    }
+
+    if submission.hold {
+        moderated = false;
+    }
+
+    if let Some(max) = state.config.max_links_auto_publish {
+        let links = antispam::count_links(submission.comment);
+        if links > max {
+            match state.config.max_links_action {
+                config::LinkLimitAction::Hold => {
+                    info!(
+                        client_ip,
+                        commenter_id, links, "Held comment with too many links for moderation"
+                    );
+                    moderated = false;
+                }
+                config::LinkLimitAction::Reject => {
+                    info!(
+                        client_ip,
+                        commenter_id, links, "Rejected comment with too many links"
+                    );
+                    record_spam(state, &client_ip, &commenter_id);
+                    return Err(Error::Forbidden(String::from(
+                        "Comment contains too many links",
+                    )));
+                }
+            }
+        }
+    }
+
+    if moderated && state.config.akismet_api_key.is_some() {
+        let user_agent = match req.headers().get("user-agent") {
+            Some(ua) => ua.to_str().unwrap_or(""),
+            None => "",
+        };
+
+        let check = antispam::SpamCheck {
+            article_url: &decoded_article,
+            client_ip: &client_ip,
+            user_agent,
+            name: &commenter.name,
+            email: &commenter.email,
+            comment: &clean_comment_text,
+        };
+
+        match antispam::is_spam(state, &check).await {
+            Ok(true) => {
+                info!(
+                    "Akismet flagged comment from '{commenter_id}' as spam; holding for moderation"
+                );
+                moderated = false;
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Unable to check comment for spam: {e}"),
+        }
+    }
+
+    let article = ammonia::clean(submission.article);
+    let parent = if submission.parent == 0 {
         None
+    } else {
+        Some(submission.parent)
+    };
+    let timestamp = sys_t.as_secs() as i64;
+
+    let (poster_id, text) = (commenter_id.clone(), clean_comment_text.clone());
+    let comment_id = db::run(&state.db, move |db| {
+        db.add_comment(&db::NewComment {
+            article: &article,
+            commenter_id: &poster_id,
+            parent,
+            comment: &text,
+            moderated,
+            timestamp,
+            country: country.as_deref(),
+            author,
+        })
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not add comment: {e}")))?;
+
+    if submission.subscribe {
+        subscriptions::subscribe(state, submission.article, &commenter_id, &commenter.email).await;
+    }
+
+    let mentioned = mentions::resolve(state, submission.article, &clean_comment_text).await;
+
+    webhooks::dispatch(
+        state,
+        webhooks::COMMENT_CREATED,
+        webhooks::CommentEvent {
+            id: comment_id,
+            article: &decoded_article,
+            parent,
+            poster_name: &commenter.name,
+            comment: &clean_comment_text,
+            published: moderated,
+        },
+    );
+
+    if moderated {
+        state
+            .reputation
+            .record(reputation::ip(&client_ip), reputation::Signal::Approved);
+        state.reputation.record(
+            reputation::commenter(&commenter_id),
+            reputation::Signal::Approved,
+        );
+        live::comment_published(state, comment_id).await;
+    }
+
+    let alert = notify::Alert::new(
+        notify::AlertKind::NewComment {
+            pending: !moderated,
+        },
+        &decoded_article,
+        &commenter.name,
+        &clean_comment_text,
+    );
+    if submission.hold {
+        notify::dispatch_throttled(state, alert);
+    } else {
+        notify::dispatch(state, alert);
+    }
+
+    let mut notified = HashSet::new();
+    if state.config.enable_email_notifications {
+        if let (true, Some(parent)) = (moderated, parent) {
+            match email::send_reply_notification(
+                state,
+                parent,
+                &commenter_id,
+                &decoded_article,
+                &commenter.name,
+                &clean_comment_text,
+            )
+            .await
+            {
+                Ok(Some(to)) => {
+                    notified.insert(to.to_lowercase());
+                }
+                Ok(None) => {}
+                Err(e) => info!("Unable to send reply notification email: {e}"),
+            }
+        }
+
+        if moderated {
+            notified.extend(
+                mentions::notify(
+                    state,
+                    &mentioned,
+                    &commenter_id,
+                    parent,
+                    &decoded_article,
+                    &commenter.name,
+                    &clean_comment_text,
+                )
+                .await,
+            );
+        }
+    }
+
+    // Subscribers asked for these themselves, so they don't depend on enable_email_notifications.
+    if moderated {
+        subscriptions::notify(
+            state,
+            submission.article,
+            &commenter_id,
+            notified,
+            &decoded_article,
+            &commenter.name,
+            &clean_comment_text,
+        )
+        .await;
+    }
+
+    Ok(Posted {
+        published: moderated,
+        mentions: mentions::names(&mentioned),
+    })
+}
+
+/// Count a rejected comment against its poster and their IP address.
+fn record_spam(state: &web::Data<AppState>, client_ip: &str, commenter_id: &str) {
+    state.stats.record(stats::SPAM_BLOCKED);
+    state
+        .reputation
+        .record(reputation::ip(client_ip), reputation::Signal::Spam);
+    state.reputation.record(
+        reputation::commenter(commenter_id),
+        reputation::Signal::Spam,
+    );
+}
+
+/// Whether an article has stopped taking new comments, either because it was locked or because it
+/// opened more than `close_after_days` ago.
+fn comments_closed(
+    config: &config::ConfigFile,
+    lock: &db::ArticleLock,
+    opened_at: Option<i64>,
+    now: i64,
+) -> bool {
+    if lock.locked {
+        return true;
+    }
+
+    match (config.close_after_days, opened_at) {
+        (Some(days), Some(opened_at)) => now - opened_at > days as i64 * 24 * 60 * 60,
+        _ => false,
+    }
+}
+
+/// Check `comment`, as submitted, against `max_comment_bytes` and `min_comment_chars`. Too long is
+/// a 413 and too short a 422, so the widget can tell them apart.
+fn check_comment_length(config: &config::ConfigFile, comment: &str) -> Result<(), Error> {
+    if let Some(max) = config.max_comment_bytes {
+        if comment.len() > max {
+            return Err(Error::TooLarge(format!(
+                "Comments may be at most {max} bytes"
+            )));
+        }
+    }
+
+    if let Some(min) = config.min_comment_chars {
+        if comment.trim().chars().count() < min {
+            return Err(Error::Unprocessable(format!(
+                "Comments must be at least {min} characters"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// actix-web's default limit on form bodies.
+const DEFAULT_FORM_LIMIT: usize = 16 * 1024;
+
+/// Room for a comment of `max_comment_bytes`, even if every byte is percent-encoded, alongside
+/// the other fields.
+fn form_limit(config: &config::ConfigFile) -> usize {
+    match config.max_comment_bytes {
+        Some(max) => DEFAULT_FORM_LIMIT.max(max.saturating_mul(3).saturating_add(4096)),
+        None => DEFAULT_FORM_LIMIT,
     }
 }
 
+/// How deeply replies nest in the tree format unless configured otherwise.
+const DEFAULT_TREE_MAX_DEPTH: usize = 5;
+
+/// Nests `comments` under their parents, keeping the order they were fetched in at each level.
+/// Replies deeper than `max_depth` hang off their ancestor at `max_depth`, and replies whose parent
+/// isn't in `comments` (e.g. it's on another page) are returned at the top level.
+fn nest_comments(comments: Vec<db::Comment>, max_depth: usize) -> Vec<db::Comment> {
+    let index: HashMap<i64, usize> = comments
+        .iter()
+        .enumerate()
+        .map(|(i, comment)| (comment.id, i))
+        .collect();
+
+    // Parents are always posted before their replies, so walking in id order sees each parent's
+    // attachment point before any of its children need it.
+    let mut by_id: Vec<usize> = (0..comments.len()).collect();
+    by_id.sort_by_key(|&i| comments[i].id);
+
+    let mut depth = vec![0; comments.len()];
+    let mut attach_to: Vec<Option<usize>> = vec![None; comments.len()];
+    for &i in &by_id {
+        let Some(&p) = index.get(&comments[i].parent) else {
+            continue;
+        };
+        if p == i {
+            continue;
+        }
+
+        if depth[p] < max_depth {
+            depth[i] = depth[p] + 1;
+            attach_to[i] = Some(p);
+        } else {
+            depth[i] = depth[p];
+            attach_to[i] = attach_to[p];
+        }
+    }
+
+    let mut children: Vec<Vec<usize>> = vec![vec![]; comments.len()];
+    let mut roots = vec![];
+    for (i, parent) in attach_to.iter().enumerate() {
+        match parent {
+            Some(p) => children[*p].push(i),
+            None => roots.push(i),
+        }
+    }
+
+    fn build(i: usize, slots: &mut [Option<db::Comment>], children: &[Vec<usize>]) -> db::Comment {
+        let mut comment = slots[i].take().expect("each comment is placed once");
+        comment.children = Some(
+            children[i]
+                .iter()
+                .map(|&c| build(c, slots, children))
+                .collect(),
+        );
+        comment
+    }
+
+    let mut slots: Vec<Option<db::Comment>> = comments.into_iter().map(Some).collect();
+    roots
+        .into_iter()
+        .map(|i| build(i, &mut slots, &children))
+        .collect()
+}
+
+/// Gravatar accepts a SHA-256 hash of the trimmed, lowercased address, so the email itself is never
+/// exposed. Commenters without an email get no avatar.
+fn gravatar_url(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    if email.is_empty() {
+        return None;
+    }
+
+    let hash = hex::encode(Sha256::digest(email.as_bytes()));
+    Some(format!(
+        "https://www.gravatar.com/avatar/{hash}?d=identicon"
+    ))
+}
+
+/// A keyed hash of a client IP, so votes can be matched by address without storing it.
+fn hash_ip(secret: &str, client_ip: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(client_ip.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn is_collapsed(config: &config::ConfigFile, comment: &db::Comment) -> bool {
+    match config.collapse_below_score {
+        Some(threshold) => !comment.pinned && comment.votes < threshold,
+        None => false,
+    }
+}
+
+/// The address a request came from. The forwarding headers are only believed when the connection
+/// comes from one of `trusted_proxies`, since anyone else can put whatever they like in them.
 fn get_client_ip(req: &HttpRequest) -> String {
-    if let Some(ip) = req.headers().get("x-forwarded-for") {
-        if let Ok(ip_str) = ip.to_str() {
-            return String::from(ip_str);
+    let Some(peer) = req.peer_addr().map(|addr| addr.ip().to_canonical()) else {
+        return String::from("");
+    };
+
+    let trusted = |ip: &IpAddr| {
+        req.app_data::<web::Data<AppState>>()
+            .is_some_and(|state| state.config.trusted_proxies.contains(ip))
+    };
+    if !trusted(&peer) {
+        return peer.to_string();
+    }
+
+    // Each proxy appends the address it heard from, so the client is the last one that isn't
+    // itself a trusted proxy.
+    if let Some(forwarded) = req.headers().get("x-forwarded-for") {
+        if let Ok(forwarded) = forwarded.to_str() {
+            let hops: Vec<&str> = forwarded.split(',').map(str::trim).collect();
+            let client = hops.iter().rev().find(|hop| {
+                hop.parse::<IpAddr>()
+                    .map_or(true, |ip| !trusted(&ip.to_canonical()))
+            });
+            if let Some(client) = client.or(hops.first()) {
+                return String::from(*client);
+            }
         }
     } else if let Some(ip) = req.headers().get("x-real-ip") {
         if let Ok(ip_str) = ip.to_str() {
-            return String::from(ip_str);
+            return String::from(ip_str.trim());
         }
-    } else if let Some(ip) = req.peer_addr() {
-        return ip.ip().to_string();
     }
 
-    String::from("")
+    peer.to_string()
+}
+
+/// The key a client's proof-of-work, rate-limit and reputation state is tracked under. Drops the
+/// port some proxies append, and collapses IPv6 addresses to their /64, since a single allocation
+/// gives an attacker a fresh address for every request. Anything that isn't an address is used
+/// as-is.
+fn normalize_ip(ip: &str) -> String {
+    let ip = ip.trim();
+    let addr = ip
+        .parse::<IpAddr>()
+        .or_else(|_| ip.parse::<SocketAddr>().map(|addr| addr.ip()));
+
+    match addr {
+        Ok(IpAddr::V4(v4)) => v4.to_string(),
+        Ok(IpAddr::V6(v6)) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => {
+                let s = v6.segments();
+                format!("{}/64", Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+            }
+        },
+        Err(_) => String::from(ip),
+    }
 }
 
 fn base64_decode(input: String) -> Option<String> {
     if let Ok(decode) = BASE64_STANDARD.decode(input) {
-        if let Ok(utf8) = String::from_utf8(decode) {
-            Some(utf8)
-        } else {
-            None
-        }
+        String::from_utf8(decode).ok()
     } else {
         None
     }
@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! `@name` mentions. A mention is an `@` followed by the name of someone with a published comment
+//! on the same article; anything else that looks like one is left alone. Mentioned commenters are
+//! emailed once the comment is published, unless they turned reply notifications off.
+
+use actix_web::web;
+use std::collections::HashSet;
+use tracing::info;
+
+use crate::db::{self, ReplyRecipient};
+use crate::{email, AppState};
+
+/// The commenters on `article` that `comment` mentions, in the order they are first mentioned.
+/// `comment` is the sanitized text as stored, and names are matched in the same form, ignoring
+/// case. Where one name is a prefix of another, the longer one wins.
+pub async fn resolve(
+    state: &web::Data<AppState>,
+    article: &str,
+    comment: &str,
+) -> Vec<ReplyRecipient> {
+    if !comment.contains('@') {
+        return vec![];
+    }
+
+    let thread = String::from(article);
+    let participants = match db::run(&state.db, move |db| db.get_thread_participants(&thread)).await
+    {
+        Ok(participants) => participants,
+        Err(e) => {
+            info!("Unable to look up participants to resolve mentions: {e}");
+            return vec![];
+        }
+    };
+
+    let keys: Vec<String> = participants
+        .iter()
+        .map(|participant| ammonia::clean_text(&participant.name).to_lowercase())
+        .collect();
+    let text = comment.to_lowercase();
+
+    let mut mentioned: Vec<&str> = vec![];
+    for (at, _) in text.match_indices('@') {
+        // Skip email addresses and the like.
+        if text[..at].chars().next_back().is_some_and(is_name_char) {
+            continue;
+        }
+
+        let rest = &text[at + 1..];
+        let longest = keys
+            .iter()
+            .filter(|key| {
+                !key.is_empty()
+                    && rest.starts_with(key.as_str())
+                    && !rest[key.len()..].chars().next().is_some_and(is_name_char)
+            })
+            .max_by_key(|key| key.len());
+
+        if let Some(key) = longest {
+            if !mentioned.contains(&key.as_str()) {
+                mentioned.push(key);
+            }
+        }
+    }
+
+    let mut recipients = vec![];
+    for key in mentioned {
+        recipients.extend(
+            participants
+                .iter()
+                .zip(&keys)
+                .filter(|(_, participant_key)| participant_key.as_str() == key)
+                .map(|(participant, _)| participant.clone()),
+        );
+    }
+
+    recipients
+}
+
+/// The names of `mentioned`, once each, for the post response.
+pub fn names(mentioned: &[ReplyRecipient]) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for recipient in mentioned {
+        if !names.contains(&recipient.name) {
+            names.push(recipient.name.clone());
+        }
+    }
+    names
+}
+
+/// Email everyone in `mentioned` about a newly published comment. The poster isn't told about
+/// mentioning themselves, and the author of `parent` already gets a reply notification, so they
+/// are skipped too. Several commenter IDs sharing an address get one email between them. Returns
+/// the addresses emailed, lowercased.
+pub async fn notify(
+    state: &web::Data<AppState>,
+    mentioned: &[ReplyRecipient],
+    poster_id: &str,
+    parent: Option<i64>,
+    url: &str,
+    poster_name: &str,
+    comment_text: &str,
+) -> HashSet<String> {
+    let parent_author = match parent {
+        Some(parent) => db::run(&state.db, move |db| db.get_comment_owner(parent))
+            .await
+            .ok()
+            .flatten(),
+        None => None,
+    };
+
+    let mut sent = HashSet::new();
+    for recipient in mentioned {
+        if !recipient.reply_notifications
+            || recipient.email.is_empty()
+            || recipient.commenter_id == poster_id
+            || parent_author.as_deref() == Some(recipient.commenter_id.as_str())
+            || !sent.insert(recipient.email.to_lowercase())
+        {
+            continue;
+        }
+
+        if let Err(e) =
+            email::send_mention_notification(state, recipient, url, poster_name, comment_text).await
+        {
+            info!("Unable to send mention notification email: {e}");
+        }
+    }
+
+    sent
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
@@ -0,0 +1,574 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use tracing::{info, warn};
+
+use crate::db::Storage;
+
+/// A single schema change. Each migration carries the equivalent SQL for every supported backend
+/// and is applied at most once, in version order.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sqlite: &'static str,
+    pub postgres: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sqlite: r#"
+CREATE TABLE IF NOT EXISTS ids (commenter_id TEXT UNIQUE,
+                                name TEXT,
+                                email TEXT,
+                                PRIMARY KEY(commenter_id)
+);
+
+CREATE TABLE IF NOT EXISTS comments (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                                     commenter_id TEXT NOT NULL,
+                                     timestamp INTEGER NOT NULL,
+                                     article TEXT NOT NULL,
+                                     parent INTEGER REFERENCES comments(id) DEFAULT NULL,
+                                     moderated BOOL DEFAULT false,
+                                     comment TEXT NOT NULL,
+                                     FOREIGN KEY(commenter_id) REFERENCES ids(commenter_id)
+);
+
+CREATE TABLE IF NOT EXISTS votes (comment_id INTEGER REFERENCES comments(id),
+                                  voter_id TEXT REFERENCES ids(commenter_id),
+                                  vote INTEGER NOT NULL,
+                                  UNIQUE(comment_id, voter_id),
+                                  FOREIGN KEY(comment_id) REFERENCES comments(id),
+                                  FOREIGN KEY(voter_id) REFERENCES ids(commenter_id)
+);
+"#,
+        postgres: r#"
+CREATE TABLE IF NOT EXISTS ids (commenter_id TEXT UNIQUE,
+                                name TEXT,
+                                email TEXT,
+                                PRIMARY KEY(commenter_id)
+);
+
+CREATE TABLE IF NOT EXISTS comments (id BIGSERIAL PRIMARY KEY,
+                                     commenter_id TEXT NOT NULL,
+                                     timestamp BIGINT NOT NULL,
+                                     article TEXT NOT NULL,
+                                     parent BIGINT REFERENCES comments(id) DEFAULT NULL,
+                                     moderated BOOLEAN DEFAULT false,
+                                     comment TEXT NOT NULL,
+                                     FOREIGN KEY(commenter_id) REFERENCES ids(commenter_id)
+);
+
+CREATE TABLE IF NOT EXISTS votes (comment_id BIGINT REFERENCES comments(id),
+                                  voter_id TEXT REFERENCES ids(commenter_id),
+                                  vote INTEGER NOT NULL,
+                                  UNIQUE(comment_id, voter_id),
+                                  FOREIGN KEY(comment_id) REFERENCES comments(id),
+                                  FOREIGN KEY(voter_id) REFERENCES ids(commenter_id)
+);
+"#,
+    },
+    Migration {
+        version: 2,
+        description: "comment edit timestamps",
+        sqlite: r#"ALTER TABLE comments ADD COLUMN edited_at INTEGER DEFAULT NULL;"#,
+        postgres: r#"ALTER TABLE comments ADD COLUMN edited_at BIGINT DEFAULT NULL;"#,
+    },
+    Migration {
+        version: 3,
+        description: "reply notification opt-out",
+        sqlite: r#"ALTER TABLE ids ADD COLUMN reply_notifications BOOL NOT NULL DEFAULT true;"#,
+        postgres: r#"ALTER TABLE ids ADD COLUMN reply_notifications BOOLEAN NOT NULL DEFAULT true;"#,
+    },
+    Migration {
+        version: 4,
+        description: "outgoing email queue",
+        sqlite: r#"
+CREATE TABLE email_queue (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                          recipient TEXT NOT NULL,
+                          subject TEXT NOT NULL,
+                          body TEXT NOT NULL,
+                          attempts INTEGER NOT NULL DEFAULT 0,
+                          next_attempt_at INTEGER NOT NULL,
+                          last_error TEXT DEFAULT NULL,
+                          created_at INTEGER NOT NULL
+);
+"#,
+        postgres: r#"
+CREATE TABLE email_queue (id BIGSERIAL PRIMARY KEY,
+                          recipient TEXT NOT NULL,
+                          subject TEXT NOT NULL,
+                          body TEXT NOT NULL,
+                          attempts BIGINT NOT NULL DEFAULT 0,
+                          next_attempt_at BIGINT NOT NULL,
+                          last_error TEXT DEFAULT NULL,
+                          created_at BIGINT NOT NULL
+);
+"#,
+    },
+    Migration {
+        version: 5,
+        description: "OAuth commenter identities",
+        sqlite: r#"
+ALTER TABLE ids ADD COLUMN oauth_provider TEXT DEFAULT NULL;
+ALTER TABLE ids ADD COLUMN oauth_subject TEXT DEFAULT NULL;
+ALTER TABLE ids ADD COLUMN verified BOOL NOT NULL DEFAULT false;
+CREATE UNIQUE INDEX ids_oauth_identity ON ids (oauth_provider, oauth_subject);
+"#,
+        postgres: r#"
+ALTER TABLE ids ADD COLUMN oauth_provider TEXT DEFAULT NULL;
+ALTER TABLE ids ADD COLUMN oauth_subject TEXT DEFAULT NULL;
+ALTER TABLE ids ADD COLUMN verified BOOLEAN NOT NULL DEFAULT false;
+CREATE UNIQUE INDEX ids_oauth_identity ON ids (oauth_provider, oauth_subject);
+"#,
+    },
+    Migration {
+        version: 6,
+        description: "persistent proof-of-work state",
+        sqlite: r#"
+CREATE TABLE pow_challenges (challenge TEXT PRIMARY KEY,
+                             client_ip TEXT NOT NULL,
+                             key TEXT NOT NULL
+);
+
+CREATE TABLE pow_transactions (client_ip TEXT NOT NULL,
+                               at_ms INTEGER NOT NULL
+);
+"#,
+        postgres: r#"
+CREATE TABLE pow_challenges (challenge TEXT PRIMARY KEY,
+                             client_ip TEXT NOT NULL,
+                             key TEXT NOT NULL
+);
+
+CREATE TABLE pow_transactions (client_ip TEXT NOT NULL,
+                               at_ms BIGINT NOT NULL
+);
+"#,
+    },
+    Migration {
+        version: 7,
+        description: "proof-of-work challenge expiry",
+        sqlite: r#"ALTER TABLE pow_challenges ADD COLUMN issued_at_ms INTEGER NOT NULL DEFAULT 0;"#,
+        postgres: r#"ALTER TABLE pow_challenges ADD COLUMN issued_at_ms BIGINT NOT NULL DEFAULT 0;"#,
+    },
+    Migration {
+        version: 8,
+        description: "full-text comment search",
+        sqlite: r#"
+CREATE VIRTUAL TABLE comments_fts USING fts5(comment, poster_name);
+
+INSERT INTO comments_fts (rowid, comment, poster_name)
+    SELECT comments.id, comments.comment, COALESCE(ids.name, '')
+    FROM comments LEFT JOIN ids ON comments.commenter_id = ids.commenter_id
+    WHERE comments.id > 0;
+
+CREATE TRIGGER comments_fts_insert AFTER INSERT ON comments BEGIN
+    INSERT INTO comments_fts (rowid, comment, poster_name)
+        SELECT new.id, new.comment, COALESCE((SELECT name FROM ids WHERE commenter_id = new.commenter_id), '');
+END;
+
+CREATE TRIGGER comments_fts_update AFTER UPDATE OF comment ON comments BEGIN
+    UPDATE comments_fts SET comment = new.comment WHERE rowid = new.id;
+END;
+
+CREATE TRIGGER comments_fts_delete AFTER DELETE ON comments BEGIN
+    DELETE FROM comments_fts WHERE rowid = old.id;
+END;
+"#,
+        postgres: r#"CREATE INDEX comments_fts ON comments USING GIN (to_tsvector('simple', comment));"#,
+    },
+    Migration {
+        version: 9,
+        description: "pinned comments",
+        sqlite: r#"ALTER TABLE comments ADD COLUMN pinned BOOL NOT NULL DEFAULT false;"#,
+        postgres: r#"ALTER TABLE comments ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT false;"#,
+    },
+    Migration {
+        version: 10,
+        description: "per-article settings",
+        sqlite: r#"
+CREATE TABLE articles (article TEXT PRIMARY KEY,
+                       locked BOOL NOT NULL DEFAULT false,
+                       voting_locked BOOL NOT NULL DEFAULT false
+);
+"#,
+        postgres: r#"
+CREATE TABLE articles (article TEXT PRIMARY KEY,
+                       locked BOOLEAN NOT NULL DEFAULT false,
+                       voting_locked BOOLEAN NOT NULL DEFAULT false
+);
+"#,
+    },
+    Migration {
+        version: 11,
+        description: "article publish dates",
+        sqlite: r#"ALTER TABLE articles ADD COLUMN published_at INTEGER DEFAULT NULL;"#,
+        postgres: r#"ALTER TABLE articles ADD COLUMN published_at BIGINT DEFAULT NULL;"#,
+    },
+    Migration {
+        version: 12,
+        description: "blocklist",
+        sqlite: r#"
+CREATE TABLE blocklist (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        kind TEXT NOT NULL,
+                        pattern TEXT NOT NULL,
+                        action TEXT NOT NULL,
+                        created_at INTEGER NOT NULL,
+                        UNIQUE(kind, pattern)
+);
+"#,
+        postgres: r#"
+CREATE TABLE blocklist (id BIGSERIAL PRIMARY KEY,
+                        kind TEXT NOT NULL,
+                        pattern TEXT NOT NULL,
+                        action TEXT NOT NULL,
+                        created_at BIGINT NOT NULL,
+                        UNIQUE(kind, pattern)
+);
+"#,
+    },
+    Migration {
+        version: 13,
+        description: "ids.approved_comments",
+        sqlite: r#"
+ALTER TABLE ids ADD COLUMN approved_comments INTEGER NOT NULL DEFAULT 0;
+UPDATE ids SET approved_comments = (SELECT COUNT(*) FROM comments
+                                    WHERE comments.commenter_id = ids.commenter_id
+                                      AND comments.moderated = true);
+"#,
+        postgres: r#"
+ALTER TABLE ids ADD COLUMN approved_comments BIGINT NOT NULL DEFAULT 0;
+UPDATE ids SET approved_comments = (SELECT COUNT(*) FROM comments
+                                    WHERE comments.commenter_id = ids.commenter_id
+                                      AND comments.moderated = true);
+"#,
+    },
+    Migration {
+        version: 14,
+        description: "email_digest",
+        sqlite: r#"
+CREATE TABLE email_digest (id INTEGER PRIMARY KEY CHECK (id = 1),
+                           last_comment_id INTEGER NOT NULL,
+                           sent_at INTEGER
+);
+INSERT INTO email_digest (id, last_comment_id) SELECT 1, COALESCE(MAX(id), 0) FROM comments;
+"#,
+        postgres: r#"
+CREATE TABLE email_digest (id INTEGER PRIMARY KEY CHECK (id = 1),
+                           last_comment_id BIGINT NOT NULL,
+                           sent_at BIGINT
+);
+INSERT INTO email_digest (id, last_comment_id) SELECT 1, COALESCE(MAX(id), 0) FROM comments;
+"#,
+    },
+    Migration {
+        version: 15,
+        description: "article registration",
+        sqlite: r#"ALTER TABLE articles ADD COLUMN registered BOOL NOT NULL DEFAULT false;"#,
+        postgres: r#"ALTER TABLE articles ADD COLUMN registered BOOLEAN NOT NULL DEFAULT false;"#,
+    },
+    Migration {
+        version: 16,
+        description: "article titles",
+        sqlite: r#"
+CREATE TABLE article_titles (url TEXT PRIMARY KEY,
+                             title TEXT,
+                             fetched_at INTEGER NOT NULL
+);
+"#,
+        postgres: r#"
+CREATE TABLE article_titles (url TEXT PRIMARY KEY,
+                             title TEXT,
+                             fetched_at BIGINT NOT NULL
+);
+"#,
+    },
+    Migration {
+        version: 17,
+        description: "comment flags",
+        sqlite: r#"
+CREATE TABLE flags (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    comment_id INTEGER NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+                    flagger_id TEXT NOT NULL REFERENCES ids(commenter_id) ON DELETE CASCADE,
+                    reason TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    UNIQUE(comment_id, flagger_id)
+);
+"#,
+        postgres: r#"
+CREATE TABLE flags (id BIGSERIAL PRIMARY KEY,
+                    comment_id BIGINT NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+                    flagger_id TEXT NOT NULL REFERENCES ids(commenter_id) ON DELETE CASCADE,
+                    reason TEXT NOT NULL,
+                    created_at BIGINT NOT NULL,
+                    UNIQUE(comment_id, flagger_id)
+);
+"#,
+    },
+    Migration {
+        version: 18,
+        description: "hashed voter IPs",
+        sqlite: r#"
+ALTER TABLE votes ADD COLUMN ip_hash TEXT DEFAULT NULL;
+CREATE INDEX votes_ip_hash ON votes (comment_id, ip_hash);
+"#,
+        postgres: r#"
+ALTER TABLE votes ADD COLUMN ip_hash TEXT DEFAULT NULL;
+CREATE INDEX votes_ip_hash ON votes (comment_id, ip_hash);
+"#,
+    },
+    Migration {
+        version: 19,
+        description: "reputation",
+        sqlite: r#"
+CREATE TABLE reputation (subject TEXT PRIMARY KEY,
+                         approved INTEGER NOT NULL DEFAULT 0,
+                         spam INTEGER NOT NULL DEFAULT 0,
+                         pow_failures INTEGER NOT NULL DEFAULT 0,
+                         flags INTEGER NOT NULL DEFAULT 0,
+                         updated_at INTEGER NOT NULL
+);
+"#,
+        postgres: r#"
+CREATE TABLE reputation (subject TEXT PRIMARY KEY,
+                         approved BIGINT NOT NULL DEFAULT 0,
+                         spam BIGINT NOT NULL DEFAULT 0,
+                         pow_failures BIGINT NOT NULL DEFAULT 0,
+                         flags BIGINT NOT NULL DEFAULT 0,
+                         updated_at BIGINT NOT NULL
+);
+"#,
+    },
+    Migration {
+        version: 20,
+        description: "thread subscriptions",
+        sqlite: r#"
+CREATE TABLE subscriptions (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                            article TEXT NOT NULL,
+                            commenter_id TEXT NOT NULL REFERENCES ids(commenter_id) ON DELETE CASCADE,
+                            created_at INTEGER NOT NULL,
+                            UNIQUE(article, commenter_id)
+);
+"#,
+        postgres: r#"
+CREATE TABLE subscriptions (id BIGSERIAL PRIMARY KEY,
+                            article TEXT NOT NULL,
+                            commenter_id TEXT NOT NULL REFERENCES ids(commenter_id) ON DELETE CASCADE,
+                            created_at BIGINT NOT NULL,
+                            UNIQUE(article, commenter_id)
+);
+"#,
+    },
+    Migration {
+        version: 21,
+        description: "commenter creation times",
+        // Existing commenters are dated to the upgrade, so retention counts their idle time from
+        // then rather than removing them all at once.
+        sqlite: r#"
+ALTER TABLE ids ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+UPDATE ids SET created_at = strftime('%s', 'now');
+"#,
+        postgres: r#"
+ALTER TABLE ids ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+UPDATE ids SET created_at = EXTRACT(EPOCH FROM NOW())::BIGINT;
+"#,
+    },
+    Migration {
+        version: 22,
+        description: "daily event counts",
+        sqlite: r#"
+CREATE TABLE daily_counts (day INTEGER NOT NULL,
+                           kind TEXT NOT NULL,
+                           count INTEGER NOT NULL,
+                           PRIMARY KEY(day, kind)
+);
+"#,
+        postgres: r#"
+CREATE TABLE daily_counts (day BIGINT NOT NULL,
+                           kind TEXT NOT NULL,
+                           count BIGINT NOT NULL,
+                           PRIMARY KEY(day, kind)
+);
+"#,
+    },
+    Migration {
+        version: 23,
+        description: "audit log",
+        // The log is append-only: the database refuses to change or delete entries.
+        sqlite: r#"
+CREATE TABLE audit_log (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        at INTEGER NOT NULL,
+                        actor TEXT NOT NULL,
+                        action TEXT NOT NULL,
+                        target TEXT NOT NULL,
+                        previous TEXT
+);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+"#,
+        postgres: r#"
+CREATE TABLE audit_log (id BIGSERIAL PRIMARY KEY,
+                        at BIGINT NOT NULL,
+                        actor TEXT NOT NULL,
+                        action TEXT NOT NULL,
+                        target TEXT NOT NULL,
+                        previous TEXT
+);
+
+CREATE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+"#,
+    },
+    Migration {
+        version: 24,
+        description: "comment countries",
+        sqlite: r#"
+ALTER TABLE comments ADD COLUMN country TEXT DEFAULT NULL;
+"#,
+        postgres: r#"
+ALTER TABLE comments ADD COLUMN country TEXT DEFAULT NULL;
+"#,
+    },
+    Migration {
+        version: 25,
+        description: "author comments",
+        sqlite: r#"ALTER TABLE comments ADD COLUMN author BOOL NOT NULL DEFAULT false;"#,
+        postgres: r#"ALTER TABLE comments ADD COLUMN author BOOLEAN NOT NULL DEFAULT false;"#,
+    },
+    Migration {
+        version: 26,
+        description: "comment change times",
+        sqlite: r#"
+ALTER TABLE comments ADD COLUMN changed_at INTEGER DEFAULT NULL;
+ALTER TABLE comments ADD COLUMN voted_at INTEGER DEFAULT NULL;
+"#,
+        postgres: r#"
+ALTER TABLE comments ADD COLUMN changed_at BIGINT DEFAULT NULL;
+ALTER TABLE comments ADD COLUMN voted_at BIGINT DEFAULT NULL;
+"#,
+    },
+    Migration {
+        version: 27,
+        description: "indexes for comment and vote lookups",
+        sqlite: r#"
+CREATE INDEX IF NOT EXISTS comments_article ON comments (article, moderated, timestamp);
+CREATE INDEX IF NOT EXISTS votes_comment ON votes (comment_id);
+CREATE INDEX IF NOT EXISTS votes_voter ON votes (voter_id, comment_id);
+"#,
+        postgres: r#"
+CREATE INDEX IF NOT EXISTS comments_article ON comments (article, moderated, timestamp);
+CREATE INDEX IF NOT EXISTS votes_comment ON votes (comment_id);
+CREATE INDEX IF NOT EXISTS votes_voter ON votes (voter_id, comment_id);
+"#,
+    },
+    Migration {
+        version: 28,
+        description: "comment attachments",
+        sqlite: r#"
+CREATE TABLE attachments (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                          comment_id INTEGER NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+                          name TEXT NOT NULL,
+                          content_type TEXT NOT NULL,
+                          size INTEGER NOT NULL,
+                          created_at INTEGER NOT NULL
+);
+CREATE INDEX attachments_comment ON attachments (comment_id, id);
+"#,
+        postgres: r#"
+CREATE TABLE attachments (id BIGSERIAL PRIMARY KEY,
+                          comment_id BIGINT NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+                          name TEXT NOT NULL,
+                          content_type TEXT NOT NULL,
+                          size BIGINT NOT NULL,
+                          created_at BIGINT NOT NULL
+);
+CREATE INDEX attachments_comment ON attachments (comment_id, id);
+"#,
+    },
+    Migration {
+        version: 29,
+        description: "removed attachments",
+        sqlite: r#"
+CREATE TABLE removed_attachments (name TEXT NOT NULL);
+CREATE INDEX attachments_name ON attachments (name);
+CREATE TRIGGER attachments_removed AFTER DELETE ON attachments BEGIN
+    INSERT INTO removed_attachments (name) VALUES (old.name);
+END;
+"#,
+        postgres: r#"
+CREATE TABLE removed_attachments (name TEXT NOT NULL);
+CREATE INDEX attachments_name ON attachments (name);
+
+CREATE FUNCTION attachments_removed() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO removed_attachments (name) VALUES (OLD.name);
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER attachments_removed AFTER DELETE ON attachments
+    FOR EACH ROW EXECUTE FUNCTION attachments_removed();
+"#,
+    },
+];
+
+/// Bring the database schema up to date, applying any migrations newer than the recorded
+/// schema version.
+pub fn run(db: &dyn Storage) -> Result<(), String> {
+    let current = db.schema_version()?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!(
+            "Applying schema migration {}: {}",
+            migration.version, migration.description
+        );
+        db.apply_migration(migration)?;
+    }
+
+    Ok(())
+}
+
+/// Warn about comment listing queries that read a whole table, which happens when an index created
+/// by the migrations above has been dropped or isn't used. Run at startup in debug mode.
+pub fn check_indexes(db: &dyn Storage) -> Result<(), String> {
+    for scan in db.full_scans()? {
+        warn!("Query plan reads a whole table; is an index missing? {scan}");
+    }
+
+    Ok(())
+}
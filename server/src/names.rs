@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Names commenters can't take: those the site owner reserves with `reserved_names`, and
+//! lookalikes of names held by verified commenters. Names are compared by their skeleton, which
+//! folds case, accents, common homoglyphs and punctuation, so "Ädmin", "ADMIN" and "аdmin" with a
+//! Cyrillic "а" all collide with "admin".
+
+use actix_web::web;
+use std::collections::HashSet;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::config::ConfigFile;
+use crate::db;
+use crate::error::Error;
+use crate::AppState;
+
+/// Why a commenter can't use a name.
+#[derive(Clone, Copy)]
+pub enum Conflict {
+    /// It matches one of `reserved_names`.
+    Reserved,
+    /// It looks like the name of another, verified, commenter.
+    Verified,
+}
+
+impl Conflict {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Conflict::Reserved => "That name is reserved",
+            Conflict::Verified => "That name belongs to a verified commenter",
+        }
+    }
+}
+
+pub struct ReservedNames {
+    skeletons: HashSet<String>,
+}
+
+impl ReservedNames {
+    pub fn new_from_config(config: &ConfigFile) -> Self {
+        ReservedNames {
+            skeletons: config
+                .reserved_names
+                .iter()
+                .map(|name| skeleton(name))
+                .filter(|skeleton| !skeleton.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        !self.skeletons.is_empty() && self.skeletons.contains(&skeleton(name))
+    }
+}
+
+/// Whether `name` is reserved or looks like a verified commenter's. `commenter_id`, when given, is
+/// the commenter taking the name, whose own verified name doesn't count. Verified commenters
+/// aren't compared against each other, since their provider vouches for them.
+pub async fn conflict(
+    state: &web::Data<AppState>,
+    name: &str,
+    commenter_id: Option<&str>,
+    verified: bool,
+) -> Result<Option<Conflict>, Error> {
+    if state.reserved_names.contains(name) {
+        return Ok(Some(Conflict::Reserved));
+    }
+
+    let skeleton = skeleton(name);
+    if verified || skeleton.is_empty() {
+        return Ok(None);
+    }
+
+    let holders = db::run(&state.db, |db| db.verified_names())
+        .await
+        .map_err(Error::Database)?;
+
+    let taken = holders.iter().any(|(holder_id, holder_name)| {
+        commenter_id != Some(holder_id.as_str()) && self::skeleton(holder_name) == skeleton
+    });
+
+    Ok(taken.then_some(Conflict::Verified))
+}
+
+/// `name` reduced to what it looks like: decomposed, stripped of accents, lowercased, with
+/// homoglyphs replaced by the letter they pass for and anything but letters and digits dropped.
+pub fn skeleton(name: &str) -> String {
+    let folded: String = name
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(homoglyph)
+        .filter(|c| c.is_alphanumeric())
+        .collect();
+
+    folded.replace("rn", "m").replace("vv", "w")
+}
+
+/// The Latin letter `c` is easily mistaken for, or `c` itself.
+fn homoglyph(c: char) -> char {
+    match c {
+        'а' | 'α' | '@' => 'a',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | '3' => 'e',
+        'һ' => 'h',
+        // Lowercase i and l, uppercase I, and 1 are hard to tell apart in many fonts.
+        'i' | 'ı' | 'і' | 'ι' | 'ӏ' | '1' | '|' | '!' => 'l',
+        'ј' => 'j',
+        'κ' | 'к' => 'k',
+        'м' => 'm',
+        'о' | 'ο' | '0' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' | '5' | '$' => 's',
+        'т' | 'τ' => 't',
+        'ս' => 'u',
+        'ν' | 'ѵ' => 'v',
+        'ԝ' => 'w',
+        'х' | 'χ' => 'x',
+        'у' => 'y',
+        _ => c,
+    }
+}
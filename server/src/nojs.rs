@@ -0,0 +1,403 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Thread pages for readers without JavaScript: `/thread/{article}` renders a thread as plain
+//! HTML and takes new comments from an ordinary form, redirecting back to the page afterwards.
+//! Enabled by `enable_thread_pages`.
+//!
+//! The form can't solve proof-of-work challenges, so everything posted through it is held for
+//! moderation, each address may only post a few comments a minute, and the site owner hears about
+//! them at most every few minutes. Readers keep one commenter ID in their session cookie, so
+//! their reputation builds up as it would in the widget.
+
+use actix_web::cookie::Cookie;
+use actix_web::http::header::{self, ContentType, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tera::{Context, Tera};
+use tracing::{info, warn};
+
+use crate::config::HoneypotAction;
+use crate::error::Error;
+use crate::ratelimit::RateLimitConfig;
+use crate::{
+    base64_decode, check_identity, dashboard, db, get_client_ip, honeypot, new_commenter,
+    normalize_ip, session, submit_comment, thread_response, titles, AppState, Submission,
+};
+use tinycomments_types::{CommentFormat, CommentSort, GetCommentsRequest};
+
+/// Posts are limited under this key rather than the route's pattern, which reading a thread
+/// shares.
+const RATE_LIMIT_KEY: &str = "POST /thread/{article:.+}";
+
+/// The limit on posts when `thread_page_rate_limit` isn't set.
+const DEFAULT_RATE_LIMIT: RateLimitConfig = RateLimitConfig {
+    burst: 3,
+    per_minute: 1,
+};
+
+const THREAD_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Comments on {{ title }}</title>
+<style>
+body { font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; color: #222; }
+.comment { border-left: 2px solid #ddd; padding: 0.2em 0 0.2em 0.8em; margin: 1em 0; }
+.meta { color: #666; font-size: 0.9em; }
+.meta img { vertical-align: middle; width: 24px; height: 24px; }
+.notice { background: #ffd; padding: 0.5em; }
+.trap { display: none; }
+label { display: block; margin: 0.5em 0; }
+input, textarea { width: 100%; max-width: 30em; }
+input[type=checkbox] { width: auto; }
+</style></head>
+<body>
+<h1>Comments on {% if url is starting_with("http") %}<a href="{{ url }}">{{ title }}</a>{% else %}{{ title }}{% endif %}</h1>
+
+{% macro comment(comment) %}
+<div class="comment" id="comment-{{ comment.id }}">
+{% if comment.collapsed %}<details><summary>{{ comment.poster_name | safe }}: hidden for its score</summary>{% endif %}
+<p class="meta">{% if comment.avatar_url %}<img src="{{ comment.avatar_url }}" alt=""> {% endif %}<strong>{{ comment.poster_name | safe }}</strong>
+&middot; {{ comment.timestamp | datetime }}{% if comment.edited_at %} (edited){% endif %}
+&middot; {{ comment.votes }} point{{ comment.votes | pluralize }}{% if comment.is_author %} &middot; author{% endif %}{% if comment.pinned %} &middot; pinned{% endif %}
+{% if not locked %}&middot; <a href="?reply={{ comment.id }}#post">Reply</a>{% endif %}</p>
+<div>{% if comment.comment_html %}{{ comment.comment_html | safe }}{% else %}{{ comment.comment | safe }}{% endif %}</div>
+{% if comment.collapsed %}</details>{% endif %}
+{% for child in comment.children | default(value=[]) %}{{ self::comment(comment=child) }}{% endfor %}
+</div>
+{% endmacro %}
+
+{% if comments %}
+{% for comment in comments %}{{ self::comment(comment=comment) }}{% endfor %}
+{% else %}
+<p>No comments yet.</p>
+{% endif %}
+
+{% if locked %}
+<p class="notice">Comments are closed on this article.</p>
+{% else %}
+<h2 id="post">{% if form.parent %}Reply to comment #{{ form.parent }} <a href="?#post">(cancel)</a>{% else %}Leave a comment{% endif %}</h2>
+{% if posted %}<p class="notice">Thanks! Your comment will appear once it has been approved.</p>{% endif %}
+{% if error %}<p class="notice">{{ error }}</p>{% endif %}
+<form method="post" action="">
+<input type="hidden" name="parent" value="{{ form.parent }}">
+<label>Name <input name="poster_name" value="{{ form.poster_name }}" required></label>
+<label>Email (optional, never shown) <input type="email" name="poster_email" value="{{ form.poster_email }}"></label>
+<label>Comment <textarea name="comment" rows="6" required>{{ form.comment }}</textarea></label>
+{% if subscriptions %}<label><input type="checkbox" name="subscribe"{% if form.subscribe %} checked{% endif %}> Email me about new comments on this article</label>{% endif %}
+{% if honeypot %}<label class="trap">Leave this empty <input name="{{ honeypot }}" tabindex="-1" autocomplete="off"></label>{% endif %}
+<button type="submit">Post comment</button>
+</form>
+{% endif %}
+</body></html>"#;
+
+/// The thread page, rendered with Tera like the dashboard. Comment text and names are marked safe
+/// since they are sanitized before they're stored.
+pub struct Templates {
+    tera: Tera,
+}
+
+impl Templates {
+    pub fn new() -> Result<Self, String> {
+        let mut tera = Tera::default();
+        tera.register_filter("datetime", dashboard::datetime);
+
+        tera.add_raw_template("thread.html", THREAD_TEMPLATE)
+            .map_err(|e| format!("Unable to load thread page template: {e:?}"))?;
+
+        Ok(Templates { tera })
+    }
+
+    fn render(&self, status: StatusCode, context: &Context) -> HttpResponse {
+        match self.tera.render("thread.html", context) {
+            Ok(body) => HttpResponse::build(status)
+                .content_type(ContentType::html())
+                .body(body),
+            Err(e) => {
+                warn!("Unable to render thread.html: {e:?}");
+                HttpResponse::InternalServerError().body("Unable to render page")
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ThreadQuery {
+    reply: Option<i64>,
+    posted: Option<String>,
+}
+
+/// The comment form's fields, echoed back into the form when posting fails. They don't share
+/// names with `/id/`'s so that a site's `honeypot_field` can be `name` or `email`.
+#[derive(Deserialize, Serialize, Default)]
+struct PostForm {
+    #[serde(default)]
+    poster_name: String,
+    #[serde(default)]
+    poster_email: String,
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    parent: String,
+    /// Sent, as "on", when the subscribe box is ticked.
+    subscribe: Option<String>,
+    /// Catches the honeypot field, whatever it is called.
+    #[serde(flatten, skip_serializing)]
+    other: HashMap<String, String>,
+}
+
+#[get("/thread/{article:.+}")]
+async fn thread(
+    path: web::Path<String>,
+    query: web::Query<ThreadQuery>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut form = PostForm {
+        parent: query.reply.map(|id| id.to_string()).unwrap_or_default(),
+        ..PostForm::default()
+    };
+
+    if let Some(commenter_id) = session::commenter_id(&req) {
+        let commenter = db::run(&state.db, move |db| db.get_commenter(&commenter_id))
+            .await
+            .map_err(Error::Database)?;
+        if let Some(commenter) = commenter {
+            form.poster_name = commenter.name;
+            form.poster_email = commenter.email;
+        }
+    }
+
+    let mut context = thread_context(&path, &state, &req).await?;
+    context.insert("form", &form);
+    context.insert("posted", &query.posted.is_some());
+
+    Ok(state.nojs.render(StatusCode::OK, &context))
+}
+
+#[post("/thread/{article:.+}")]
+async fn post(
+    path: web::Path<String>,
+    data: web::Form<PostForm>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !state.config.enable_thread_pages {
+        return Err(Error::NotFound(String::from("Thread pages are disabled")));
+    }
+
+    let client_ip = get_client_ip(&req);
+
+    let limit = state
+        .config
+        .thread_page_rate_limit
+        .as_ref()
+        .unwrap_or(&DEFAULT_RATE_LIMIT);
+    let client = format!("ip:{}", normalize_ip(&client_ip));
+    if let Err(retry_after) = state.ratelimit.check(RATE_LIMIT_KEY, &client, limit) {
+        info!(client_ip, "Refused thread page post over the rate limit");
+
+        let mut context = thread_context(&path, &state, &req).await?;
+        context.insert("form", &*data);
+        context.insert("posted", &false);
+        context.insert(
+            "error",
+            "You've posted several comments in a short time. Please wait a minute and try again.",
+        );
+
+        let mut res = state.nojs.render(StatusCode::TOO_MANY_REQUESTS, &context);
+        res.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from((retry_after.as_secs_f64().ceil() as u64).max(1)),
+        );
+        return Ok(res);
+    }
+
+    if let Some(field) = &state.config.honeypot_field {
+        if data.other.get(field).is_some_and(|value| !value.is_empty()) {
+            match state.config.honeypot_action {
+                HoneypotAction::Discard => {
+                    info!(client_ip, "Discarded comment that filled in the honeypot");
+                    return Ok(posted(None));
+                }
+                HoneypotAction::Hold => {
+                    info!(client_ip, "Comment filled in the honeypot");
+                    req.extensions_mut().insert(honeypot::Tripped);
+                }
+            }
+        }
+    }
+
+    let mut cookie = None;
+    let res = async {
+        if data.poster_name.trim().is_empty() {
+            return Err(Error::Unprocessable(String::from("Please enter your name")));
+        }
+
+        let parent = match data.parent.as_str() {
+            "" => 0,
+            parent => parent
+                .parse()
+                .map_err(|_| Error::BadRequest(String::from("Invalid parent")))?,
+        };
+
+        let commenter_id = match session_commenter(&state, &req, &client_ip, &data).await? {
+            Some(commenter_id) => commenter_id,
+            None => {
+                let commenter_id =
+                    new_commenter(&state, &client_ip, &data.poster_name, &data.poster_email)
+                        .await?;
+                cookie = session::cookie(&state, &req, &commenter_id);
+                commenter_id
+            }
+        };
+
+        submit_comment(
+            &state,
+            &req,
+            Submission {
+                article: &path,
+                commenter_id,
+                comment: &data.comment,
+                parent,
+                hold: true,
+                subscribe: data.subscribe.is_some(),
+            },
+        )
+        .await
+    }
+    .await;
+
+    match res {
+        Ok(_) => Ok(posted(cookie)),
+        Err(e) => {
+            let mut context = thread_context(&path, &state, &req).await?;
+            context.insert("form", &*data);
+            context.insert("posted", &false);
+            context.insert("error", &e.to_string());
+
+            let mut res = state.nojs.render(e.status_code(), &context);
+            if let Some(cookie) = cookie {
+                res.add_cookie(&cookie)
+                    .map_err(|e| Error::Internal(format!("Could not set session cookie: {e}")))?;
+            }
+            Ok(res)
+        }
+    }
+}
+
+/// The commenter in the session cookie, with the name and email from the form, or `None` if there
+/// is no session or its commenter is gone.
+async fn session_commenter(
+    state: &web::Data<AppState>,
+    req: &HttpRequest,
+    client_ip: &str,
+    data: &PostForm,
+) -> Result<Option<String>, Error> {
+    let Some(commenter_id) = session::commenter_id(req) else {
+        return Ok(None);
+    };
+
+    let id = commenter_id.clone();
+    let Some(commenter) = db::run(&state.db, move |db| db.get_commenter(&id))
+        .await
+        .map_err(Error::Database)?
+    else {
+        return Ok(None);
+    };
+
+    let clean_name = ammonia::clean(&data.poster_name);
+    let clean_email = ammonia::clean(&data.poster_email);
+    if commenter.name != clean_name || commenter.email != clean_email {
+        check_identity(
+            state,
+            client_ip,
+            Some(&commenter_id),
+            &clean_name,
+            &clean_email,
+            false,
+        )
+        .await?;
+
+        let id = commenter_id.clone();
+        db::run(&state.db, move |db| {
+            db.update_commenter(&id, &clean_name, &clean_email)
+        })
+        .await
+        .map_err(Error::Database)?;
+    }
+
+    Ok(Some(commenter_id))
+}
+
+/// Redirect-after-post back to the thread, so reloading the page doesn't post the comment again.
+/// `cookie` binds a new commenter to the reader's session.
+fn posted(cookie: Option<Cookie<'static>>) -> HttpResponse {
+    let mut res = HttpResponse::SeeOther();
+    res.insert_header((header::LOCATION, "?posted=1#post"));
+    if let Some(cookie) = cookie {
+        res.cookie(cookie);
+    }
+    res.finish()
+}
+
+/// Everything the page shows except the form: the article, its comments and whether it's locked.
+async fn thread_context(
+    article: &str,
+    state: &web::Data<AppState>,
+    req: &HttpRequest,
+) -> Result<Context, Error> {
+    if !state.config.enable_thread_pages {
+        return Err(Error::NotFound(String::from("Thread pages are disabled")));
+    }
+
+    let url = base64_decode(String::from(article))
+        .ok_or_else(|| Error::BadRequest(format!("Unable to decode article id: {article}")))?;
+
+    let request = GetCommentsRequest {
+        commenter_id: String::new(),
+        article: String::from(article),
+        sort: CommentSort::Oldest,
+        format: CommentFormat::Tree,
+        limit: None,
+        offset: None,
+        since: None,
+        challenge: None,
+        secret: None,
+    };
+    let response = thread_response(&request, String::new(), state, req).await?;
+
+    let title = titles::cached(state, vec![url.clone()])
+        .await
+        .remove(&url)
+        .unwrap_or_else(|| url.clone());
+
+    let mut context = Context::new();
+    context.insert("url", &url);
+    context.insert("title", &title);
+    context.insert("comments", &response.comments);
+    context.insert("locked", &response.locked);
+    context.insert("honeypot", &state.config.honeypot_field);
+    context.insert("subscriptions", &state.config.enable_subscriptions);
+    Ok(context)
+}
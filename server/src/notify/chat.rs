@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Chat channels for alerts, each posting through the service's HTTP API: Slack and Discord
+//! incoming webhooks, and a Matrix room via the client-server API.
+
+use actix_web::web;
+use rand::{thread_rng, Rng};
+use serde_json::json;
+use std::time::Duration;
+
+use super::{Alert, Notifier, Sending};
+use crate::AppState;
+
+const CHAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Discord refuses messages longer than this many characters.
+const DISCORD_MAX_CHARS: usize = 2000;
+
+pub struct Slack {
+    pub webhook_url: String,
+}
+
+impl Notifier for Slack {
+    fn name(&self) -> &'static str {
+        "Slack"
+    }
+
+    fn send<'a>(&'a self, state: &'a web::Data<AppState>, alert: &'a Alert) -> Sending<'a> {
+        Box::pin(async move {
+            // Slack reads these three as markup, so they must be escaped even in plain text.
+            let text = alert
+                .summary()
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+
+            let req = state
+                .http
+                .post(&self.webhook_url)
+                .json(&json!({ "text": text }));
+            send(req).await
+        })
+    }
+}
+
+pub struct Discord {
+    pub webhook_url: String,
+}
+
+impl Notifier for Discord {
+    fn name(&self) -> &'static str {
+        "Discord"
+    }
+
+    fn send<'a>(&'a self, state: &'a web::Data<AppState>, alert: &'a Alert) -> Sending<'a> {
+        Box::pin(async move {
+            let content: String = alert.summary().chars().take(DISCORD_MAX_CHARS).collect();
+
+            // Comments can't ping @everyone or anyone else in the server.
+            let req = state.http.post(&self.webhook_url).json(&json!({
+                "content": content,
+                "allowed_mentions": { "parse": [] },
+            }));
+            send(req).await
+        })
+    }
+}
+
+pub struct Matrix {
+    pub homeserver: String,
+    pub room_id: String,
+    pub access_token: String,
+}
+
+impl Notifier for Matrix {
+    fn name(&self) -> &'static str {
+        "Matrix"
+    }
+
+    fn send<'a>(&'a self, state: &'a web::Data<AppState>, alert: &'a Alert) -> Sending<'a> {
+        Box::pin(async move {
+            let mut url = reqwest::Url::parse(&self.homeserver)
+                .map_err(|e| format!("Invalid Matrix homeserver URL: {e}"))?;
+
+            // The transaction id only has to be unique per access token, so the homeserver can
+            // drop retried requests.
+            let txn_id = hex::encode(thread_rng().gen::<[u8; 16]>());
+            url.path_segments_mut()
+                .map_err(|_| String::from("Invalid Matrix homeserver URL"))?
+                .pop_if_empty()
+                .extend([
+                    "_matrix",
+                    "client",
+                    "v3",
+                    "rooms",
+                    &self.room_id,
+                    "send",
+                    "m.room.message",
+                    &txn_id,
+                ]);
+
+            let req = state
+                .http
+                .put(url)
+                .bearer_auth(&self.access_token)
+                .json(&json!({ "msgtype": "m.notice", "body": alert.summary() }));
+            send(req).await
+        })
+    }
+}
+
+async fn send(req: reqwest::RequestBuilder) -> Result<(), String> {
+    req.timeout(CHAT_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
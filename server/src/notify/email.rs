@@ -0,0 +1,658 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::web;
+use lettre::message::header::ContentType as LettreContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::PoolConfig;
+use lettre::{Message, SmtpTransport, Transport};
+use std::collections::HashMap;
+use std::result::Result;
+use std::time::{Duration, SystemTime};
+use tera::{Context, Tera};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use super::{Alert, AlertKind, Notifier, Sending};
+use crate::config::ConfigFile;
+use crate::i18n::Locales;
+use crate::{db, titles};
+
+const NEW_COMMENT_TEMPLATE: &str = r#"<p>A new comment was posted on {{ article_title | default(value=article_url) }} by {{ commenter_name }}:</p>
+<blockquote>{{ comment_text }}</blockquote>
+<p>Click <a href="{{ article_url }}">here</a> to view the comment.</p>"#;
+
+const REPLY_TEMPLATE: &str = r#"<p>Hi {{ recipient_name }},</p>
+<p>{{ commenter_name }} replied to your comment on {{ article_title | default(value=article_url) }}:</p>
+<blockquote>{{ comment_text }}</blockquote>
+<p>Click <a href="{{ article_url }}">here</a> to view the reply.</p>"#;
+
+const MENTION_TEMPLATE: &str = r#"<p>Hi {{ recipient_name }},</p>
+<p>{{ commenter_name }} mentioned you in a comment on {{ article_title | default(value=article_url) }}:</p>
+<blockquote>{{ comment_text }}</blockquote>
+<p>Click <a href="{{ article_url }}">here</a> to view the comment.</p>"#;
+
+const SUBSCRIPTION_TEMPLATE: &str = r#"<p>Hi {{ recipient_name }},</p>
+<p>{{ commenter_name }} commented on {{ article_title | default(value=article_url) }}:</p>
+<blockquote>{{ comment_text }}</blockquote>
+<p>Click <a href="{{ article_url }}">here</a> to view the comment.</p>
+<p><a href="{{ unsubscribe_url }}">Unsubscribe</a> from new comments on this article.</p>"#;
+
+const EXPORT_CONFIRMATION_TEMPLATE: &str = r#"<p>Hi {{ recipient_name }},</p>
+<p>Someone asked for a copy of the comments and other data stored for your commenter ID. If this
+was you, use this code to confirm the request:</p>
+<p><code>{{ token }}</code></p>
+<p>The code expires in 30 minutes. If you didn't ask for your data, you can ignore this email.</p>"#;
+
+const FLAG_TEMPLATE: &str = r#"<p>A comment by {{ commenter_name }} on {{ article_title | default(value=article_url) }} was flagged{% if flags > 1 %} by {{ flags }} commenters{% endif %}{% if hidden %} and has been hidden until you review it{% endif %}:</p>
+<blockquote>{{ comment_text }}</blockquote>
+<p>Reason given:</p>
+<blockquote>{{ reason }}</blockquote>
+<p>Click <a href="{{ article_url }}">here</a> to view the article.</p>"#;
+
+const DIGEST_TEMPLATE: &str = r#"<p>{{ count }} new comment{{ count | pluralize }} posted{% if pending_count > 0 %}, {{ pending_count }} awaiting moderation{% endif %}:</p>
+{% for comment in comments %}
+<p>{{ comment.poster_name }} on <a href="{{ comment.article }}">{{ titles[comment.article] | default(value=comment.article) }}</a>{% if comment.pending %} (pending){% endif %}:</p>
+<blockquote>{{ comment.comment }}</blockquote>
+{% endfor %}"#;
+
+/// Email bodies, rendered with Tera. Each template falls back to a built-in default, or the
+/// default locale's translation of it, unless a path is configured for it. Templates have access to `article_url`, `commenter_name`, and
+/// `comment_text`, plus `article_title` when `fetch_article_titles` found one; reply
+/// mention and subscription notifications also get `recipient_name`, and subscription
+/// notifications `unsubscribe_url`. Export confirmations only get `recipient_name` and
+/// `token`. Flag notifications get `flags`, the number of times the comment has been flagged,
+/// `reason`, and `hidden`, whether the comment was returned to the moderation queue. Digests get `count`, `pending_count`, `comments`, a list of objects with `id`,
+/// `timestamp`, `article`, `poster_name`, `comment`, and `pending`, and `titles`, a map from
+/// article URL to title. Names, titles, and comment text are sanitized before rendering, so
+/// templates are not auto-escaped.
+pub struct Templates {
+    tera: Tera,
+}
+
+impl Templates {
+    pub fn new_from_config(config: &ConfigFile, locales: &Locales) -> Result<Self, String> {
+        let mut tera = Tera::default();
+
+        for (name, path, default) in [
+            (
+                "new_comment",
+                &config.email_template_new_comment,
+                NEW_COMMENT_TEMPLATE,
+            ),
+            ("reply", &config.email_template_reply, REPLY_TEMPLATE),
+            ("mention", &config.email_template_mention, MENTION_TEMPLATE),
+            (
+                "subscription",
+                &config.email_template_subscription,
+                SUBSCRIPTION_TEMPLATE,
+            ),
+            (
+                "export_confirmation",
+                &config.email_template_export_confirmation,
+                EXPORT_CONFIRMATION_TEMPLATE,
+            ),
+            ("flag", &config.email_template_flag, FLAG_TEMPLATE),
+            ("digest", &config.email_template_digest, DIGEST_TEMPLATE),
+        ] {
+            let res = match path {
+                Some(path) => tera.add_template_file(path, Some(name)),
+                None => {
+                    tera.add_raw_template(name, locales.email_template(name).unwrap_or(default))
+                }
+            };
+
+            if let Err(e) = res {
+                return Err(format!("Unable to load {name} email template: {e:?}"));
+            }
+        }
+
+        Ok(Templates { tera })
+    }
+
+    fn render(&self, name: &str, context: &Context) -> Result<String, String> {
+        self.tera
+            .render(name, context)
+            .map_err(|e| format!("Unable to render {name} email template: {e:?}"))
+    }
+}
+
+/// Alerts by email to `email_notify_address`. New comments are left out in digest mode, since the
+/// digest covers them.
+pub struct EmailNotifier;
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn send<'a>(&'a self, state: &'a web::Data<crate::AppState>, alert: &'a Alert) -> Sending<'a> {
+        Box::pin(async move {
+            match &alert.kind {
+                AlertKind::NewComment { .. } => {
+                    if state.config.email_digest_interval_mins.is_some() {
+                        return Ok(());
+                    }
+                    send_email(state, &alert.url, &alert.commenter, &alert.comment_text).await
+                }
+                AlertKind::Flagged {
+                    reason,
+                    flags,
+                    hidden,
+                } => {
+                    send_flag_notification(
+                        state,
+                        &alert.url,
+                        &alert.commenter,
+                        &alert.comment_text,
+                        reason,
+                        *flags,
+                        *hidden,
+                    )
+                    .await
+                }
+            }
+        })
+    }
+}
+
+pub async fn send_email(
+    state: &web::Data<crate::AppState>,
+    url: &str,
+    commenter: &str,
+    comment_text: &str,
+) -> Result<(), String> {
+    let Some(to) = &state.config.email_notify_address else {
+        return Err(String::from("No email_notify_address configured"));
+    };
+
+    let mut context = Context::new();
+    context.insert("article_url", url);
+    context.insert("commenter_name", commenter);
+    context.insert("comment_text", comment_text);
+    if let Some(title) = titles::lookup(state, url).await {
+        context.insert("article_title", &ammonia::clean_text(&title));
+    }
+
+    deliver(
+        state,
+        to,
+        format!("New comment from {commenter}"),
+        state.email_templates.render("new_comment", &context)?,
+    )
+    .await
+}
+
+/// Let the author of `parent` know that someone replied to their comment, unless they have opted
+/// out of reply notifications or are replying to themselves. Returns the address notified, if
+/// any.
+pub async fn send_reply_notification(
+    state: &web::Data<crate::AppState>,
+    parent: i64,
+    replier_id: &str,
+    url: &str,
+    replier: &str,
+    comment_text: &str,
+) -> Result<Option<String>, String> {
+    let Some(recipient) = db::run(&state.db, move |db| db.get_reply_recipient(parent)).await?
+    else {
+        return Ok(None);
+    };
+
+    if !recipient.reply_notifications || recipient.commenter_id == replier_id {
+        return Ok(None);
+    }
+
+    if recipient.email.is_empty() {
+        return Ok(None);
+    }
+
+    info!("Sending reply notification for comment {parent}");
+
+    let mut context = Context::new();
+    context.insert("article_url", url);
+    context.insert("commenter_name", replier);
+    context.insert("comment_text", comment_text);
+    context.insert("recipient_name", &recipient.name);
+    if let Some(title) = titles::lookup(state, url).await {
+        context.insert("article_title", &ammonia::clean_text(&title));
+    }
+
+    deliver(
+        state,
+        &recipient.email,
+        format!("{replier} replied to your comment"),
+        state.email_templates.render("reply", &context)?,
+    )
+    .await?;
+
+    Ok(Some(recipient.email))
+}
+
+/// Let a commenter know they were mentioned in a comment. The caller decides who to tell; see
+/// [`crate::mentions::notify`].
+pub async fn send_mention_notification(
+    state: &web::Data<crate::AppState>,
+    recipient: &db::ReplyRecipient,
+    url: &str,
+    mentioner: &str,
+    comment_text: &str,
+) -> Result<(), String> {
+    let mut context = Context::new();
+    context.insert("article_url", url);
+    context.insert("commenter_name", mentioner);
+    context.insert("comment_text", comment_text);
+    context.insert("recipient_name", &recipient.name);
+    if let Some(title) = titles::lookup(state, url).await {
+        context.insert("article_title", &ammonia::clean_text(&title));
+    }
+
+    deliver(
+        state,
+        &recipient.email,
+        format!("{mentioner} mentioned you in a comment"),
+        state.email_templates.render("mention", &context)?,
+    )
+    .await
+}
+
+/// Tell a subscriber about a new comment on an article they follow.
+pub async fn send_subscription_notification(
+    state: &web::Data<crate::AppState>,
+    subscriber: &db::Subscriber,
+    url: &str,
+    commenter: &str,
+    comment_text: &str,
+    unsubscribe_url: &str,
+) -> Result<(), String> {
+    let mut context = Context::new();
+    context.insert("article_url", url);
+    context.insert("commenter_name", commenter);
+    context.insert("comment_text", comment_text);
+    context.insert("recipient_name", &subscriber.name);
+    context.insert("unsubscribe_url", unsubscribe_url);
+    if let Some(title) = titles::lookup(state, url).await {
+        context.insert("article_title", &ammonia::clean_text(&title));
+    }
+
+    deliver(
+        state,
+        &subscriber.email,
+        format!("{commenter} commented on an article you follow"),
+        state.email_templates.render("subscription", &context)?,
+    )
+    .await
+}
+
+/// Send the token a commenter needs to confirm a request for a copy of their data.
+pub async fn send_export_confirmation(
+    state: &web::Data<crate::AppState>,
+    to: &str,
+    recipient_name: &str,
+    token: &str,
+) -> Result<(), String> {
+    let mut context = Context::new();
+    context.insert("recipient_name", recipient_name);
+    context.insert("token", token);
+
+    deliver(
+        state,
+        to,
+        String::from("Confirm your data export request"),
+        state
+            .email_templates
+            .render("export_confirmation", &context)?,
+    )
+    .await
+}
+
+/// Tell `email_notify_address` that a commenter flagged a comment. Sent even in digest mode, since
+/// a flagged comment may need attention before the next digest goes out.
+pub async fn send_flag_notification(
+    state: &web::Data<crate::AppState>,
+    url: &str,
+    commenter: &str,
+    comment_text: &str,
+    reason: &str,
+    flags: i64,
+    hidden: bool,
+) -> Result<(), String> {
+    let Some(to) = &state.config.email_notify_address else {
+        return Err(String::from("No email_notify_address configured"));
+    };
+
+    let mut context = Context::new();
+    context.insert("article_url", url);
+    context.insert("commenter_name", commenter);
+    context.insert("comment_text", comment_text);
+    context.insert("reason", reason);
+    context.insert("flags", &flags);
+    context.insert("hidden", &hidden);
+    if let Some(title) = titles::lookup(state, url).await {
+        context.insert("article_title", &ammonia::clean_text(&title));
+    }
+
+    deliver(
+        state,
+        to,
+        format!("Comment from {commenter} was flagged"),
+        state.email_templates.render("flag", &context)?,
+    )
+    .await
+}
+
+/// The most comments listed in one digest. Any beyond this are left for the next one.
+const DIGEST_MAX_COMMENTS: i64 = 500;
+
+/// Queue a digest of the comments posted since the last one, if there are any.
+pub async fn send_digest(state: &web::Data<crate::AppState>) -> Result<(), String> {
+    let Some(to) = &state.config.email_notify_address else {
+        return Err(String::from("No email_notify_address configured"));
+    };
+
+    let comments = db::run(&state.db, |db| db.digest_comments(DIGEST_MAX_COMMENTS)).await?;
+    let Some(last_comment_id) = comments.last().map(|comment| comment.id) else {
+        return Ok(());
+    };
+
+    let Some(now) = unix_now() else {
+        return Err(String::from("Could not generate timestamp"));
+    };
+
+    let count = comments.len();
+    let pending_count = comments.iter().filter(|comment| comment.pending).count();
+    info!("Sending digest of {count} comments");
+
+    let mut titles = HashMap::new();
+    for comment in &comments {
+        if titles.contains_key(&comment.article) {
+            continue;
+        }
+        if let Some(title) = titles::lookup(state, &comment.article).await {
+            titles.insert(comment.article.clone(), ammonia::clean_text(&title));
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("count", &count);
+    context.insert("pending_count", &pending_count);
+    context.insert("comments", &comments);
+    context.insert("titles", &titles);
+
+    let subject = match (count, pending_count) {
+        (1, 0) => String::from("1 new comment"),
+        (_, 0) => format!("{count} new comments"),
+        _ => format!("{count} new comments, {pending_count} awaiting moderation"),
+    };
+
+    deliver(
+        state,
+        to,
+        subject,
+        state.email_templates.render("digest", &context)?,
+    )
+    .await?;
+
+    db::run(&state.db, move |db| {
+        db.mark_digest_sent(last_comment_id, now)
+    })
+    .await
+}
+
+/// Wakes the delivery task when a new message has been queued.
+pub struct Queue {
+    wake: mpsc::UnboundedSender<()>,
+    /// Held while processing the queue, so a shutdown flush can't send a message twice.
+    busy: Mutex<()>,
+}
+
+impl Queue {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<()>) {
+        let (wake, receiver) = mpsc::unbounded_channel();
+        (
+            Queue {
+                wake,
+                busy: Mutex::new(()),
+            },
+            receiver,
+        )
+    }
+}
+
+enum SendError {
+    Transient(String),
+    Permanent(String),
+}
+
+/// Persist a message to the outgoing queue. Delivery happens in the background task started by
+/// [`spawn_queue_worker`], so SMTP latency never holds up a request.
+async fn deliver(
+    state: &web::Data<crate::AppState>,
+    to: &str,
+    subject: String,
+    body: String,
+) -> Result<(), String> {
+    let Some(now) = unix_now() else {
+        return Err(String::from("Could not generate timestamp"));
+    };
+
+    let to = String::from(to);
+    let subject = state.locales.translate_default(&subject);
+    db::run(&state.db, move |db| {
+        db.enqueue_email(&to, &subject, &body, now)
+    })
+    .await?;
+
+    let _ = state.email_queue.wake.send(());
+    Ok(())
+}
+
+/// Start the background task that delivers queued email, retrying transient SMTP failures with
+/// exponential backoff.
+pub fn spawn_queue_worker(
+    state: web::Data<crate::AppState>,
+    mut wake: mpsc::UnboundedReceiver<()>,
+) {
+    actix_web::rt::spawn(async move {
+        loop {
+            if let Err(e) = process_queue(&state).await {
+                warn!("Unable to process email queue: {e}");
+            }
+
+            tokio::select! {
+                _ = wake.recv() => {}
+                _ = sleep(QUEUE_POLL_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const QUEUE_BATCH_SIZE: i64 = 50;
+const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+/// Make one last delivery attempt for every message that is due. Messages that still fail stay
+/// queued for the next run.
+pub async fn flush(state: &web::Data<crate::AppState>) {
+    if let Err(e) = process_queue(state).await {
+        warn!("Unable to flush email queue: {e}");
+    }
+}
+
+async fn process_queue(state: &web::Data<crate::AppState>) -> Result<(), String> {
+    let _busy = state.email_queue.busy.lock().await;
+
+    let Some(now) = unix_now() else {
+        return Err(String::from("Could not generate timestamp"));
+    };
+
+    let due = db::run(&state.db, move |db| db.due_emails(now, QUEUE_BATCH_SIZE)).await?;
+
+    for email in due {
+        let id = email.id;
+        let attempts = email.attempts + 1;
+
+        let sender = state.clone();
+        let res = match web::block(move || send_now(&sender, email)).await {
+            Ok(res) => res,
+            Err(e) => Err(SendError::Transient(format!("{e:?}"))),
+        };
+
+        let max_attempts = state.config.email_max_attempts.unwrap_or(8) as i64;
+
+        match res {
+            Ok(()) => {
+                info!("Delivered queued email {id}");
+                db::run(&state.db, move |db| db.delete_email(id)).await?;
+            }
+            Err(SendError::Transient(e)) if attempts < max_attempts => {
+                let base = state.config.email_retry_base_secs.unwrap_or(30) as i64;
+                let backoff = (base << (attempts - 1).min(20)).min(MAX_BACKOFF_SECS);
+
+                info!("Unable to deliver queued email {id} (attempt {attempts}), retrying in {backoff}s: {e}");
+                db::run(&state.db, move |db| {
+                    db.reschedule_email(id, attempts, now + backoff, &e)
+                })
+                .await?;
+            }
+            Err(SendError::Transient(e)) | Err(SendError::Permanent(e)) => {
+                warn!("Giving up on queued email {id} after {attempts} attempts: {e}");
+                db::run(&state.db, move |db| db.delete_email(id)).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn unix_now() -> Option<i64> {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(t) => Some(t.as_secs() as i64),
+        Err(_) => None,
+    }
+}
+
+/// The SMTP transport for `email_smtp_host`, or `None` if there isn't one. It is built once and
+/// shared, so messages reuse its pooled connections rather than each doing a fresh TLS handshake
+/// and login.
+pub fn mailer_from_config(config: &ConfigFile) -> Result<Option<SmtpTransport>, String> {
+    let Some(smtp_host) = &config.email_smtp_host else {
+        return Ok(None);
+    };
+
+    let mut relay = SmtpTransport::relay(smtp_host)
+        .map_err(|e| format!("Unable to configure SMTP relay: {e:?}"))?
+        .timeout(Some(Duration::from_secs(
+            config.email_smtp_timeout_secs.unwrap_or(60),
+        )))
+        .pool_config(
+            PoolConfig::new()
+                .max_size(config.email_smtp_pool_size.unwrap_or(4))
+                .idle_timeout(Duration::from_secs(
+                    config.email_smtp_idle_timeout_secs.unwrap_or(60),
+                )),
+        );
+
+    if let Some(user) = &config.email_smtp_user {
+        let pass = config.email_smtp_pass.clone().unwrap_or_default();
+        relay = relay.credentials(Credentials::new(user.to_owned(), pass));
+    }
+
+    Ok(Some(relay.build()))
+}
+
+fn send_now(state: &web::Data<crate::AppState>, email: db::QueuedEmail) -> Result<(), SendError> {
+    let (Some(sender_name), Some(sender_address)) = (
+        &state.config.email_sender_name,
+        &state.config.email_sender_address,
+    ) else {
+        return Err(SendError::Permanent(String::from(
+            "Email sender name and address must be configured",
+        )));
+    };
+
+    let Some(mailer) = &state.mailer else {
+        return Err(SendError::Permanent(String::from(
+            "No email_smtp_host configured",
+        )));
+    };
+
+    let from = match format!("{sender_name} <{sender_address}>").parse() {
+        Ok(from) => from,
+        Err(e) => {
+            return Err(SendError::Permanent(format!(
+                "Invalid sender address: {e:?}"
+            )))
+        }
+    };
+
+    let to = match email.recipient.parse() {
+        Ok(to) => to,
+        Err(e) => {
+            return Err(SendError::Permanent(format!(
+                "Invalid recipient address {}: {e:?}",
+                email.recipient
+            )))
+        }
+    };
+
+    let msg = match Message::builder()
+        .from(from)
+        .to(to)
+        .subject(email.subject)
+        .header(LettreContentType::TEXT_HTML)
+        .body(email.body)
+    {
+        Ok(msg) => msg,
+        Err(e) => {
+            return Err(SendError::Permanent(format!(
+                "Unable to build message: {e:?}"
+            )))
+        }
+    };
+
+    // A pooled connection the server has since dropped can pass the pool's health check and
+    // then fail mid-send. The pool discards it, so one more try goes out on a fresh connection.
+    let mut res = mailer.send(&msg);
+    if let Err(e) = &res {
+        if is_connection_error(e) {
+            info!("SMTP connection failed, reconnecting: {e}");
+            res = mailer.send(&msg);
+        }
+    }
+
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) if e.is_permanent() => Err(SendError::Permanent(format!(
+            "Unable to send message: {e:?}"
+        ))),
+        Err(e) => Err(SendError::Transient(format!(
+            "Unable to send message: {e:?}"
+        ))),
+    }
+}
+
+/// Errors talking to the SMTP server, as opposed to it refusing the message.
+fn is_connection_error(e: &lettre::transport::smtp::Error) -> bool {
+    !(e.is_response() || e.is_client() || e.is_transient() || e.is_permanent())
+}
@@ -0,0 +1,245 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Alerts for the site owner about new and flagged comments. Each goes to every configured
+//! channel: email to `email_notify_address` when `enable_email_notifications` is on, and each
+//! entry in `notifiers`. Notifications for commenters, such as replies, only go by email.
+
+pub mod chat;
+pub mod email;
+pub mod ntfy;
+
+use actix_web::web;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::config::{ConfigFile, NotifierKind};
+use crate::{titles, AppState};
+
+pub enum AlertKind {
+    /// A comment was posted. `pending` if it's waiting for moderation.
+    NewComment { pending: bool },
+    /// A reader flagged a comment. `hidden` if that returned it to the moderation queue.
+    Flagged {
+        reason: String,
+        flags: i64,
+        hidden: bool,
+    },
+}
+
+pub struct Alert {
+    pub kind: AlertKind,
+    /// The article's URL.
+    pub url: String,
+    /// Filled in from `fetch_article_titles` before the alert is sent.
+    pub title: Option<String>,
+    pub commenter: String,
+    /// Sanitized, as stored.
+    pub comment_text: String,
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, url: &str, commenter: &str, comment_text: &str) -> Self {
+        Alert {
+            kind,
+            url: String::from(url),
+            title: None,
+            commenter: String::from(commenter),
+            comment_text: String::from(comment_text),
+        }
+    }
+
+    /// A plain-text account of the alert, for channels that don't take HTML.
+    pub fn summary(&self) -> String {
+        let article = self.title.as_deref().unwrap_or(&self.url);
+        let commenter = plain_text(&self.commenter);
+        let comment = plain_text(&self.comment_text);
+
+        let headline = match &self.kind {
+            AlertKind::NewComment { pending: false } => {
+                format!("New comment from {commenter} on {article}")
+            }
+            AlertKind::NewComment { pending: true } => {
+                format!("New comment from {commenter} on {article}, awaiting moderation")
+            }
+            AlertKind::Flagged {
+                reason,
+                flags,
+                hidden,
+            } => {
+                let mut headline = format!("A comment by {commenter} on {article} was flagged");
+                if *flags > 1 {
+                    headline.push_str(&format!(" by {flags} commenters"));
+                }
+                if *hidden {
+                    headline.push_str(" and has been hidden until you review it");
+                }
+                headline.push_str(&format!(". Reason given: {}", plain_text(reason)));
+                headline
+            }
+        };
+
+        format!("{headline}\n\n{comment}\n\n{}", self.url)
+    }
+}
+
+/// What [`Notifier::send`] returns.
+pub type Sending<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// A channel alerts can be sent to.
+pub trait Notifier: Send + Sync {
+    /// Names the channel in logs.
+    fn name(&self) -> &'static str;
+
+    fn send<'a>(&'a self, state: &'a web::Data<AppState>, alert: &'a Alert) -> Sending<'a>;
+}
+
+struct Channel {
+    notifier: Box<dyn Notifier>,
+    /// Skip comments that were published straight away.
+    pending_only: bool,
+}
+
+/// At most one alert goes out per this interval for comments sent through [`dispatch_throttled`].
+const THROTTLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Every configured channel.
+pub struct Notifiers {
+    channels: Vec<Channel>,
+    /// When the last throttled alert was sent, and how many have been skipped since.
+    throttled: Mutex<(Option<Instant>, u64)>,
+}
+
+impl Notifiers {
+    pub fn new_from_config(config: &ConfigFile) -> Self {
+        let mut channels = vec![];
+
+        if config.enable_email_notifications {
+            channels.push(Channel {
+                notifier: Box::new(email::EmailNotifier),
+                pending_only: false,
+            });
+        }
+
+        for entry in &config.notifiers {
+            let notifier: Box<dyn Notifier> = match &entry.kind {
+                NotifierKind::Slack { webhook_url } => Box::new(chat::Slack {
+                    webhook_url: webhook_url.clone(),
+                }),
+                NotifierKind::Discord { webhook_url } => Box::new(chat::Discord {
+                    webhook_url: webhook_url.clone(),
+                }),
+                NotifierKind::Matrix {
+                    homeserver,
+                    room_id,
+                    access_token,
+                } => Box::new(chat::Matrix {
+                    homeserver: homeserver.clone(),
+                    room_id: room_id.clone(),
+                    access_token: access_token.clone(),
+                }),
+                NotifierKind::Ntfy {
+                    server,
+                    topic,
+                    token,
+                } => Box::new(ntfy::Ntfy {
+                    server: server
+                        .clone()
+                        .unwrap_or_else(|| String::from(ntfy::DEFAULT_SERVER)),
+                    topic: topic.clone(),
+                    token: token.clone(),
+                }),
+            };
+
+            channels.push(Channel {
+                notifier,
+                pending_only: entry.pending_only,
+            });
+        }
+
+        Notifiers {
+            channels,
+            throttled: Mutex::new((None, 0)),
+        }
+    }
+}
+
+/// Send `alert` to every channel in the background, so slow chat services don't hold up the
+/// request that raised it.
+pub fn dispatch(state: &web::Data<AppState>, mut alert: Alert) {
+    if state.notifiers.channels.is_empty() {
+        return;
+    }
+
+    let state = state.clone();
+    actix_web::rt::spawn(async move {
+        alert.title = titles::lookup(&state, &alert.url)
+            .await
+            .map(|title| ammonia::clean_text(&title));
+
+        let published = matches!(alert.kind, AlertKind::NewComment { pending: false });
+        for channel in &state.notifiers.channels {
+            if channel.pending_only && published {
+                continue;
+            }
+
+            if let Err(e) = channel.notifier.send(&state, &alert).await {
+                info!(
+                    "Unable to send {} notification: {e}",
+                    channel.notifier.name()
+                );
+            }
+        }
+    });
+}
+
+/// Like [`dispatch`], but for comments posted without proof-of-work, which anyone can send as fast
+/// as the rate limit allows. Only one alert is sent per [`THROTTLE_INTERVAL`]; the comments in
+/// between still wait in the moderation queue.
+pub fn dispatch_throttled(state: &web::Data<AppState>, alert: Alert) {
+    let skipped = {
+        let mut throttled = state.notifiers.throttled.lock().unwrap();
+        let (last, skipped) = &mut *throttled;
+        let now = Instant::now();
+
+        if last.is_some_and(|last| now.duration_since(last) < THROTTLE_INTERVAL) {
+            *skipped += 1;
+            return;
+        }
+        *last = Some(now);
+        std::mem::take(skipped)
+    };
+
+    if skipped > 0 {
+        info!(skipped, "Skipped alerts for throttled comments");
+    }
+    dispatch(state, alert);
+}
+
+/// Undo `ammonia::clean_text`, and drop any tags the HTML policy kept, for channels that show
+/// text as it is.
+pub fn plain_text(text: &str) -> String {
+    crate::html::decode_entities(&crate::html::strip_tags(text))
+}
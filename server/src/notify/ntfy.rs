@@ -0,0 +1,89 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Push notifications through an [ntfy](https://ntfy.sh) server, so moderators hear about new
+//! comments on their phones. Messages are published as JSON to the server's root URL.
+
+use actix_web::web;
+use serde_json::json;
+use std::time::Duration;
+
+use super::{Alert, AlertKind, Notifier, Sending};
+use crate::AppState;
+
+const NTFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_SERVER: &str = "https://ntfy.sh";
+
+pub struct Ntfy {
+    pub server: String,
+    pub topic: String,
+    /// An access token, for servers that protect the topic.
+    pub token: Option<String>,
+}
+
+impl Notifier for Ntfy {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn send<'a>(&'a self, state: &'a web::Data<AppState>, alert: &'a Alert) -> Sending<'a> {
+        Box::pin(async move {
+            // Anything that needs a moderator is high priority, so it gets past do-not-disturb
+            // on phones that allow it.
+            let (title, tag, priority) = match &alert.kind {
+                AlertKind::NewComment { pending: true } => {
+                    ("New comment awaiting moderation", "speech_balloon", 4)
+                }
+                AlertKind::NewComment { pending: false } => ("New comment", "speech_balloon", 3),
+                AlertKind::Flagged { .. } => ("Comment flagged", "triangular_flag_on_post", 4),
+            };
+
+            let mut body = json!({
+                "topic": self.topic,
+                "title": title,
+                "message": alert.summary(),
+                "tags": [tag],
+                "priority": priority,
+            });
+            // Without public_url the alert only has the article path, which ntfy can't open.
+            if alert.url.starts_with("https://") || alert.url.starts_with("http://") {
+                body["click"] = json!(alert.url);
+            }
+
+            let mut req = state
+                .http
+                .post(&self.server)
+                .timeout(NTFY_TIMEOUT)
+                .json(&body);
+            if let Some(token) = &self.token {
+                req = req.bearer_auth(token);
+            }
+
+            req.send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
@@ -0,0 +1,434 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::{get, web, HttpRequest, HttpResponse, ResponseError};
+use rand::{thread_rng, Rng};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::ConfigFile;
+use crate::error::Error;
+use crate::{check_identity, db, get_client_ip, session, AppState};
+
+/// How long a login may take between leaving for the provider and coming back.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Provider {
+    GitHub,
+    Google,
+}
+
+impl Provider {
+    fn from_path(name: &str) -> Option<Self> {
+        match name {
+            "github" => Some(Provider::GitHub),
+            "google" => Some(Provider::Google),
+            _ => None,
+        }
+    }
+
+    /// The name stored in `ids.oauth_provider` and used in callback URLs.
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::GitHub => "github",
+            Provider::Google => "google",
+        }
+    }
+
+    fn credentials<'a>(&self, config: &'a ConfigFile) -> Option<(&'a str, &'a str)> {
+        let (id, secret) = match self {
+            Provider::GitHub => (
+                &config.oauth_github_client_id,
+                &config.oauth_github_client_secret,
+            ),
+            Provider::Google => (
+                &config.oauth_google_client_id,
+                &config.oauth_google_client_secret,
+            ),
+        };
+
+        Some((id.as_deref()?, secret.as_deref()?))
+    }
+
+    fn authorize_url(&self) -> &'static str {
+        match self {
+            Provider::GitHub => "https://github.com/login/oauth/authorize",
+            Provider::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+        }
+    }
+
+    fn token_url(&self) -> &'static str {
+        match self {
+            Provider::GitHub => "https://github.com/login/oauth/access_token",
+            Provider::Google => "https://oauth2.googleapis.com/token",
+        }
+    }
+
+    fn scope(&self) -> &'static str {
+        match self {
+            Provider::GitHub => "read:user user:email",
+            Provider::Google => "openid email profile",
+        }
+    }
+}
+
+struct PendingLogin {
+    provider: Provider,
+    return_to: String,
+    started: Instant,
+}
+
+/// Logins in progress, keyed by the random `state` parameter sent to the provider.
+pub struct OAuthLogins {
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl OAuthLogins {
+    pub fn new() -> Self {
+        OAuthLogins {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn start(&self, provider: Provider, return_to: String) -> String {
+        let mut rand_bytes = [0u8; 32];
+        thread_rng().fill(&mut rand_bytes);
+        let state = hex::encode(rand_bytes);
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, pending| pending.started.elapsed() < LOGIN_TIMEOUT);
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                provider,
+                return_to,
+                started: Instant::now(),
+            },
+        );
+
+        state
+    }
+
+    /// Each state is single-use: it is removed whether or not it matches.
+    fn finish(&self, provider: Provider, state: &str) -> Option<String> {
+        let pending = self.pending.lock().unwrap().remove(state)?;
+
+        if pending.provider != provider || pending.started.elapsed() >= LOGIN_TIMEOUT {
+            return None;
+        }
+
+        Some(pending.return_to)
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    return_to: String,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+    id: i64,
+    login: String,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GoogleUser {
+    sub: String,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+/// The provider account a commenter signed in with.
+struct Identity {
+    subject: String,
+    name: String,
+    email: String,
+}
+
+fn callback_url(config: &ConfigFile, provider: Provider) -> Option<String> {
+    let base = config.oauth_base_url.as_ref()?;
+    Some(format!(
+        "{}/oauth/{}/callback/",
+        base.trim_end_matches('/'),
+        provider.name()
+    ))
+}
+
+fn return_allowed(config: &ConfigFile, return_to: &str) -> bool {
+    config
+        .oauth_return_urls
+        .iter()
+        .any(|prefix| return_to.starts_with(prefix))
+}
+
+fn bad_request(msg: &str) -> HttpResponse {
+    HttpResponse::BadRequest().body(String::from(msg))
+}
+
+#[get("/oauth/{provider}/login/")]
+async fn login(
+    path: web::Path<String>,
+    query: web::Query<LoginQuery>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let Some(provider) = Provider::from_path(&path) else {
+        return HttpResponse::NotFound().body("Unknown login provider");
+    };
+
+    let (Some((client_id, _)), Some(redirect_uri)) = (
+        provider.credentials(&state.config),
+        callback_url(&state.config, provider),
+    ) else {
+        return HttpResponse::NotFound().body("Login provider not configured");
+    };
+
+    if !return_allowed(&state.config, &query.return_to) {
+        return bad_request("Return URL not allowed");
+    }
+
+    let login_state = state.oauth.start(provider, query.return_to.clone());
+
+    let url = match Url::parse_with_params(
+        provider.authorize_url(),
+        &[
+            ("client_id", client_id),
+            ("redirect_uri", &redirect_uri),
+            ("response_type", "code"),
+            ("scope", provider.scope()),
+            ("state", &login_state),
+        ],
+    ) {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{e}")),
+    };
+
+    HttpResponse::Found()
+        .insert_header(("location", url.as_str()))
+        .finish()
+}
+
+#[get("/oauth/{provider}/callback/")]
+async fn callback(
+    path: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let Some(provider) = Provider::from_path(&path) else {
+        return HttpResponse::NotFound().body("Unknown login provider");
+    };
+
+    let Some(return_to) = state.oauth.finish(provider, &query.state) else {
+        return bad_request("Login expired or invalid; please try again");
+    };
+
+    if let Some(error) = &query.error {
+        return bad_request(&format!("Login was not completed: {error}"));
+    }
+
+    let Some(code) = &query.code else {
+        return bad_request("Missing authorization code");
+    };
+
+    let identity = match fetch_identity(&state, provider, code).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("{} login failed: {e}", provider.name());
+            return HttpResponse::BadGateway().body("Unable to complete login with provider");
+        }
+    };
+
+    let client_ip = get_client_ip(&req);
+    let commenter_id = match find_or_create_commenter(&state, &client_ip, provider, identity).await
+    {
+        Ok(commenter_id) => commenter_id,
+        Err(e) => {
+            return HttpResponse::build(e.status_code()).body(format!("Could not sign in: {e}"))
+        }
+    };
+
+    // The id goes in the fragment so it is never sent to the article's server. With sessions it
+    // goes in a cookie as well, which the widget falls back from if the browser won't send it.
+    let mut url = match Url::parse(&return_to) {
+        Ok(url) => url,
+        Err(e) => return bad_request(&format!("Invalid return URL: {e}")),
+    };
+
+    let mut response = HttpResponse::Found();
+    if let Some(cookie) = session::cookie(&state, &req, &commenter_id) {
+        response.cookie(cookie);
+    }
+    url.set_fragment(Some(&format!("tinycomments_commenter_id={commenter_id}")));
+
+    response.insert_header(("location", url.as_str())).finish()
+}
+
+async fn fetch_identity(
+    state: &web::Data<AppState>,
+    provider: Provider,
+    code: &str,
+) -> Result<Identity, String> {
+    let (client_id, client_secret) = provider
+        .credentials(&state.config)
+        .ok_or(String::from("provider not configured"))?;
+    let redirect_uri =
+        callback_url(&state.config, provider).ok_or(String::from("oauth_base_url not set"))?;
+
+    let token = state
+        .http
+        .post(provider.token_url())
+        .header("accept", "application/json")
+        .form(&[
+            ("client_id", client_id),
+            ("client_secret", client_secret),
+            ("code", code),
+            ("redirect_uri", &redirect_uri),
+            ("grant_type", "authorization_code"),
+        ])
+        .send()
+        .await
+        .map_err(|e| format!("token request failed: {e}"))?
+        .error_for_status()
+        .map_err(|e| format!("token request failed: {e}"))?
+        .json::<TokenResponse>()
+        .await
+        .map_err(|e| format!("unexpected token response: {e}"))?
+        .access_token;
+
+    match provider {
+        Provider::GitHub => {
+            let user =
+                user_info::<GitHubUser>(state, "https://api.github.com/user", &token).await?;
+            Ok(Identity {
+                subject: user.id.to_string(),
+                name: user.name.unwrap_or(user.login),
+                email: user.email.unwrap_or_default(),
+            })
+        }
+        Provider::Google => {
+            let user = user_info::<GoogleUser>(
+                state,
+                "https://openidconnect.googleapis.com/v1/userinfo",
+                &token,
+            )
+            .await?;
+            Ok(Identity {
+                subject: user.sub,
+                name: user.name.unwrap_or_default(),
+                email: user.email.unwrap_or_default(),
+            })
+        }
+    }
+}
+
+async fn user_info<T: for<'de> Deserialize<'de>>(
+    state: &web::Data<AppState>,
+    url: &str,
+    token: &str,
+) -> Result<T, String> {
+    state
+        .http
+        .get(url)
+        .bearer_auth(token)
+        .header("user-agent", "tinycomments")
+        .header("accept", "application/json")
+        .send()
+        .await
+        .map_err(|e| format!("user info request failed: {e}"))?
+        .error_for_status()
+        .map_err(|e| format!("user info request failed: {e}"))?
+        .json::<T>()
+        .await
+        .map_err(|e| format!("unexpected user info response: {e}"))
+}
+
+/// Returning users get the id they were issued on their first login. Either way, the name and
+/// email from the provider go through the same checks as any other commenter's, on every login;
+/// a provider vouches for who someone is, not for their address or their right to a name here.
+async fn find_or_create_commenter(
+    state: &web::Data<AppState>,
+    client_ip: &str,
+    provider: Provider,
+    identity: Identity,
+) -> Result<String, Error> {
+    let clean_name = ammonia::clean(&identity.name);
+    let clean_email = ammonia::clean(&identity.email);
+
+    check_identity(state, client_ip, None, &clean_name, &clean_email, true).await?;
+
+    let subject = identity.subject.clone();
+    if let Some(commenter_id) = db::run(&state.db, move |db| {
+        db.find_oauth_commenter(provider.name(), &subject)
+    })
+    .await
+    .map_err(Error::Database)?
+    {
+        return Ok(commenter_id);
+    }
+
+    let mut rand_bytes = [0u8; 32];
+    thread_rng().fill(&mut rand_bytes);
+    let commenter_id = hex::encode(rand_bytes);
+
+    info!(
+        "Generating new ID '{}' for {} user '{}' name: '{}'",
+        commenter_id,
+        provider.name(),
+        identity.subject,
+        clean_name
+    );
+
+    let new_id = commenter_id.clone();
+    db::run(&state.db, move |db| {
+        db.add_oauth_commenter(
+            &new_id,
+            &clean_name,
+            &clean_email,
+            provider.name(),
+            &identity.subject,
+        )
+    })
+    .await
+    .map_err(Error::Database)?;
+
+    Ok(commenter_id)
+}
@@ -19,75 +19,421 @@
  * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
  * SOFTWARE.
  */
+use actix_web::web;
+use argon2::{Algorithm, Argon2, Params, Version};
 use hmac::{Hmac, Mac};
 use rand::{thread_rng, Rng};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::config::{ConfigFile, PowAlgorithm};
+use crate::db::{self, PowState, StoredChallenge, StoredTransaction};
+use crate::error::Error;
+use crate::reputation::{self, Reputations, Signal};
+use crate::{normalize_ip, stats, AppState};
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// How often changed proof-of-work state is written to the database.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Default for `pow_challenge_ttl_secs`.
+const DEFAULT_CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+/// Default for `pow_argon2_memory_kib`.
+const DEFAULT_ARGON2_MEMORY_KIB: u32 = 4096;
+
+/// Default for `pow_argon2_iterations`.
+const DEFAULT_ARGON2_ITERATIONS: u32 = 1;
+
+/// Difficulty of an HMAC challenge before any penalty for heavy traffic.
+const HMAC_BASE_BITS: u32 = 16;
+
+/// An Argon2id hash costs far more than an HMAC, so solving an Argon2id challenge only takes about
+/// four of them before any penalty for heavy traffic.
+const ARGON2_BASE_BITS: u32 = 2;
+
+/// Transactions older than this don't count towards a client's challenge difficulty.
+const TX_WINDOW: Duration = Duration::from_secs(30);
+
+/// Requests a client with no reputation can make within [`TX_WINDOW`] before it is challenged.
+/// Each point of the client IP's reputation score allows one more or one fewer.
+const BASE_ALLOWANCE: i64 = 5;
+
+/// The most requests any client can make within [`TX_WINDOW`] without being challenged.
+const MAX_ALLOWANCE: i64 = 20;
+
+/// Time constant of the server-wide request rate: a request's weight decays by a factor of e over
+/// this long, so under steady traffic the rate approximates requests per minute.
+const LOAD_WINDOW: Duration = Duration::from_secs(60);
+
+/// The most bits of difficulty server-wide load can add to a challenge.
+const MAX_LOAD_BITS: u32 = 8;
+
+/// How long proof-of-work outcomes are gathered before the failure ratio is checked.
+const OUTCOME_WINDOW: Duration = Duration::from_secs(300);
+
+/// Fewer answers than this in a window are too few to judge.
+const OUTCOME_MIN_ANSWERS: u64 = 20;
+
+/// The share of answers failing, expiring or coming from the wrong address that is logged as a
+/// likely attack.
+const FAILURE_ALERT_RATIO: f64 = 0.5;
+
 pub struct Pow {
+    /// Derived from the challenge's key and binding; this is what clients solve against.
     pub key: String,
     pub challenge: String,
 }
 
+/// The action a challenge was issued for and a hash of the request payload. Clients are handed a
+/// key derived from the challenge's random key and its binding, so a solution only verifies when
+/// submitted with the same request that was challenged and can't be replayed elsewhere.
+#[derive(Clone, Copy)]
+pub struct Binding([u8; 32]);
+
+impl Binding {
+    pub fn new(action: &str, payload: &[&str]) -> Self {
+        let mut hasher = Sha256::new();
+        for part in std::iter::once(&action).chain(payload) {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+
+        Binding(hasher.finalize().into())
+    }
+
+    /// The key clients solve against.
+    fn key(&self, key: &[u8; 32]) -> [u8; 32] {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(&self.0);
+        mac.finalize().into_bytes().into()
+    }
+}
+
 pub struct PowChallenge {
     pub client_ip: String,
+    /// Random, and kept server-side. See [`Binding`].
     pub key: [u8; 32],
+    pub issued: Instant,
+    /// Leading zero bits the hash of an Argon2id solution must have. None for HMAC challenges.
+    pub argon2_bits: Option<u32>,
+}
+
+/// The request rate across all clients, decayed over [`LOAD_WINDOW`].
+struct Load {
+    rate: f64,
+    updated: Instant,
+    surging: bool,
+}
+
+/// Why an answer to a challenge was refused.
+enum Rejection {
+    Expired,
+    IpMismatch,
+    Wrong,
+    Unknown,
+    Internal(String),
+}
+
+impl Rejection {
+    fn message(self) -> String {
+        match self {
+            Rejection::Expired => String::from("Challenge expired"),
+            Rejection::IpMismatch => String::from("Forbidden. Client IP Mismatch."),
+            Rejection::Wrong => String::from("Forbidden"),
+            Rejection::Unknown => String::from("Invalid challenge"),
+            Rejection::Internal(e) => e,
+        }
+    }
+}
+
+/// Answers to challenges in the current [`OUTCOME_WINDOW`].
+struct Outcomes {
+    started: Instant,
+    solved: u64,
+    failed: u64,
+    expired: u64,
+    ip_mismatch: u64,
+    /// The last window's failure ratio was over [`FAILURE_ALERT_RATIO`].
+    alarmed: bool,
 }
 
-pub struct PowError {
-    pub code: u16,
-    pub status: Option<String>,
-    pub challenge: Option<String>,
-    pub key: Option<String>,
+impl Outcomes {
+    fn new() -> Self {
+        Outcomes {
+            started: Instant::now(),
+            solved: 0,
+            failed: 0,
+            expired: 0,
+            ip_mismatch: 0,
+            alarmed: false,
+        }
+    }
+
+    /// Log the window's failure ratio if it's out of the ordinary, then start a new window.
+    fn review(&mut self) {
+        let failures = self.failed + self.expired + self.ip_mismatch;
+        let answers = failures + self.solved;
+        let alarmed = answers >= OUTCOME_MIN_ANSWERS
+            && failures as f64 / answers as f64 >= FAILURE_ALERT_RATIO;
+
+        if alarmed {
+            warn!(
+                solved = self.solved,
+                failed = self.failed,
+                expired = self.expired,
+                ip_mismatch = self.ip_mismatch,
+                window_secs = self.started.elapsed().as_secs(),
+                "Most proof-of-work answers are failing; clients may be guessing, replaying or \
+                 farming out challenges"
+            );
+        } else if self.alarmed {
+            info!(
+                solved = self.solved,
+                failures, "Proof-of-work failure ratio back to normal"
+            );
+        }
+
+        *self = Outcomes {
+            alarmed,
+            ..Outcomes::new()
+        };
+    }
 }
 
 pub struct PowTable {
     challenges: Mutex<HashMap<String, PowChallenge>>,
     transactions: Mutex<HashMap<String, [Option<Instant>; 32]>>,
+    dirty: AtomicBool,
+    challenge_ttl: Duration,
+    load: Mutex<Load>,
+    /// `pow_load_threshold`, in requests per minute.
+    load_threshold: Option<f64>,
+    /// Set when `pow_algorithm` is `argon2id`.
+    argon2: Option<Params>,
+    reputation: Arc<Reputations>,
+    stats: Arc<stats::Counters>,
+    outcomes: Mutex<Outcomes>,
 }
 
 impl PowTable {
-    pub fn new() -> Self {
-        PowTable {
+    pub fn new_from_config(
+        config: &ConfigFile,
+        reputation: Arc<Reputations>,
+        stats: Arc<stats::Counters>,
+    ) -> Result<Self, String> {
+        let argon2 = match config.pow_algorithm {
+            PowAlgorithm::Hmac => None,
+            PowAlgorithm::Argon2id => Some(argon2_params(config)?),
+        };
+
+        Ok(PowTable {
             challenges: Mutex::new(HashMap::new()),
             transactions: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+            challenge_ttl: config
+                .pow_challenge_ttl_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_CHALLENGE_TTL),
+            load: Mutex::new(Load {
+                rate: 0.0,
+                updated: Instant::now(),
+                surging: false,
+            }),
+            load_threshold: config.pow_load_threshold,
+            argon2,
+            reputation,
+            stats,
+            outcomes: Mutex::new(Outcomes::new()),
+        })
+    }
+
+    /// The puzzle clients are given, as reported by `/pow/get/`.
+    pub fn algorithm(&self) -> &'static str {
+        match self.argon2 {
+            Some(_) => "argon2id",
+            None => "hmac",
+        }
+    }
+
+    /// The key sent to clients. Argon2id keys carry the hash parameters and difficulty ahead of
+    /// the salt, so clients can tell which puzzle they've been given.
+    fn key_string(&self, key: &[u8; 32], argon2_bits: Option<u32>) -> String {
+        match (&self.argon2, argon2_bits) {
+            (Some(params), Some(bits)) => format!(
+                "argon2id$m={},t={},p=1,b={bits}${}",
+                params.m_cost(),
+                params.t_cost(),
+                hex::encode(key)
+            ),
+            _ => hex::encode(key),
+        }
+    }
+
+    /// Load state saved by a previous run.
+    pub fn restore(&self, saved: PowState) {
+        let (now, now_ms) = (Instant::now(), unix_ms());
+
+        let mut challenges = self.challenges.lock().unwrap();
+        for stored in saved.challenges {
+            let Some((key, argon2_bits)) = parse_key(&stored.key) else {
+                continue;
+            };
+
+            let age =
+                Duration::from_millis(now_ms.saturating_sub(stored.issued_at_ms).max(0) as u64);
+            if age >= self.challenge_ttl {
+                continue;
+            }
+            let Some(issued) = now.checked_sub(age) else {
+                continue;
+            };
+
+            challenges.insert(
+                stored.challenge,
+                PowChallenge {
+                    client_ip: stored.client_ip,
+                    key,
+                    issued,
+                    argon2_bits,
+                },
+            );
+        }
+
+        let mut transactions = self.transactions.lock().unwrap();
+        for tx in saved.transactions {
+            let age = Duration::from_millis(now_ms.saturating_sub(tx.at_ms).max(0) as u64);
+            if age >= TX_WINDOW {
+                continue;
+            }
+            let Some(at) = now.checked_sub(age) else {
+                continue;
+            };
+
+            let slots = transactions.entry(tx.client_ip).or_insert([None; 32]);
+            if let Some(slot) = slots.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(at);
+            }
+        }
+
+        info!(
+            "Restored {} proof-of-work challenges for {} clients",
+            challenges.len(),
+            transactions.len()
+        );
+    }
+
+    /// The current state, or None if nothing has changed since the last snapshot.
+    pub fn snapshot(&self) -> Option<PowState> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return None;
+        }
+
+        let mut saved = PowState::default();
+
+        let now_ms = unix_ms();
+
+        for (challenge, pow) in self.challenges.lock().unwrap().iter() {
+            saved.challenges.push(StoredChallenge {
+                challenge: challenge.clone(),
+                client_ip: pow.client_ip.clone(),
+                key: self.key_string(&pow.key, pow.argon2_bits),
+                issued_at_ms: now_ms - pow.issued.elapsed().as_millis() as i64,
+            });
+        }
+
+        for (ip, txvec) in self.transactions.lock().unwrap().iter() {
+            for tx in txvec.iter().flatten().filter(|tx| tx.elapsed() < TX_WINDOW) {
+                saved.transactions.push(StoredTransaction {
+                    client_ip: ip.clone(),
+                    at_ms: now_ms - tx.elapsed().as_millis() as i64,
+                });
+            }
+        }
+
+        Some(saved)
+    }
+
+    /// Drop challenges that have outlived their TTL and clients with no recent transactions.
+    pub fn purge_expired(&self) {
+        let expired_challenges = {
+            let mut challenges = self.challenges.lock().unwrap();
+            let before = challenges.len();
+            challenges.retain(|_, pow| pow.issued.elapsed() < self.challenge_ttl);
+            before - challenges.len()
+        };
+
+        let idle_clients = {
+            let mut transactions = self.transactions.lock().unwrap();
+            let before = transactions.len();
+            transactions
+                .retain(|_, txvec| txvec.iter().flatten().any(|tx| tx.elapsed() < TX_WINDOW));
+            before - transactions.len()
+        };
+
+        // Challenges nobody answered aren't counted: a reader who opens a page and leaves hasn't
+        // failed anything.
+        if expired_challenges > 0 || idle_clients > 0 {
+            debug!(
+                "Purged {expired_challenges} expired challenges and {idle_clients} idle clients"
+            );
+            self.mark_dirty();
+        }
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Count answers to challenges towards `/admin/stats/` and the failure ratio.
+    fn record_outcome(&self, kind: &'static str, count: u64) {
+        self.stats.add(kind, count as i64);
+
+        let mut outcomes = self.outcomes.lock().unwrap();
+        if outcomes.started.elapsed() >= OUTCOME_WINDOW {
+            outcomes.review();
+        }
+        match kind {
+            stats::POW_SOLVED => outcomes.solved += count,
+            stats::POW_EXPIRED => outcomes.expired += count,
+            stats::POW_IP_MISMATCH => outcomes.ip_mismatch += count,
+            _ => outcomes.failed += count,
         }
     }
 
-    pub fn handle(&self, ip: &String, challenge: &Option<String>, secret: &Option<String>) -> Option<PowError> {
+    pub fn handle(
+        &self,
+        ip: &str,
+        binding: Binding,
+        challenge: &Option<String>,
+        secret: &Option<String>,
+    ) -> Result<(), Error> {
         if let Some(challenge) = challenge {
             if let Some(secret) = secret {
-                if let Err(_e) = self.validate_pow(ip, challenge, secret)
-                {
-                    return Some(PowError {
-                        code: 403,
-                        status: Some(String::from("Challenge not accepted.")),
-                        challenge: None,
-                        key: None,
-                    });
+                if let Err(_e) = self.validate_pow(ip, binding, challenge, secret) {
+                    self.reputation
+                        .record(reputation::ip(ip), Signal::PowFailure);
+                    return Err(Error::Forbidden(String::from("Challenge not accepted.")));
                 }
             } else {
-                return Some(PowError {
-                    code: 500,
-                    status: Some(String::from("Challenge proof incomplete: no secret provided")),
-                    challenge: None,
-                    key: None,
-                });
+                return Err(Error::BadRequest(String::from(
+                    "Challenge proof incomplete: no secret provided",
+                )));
             }
-        } else if let Some(challenge) = self.get_challenge(ip) {
-            return Some(PowError {
-                code: 401,
-                status: None,
-                challenge: Some(challenge.challenge),
-                key: Some(challenge.key),
+        } else if let Some(challenge) = self.get_challenge(ip, binding) {
+            return Err(Error::ChallengeRequired {
+                challenge: challenge.challenge,
+                key: challenge.key,
             });
         }
 
-        None
+        Ok(())
     }
 
     fn get_txcount(&self, ip: &str, add_transaction: bool) -> Result<u32, String> {
@@ -102,7 +448,7 @@ impl PowTable {
                         let mut i = 0;
 
                         for tx in txvec.iter().flatten() {
-                            if tx.elapsed().as_secs() < 30 {
+                            if tx.elapsed() < TX_WINDOW {
                                 tx_count += 1;
                                 new_instants[i] = Some(*tx);
                                 i += 1;
@@ -110,6 +456,7 @@ impl PowTable {
                         }
 
                         if add_transaction {
+                            self.mark_dirty();
                             if i < 32 {
                                 new_instants[i] = Some(now);
                             } else {
@@ -123,6 +470,7 @@ impl PowTable {
                     }
                     None => {
                         if add_transaction {
+                            self.mark_dirty();
                             new_instants[0] = Some(now);
                             txhash.insert(ip.to_owned(), new_instants);
 
@@ -137,10 +485,52 @@ impl PowTable {
         }
     }
 
-    pub fn get_challenge(&self, ip: &str) -> Option<Pow> {
+    /// Count a request towards the server-wide rate. While the rate is above `pow_load_threshold`,
+    /// returns the extra bits of difficulty every challenge should carry: one more for each
+    /// doubling of the rate past the threshold.
+    fn load_bits(&self) -> Option<u32> {
+        let threshold = self.load_threshold?;
+        let mut load = self.load.lock().unwrap();
+
+        let decay = (-load.updated.elapsed().as_secs_f64() / LOAD_WINDOW.as_secs_f64()).exp();
+        load.rate = load.rate * decay + 1.0;
+        load.updated = Instant::now();
+
+        let surging = load.rate > threshold;
+        if surging != load.surging {
+            load.surging = surging;
+            if surging {
+                warn!(
+                    rate = load.rate as u64,
+                    "Request rate above pow_load_threshold; challenging all clients"
+                );
+            } else {
+                info!(
+                    rate = load.rate as u64,
+                    "Request rate back below pow_load_threshold"
+                );
+            }
+        }
+
+        surging.then(|| ((load.rate / threshold).log2() as u32).min(MAX_LOAD_BITS))
+    }
+
+    pub fn get_challenge(&self, ip: &str, binding: Binding) -> Option<Pow> {
+        let ip = &normalize_ip(ip);
+        let load_bits = self.load_bits();
+
+        let allowance = (BASE_ALLOWANCE + self.reputation.score(&reputation::ip(ip)))
+            .clamp(0, MAX_ALLOWANCE) as u32;
+
         if let Ok(count) = self.get_txcount(ip, true) {
-            if count > 5 {
-                if let Ok(pow) = self.generate_pow(ip, 16 + count - 5) {
+            if count > allowance || load_bits.is_some() {
+                let base_bits = match self.argon2 {
+                    Some(_) => ARGON2_BASE_BITS,
+                    None => HMAC_BASE_BITS,
+                };
+                let bits = base_bits + count.saturating_sub(allowance) + load_bits.unwrap_or(0);
+                if let Ok(pow) = self.generate_pow(ip, bits, binding) {
+                    self.stats.record(stats::POW_ISSUED);
                     return Some(pow);
                 }
             }
@@ -149,13 +539,17 @@ impl PowTable {
         None
     }
 
-    pub fn generate_pow(&self, ip: &str, bits: u32) -> Result<Pow, String> {
+    pub fn generate_pow(&self, ip: &str, bits: u32, binding: Binding) -> Result<Pow, String> {
         let mut rng = thread_rng();
 
         let mut key_rand_bytes = [0u8; 32];
         rng.fill(&mut key_rand_bytes);
 
-        let hexkey = hex::encode(key_rand_bytes);
+        if self.argon2.is_some() {
+            return self.generate_argon2_pow(ip, key_rand_bytes, bits, binding);
+        }
+
+        let hexkey = hex::encode(binding.key(&key_rand_bytes));
 
         let mut mac = HmacSha256::new_from_slice(hexkey.as_bytes()).expect("?!?");
         let secret = format!("{}", rng.gen_range(0..u64::pow(2, bits)));
@@ -170,8 +564,11 @@ impl PowTable {
                     PowChallenge {
                         client_ip: ip.to_owned(),
                         key: key_rand_bytes,
+                        issued: Instant::now(),
+                        argon2_bits: None,
                     },
                 );
+                self.mark_dirty();
 
                 Ok(Pow {
                     key: hexkey.to_string(),
@@ -182,20 +579,121 @@ impl PowTable {
         }
     }
 
+    /// An Argon2id challenge is solved by finding a number whose hash, salted with the key, starts
+    /// with `bits` zero bits. Unlike an HMAC challenge, there's no answer to hash up front, so the
+    /// challenge itself is just a random identifier.
+    fn generate_argon2_pow(
+        &self,
+        ip: &str,
+        key: [u8; 32],
+        bits: u32,
+        binding: Binding,
+    ) -> Result<Pow, String> {
+        let mut id = [0u8; 16];
+        thread_rng().fill(&mut id);
+        let challenge = hex::encode(id);
+
+        match self.challenges.lock() {
+            Ok(mut hash) => {
+                hash.insert(
+                    challenge.clone(),
+                    PowChallenge {
+                        client_ip: ip.to_owned(),
+                        key,
+                        issued: Instant::now(),
+                        argon2_bits: Some(bits),
+                    },
+                );
+                self.mark_dirty();
+
+                Ok(Pow {
+                    key: self.key_string(&binding.key(&key), Some(bits)),
+                    challenge,
+                })
+            }
+            Err(e) => Err(format!("Could not get lock: {e:?}")),
+        }
+    }
+
+    fn argon2_solves(&self, key: &[u8; 32], bits: u32, client_secret: &str) -> bool {
+        let Some(params) = &self.argon2 else {
+            return false;
+        };
+
+        let mut out = [0u8; 32];
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+        if argon2
+            .hash_password_into(client_secret.as_bytes(), key, &mut out)
+            .is_err()
+        {
+            return false;
+        }
+
+        leading_zero_bits(&out) >= bits
+    }
+
     pub fn validate_pow(
         &self,
-        ip: &String,
+        ip: &str,
+        binding: Binding,
         client_challenge: &str,
         client_secret: &str,
     ) -> Result<String, String> {
+        let result = self.check_answer(ip, binding, client_challenge, client_secret);
+
+        let kind = match &result {
+            Ok(()) => Some(stats::POW_SOLVED),
+            Err(Rejection::Expired) => Some(stats::POW_EXPIRED),
+            Err(Rejection::IpMismatch) => Some(stats::POW_IP_MISMATCH),
+            Err(Rejection::Wrong | Rejection::Unknown) => Some(stats::POW_FAILED),
+            Err(Rejection::Internal(_)) => None,
+        };
+        if let Some(kind) = kind {
+            self.record_outcome(kind, 1);
+        }
+
+        result
+            .map(|()| String::from("Ok"))
+            .map_err(Rejection::message)
+    }
+
+    fn check_answer(
+        &self,
+        ip: &str,
+        binding: Binding,
+        client_challenge: &str,
+        client_secret: &str,
+    ) -> Result<(), Rejection> {
+        let ip = &normalize_ip(ip);
         match self.challenges.lock() {
             Ok(mut hash) => match hash.get(client_challenge) {
                 Some(challenge) => {
+                    if challenge.issued.elapsed() >= self.challenge_ttl {
+                        hash.remove(client_challenge);
+                        self.mark_dirty();
+                        return Err(Rejection::Expired);
+                    }
+
                     if challenge.client_ip != *ip {
-                        return Err(String::from("Forbidden. Client IP Mismatch."));
+                        return Err(Rejection::IpMismatch);
+                    }
+
+                    if let Some(bits) = challenge.argon2_bits {
+                        // Only one attempt per challenge, so bad solutions can't make the server
+                        // hash over and over.
+                        let key = binding.key(&challenge.key);
+                        hash.remove(client_challenge);
+                        self.mark_dirty();
+                        drop(hash);
+
+                        return match self.argon2_solves(&key, bits, client_secret) {
+                            true => Ok(()),
+                            false => Err(Rejection::Wrong),
+                        };
                     }
 
-                    let mut mac = HmacSha256::new_from_slice(hex::encode(challenge.key).as_bytes())
+                    let key = hex::encode(binding.key(&challenge.key));
+                    let mut mac = HmacSha256::new_from_slice(key.as_bytes())
                         .expect("Cannot make hmac instance");
 
                     mac.update(client_secret.as_bytes());
@@ -205,14 +703,91 @@ impl PowTable {
 
                     if computed == *client_challenge {
                         hash.remove(client_challenge);
-                        Ok(String::from("Ok"))
+                        self.mark_dirty();
+                        Ok(())
                     } else {
-                        Err(String::from("Forbidden"))
+                        Err(Rejection::Wrong)
                     }
                 }
-                None => Err(String::from("Invalid challenge")),
+                None => Err(Rejection::Unknown),
             },
-            Err(e) => Err(format!("Internal server error: {e:?}")),
+            Err(e) => Err(Rejection::Internal(format!("Internal server error: {e:?}"))),
         }
     }
 }
+
+fn argon2_params(config: &ConfigFile) -> Result<Params, String> {
+    Params::new(
+        config
+            .pow_argon2_memory_kib
+            .unwrap_or(DEFAULT_ARGON2_MEMORY_KIB),
+        config
+            .pow_argon2_iterations
+            .unwrap_or(DEFAULT_ARGON2_ITERATIONS),
+        1,
+        Some(32),
+    )
+    .map_err(|e| format!("Invalid Argon2 parameters: {e}"))
+}
+
+/// The key bytes and, for Argon2id challenges, the difficulty, from a key made by
+/// [`PowTable::key_string`].
+fn parse_key(stored: &str) -> Option<([u8; 32], Option<u32>)> {
+    let (hexkey, argon2_bits) = match stored.strip_prefix("argon2id$") {
+        Some(rest) => {
+            let (params, hexkey) = rest.split_once('$')?;
+            let bits = params
+                .split(',')
+                .find_map(|param| param.strip_prefix("b="))?
+                .parse()
+                .ok()?;
+            (hexkey, Some(bits))
+        }
+        None => (stored, None),
+    };
+
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(hexkey, &mut key).ok()?;
+    Some((key, argon2_bits))
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        if *byte != 0 {
+            return bits + byte.leading_zeros();
+        }
+        bits += 8;
+    }
+    bits
+}
+
+fn unix_ms() -> i64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(t) => t.as_millis() as i64,
+        Err(_) => 0,
+    }
+}
+
+/// Write the proof-of-work state to the database if it has changed.
+pub async fn persist(state: &web::Data<AppState>) {
+    let Some(saved) = state.pow.snapshot() else {
+        return;
+    };
+
+    if let Err(e) = db::run(&state.db, move |db| db.save_pow_state(&saved)).await {
+        // Try again on the next pass.
+        state.pow.mark_dirty();
+        warn!("Unable to save proof-of-work state: {e}");
+    }
+}
+
+/// Start the background task that periodically saves proof-of-work state.
+pub fn spawn_persist_worker(state: web::Data<AppState>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            sleep(PERSIST_INTERVAL).await;
+            persist(&state).await;
+        }
+    });
+}
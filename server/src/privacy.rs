@@ -0,0 +1,167 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Data-access requests from commenters.
+
+use actix_web::{post, web, HttpRequest};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::{db, email, error::Error, get_client_ip, pow, session, AppState};
+
+/// How long an emailed export confirmation token stays valid.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+struct PendingExport {
+    commenter_id: String,
+    requested: Instant,
+}
+
+/// Export requests waiting for the commenter to confirm them, keyed by the emailed token.
+pub struct ExportConfirmations {
+    pending: Mutex<HashMap<String, PendingExport>>,
+}
+
+impl ExportConfirmations {
+    pub fn new() -> Self {
+        ExportConfirmations {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn start(&self, commenter_id: &str) -> String {
+        let mut rand_bytes = [0u8; 16];
+        thread_rng().fill(&mut rand_bytes);
+        let token = hex::encode(rand_bytes);
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, pending| pending.requested.elapsed() < CONFIRMATION_TIMEOUT);
+        pending.insert(
+            token.clone(),
+            PendingExport {
+                commenter_id: String::from(commenter_id),
+                requested: Instant::now(),
+            },
+        );
+
+        token
+    }
+
+    /// Each token is single-use: it is removed whether or not it matches.
+    fn confirm(&self, commenter_id: &str, token: &str) -> bool {
+        let Some(pending) = self.pending.lock().unwrap().remove(token) else {
+            return false;
+        };
+
+        pending.commenter_id == commenter_id && pending.requested.elapsed() < CONFIRMATION_TIMEOUT
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportRequest {
+    #[serde(default)]
+    commenter_id: String,
+    /// The token from the confirmation email, when `export_email_confirmation` is enabled.
+    token: Option<String>,
+    challenge: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ExportResponse {
+    code: u16,
+    status: String,
+    data: Option<db::CommenterExport>,
+    challenge: Option<String>,
+    key: Option<String>,
+}
+
+/// Return everything stored about a commenter: their name and email, and every comment and vote
+/// they've made. When `export_email_confirmation` is enabled and the commenter has an email
+/// address, the first request only sends a token to that address, and the data is returned once
+/// the request is repeated with the token.
+#[post("/id/export/")]
+async fn export(
+    data: web::Form<ExportRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: session::Session,
+) -> Result<web::Json<ExportResponse>, Error> {
+    let mut response = ExportResponse {
+        code: 200,
+        status: String::from("OK"),
+        data: None,
+        challenge: None,
+        key: None,
+    };
+
+    state.pow.handle(
+        &get_client_ip(&req),
+        pow::Binding::new(
+            "/id/export/",
+            &[&data.commenter_id, data.token.as_deref().unwrap_or("")],
+        ),
+        &data.challenge,
+        &data.secret,
+    )?;
+
+    let client_ip = get_client_ip(&req);
+    let commenter_id = session.or(&data.commenter_id);
+    info!(client_ip, commenter_id, "Exporting commenter data");
+
+    let lookup_id = commenter_id.clone();
+    let export = db::run(&state.db, move |db| db.export_commenter(&lookup_id))
+        .await
+        .map_err(Error::Database)?
+        .ok_or_else(|| Error::NotFound(String::from("Unknown commenter")))?;
+
+    let email = &export.commenter.email;
+    if state.config.export_email_confirmation && !email.is_empty() {
+        match &data.token {
+            Some(token) => {
+                if !state.export_confirmations.confirm(&commenter_id, token) {
+                    return Err(Error::Forbidden(String::from(
+                        "Invalid or expired confirmation token",
+                    )));
+                }
+            }
+            None => {
+                let token = state.export_confirmations.start(&commenter_id);
+                let name = &export.commenter.name;
+                email::send_export_confirmation(&state, email, name, &token)
+                    .await
+                    .map_err(|e| {
+                        Error::Internal(format!("Unable to send confirmation email: {e}"))
+                    })?;
+                response.status = String::from("Confirmation email sent");
+                return Ok(web::Json(response));
+            }
+        }
+    }
+
+    response.data = Some(export);
+    Ok(web::Json(response))
+}
@@ -0,0 +1,202 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_http::h1;
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    web, Error, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{api, get_client_ip, normalize_ip, session, AppState};
+
+/// How often idle buckets are swept from the table.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A token bucket limit for one endpoint. Each client may make `burst` requests at once, after
+/// which tokens refill at `per_minute`.
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(Serialize)]
+struct RateLimitResponse {
+    code: u16,
+    status: String,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// The limit the bucket was last checked against, so pruning knows when it is full again.
+    capacity: f64,
+    rate: f64,
+}
+
+impl Bucket {
+    fn is_full(&self, now: Instant) -> bool {
+        now.duration_since(self.updated).as_secs_f64() * self.rate >= self.capacity - self.tokens
+    }
+}
+
+/// Token buckets keyed by endpoint and client (IP address or commenter id).
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+    last_prune: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+            last_prune: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Take a token from the client's bucket, or return how long until one is available.
+    pub fn check(&self, path: &str, client: &str, limit: &RateLimitConfig) -> Result<(), Duration> {
+        let capacity = f64::from(limit.burst.max(1));
+        let rate = f64::from(limit.per_minute) / 60.0;
+        let now = Instant::now();
+
+        self.prune(now);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry((String::from(path), String::from(client)))
+            .or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+                capacity,
+                rate,
+            });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+        bucket.capacity = capacity;
+        bucket.rate = rate;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        } else {
+            Err(Duration::from_secs(60))
+        }
+    }
+
+    /// Drop buckets that have refilled completely since they were last used; they are
+    /// indistinguishable from new ones. Partly drained buckets are kept however long they idle.
+    fn prune(&self, now: Instant) {
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if now.duration_since(*last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        *last_prune = now;
+
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| !bucket.is_full(now));
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientIds {
+    commenter_id: Option<String>,
+    voter_id: Option<String>,
+}
+
+/// Enforce the `rate_limits` configured for the request's route, separately for the client's IP
+/// address and for the commenter id in the request body, if any.
+pub async fn middleware(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let Some(path) = api::route(req.request()) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let Some(limit) = state.config.rate_limits.get(&path) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let mut clients = vec![format!(
+        "ip:{}",
+        normalize_ip(&get_client_ip(req.request()))
+    )];
+    let session_id = session::commenter_id(req.request());
+    if let Some(id) = &session_id {
+        clients.push(format!("id:{id}"));
+    }
+
+    let is_form = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/x-www-form-urlencoded"));
+
+    if is_form && session_id.is_none() {
+        // Read the body to find the commenter, then put it back for the handler.
+        let body = req.extract::<web::Bytes>().await?;
+        if let Ok(ids) = serde_urlencoded::from_bytes::<ClientIds>(&body) {
+            if let Some(id) = ids.commenter_id.or(ids.voter_id) {
+                if !id.is_empty() {
+                    clients.push(format!("id:{id}"));
+                }
+            }
+        }
+        let (_, mut payload) = h1::Payload::create(true);
+        payload.unread_data(body);
+        req.set_payload(payload.into());
+    }
+
+    for client in clients.iter() {
+        if let Err(retry_after) = state.ratelimit.check(&path, client, limit) {
+            let res = HttpResponse::TooManyRequests()
+                .insert_header((
+                    "retry-after",
+                    (retry_after.as_secs_f64().ceil() as u64).max(1).to_string(),
+                ))
+                .json(RateLimitResponse {
+                    code: 429,
+                    status: String::from("Too many requests"),
+                });
+            return Ok(req.into_response(res));
+        }
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
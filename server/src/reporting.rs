@@ -0,0 +1,373 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Optional error reporting to Sentry, or any service that accepts Sentry's event API, such as
+//! GlitchTip. Handler errors that end in a 5xx response and panics are sent with the route,
+//! method, request id, article, and a hash of the client's IP address. Reports are queued and
+//! sent by a background task, so an unreachable endpoint never slows down a request.
+
+use actix_http::h1;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, Error, HttpMessage,
+};
+use rand::{thread_rng, Rng};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::{config::ConfigFile, get_client_ip, hash_ip, logging::RequestId};
+
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Form bodies up to this size are read for the article they name. Larger ones are left alone,
+/// and reported without it.
+const MAX_BUFFERED_BODY: usize = 64 * 1024;
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+tokio::task_local! {
+    /// The request being handled, for reports of panics raised while handling it.
+    static CONTEXT: RequestContext;
+}
+
+/// Where reports are sent, from the `[error_reporting]` table.
+#[derive(Deserialize, Debug)]
+pub struct ErrorReporting {
+    /// The project's DSN, e.g. `https://PUBLIC_KEY@o0.ingest.sentry.io/123`.
+    pub dsn: String,
+    /// Sent with each report, to tell e.g. staging and production apart.
+    pub environment: Option<String>,
+}
+
+impl ErrorReporting {
+    pub fn validate(&self) -> Vec<String> {
+        match Dsn::parse(&self.dsn) {
+            Ok(_) => vec![],
+            Err(e) => vec![format!("error_reporting.dsn {e}")],
+        }
+    }
+}
+
+/// The store endpoint and key parsed from a DSN.
+struct Dsn {
+    store_url: String,
+    public_key: String,
+}
+
+impl Dsn {
+    fn parse(dsn: &str) -> Result<Self, String> {
+        let url = Url::parse(dsn).map_err(|e| format!("is not a URL: {e}"))?;
+
+        if url.scheme() != "https" && url.scheme() != "http" {
+            return Err(String::from("must be an http or https URL"));
+        }
+
+        let public_key = url.username();
+        if public_key.is_empty() {
+            return Err(String::from("has no public key"));
+        }
+
+        let Some(host) = url.host_str() else {
+            return Err(String::from("has no host"));
+        };
+
+        let path = url.path().trim_end_matches('/');
+        let (prefix, project) = path.rsplit_once('/').unwrap_or(("", path));
+        if project.is_empty() || !project.chars().all(|c| c.is_ascii_digit()) {
+            return Err(String::from("has no project id"));
+        }
+
+        let port = match url.port() {
+            Some(port) => format!(":{port}"),
+            None => String::new(),
+        };
+
+        Ok(Dsn {
+            store_url: format!(
+                "{}://{host}{port}{prefix}/api/{project}/store/",
+                url.scheme()
+            ),
+            public_key: String::from(public_key),
+        })
+    }
+}
+
+struct Reporter {
+    queue: mpsc::UnboundedSender<Event>,
+    environment: Option<String>,
+    /// Key for hashing client IPs. It is made up at startup, so hashes match within a run of the
+    /// server and can't be reversed by trying every address.
+    ip_key: String,
+}
+
+#[derive(Clone, Default)]
+struct RequestContext {
+    route: String,
+    method: String,
+    request_id: Option<String>,
+    client_ip_hash: String,
+    article: Option<String>,
+}
+
+/// An event in the shape Sentry's store endpoint accepts.
+#[derive(Serialize)]
+pub struct Event {
+    event_id: String,
+    timestamp: f64,
+    platform: &'static str,
+    level: &'static str,
+    logger: &'static str,
+    release: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    environment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction: Option<String>,
+    exception: Exceptions,
+    tags: HashMap<&'static str, String>,
+    extra: HashMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct Exceptions {
+    values: Vec<Exception>,
+}
+
+#[derive(Serialize)]
+struct Exception {
+    #[serde(rename = "type")]
+    kind: String,
+    value: String,
+}
+
+impl Reporter {
+    fn report(&self, kind: &str, message: String, context: Option<RequestContext>) {
+        let mut rand_bytes = [0u8; 16];
+        thread_rng().fill(&mut rand_bytes);
+
+        let timestamp = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(t) => t.as_secs_f64(),
+            Err(_) => 0.0,
+        };
+
+        let mut tags = HashMap::new();
+        let mut extra = HashMap::new();
+        let mut transaction = None;
+
+        if let Some(context) = context {
+            tags.insert("method", context.method);
+            tags.insert("client_ip_hash", context.client_ip_hash);
+            if let Some(request_id) = context.request_id {
+                tags.insert("request_id", request_id);
+            }
+            if let Some(article) = context.article {
+                extra.insert("article", article);
+            }
+            tags.insert("route", context.route.clone());
+            transaction = Some(context.route);
+        }
+
+        let event = Event {
+            event_id: hex::encode(rand_bytes),
+            timestamp,
+            platform: "other",
+            level: "error",
+            logger: "tinycomments",
+            release: concat!("tinycomments@", env!("CARGO_PKG_VERSION")),
+            environment: self.environment.clone(),
+            transaction,
+            exception: Exceptions {
+                values: vec![Exception {
+                    kind: String::from(kind),
+                    value: message,
+                }],
+            },
+            tags,
+            extra,
+        };
+
+        if self.queue.send(event).is_err() {
+            warn!("Error reporting task is not running; dropping report");
+        }
+    }
+}
+
+/// Set up error reporting, if it is configured, and install a panic hook that reports panics.
+/// The returned queue is handed to [`spawn_worker`] once the server's state is built.
+pub fn init(config: &ConfigFile) -> Option<mpsc::UnboundedReceiver<Event>> {
+    let reporting = config.error_reporting.as_ref()?;
+
+    let mut key = [0u8; 32];
+    thread_rng().fill(&mut key);
+
+    let (queue, receiver) = mpsc::unbounded_channel();
+    let reporter = Reporter {
+        queue,
+        environment: reporting.environment.clone(),
+        ip_key: hex::encode(key),
+    };
+
+    if REPORTER.set(reporter).is_err() {
+        return None;
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(reporter) = REPORTER.get() {
+            let payload = info.payload();
+            let message = match (
+                payload.downcast_ref::<&str>(),
+                payload.downcast_ref::<String>(),
+            ) {
+                (Some(message), _) => String::from(*message),
+                (_, Some(message)) => message.clone(),
+                _ => String::from("Box<dyn Any>"),
+            };
+            let message = match info.location() {
+                Some(location) => format!("{message} at {location}"),
+                None => message,
+            };
+
+            reporter.report("panic", message, CONTEXT.try_with(|c| c.clone()).ok());
+        }
+
+        default_hook(info);
+    }));
+
+    Some(receiver)
+}
+
+/// Start the background task that sends reports.
+pub fn spawn_worker(
+    http: reqwest::Client,
+    config: &ConfigFile,
+    mut events: mpsc::UnboundedReceiver<Event>,
+) {
+    let Some(Ok(dsn)) = config.error_reporting.as_ref().map(|r| Dsn::parse(&r.dsn)) else {
+        return;
+    };
+
+    let auth = format!(
+        "Sentry sentry_version=7, sentry_client=tinycomments/{}, sentry_key={}",
+        env!("CARGO_PKG_VERSION"),
+        dsn.public_key
+    );
+
+    actix_web::rt::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let req = http
+                .post(&dsn.store_url)
+                .timeout(REPORT_TIMEOUT)
+                .header("x-sentry-auth", &auth)
+                .json(&event);
+
+            actix_web::rt::spawn(async move {
+                match req.send().await {
+                    Ok(res) if res.status().is_success() => {
+                        debug!("Sent error report {}", event.event_id);
+                    }
+                    Ok(res) => warn!("Error reporting endpoint returned {}", res.status()),
+                    Err(e) => warn!("Unable to send error report: {e}"),
+                }
+            });
+        }
+    });
+}
+
+/// Report handler errors that end in a 5xx response, and keep the request's details at hand for
+/// the panic hook.
+pub async fn middleware(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(reporter) = REPORTER.get() else {
+        return next.call(req).await;
+    };
+
+    let mut context = RequestContext {
+        route: req
+            .match_pattern()
+            .unwrap_or_else(|| String::from(req.path())),
+        method: req.method().to_string(),
+        request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+        client_ip_hash: hash_ip(&reporter.ip_key, &get_client_ip(req.request())),
+        article: article_field(req.query_string().as_bytes()),
+    };
+
+    if context.article.is_none() && is_small_form(&req) {
+        // Read the body to look for the article, then put it back for the handler.
+        let body = req.extract::<web::Bytes>().await?;
+        context.article = article_field(&body);
+        let (_, mut payload) = h1::Payload::create(true);
+        payload.unread_data(body);
+        req.set_payload(payload.into());
+    }
+
+    let res = CONTEXT.scope(context.clone(), next.call(req)).await;
+
+    match &res {
+        Ok(res) if res.status().is_server_error() => {
+            let message = match res.response().error() {
+                Some(e) => e.to_string(),
+                None => format!("{} response", res.status()),
+            };
+            reporter.report("error", message, Some(context));
+        }
+        Err(e) if e.as_response_error().status_code().is_server_error() => {
+            reporter.report("error", e.to_string(), Some(context));
+        }
+        _ => {}
+    }
+
+    res
+}
+
+fn is_small_form(req: &ServiceRequest) -> bool {
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    is_form && length.is_some_and(|length| length <= MAX_BUFFERED_BODY)
+}
+
+fn article_field(form: &[u8]) -> Option<String> {
+    serde_urlencoded::from_bytes::<HashMap<String, String>>(form)
+        .ok()?
+        .remove("article")
+}
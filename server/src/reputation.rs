@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! Per-IP and per-commenter reputation. Published comments raise a subject's score; rejected spam,
+//! failed proof-of-work attempts and flags from other commenters lower it. An IP address's score
+//! sets how many requests it can make before it has to solve a challenge, and the scores of a
+//! comment's poster and their IP address can hold the comment for moderation or publish it
+//! without review.
+//!
+//! Counts are kept in memory and written to the database in the background.
+use actix_web::web;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::db::{self, Reputation};
+use crate::{normalize_ip, AppState};
+
+/// How often changed reputations are written to the database.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+const APPROVED_WEIGHT: i64 = 1;
+const SPAM_WEIGHT: i64 = -10;
+const POW_FAILURE_WEIGHT: i64 = -1;
+const FLAG_WEIGHT: i64 = -3;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Signal {
+    /// A comment was published.
+    Approved,
+    /// A comment was rejected by the site owner, the blocklist or the link limit.
+    Spam,
+    /// A proof-of-work solution didn't verify.
+    PowFailure,
+    /// Another commenter flagged a comment.
+    Flagged,
+}
+
+pub fn ip(client_ip: &str) -> String {
+    format!("ip:{}", normalize_ip(client_ip))
+}
+
+pub fn commenter(commenter_id: &str) -> String {
+    format!("commenter:{commenter_id}")
+}
+
+pub struct Reputations {
+    entries: Mutex<HashMap<String, Reputation>>,
+    /// Subjects changed since the last save.
+    dirty: Mutex<HashSet<String>>,
+}
+
+impl Reputations {
+    pub fn new() -> Self {
+        Reputations {
+            entries: Mutex::new(HashMap::new()),
+            dirty: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Load reputations saved by a previous run.
+    pub fn restore(&self, saved: Vec<(String, Reputation)>) {
+        self.entries.lock().unwrap().extend(saved);
+    }
+
+    pub fn record(&self, subject: String, signal: Signal) {
+        let mut entries = self.entries.lock().unwrap();
+        let reputation = entries.entry(subject.clone()).or_default();
+        match signal {
+            Signal::Approved => reputation.approved += 1,
+            Signal::Spam => reputation.spam += 1,
+            Signal::PowFailure => reputation.pow_failures += 1,
+            Signal::Flagged => reputation.flags += 1,
+        }
+
+        self.dirty.lock().unwrap().insert(subject);
+    }
+
+    /// Zero for subjects nothing has been recorded against.
+    pub fn score(&self, subject: &str) -> i64 {
+        match self.entries.lock().unwrap().get(subject) {
+            Some(reputation) => {
+                reputation.approved * APPROVED_WEIGHT
+                    + reputation.spam * SPAM_WEIGHT
+                    + reputation.pow_failures * POW_FAILURE_WEIGHT
+                    + reputation.flags * FLAG_WEIGHT
+            }
+            None => 0,
+        }
+    }
+
+    /// The lower of the commenter's and their IP address's scores.
+    pub fn poster_score(&self, client_ip: &str, commenter_id: &str) -> i64 {
+        self.score(&ip(client_ip))
+            .min(self.score(&commenter(commenter_id)))
+    }
+
+    /// Reputations changed since the last call.
+    fn take_dirty(&self) -> Vec<(String, Reputation)> {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        let entries = self.entries.lock().unwrap();
+
+        dirty
+            .into_iter()
+            .filter_map(|subject| {
+                let reputation = *entries.get(&subject)?;
+                Some((subject, reputation))
+            })
+            .collect()
+    }
+}
+
+pub async fn persist(state: &web::Data<AppState>) {
+    let changed = state.reputation.take_dirty();
+    if changed.is_empty() {
+        return;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+
+    let subjects: Vec<String> = changed.iter().map(|(subject, _)| subject.clone()).collect();
+    if let Err(e) = db::run(&state.db, move |db| db.save_reputation(&changed, now)).await {
+        // Try again on the next pass.
+        state.reputation.dirty.lock().unwrap().extend(subjects);
+        warn!("Unable to save reputations: {e}");
+    }
+}
+
+/// Start the background task that periodically saves changed reputations.
+pub fn spawn_persist_worker(state: web::Data<AppState>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            sleep(PERSIST_INTERVAL).await;
+            persist(&state).await;
+        }
+    });
+}
@@ -0,0 +1,97 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::{post, web, HttpRequest};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{db, error::Error, get_client_ip, pow, AppState};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchRequest {
+    query: String,
+    /// Base64 article id to search within; searches every article when absent.
+    article: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    challenge: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchResponse {
+    code: u16,
+    status: String,
+    results: Vec<db::SearchResult>,
+    total_count: i64,
+    next_cursor: Option<i64>,
+    challenge: Option<String>,
+    key: Option<String>,
+}
+
+#[post("/comment/search/")]
+async fn search(
+    data: web::Form<SearchRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<SearchResponse>, Error> {
+    let client_ip = get_client_ip(&req);
+
+    let binding = pow::Binding::new(
+        "/comment/search/",
+        &[&data.query, data.article.as_deref().unwrap_or("")],
+    );
+    state
+        .pow
+        .handle(&client_ip, binding, &data.challenge, &data.secret)?;
+
+    let query = String::from(data.query.trim());
+    if query.is_empty() {
+        return Err(Error::BadRequest(String::from("Empty search query")));
+    }
+
+    let limit = data.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = data.offset.unwrap_or(0).max(0);
+    let article = data.article.clone().filter(|article| !article.is_empty());
+
+    info!(client_ip, query, article, "Searching comments");
+
+    let (results, total_count) = db::run(&state.db, move |db| {
+        db.search_comments(&query, article.as_deref(), limit, offset)
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Could not search comments: {e}")))?;
+
+    let next = offset + results.len() as i64;
+    Ok(web::Json(SearchResponse {
+        code: 200,
+        status: String::from("OK"),
+        results,
+        total_count,
+        next_cursor: (next < total_count).then_some(next),
+        challenge: None,
+        key: None,
+    }))
+}
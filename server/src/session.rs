@@ -0,0 +1,119 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Commenter IDs carried in a signed HttpOnly cookie, which is preferred over the ID in the request
+//! body. The widget keeps its ID too, for browsers that refuse cookies from another site. Enabled
+//! by `commenter_sessions`.
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+
+use crate::{admin::constant_time_eq, AppState};
+
+const COOKIE_NAME: &str = "tinycomments_commenter";
+
+/// Session cookies are renewed whenever a new ID is issued, so this only limits how long an
+/// unused one lasts.
+const COOKIE_MAX_AGE_DAYS: i64 = 365;
+
+/// Extracts the commenter ID from a valid session cookie, if sessions are enabled and the request
+/// carries one.
+pub struct Session(Option<String>);
+
+impl Session {
+    /// The session's commenter ID, or the one the client sent in the request body when there is no
+    /// session.
+    pub fn or(self, body_id: &str) -> String {
+        self.0.unwrap_or_else(|| String::from(body_id))
+    }
+}
+
+impl FromRequest for Session {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Session(commenter_id(req))))
+    }
+}
+
+/// The commenter ID in the request's session cookie, if its signature checks out.
+pub fn commenter_id(req: &HttpRequest) -> Option<String> {
+    let secret = secret(req.app_data::<web::Data<AppState>>()?)?;
+    let cookie = req.cookie(COOKIE_NAME)?;
+    let (id, supplied) = cookie.value().split_once('.')?;
+
+    if constant_time_eq(supplied.as_bytes(), signature(secret, id).as_bytes()) {
+        Some(String::from(id))
+    } else {
+        None
+    }
+}
+
+/// A session cookie for `commenter_id`, or `None` if sessions are disabled. It is sent with every
+/// route, versioned or not. When the widget is embedded on other origins the cookie has to be
+/// `SameSite=None` to come back with its requests, which browsers only accept on `Secure` cookies.
+pub fn cookie(
+    state: &web::Data<AppState>,
+    req: &HttpRequest,
+    commenter_id: &str,
+) -> Option<Cookie<'static>> {
+    let secret = secret(state)?;
+
+    let (same_site, secure) = if state.config.allowed_origins.is_empty() {
+        (SameSite::Lax, req.connection_info().scheme() == "https")
+    } else {
+        (SameSite::None, true)
+    };
+
+    Some(
+        Cookie::build(
+            COOKIE_NAME,
+            format!("{commenter_id}.{}", signature(secret, commenter_id)),
+        )
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(same_site)
+        .max_age(time::Duration::days(COOKIE_MAX_AGE_DAYS))
+        .finish(),
+    )
+}
+
+fn secret(state: &web::Data<AppState>) -> Option<&str> {
+    if !state.config.commenter_sessions {
+        return None;
+    }
+
+    state.config.commenter_session_secret.as_deref()
+}
+
+fn signature(secret: &str, commenter_id: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(commenter_id.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
@@ -0,0 +1,264 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Lookups of clients in public spam databases: StopForumSpam for IP addresses and emails, and
+//! DNS blocklists for IP addresses. Results are cached for `spam_list_cache_secs`, and a lookup
+//! that fails or times out counts as not listed, so an unreachable provider never blocks
+//! commenting.
+
+use actix_web::web;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::config::ConfigFile;
+use crate::AppState;
+
+const STOPFORUMSPAM_URL: &str = "https://api.stopforumspam.org/api";
+
+/// The longest to wait for any one lookup.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Expired results are cleared out once the cache holds this many.
+const MAX_CACHED: usize = 10_000;
+
+/// Recent lookup results, keyed by `ip:` or `email:` and the address. A cached `Some` names the
+/// list that had the address.
+#[derive(Default)]
+pub struct SpamLists {
+    cache: Mutex<HashMap<String, (Instant, Option<String>)>>,
+}
+
+#[derive(Deserialize)]
+struct SfsResponse {
+    success: u8,
+    ip: Option<SfsResult>,
+    email: Option<SfsResult>,
+}
+
+#[derive(Deserialize)]
+struct SfsResult {
+    appears: u8,
+    confidence: Option<f64>,
+}
+
+impl SpamLists {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn cached(&self, key: &str, ttl: Duration) -> Option<Option<String>> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(key)
+            .filter(|(at, _)| at.elapsed() < ttl)
+            .map(|(_, listing)| listing.clone())
+    }
+
+    fn store(&self, key: String, listing: Option<String>, ttl: Duration) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (at, _)| at.elapsed() < ttl);
+        }
+        cache.insert(key, (Instant::now(), listing));
+    }
+}
+
+/// Whether any lookups are configured.
+pub fn enabled(config: &ConfigFile) -> bool {
+    config.enable_stopforumspam || !config.dnsbls.is_empty()
+}
+
+/// Look up a client's IP address and, if given, their email. Returns the name of the list that
+/// has either, if one does.
+pub async fn check(
+    state: &web::Data<AppState>,
+    client_ip: &str,
+    email: Option<&str>,
+) -> Option<String> {
+    if !enabled(&state.config) {
+        return None;
+    }
+
+    let ttl = Duration::from_secs(state.config.spam_list_cache_secs.unwrap_or(3600));
+
+    if let Some(name) = reversed(client_ip) {
+        let key = format!("ip:{client_ip}");
+        let listing = match state.spam_lists.cached(&key, ttl) {
+            Some(listing) => listing,
+            None => {
+                let (sfs, dnsbl) = tokio::join!(
+                    stopforumspam(state, "ip", client_ip),
+                    dnsbls(&state.config, &name)
+                );
+                match (sfs, dnsbl) {
+                    (Ok(sfs), Ok(dnsbl)) => {
+                        let listing = sfs.or(dnsbl);
+                        state.spam_lists.store(key, listing.clone(), ttl);
+                        listing
+                    }
+                    (Ok(Some(listing)), Err(_)) | (Err(_), Ok(Some(listing))) => Some(listing),
+                    _ => None,
+                }
+            }
+        };
+        if listing.is_some() {
+            return listing;
+        }
+    }
+
+    let email = email.filter(|email| !email.is_empty() && state.config.enable_stopforumspam)?;
+    let key = format!("email:{}", email.to_lowercase());
+    if let Some(listing) = state.spam_lists.cached(&key, ttl) {
+        return listing;
+    }
+    let listing = stopforumspam(state, "email", email).await.ok()?;
+    state.spam_lists.store(key, listing.clone(), ttl);
+    listing
+}
+
+/// Ask StopForumSpam about an `ip` or `email`.
+async fn stopforumspam(
+    state: &web::Data<AppState>,
+    field: &str,
+    value: &str,
+) -> Result<Option<String>, String> {
+    if !state.config.enable_stopforumspam {
+        return Ok(None);
+    }
+
+    let res = state
+        .http
+        .get(STOPFORUMSPAM_URL)
+        .query(&[("json", ""), (field, value)])
+        .timeout(LOOKUP_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    let body = match res {
+        Ok(res) => res.json::<SfsResponse>().await,
+        Err(e) => Err(e),
+    };
+
+    let body = match body {
+        Ok(body) if body.success == 1 => body,
+        Ok(_) => {
+            warn!(field, "StopForumSpam lookup was unsuccessful");
+            return Err(String::from("unsuccessful"));
+        }
+        Err(e) => {
+            warn!(field, "StopForumSpam lookup failed: {e}");
+            return Err(e.to_string());
+        }
+    };
+
+    let min_confidence = state.config.stopforumspam_min_confidence.unwrap_or(50.0);
+    let result = if field == "ip" { body.ip } else { body.email };
+    let listed = result.is_some_and(|result| {
+        result.appears > 0 && result.confidence.unwrap_or(0.0) >= min_confidence
+    });
+
+    Ok(listed.then(|| String::from("StopForumSpam")))
+}
+
+/// Look an IP address, as [`reversed`], up in every configured DNS blocklist at once. Fails only
+/// if every lookup did.
+async fn dnsbls(config: &ConfigFile, name: &str) -> Result<Option<String>, String> {
+    if config.dnsbls.is_empty() {
+        return Ok(None);
+    }
+
+    let lookups: Vec<_> = config
+        .dnsbls
+        .iter()
+        .map(|zone| {
+            let zone = String::from(zone.trim_matches('.'));
+            let query = format!("{name}.{zone}:0");
+            actix_web::rt::spawn(async move { (zone, dnsbl_lists(&query).await) })
+        })
+        .collect();
+
+    let mut listed_by = vec![];
+    let mut failures = 0;
+    for lookup in lookups {
+        match lookup.await {
+            Ok((zone, Ok(true))) => listed_by.push(zone),
+            Ok((_, Ok(false))) => {}
+            Ok((zone, Err(e))) => {
+                warn!(zone, "DNSBL lookup failed: {e}");
+                failures += 1;
+            }
+            Err(_) => failures += 1,
+        }
+    }
+
+    if failures == config.dnsbls.len() {
+        return Err(String::from("every DNSBL lookup failed"));
+    }
+
+    debug!(name, ?listed_by, "DNSBL lookups");
+    if listed_by.len() >= config.dnsbl_min_listings.unwrap_or(1) {
+        Ok(Some(listed_by.join(", ")))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Whether the zone has an entry for the name. Listed addresses resolve into 127.0.0.0/8, and
+/// unlisted ones don't resolve at all. 127.255.255.0/24 is how Spamhaus refuses a query, such as
+/// one relayed by a public resolver, rather than a listing.
+async fn dnsbl_lists(query: &str) -> Result<bool, String> {
+    match timeout(LOOKUP_TIMEOUT, lookup_host(query)).await {
+        Err(_) => Err(String::from("timed out")),
+        Ok(Err(_)) => Ok(false),
+        Ok(Ok(mut addrs)) => Ok(addrs.any(|addr| match addr.ip() {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                octets[0] == 127 && octets[1..3] != [255, 255]
+            }
+            IpAddr::V6(_) => false,
+        })),
+    }
+}
+
+/// The name to look an address up under: its octets, or for IPv6 its nibbles, in reverse order.
+/// Loopback and private addresses are never listed, so aren't looked up at all.
+fn reversed(client_ip: &str) -> Option<String> {
+    match client_ip.parse::<IpAddr>().ok()?.to_canonical() {
+        IpAddr::V4(ip) if ip.is_loopback() || ip.is_private() => None,
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            Some(format!("{d}.{c}.{b}.{a}"))
+        }
+        IpAddr::V6(ip) if ip.is_loopback() => None,
+        IpAddr::V6(ip) => {
+            let hex = hex::encode(ip.octets());
+            let nibbles: Vec<String> = hex.chars().rev().map(String::from).collect();
+            Some(nibbles.join("."))
+        }
+    }
+}
@@ -0,0 +1,302 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Activity over time for site owners, from `/admin/stats/`. Comment and vote figures come straight
+//! from the stored comments; events that leave nothing behind, such as blocked spam and issued
+//! proof-of-work challenges, are counted per UTC day in memory and saved by the `stats` job.
+//!
+//! The same events are also totalled since startup for `/admin/metrics/`, in Prometheus' text
+//! format, so a monitoring system can scrape them and alert on rates.
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::auth::require_admin;
+use crate::error::Error;
+use crate::{base64_decode, db, AppState};
+
+/// A submission refused or discarded as spam.
+pub const SPAM_BLOCKED: &str = "spam_blocked";
+/// A proof-of-work challenge sent to a client.
+pub const POW_ISSUED: &str = "pow_issued";
+/// A proof-of-work answer that didn't check out, or named a challenge that was never issued.
+pub const POW_FAILED: &str = "pow_failed";
+/// A proof-of-work challenge answered correctly.
+pub const POW_SOLVED: &str = "pow_solved";
+/// A proof-of-work challenge answered after it expired.
+pub const POW_EXPIRED: &str = "pow_expired";
+/// A proof-of-work answer sent from a different address than the challenge was issued to.
+pub const POW_IP_MISMATCH: &str = "pow_ip_mismatch";
+
+const DAY: i64 = 24 * 60 * 60;
+const WEEK: i64 = 7 * DAY;
+/// Weeks start on Monday; the Unix epoch fell on a Thursday.
+const WEEK_OFFSET: i64 = 4 * DAY;
+
+/// What `/admin/metrics/` reports, with each metric's help text.
+const METRICS: &[(&str, &str)] = &[
+    (SPAM_BLOCKED, "Submissions refused or discarded as spam."),
+    (POW_ISSUED, "Proof-of-work challenges sent to clients."),
+    (POW_SOLVED, "Proof-of-work challenges answered correctly."),
+    (
+        POW_FAILED,
+        "Proof-of-work answers that were wrong or named an unknown challenge.",
+    ),
+    (
+        POW_EXPIRED,
+        "Proof-of-work challenges answered after they expired.",
+    ),
+    (
+        POW_IP_MISMATCH,
+        "Proof-of-work answers sent from another address than the challenge was issued to.",
+    ),
+];
+
+/// The widest range a single request may cover, in buckets.
+const MAX_BUCKETS: i64 = 1000;
+/// How many articles `top_articles` lists.
+const TOP_ARTICLES: i64 = 10;
+
+/// Event counts not yet saved, by UTC day and kind, and the totals since startup.
+#[derive(Default)]
+pub struct Counters {
+    pending: Mutex<HashMap<(i64, &'static str), i64>>,
+    totals: Mutex<HashMap<&'static str, i64>>,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, kind: &'static str) {
+        self.add(kind, 1);
+    }
+
+    pub fn add(&self, kind: &'static str, count: i64) {
+        let day = now() / DAY * DAY;
+        *self.pending.lock().unwrap().entry((day, kind)).or_insert(0) += count;
+        *self.totals.lock().unwrap().entry(kind).or_insert(0) += count;
+    }
+
+    fn take(&self) -> Vec<(i64, &'static str, i64)> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+            .into_iter()
+            .map(|((day, kind), count)| (day, kind, count))
+            .collect()
+    }
+
+    fn restore(&self, counts: Vec<(i64, &'static str, i64)>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (day, kind, count) in counts {
+            *pending.entry((day, kind)).or_insert(0) += count;
+        }
+    }
+}
+
+/// Add the counts recorded since the last call to those in the database.
+pub async fn persist(state: &web::Data<AppState>) -> Result<(), String> {
+    let counts = state.stats.take();
+    if counts.is_empty() {
+        return Ok(());
+    }
+
+    let saved = counts.clone();
+    if let Err(e) = db::run(&state.db, move |db| db.add_daily_counts(&saved)).await {
+        // Try again on the next pass.
+        state.stats.restore(counts);
+        return Err(format!("Unable to save statistics: {e}"));
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    Week,
+}
+
+impl Bucket {
+    /// Length and alignment, in seconds.
+    fn span(&self) -> (i64, i64) {
+        match self {
+            Bucket::Day => (DAY, 0),
+            Bucket::Week => (WEEK, WEEK_OFFSET),
+        }
+    }
+
+    fn start(&self, t: i64) -> i64 {
+        let (size, offset) = self.span();
+        (t - offset).div_euclid(size) * size + offset
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatsRequest {
+    /// Unix timestamps bounding the range, `to` exclusive. Defaults to the 30 days up to now.
+    from: Option<i64>,
+    to: Option<i64>,
+    #[serde(default)]
+    bucket: Bucket,
+}
+
+/// Activity in one day or week. Event counts are kept by whole UTC day, so a range starting or
+/// ending mid-day includes all of that day's events.
+#[derive(Serialize, Deserialize, Default)]
+pub struct StatsBucket {
+    /// Unix time the bucket starts.
+    start: i64,
+    /// Comments posted, published or not.
+    comments: i64,
+    spam_blocked: i64,
+    pow_issued: i64,
+    pow_solved: i64,
+    pow_failed: i64,
+    pow_expired: i64,
+    pow_ip_mismatch: i64,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    code: u16,
+    status: String,
+    from: i64,
+    to: i64,
+    bucket: Bucket,
+    series: Vec<StatsBucket>,
+    /// The articles with the most comments posted in the range, busiest first.
+    top_articles: Vec<db::ArticleActivity>,
+    /// Votes on the comments posted in the range.
+    votes: db::VoteTotals,
+}
+
+#[post("/admin/stats/")]
+async fn stats(
+    data: web::Form<StatsRequest>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<web::Json<StatsResponse>, Error> {
+    require_admin(&req)?;
+
+    let to = data.to.unwrap_or_else(now);
+    let from = data.from.unwrap_or(to - 30 * DAY);
+    let bucket = data.bucket;
+    let (size, offset) = bucket.span();
+
+    if from >= to {
+        return Err(Error::BadRequest(String::from("from must be before to")));
+    }
+    if (to - from) / size > MAX_BUCKETS {
+        return Err(Error::BadRequest(format!(
+            "At most {MAX_BUCKETS} buckets may be requested at once"
+        )));
+    }
+
+    let (comments, counts, mut top_articles, votes) = db::run(&state.db, move |db| {
+        Ok((
+            db.comments_per_bucket(from, to, size, offset)?,
+            db.daily_counts(from / DAY * DAY, to)?,
+            db.top_articles(from, to, TOP_ARTICLES)?,
+            db.vote_totals(from, to)?,
+        ))
+    })
+    .await
+    .map_err(Error::Database)?;
+
+    let first = bucket.start(from);
+    let mut series: Vec<StatsBucket> = (0..)
+        .map(|i| first + i * size)
+        .take_while(|start| *start < to)
+        .map(|start| StatsBucket {
+            start,
+            comments: comments.get(&start).copied().unwrap_or(0),
+            ..Default::default()
+        })
+        .collect();
+
+    for (day, kind, count) in counts {
+        let i = (bucket.start(day) - first) / size;
+        let Some(entry) = usize::try_from(i).ok().and_then(|i| series.get_mut(i)) else {
+            continue;
+        };
+        match kind.as_str() {
+            SPAM_BLOCKED => entry.spam_blocked += count,
+            POW_ISSUED => entry.pow_issued += count,
+            POW_SOLVED => entry.pow_solved += count,
+            POW_FAILED => entry.pow_failed += count,
+            POW_EXPIRED => entry.pow_expired += count,
+            POW_IP_MISMATCH => entry.pow_ip_mismatch += count,
+            _ => warn!("Unknown statistic {kind}"),
+        }
+    }
+
+    for article in top_articles.iter_mut() {
+        if let Some(decoded) = base64_decode(article.article.clone()) {
+            article.article = decoded;
+        }
+    }
+
+    Ok(web::Json(StatsResponse {
+        code: 200,
+        status: String::from("OK"),
+        from,
+        to,
+        bucket,
+        series,
+        top_articles,
+        votes,
+    }))
+}
+
+/// Every counter since startup, in Prometheus' text exposition format.
+#[get("/admin/metrics/")]
+async fn metrics(state: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+
+    let totals = state.stats.totals.lock().unwrap().clone();
+    let mut body = String::new();
+    for (kind, help) in METRICS {
+        let name = format!("tinycomments_{kind}_total");
+        body.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+            totals.get(kind).copied().unwrap_or(0)
+        ));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(body))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,185 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Per-thread email subscriptions. Commenters who ask to be notified of new comments when they
+//! post are emailed about each comment published on the article after theirs, with a signed link
+//! to `/subscriptions/unsubscribe/{token}` for stopping. Enabled by `enable_subscriptions`.
+
+use actix_web::http::header::ContentType;
+use actix_web::{get, post, web, HttpResponse};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::time::SystemTime;
+use tracing::info;
+
+use crate::admin::constant_time_eq;
+use crate::{db, email, AppState};
+
+const CONFIRM_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Unsubscribe</title></head>
+<body>
+<p>Stop emails about new comments on this article?</p>
+<form method="post" action=""><button type="submit">Unsubscribe</button></form>
+</body></html>"#;
+
+const DONE_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Unsubscribed</title></head>
+<body><p>You won't get any more emails about new comments on this article.</p></body></html>"#;
+
+const INVALID_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Unsubscribe</title></head>
+<body><p>This unsubscribe link is invalid, or you have already unsubscribed.</p></body></html>"#;
+
+/// Subscribe `commenter_id` to new comments on `article`, if subscriptions are enabled and they
+/// gave an email address to send them to.
+pub async fn subscribe(
+    state: &web::Data<AppState>,
+    article: &str,
+    commenter_id: &str,
+    email: &str,
+) {
+    if !state.config.enable_subscriptions || email.is_empty() {
+        return;
+    }
+
+    let created_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+
+    let (article, subscriber) = (String::from(article), String::from(commenter_id));
+    if let Err(e) = db::run(&state.db, move |db| {
+        db.add_subscription(&article, &subscriber, created_at)
+    })
+    .await
+    {
+        info!("Unable to subscribe {commenter_id}: {e}");
+    }
+}
+
+/// Email the subscribers to `article` about a newly published comment. The poster isn't told
+/// about their own comment, and neither is anyone in `notified`, the addresses that already heard
+/// about it as a reply or mention.
+pub async fn notify(
+    state: &web::Data<AppState>,
+    article: &str,
+    poster_id: &str,
+    notified: HashSet<String>,
+    url: &str,
+    poster_name: &str,
+    comment_text: &str,
+) {
+    let (Some(base), Some(secret)) = (&state.config.public_url, &state.config.unsubscribe_secret)
+    else {
+        return;
+    };
+    if !state.config.enable_subscriptions {
+        return;
+    }
+
+    let thread = String::from(article);
+    let subscribers = match db::run(&state.db, move |db| db.get_subscribers(&thread)).await {
+        Ok(subscribers) => subscribers,
+        Err(e) => {
+            info!("Unable to look up subscribers: {e}");
+            return;
+        }
+    };
+
+    let mut sent = notified;
+    for subscriber in subscribers {
+        if subscriber.email.is_empty()
+            || subscriber.commenter_id == poster_id
+            || !sent.insert(subscriber.email.to_lowercase())
+        {
+            continue;
+        }
+
+        let unsubscribe_url = format!(
+            "{}/subscriptions/unsubscribe/{}",
+            base.trim_end_matches('/'),
+            token(secret, subscriber.id)
+        );
+
+        if let Err(e) = email::send_subscription_notification(
+            state,
+            &subscriber,
+            url,
+            poster_name,
+            comment_text,
+            &unsubscribe_url,
+        )
+        .await
+        {
+            info!("Unable to send subscription notification email: {e}");
+        }
+    }
+}
+
+/// Unsubscribe links only change state on POST, so mail scanners that follow them don't
+/// unsubscribe anyone.
+#[get("/subscriptions/unsubscribe/{token}")]
+async fn confirm(path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
+    if verify(&state, &path).is_none() {
+        return page(HttpResponse::NotFound(), INVALID_PAGE);
+    }
+
+    page(HttpResponse::Ok(), CONFIRM_PAGE)
+}
+
+#[post("/subscriptions/unsubscribe/{token}")]
+async fn unsubscribe(path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
+    let Some(id) = verify(&state, &path) else {
+        return page(HttpResponse::NotFound(), INVALID_PAGE);
+    };
+
+    match db::run(&state.db, move |db| db.remove_subscription(id)).await {
+        Ok(true) => page(HttpResponse::Ok(), DONE_PAGE),
+        Ok(false) => page(HttpResponse::NotFound(), INVALID_PAGE),
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB Error: {e}")),
+    }
+}
+
+fn page(mut builder: actix_web::HttpResponseBuilder, body: &'static str) -> HttpResponse {
+    builder.content_type(ContentType::html()).body(body)
+}
+
+/// The subscription id, if `token` is one of ours.
+fn verify(state: &web::Data<AppState>, token: &str) -> Option<i64> {
+    let secret = state.config.unsubscribe_secret.as_deref()?;
+    let (id, supplied) = token.split_once('.')?;
+    let id = id.parse().ok()?;
+
+    constant_time_eq(supplied.as_bytes(), signature(secret, id).as_bytes()).then_some(id)
+}
+
+fn token(secret: &str, id: i64) -> String {
+    format!("{id}.{}", signature(secret, id))
+}
+
+fn signature(secret: &str, id: i64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("unsubscribe:{id}").as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
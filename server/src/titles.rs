@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Looks up the `<title>` of the page behind an article URL, so emails and the dashboard can name
+//! posts rather than only linking them. Titles are cached in the database, and pages are only
+//! fetched from public addresses.
+
+use actix_web::web;
+use reqwest::{header, redirect, Url};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, info};
+
+use crate::{db, html, AppState};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Only this much of a page is read looking for its title.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+const MAX_REDIRECTS: usize = 3;
+
+/// Titles longer than this many characters are truncated.
+const MAX_TITLE_CHARS: usize = 200;
+
+/// How long to wait before trying a page again after failing to get a title from it.
+const RETRY_AFTER_SECS: i64 = 24 * 60 * 60;
+
+/// The title of the page at `url`, fetching it if it isn't cached. `None` when
+/// `fetch_article_titles` is off, `url` isn't an http(s) URL, or the page has no usable title.
+pub async fn lookup(state: &web::Data<AppState>, url: &str) -> Option<String> {
+    if !state.config.fetch_article_titles {
+        return None;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or(0);
+
+    let key = String::from(url);
+    match db::run(&state.db, move |db| db.get_article_title(&key)).await {
+        Ok(Some((title, _))) if title.is_some() => return title,
+        Ok(Some((None, fetched_at))) if now - fetched_at < RETRY_AFTER_SECS => return None,
+        Ok(_) => {}
+        Err(e) => {
+            info!("Unable to read cached title for {url}: {e}");
+            return None;
+        }
+    }
+
+    let title = match fetch(url).await {
+        Ok(title) => title,
+        Err(e) => {
+            info!("Unable to fetch title for {url}: {e}");
+            None
+        }
+    };
+
+    let key = String::from(url);
+    let cached = title.clone();
+    if let Err(e) = db::run(&state.db, move |db| {
+        db.set_article_title(&key, cached.as_deref(), now)
+    })
+    .await
+    {
+        info!("Unable to cache title for {url}: {e}");
+    }
+
+    title
+}
+
+/// The cached titles of whichever of `urls` have one, without fetching anything.
+pub async fn cached(state: &web::Data<AppState>, urls: Vec<String>) -> HashMap<String, String> {
+    if !state.config.fetch_article_titles || urls.is_empty() {
+        return HashMap::new();
+    }
+
+    match db::run(&state.db, move |db| db.get_article_titles(&urls)).await {
+        Ok(titles) => titles,
+        Err(e) => {
+            info!("Unable to read cached titles: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Fetch `url` and extract its title, following a few redirects. Every host is resolved up front
+/// and the request is pinned to the checked addresses, so a name can't be re-resolved to an
+/// internal address between the check and the connection.
+async fn fetch(url: &str) -> Result<Option<String>, String> {
+    let mut url = Url::parse(url).map_err(|e| format!("Invalid URL: {e}"))?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(format!("Unsupported scheme {}", url.scheme()));
+        }
+
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            return Err(String::from("URL has no host"));
+        };
+        let host = String::from(host.trim_start_matches('[').trim_end_matches(']'));
+
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((&host[..], port))
+            .await
+            .map_err(|e| format!("Unable to resolve {host}: {e}"))?
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(format!("{host} did not resolve"));
+        }
+
+        if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+            return Err(format!(
+                "{host} resolves to non-public address {}",
+                addr.ip()
+            ));
+        }
+
+        let client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .timeout(FETCH_TIMEOUT)
+            .no_proxy()
+            .resolve_to_addrs(&host, &addrs)
+            .build()
+            .map_err(|e| format!("Unable to build client: {e}"))?;
+
+        debug!("Fetching title from {url}");
+        let mut res = client
+            .get(url.clone())
+            .header(header::ACCEPT, "text/html")
+            .send()
+            .await
+            .map_err(|e| format!("Request failed: {e}"))?;
+
+        if res.status().is_redirection() {
+            let Some(location) = res
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+            else {
+                return Err(format!("{} without a location", res.status()));
+            };
+            url = url
+                .join(location)
+                .map_err(|e| format!("Invalid redirect: {e}"))?;
+            continue;
+        }
+
+        if !res.status().is_success() {
+            return Err(format!("Status {}", res.status()));
+        }
+
+        let mut body = vec![];
+        while body.len() < MAX_BODY_BYTES {
+            match res.chunk().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(e) => return Err(format!("Unable to read body: {e}")),
+            }
+        }
+        body.truncate(MAX_BODY_BYTES);
+
+        return Ok(parse_title(&String::from_utf8_lossy(&body)));
+    }
+
+    Err(String::from("Too many redirects"))
+}
+
+/// Whether `ip` is reachable on the public internet, as opposed to loopback, private, link-local,
+/// or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_v4(ip);
+            }
+            is_public_v6(ip)
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space for carrier-grade NAT.
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments.
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        // Benchmarking.
+        || (a == 198 && (b == 18 || b == 19))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local.
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local and the deprecated site-local.
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation.
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // NAT64, which could reach any IPv4 address.
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+/// The text of the first `<title>` element, with entities decoded and whitespace collapsed.
+fn parse_title(html: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets the same, so they can be used to slice `html`.
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+
+    let title = html::decode_entities(&html[start..end])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if title.is_empty() {
+        return None;
+    }
+
+    Some(title.chars().take(MAX_TITLE_CHARS).collect())
+}
@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::web;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::AppState;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const COMMENT_CREATED: &str = "comment.created";
+pub const COMMENT_APPROVED: &str = "comment.approved";
+pub const VOTE_CAST: &str = "vote.cast";
+
+/// Every event a webhook can subscribe to.
+pub const EVENTS: [&str; 3] = [COMMENT_CREATED, COMMENT_APPROVED, VOTE_CAST];
+
+/// A signed request waiting to be sent by the delivery task.
+pub struct Delivery {
+    url: String,
+    event: &'static str,
+    req: reqwest::RequestBuilder,
+}
+
+/// Hands deliveries to the background task and counts those not yet finished, so shutdown can
+/// wait for them.
+pub struct Deliveries {
+    queue: mpsc::UnboundedSender<Delivery>,
+    pending: AtomicUsize,
+}
+
+impl Deliveries {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Delivery>) {
+        let (queue, receiver) = mpsc::unbounded_channel();
+        (
+            Deliveries {
+                queue,
+                pending: AtomicUsize::new(0),
+            },
+            receiver,
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a, T: Serialize> {
+    event: &'a str,
+    timestamp: u64,
+    data: T,
+}
+
+#[derive(Serialize)]
+pub struct CommentEvent<'a> {
+    pub id: i64,
+    pub article: &'a str,
+    pub parent: Option<i64>,
+    pub poster_name: &'a str,
+    pub comment: &'a str,
+    pub published: bool,
+}
+
+#[derive(Serialize)]
+pub struct VoteEvent {
+    pub comment_id: i64,
+    pub vote: i64,
+}
+
+/// POST `data` to every webhook subscribed to `event`. Deliveries run in the background task
+/// started by [`spawn_delivery_worker`], so a slow or unreachable endpoint never delays the
+/// request that triggered it.
+///
+/// Payloads are signed with HMAC-SHA256 using the webhook's secret; the hex digest is sent in the
+/// `X-Tinycomments-Signature` header as `sha256=<digest>`.
+pub fn dispatch<T: Serialize>(state: &web::Data<AppState>, event: &'static str, data: T) {
+    if state.config.webhooks.is_empty() {
+        return;
+    }
+
+    let timestamp = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(t) => t.as_secs(),
+        Err(_) => 0,
+    };
+
+    let body = match serde_json::to_string(&Payload {
+        event,
+        timestamp,
+        data,
+    }) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Unable to serialize {event} webhook payload: {e}");
+            return;
+        }
+    };
+
+    for hook in state.config.webhooks.iter() {
+        if !hook.events.is_empty() && !hook.events.iter().any(|e| e == event) {
+            continue;
+        }
+
+        let mut req = state
+            .http
+            .post(&hook.url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .header("content-type", "application/json")
+            .header("x-tinycomments-event", event);
+
+        if let Some(secret) = &hook.secret {
+            let mut mac =
+                HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+            mac.update(body.as_bytes());
+            let signature = hex::encode(mac.finalize().into_bytes());
+
+            req = req.header("x-tinycomments-signature", format!("sha256={signature}"));
+        }
+
+        let delivery = Delivery {
+            url: hook.url.clone(),
+            event,
+            req: req.body(body.clone()),
+        };
+
+        state.webhooks.pending.fetch_add(1, Ordering::AcqRel);
+        if state.webhooks.queue.send(delivery).is_err() {
+            state.webhooks.pending.fetch_sub(1, Ordering::AcqRel);
+            warn!("Webhook delivery task is not running; dropping {event} webhook");
+        }
+    }
+}
+
+/// Start the background task that sends webhook deliveries, each concurrently.
+pub fn spawn_delivery_worker(
+    state: web::Data<AppState>,
+    mut deliveries: mpsc::UnboundedReceiver<Delivery>,
+) {
+    actix_web::rt::spawn(async move {
+        while let Some(Delivery { url, event, req }) = deliveries.recv().await {
+            let state = state.clone();
+
+            actix_web::rt::spawn(async move {
+                match req.send().await {
+                    Ok(res) if res.status().is_success() => {
+                        debug!("Delivered {event} webhook to {url}");
+                    }
+                    Ok(res) => warn!("Webhook {url} returned {} for {event}", res.status()),
+                    Err(e) => warn!("Unable to deliver {event} webhook to {url}: {e}"),
+                }
+
+                state.webhooks.pending.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+    });
+}
+
+/// Wait for queued and in-flight deliveries to finish.
+pub async fn drain(state: &web::Data<AppState>) {
+    while state.webhooks.pending.load(Ordering::Acquire) > 0 {
+        sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A list of words and phrases that either hold a comment for moderation or are masked out of it
+//! before it is stored.
+
+use regex::{Regex, RegexBuilder};
+use std::fs;
+
+use crate::config::{ConfigFile, WordFilterAction};
+
+pub struct WordFilter {
+    pattern: Option<Regex>,
+    pub action: WordFilterAction,
+}
+
+impl WordFilter {
+    /// Load the terms from `word_filter_path`: one word or phrase per line, matched
+    /// case-insensitively on word boundaries. Blank lines and lines starting with `#` are ignored.
+    pub fn new_from_config(config: &ConfigFile) -> Result<Self, String> {
+        let action = config.word_filter_action;
+
+        let Some(path) = &config.word_filter_path else {
+            return Ok(WordFilter {
+                pattern: None,
+                action,
+            });
+        };
+
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read word filter '{path}': {e}"))?;
+
+        let mut terms: Vec<&str> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+
+        if terms.is_empty() {
+            return Ok(WordFilter {
+                pattern: None,
+                action,
+            });
+        }
+
+        // Prefer the longest match so a phrase is masked whole rather than one of its words.
+        terms.sort_by_key(|term| std::cmp::Reverse(term.len()));
+        terms.dedup();
+
+        let alternatives: Vec<String> = terms.iter().map(|term| bounded(term)).collect();
+
+        let pattern = RegexBuilder::new(&alternatives.join("|"))
+            .case_insensitive(true)
+            .size_limit(1 << 24)
+            .build()
+            .map_err(|e| format!("Unable to compile word filter '{path}': {e}"))?;
+
+        Ok(WordFilter {
+            pattern: Some(pattern),
+            action,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.pattern.as_ref().is_some_and(|re| re.is_match(text))
+    }
+
+    /// Replace every character of each filtered term with `*`.
+    pub fn mask(&self, text: &str) -> String {
+        match &self.pattern {
+            Some(re) => re
+                .replace_all(text, |caps: &regex::Captures| {
+                    "*".repeat(caps[0].chars().count())
+                })
+                .into_owned(),
+            None => text.to_string(),
+        }
+    }
+}
+
+/// Escape a term and anchor it to word boundaries. A boundary is only added next to a word
+/// character, since `\b` would never match beside punctuation at either end of the term.
+fn bounded(term: &str) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let start = if term.starts_with(is_word) { r"\b" } else { "" };
+    let end = if term.ends_with(is_word) { r"\b" } else { "" };
+
+    format!("{start}{}{end}", regex::escape(term))
+}
@@ -0,0 +1,425 @@
+/*
+ * Copyright (c) 2024 Marcus Butler
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The requests and responses of the tinycomments HTTP API, shared by the server and
+//! `tinycomments-client`.
+//!
+//! Requests are sent as form data and responses come back as JSON. Every response carries a
+//! `code` matching its HTTP status and a human-readable `status`. Errors come back as an
+//! [`ErrorResponse`] instead of the endpoint's usual response, and a 401 carries a proof-of-work
+//! challenge to solve before sending the request again with `challenge` and `secret` set.
+//!
+//! Articles are identified by their URL, base64-encoded.
+//!
+//! Endpoints are mounted under [`API_PREFIX`], e.g. `/api/v1/comment/get/`. They are also served
+//! at their old unversioned paths, which are deprecated.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Where the current version of the API is mounted, relative to the server's base URL.
+pub const API_PREFIX: &str = "/api/v1";
+
+/// The body of every error response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub code: u16,
+    pub status: String,
+    /// Set on a 401: the challenge to solve, to be sent back as `challenge`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>,
+    /// Set on a 401: the key the solution is derived from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// The id the request was logged under, also sent in the `X-Request-Id` header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// A published comment.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Comment {
+    pub id: i64,
+    pub timestamp: i64,
+    /// The comment this replies to, or 0 for a top-level comment.
+    pub parent: i64,
+    pub poster_name: String,
+    pub comment: String,
+    pub votes: i64,
+    /// The vote the requesting commenter cast on this comment: -1, 0 or 1.
+    pub myvote: i64,
+    pub edited_at: Option<i64>,
+    /// The poster signed in through an OAuth provider rather than self-asserting a name and email.
+    pub verified: bool,
+    /// Pinned by the site owner; pinned comments come before all others.
+    pub pinned: bool,
+    /// Posted by the site owner, so widgets can mark it as an official response.
+    #[serde(default)]
+    pub is_author: bool,
+    /// Voted below `collapse_below_score`, so widgets should show it folded. The comment is
+    /// otherwise returned as usual.
+    pub collapsed: bool,
+    /// Only set when Gravatar support is enabled.
+    pub avatar_url: Option<String>,
+    /// `comment` with its URLs turned into links, only set when `autolink` is enabled. Widgets
+    /// show it in place of `comment`.
+    #[serde(default)]
+    pub comment_html: Option<String>,
+    /// Used by the server to derive `avatar_url`; never sent to readers.
+    #[serde(skip)]
+    pub poster_email: String,
+    /// URLs of the images attached to the comment, in the order they were attached.
+    #[serde(default)]
+    pub attachments: Vec<String>,
+    /// Where the attachments are stored, used by the server to derive `attachments`.
+    #[serde(skip)]
+    pub attachment_names: Vec<String>,
+    /// Replies, only populated when the client asks for the tree format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub children: Option<Vec<Comment>>,
+}
+
+/// Order of the comments in a thread.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentSort {
+    #[default]
+    Oldest,
+    Newest,
+    /// Highest vote score first.
+    Top,
+}
+
+/// Shape of the `comments` array in a [`GetCommentsResponse`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentFormat {
+    /// Every comment at the top level, linked by `parent`.
+    #[default]
+    Flat,
+    /// Top-level comments with replies nested in `children`.
+    Tree,
+}
+
+/// Which votes readers may cast.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VotingMode {
+    /// Upvotes and downvotes.
+    #[default]
+    Updown,
+    /// Upvotes only, so readers can "like" a comment but not downvote it.
+    UpOnly,
+    /// No voting at all.
+    Disabled,
+}
+
+/// `/id/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdRequest {
+    pub name: String,
+    pub email: String,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdResponse {
+    pub commenter_id: String,
+    pub code: u16,
+    pub status: String,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+    /// The ID was also issued as a session cookie, which the server prefers over the ID in later
+    /// request bodies.
+    pub session: bool,
+}
+
+/// `/comment/get/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetCommentsRequest {
+    /// Left out when the commenter has a session cookie.
+    #[serde(default)]
+    pub commenter_id: String,
+    pub article: String,
+    #[serde(default)]
+    pub sort: CommentSort,
+    #[serde(default)]
+    pub format: CommentFormat,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    /// The `synced_at` of an earlier response. Only comments posted, edited, approved or pinned
+    /// since then are returned, with `vote_changes` for the rest, and `limit` and `offset` are
+    /// ignored. Comments removed in the meantime aren't reported, so clients should still fetch
+    /// the whole thread now and then.
+    pub since: Option<i64>,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetCommentsResponse {
+    pub code: u16,
+    pub status: String,
+    pub comments: Vec<Comment>,
+    pub total_count: i64,
+    pub next_cursor: Option<i64>,
+    /// When the thread was read, to send as `since` next time.
+    #[serde(default)]
+    pub synced_at: i64,
+    /// With `since`: the new scores of comments voted on since then that aren't in `comments`,
+    /// keyed by id.
+    #[serde(default)]
+    pub vote_changes: HashMap<i64, i64>,
+    /// The article is closed to new comments, so the widget should hide its comment form.
+    pub locked: bool,
+    pub voting_locked: bool,
+    /// Which vote controls the widget should offer.
+    pub voting_mode: VotingMode,
+    /// The configured comment length limits, so the widget can count characters as posting does.
+    pub max_comment_bytes: Option<usize>,
+    pub min_comment_chars: Option<usize>,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/comment/{id}/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetCommentRequest {
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+/// One comment, for linking to it directly.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetCommentResponse {
+    pub code: u16,
+    pub status: String,
+    /// The base64 id of the article the comment is on.
+    pub article: String,
+    pub comment: Comment,
+    /// The comments it replies to, starting from the top-level comment. Stops early if one of
+    /// them is no longer published.
+    pub ancestors: Vec<Comment>,
+    /// Its published replies, oldest first. Their own replies are left out.
+    pub replies: Vec<Comment>,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/comment/count/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CountCommentsRequest {
+    /// Comma-separated base64 article ids.
+    pub articles: String,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CountCommentsResponse {
+    pub code: u16,
+    pub status: String,
+    pub counts: HashMap<String, i64>,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/comment/post/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewCommentRequest {
+    pub article: String,
+    #[serde(default)]
+    pub commenter_id: String,
+    pub comment: String,
+    /// The comment this replies to, or 0 for a top-level comment.
+    pub parent: i64,
+    /// Email the commenter about later comments on the article, if the server allows it.
+    #[serde(default)]
+    pub subscribe: bool,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewCommentResponse {
+    pub code: u16,
+    /// "OK", or a message saying the comment was held for moderation.
+    pub status: String,
+    /// Names of the thread's commenters that the comment @mentions, for highlighting.
+    #[serde(default)]
+    pub mentions: Vec<String>,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/comment/edit/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditCommentRequest {
+    #[serde(default)]
+    pub commenter_id: String,
+    pub comment_id: i64,
+    pub comment: String,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditCommentResponse {
+    pub code: u16,
+    pub status: String,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/comment/delete/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteCommentRequest {
+    #[serde(default)]
+    pub commenter_id: String,
+    pub comment_id: i64,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteCommentResponse {
+    pub code: u16,
+    pub status: String,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/id/update/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateIdRequest {
+    /// Left out when the commenter has a session cookie.
+    #[serde(default)]
+    pub commenter_id: String,
+    pub name: String,
+    pub email: String,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateIdResponse {
+    pub code: u16,
+    pub status: String,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/id/info/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdInfoRequest {
+    /// Left out when the commenter has a session cookie.
+    #[serde(default)]
+    pub commenter_id: String,
+}
+
+/// What the server has stored for a commenter ID.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdInfoResponse {
+    pub code: u16,
+    pub status: String,
+    pub name: String,
+    pub email: String,
+    /// Signed in through an OAuth provider. Changing the name or email drops this.
+    pub verified: bool,
+    pub reply_notifications: bool,
+    /// How many of their comments have been published.
+    pub approved_comments: i64,
+}
+
+/// `/id/notifications/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationSettingsRequest {
+    #[serde(default)]
+    pub commenter_id: String,
+    /// Covers replies to the commenter's comments and @mentions of them.
+    pub reply_notifications: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationSettingsResponse {
+    pub code: u16,
+    pub status: String,
+}
+
+/// `/comment/vote/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VoteRequest {
+    #[serde(default)]
+    pub voter_id: String,
+    pub comment_id: i64,
+    /// -1, 1, or 0 to take back a vote.
+    pub vote: i64,
+    pub challenge: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VoteResponse {
+    pub code: u16,
+    pub status: String,
+    pub challenge: Option<String>,
+    pub key: Option<String>,
+}
+
+/// `/widget/bootstrap/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BootstrapResponse {
+    pub code: u16,
+    pub status: String,
+    /// Name of the hidden field to add to the comment form, if any.
+    pub honeypot_field: Option<String>,
+    pub voting_mode: VotingMode,
+    pub max_comment_bytes: Option<usize>,
+    pub min_comment_chars: Option<usize>,
+    /// The comment form may offer to subscribe to the thread.
+    #[serde(default)]
+    pub subscriptions: bool,
+}
+
+/// `/pow/get/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetPowResponse {
+    pub code: u16,
+    pub key: String,
+    pub challenge: String,
+    /// `hmac` or `argon2id`.
+    pub algorithm: String,
+}
+
+/// `/pow/validate/`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidatePowRequest {
+    pub challenge: String,
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidatePowResponse {
+    pub code: u16,
+    pub status: String,
+}